@@ -1,37 +1,529 @@
+use std::fmt;
+
 use serde::{Deserialize, Serialize};
 
+macro_rules! id {
+    ($(#[$meta:meta])* $name:ident) => {
+        $(#[$meta])*
+        #[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+        #[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+        #[serde(transparent)]
+        pub struct $name(pub String);
+
+        impl $name {
+            pub fn as_str(&self) -> &str {
+                &self.0
+            }
+        }
+
+        impl fmt::Display for $name {
+            fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                f.write_str(&self.0)
+            }
+        }
+
+        impl From<String> for $name {
+            fn from(id: String) -> Self {
+                $name(id)
+            }
+        }
+
+        impl From<&str> for $name {
+            fn from(id: &str) -> Self {
+                $name(id.to_owned())
+            }
+        }
+    };
+}
+
+id! {
+    /// Identifies a light, including groups and composites, which are
+    /// listed by `Enumerate` as lights of their own.
+    LightId
+}
+
+id! {
+    /// The name a group was made with, which is not the id of the light
+    /// standing in for it.
+    GroupId
+}
+
+id! {
+    /// The name a composite light was made with.
+    CompositeId
+}
+
+id! {
+    /// Identifies an input device, such as a motion sensor, by the id it
+    /// reports under.
+    SensorId
+}
+
 #[derive(Serialize, Deserialize, Debug)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
 pub enum Request {
     Enumerate,
     CheckAuth,
-    MakeGroup { lights: Vec<String>, id: String },
-    AddLightToGroup { light: String, group: String },
-    RemoveLightFromGroup { light: String, group: String },
+    MakeGroup {
+        lights: Vec<LightId>,
+        id: GroupId,
+    },
+    AddLightToGroup {
+        light: LightId,
+        group: GroupId,
+    },
+    RemoveLightFromGroup {
+        light: LightId,
+        group: GroupId,
+    },
+    RescanIntegration {
+        name: String,
+    },
+    SetGroupRole {
+        group: GroupId,
+        role: GroupRole,
+    },
+    SetGroupBrightnessMode {
+        group: GroupId,
+        mode: BrightnessMode,
+    },
+    SetPowerOnDefaults {
+        light: LightId,
+        defaults: PowerOnDefaults,
+    },
+    /// Has a light switch itself off this long after each time it is
+    /// switched on, or never if unset.
+    SetAutoOff {
+        light: LightId,
+        after_secs: Option<u64>,
+    },
+    RunScene {
+        entries: Vec<SceneEntry>,
+    },
+    /// Saves the current state of the lights as a scene, so lighting set up
+    /// by hand can be brought back with `RecallScene`.
+    SnapshotScene {
+        name: String,
+        lights: Vec<LightId>,
+    },
+    RecallScene {
+        name: String,
+    },
+    SunTimes,
+    SetTemporary {
+        light: LightId,
+        state: TemporaryState,
+        duration_secs: u64,
+    },
+    Notify {
+        lights: Vec<LightId>,
+        color: Color,
+        pattern: Pattern,
+        cycles: u32,
+    },
+    ExportState {
+        passphrase: Option<String>,
+    },
+    ImportState {
+        archive: Archive,
+        passphrase: Option<String>,
+    },
+    ListDevices,
+    SetRoom {
+        light: LightId,
+        room: Option<String>,
+    },
+    ForgetDevice {
+        light: LightId,
+    },
+    MakeComposite {
+        id: CompositeId,
+        composite: Composite,
+    },
+    /// Reverts the light's most recent change, including its power state.
+    Undo {
+        light: LightId,
+    },
+    SetPolicy {
+        light: LightId,
+        policy: Policy,
+    },
+    SetStrip {
+        light: LightId,
+        strip: StripConfig,
+    },
+    /// Latency and success rate of every device over its recent commands.
+    DeviceStats,
+    /// Assigns a device to a structure, such as "home" or "cabin", for a
+    /// bridge serving more than one.
+    SetStructure {
+        light: LightId,
+        structure: Option<String>,
+    },
+    /// Lists a device to Google or leaves it out, such as the segments of a
+    /// strip that are only controlled as a group.
+    SetExposed {
+        light: LightId,
+        exposed: bool,
+    },
+    /// The registered devices assigned to a structure.
+    ListStructure {
+        structure: String,
+    },
+    /// Provides this many simulated lights for trying the bridge without
+    /// hardware, removing them all at 0.
+    Simulate {
+        lights: u8,
+    },
+    /// Lets apps pair with the emulated Hue bridge for a while, as pressing
+    /// the link button of a real one does.
+    PressLinkButton,
+    /// Runs a compiled program, base64 encoded, on an ESP strip.
+    ProgramUpload {
+        light: LightId,
+        program: String,
+    },
+    /// Compiles a program from source and runs it on an ESP strip, failing
+    /// with the compiler's complaints if it doesn't build.
+    CompileProgram {
+        light: LightId,
+        language: ProgramLanguage,
+        source: String,
+    },
+    /// Shows a frame of raw channel levels, base64 encoded, on an ESP strip.
+    RawWrite {
+        light: LightId,
+        frame: String,
+    },
+    /// Starts a program upload in chunks, for programs too large to send
+    /// reliably at once, or resumes the one under way for the same program.
+    UploadInit {
+        light: LightId,
+        size: u64,
+        /// Hex SHA-256 of the whole program.
+        sha256: String,
+    },
+    /// Adds a base64 encoded chunk at `offset`, which must not be past what
+    /// was received.
+    UploadAppend {
+        upload: String,
+        offset: u64,
+        chunk: String,
+        /// Hex SHA-256 of the chunk.
+        sha256: String,
+    },
+    /// Runs a fully received upload on its strip.
+    UploadCommit {
+        upload: String,
+    },
+    /// Records a reading from an input device, registering it the first
+    /// time it reports.
+    ReportSensor {
+        sensor: SensorId,
+        reading: SensorReading,
+    },
+    /// Every input device the bridge has heard from.
+    ListSensors,
+    /// Lists a sensor to Google or leaves it out, which it is until asked.
+    SetSensorExposed {
+        sensor: SensorId,
+        exposed: bool,
+    },
+    /// Recent temperature and humidity readings of a climate sensor.
+    SensorHistory {
+        sensor: SensorId,
+    },
+    /// Waits for a remote to be pointed at a Broadlink RM blaster and a
+    /// button pressed, then stores what it sent under `name`, replacing any
+    /// code already stored under it.
+    LearnCode {
+        blaster: String,
+        name: String,
+        /// Sweeps for a 315 or 433 MHz remote instead of listening for
+        /// infrared, on blasters that support it.
+        #[serde(default)]
+        rf: bool,
+    },
+    /// The names of every stored IR and RF code.
+    ListCodes,
+    DeleteCode {
+        name: String,
+    },
+    /// Saves a color under a name that can be used in place of it, replacing
+    /// any color saved under the same name in any case.
+    SaveColor {
+        name: String,
+        color: Color,
+    },
+    ListColors,
+    DeleteColor {
+        name: String,
+    },
+    /// Keeps rules from changing a light for this long after each time
+    /// someone changes it, or lets them change it any time if unset.
+    SetOverrideHold {
+        light: LightId,
+        hold_secs: Option<u64>,
+    },
+    /// Lets rules change a light again before its override hold runs out.
+    ClearOverride {
+        light: LightId,
+    },
+}
+
+impl Request {
+    /// Whether the request leaves everything as it was, which makes it
+    /// allowed with the read-only guest token.
+    pub fn read_only(&self) -> bool {
+        matches!(
+            self,
+            Request::Enumerate
+                | Request::CheckAuth
+                | Request::SunTimes
+                | Request::ListDevices
+                | Request::DeviceStats
+                | Request::ListStructure { .. }
+                | Request::ListSensors
+                | Request::SensorHistory { .. }
+                | Request::ListCodes
+                | Request::ListColors
+        )
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
 pub struct EnumerateResponse {
     pub lights: Vec<Light>,
     pub groups: Vec<Group>,
+    /// Patterns usable with `Notify`.
+    #[serde(default)]
+    pub effects: Vec<Choice>,
+    /// Programs that can be run on ESP strips.
+    #[serde(default)]
+    pub programs: Vec<Choice>,
+}
+
+/// One line of the streamed enumerate at `/api/<token>/enumerate`. Lights
+/// come first, then groups, effects and programs.
+#[derive(Serialize, Deserialize, Debug)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub enum EnumerateItem {
+    Light(Light),
+    Group(Group),
+    Effect(Choice),
+    Program(Choice),
+}
+
+/// Something that can be picked for a set of lights.
+#[derive(Serialize, Deserialize, Debug)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct Choice {
+    pub name: String,
+    /// Ids of the lights it applies to.
+    pub lights: Vec<LightId>,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
 pub struct Group {
     pub name: String,
-    pub lights: Vec<String>,
+    pub lights: Vec<LightId>,
+    #[serde(default)]
+    pub role: GroupRole,
+    #[serde(default)]
+    pub brightness_mode: BrightnessMode,
+}
+
+/// How a brightness command for a group is applied to its members.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub enum BrightnessMode {
+    /// Every member is set to the requested level.
+    #[default]
+    Uniform,
+    /// Members are scaled so their average reaches the requested level,
+    /// keeping their levels relative to each other.
+    Proportional,
+}
+
+/// A virtual light driving a white light and a color light as one, such as
+/// a bulb and an RGB strip lighting the same shelf.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct Composite {
+    /// Receives white temperatures.
+    pub white: LightId,
+    /// Receives RGB colors.
+    pub color: LightId,
+    /// Share of the requested brightness each channel gets, relative to the
+    /// other. The channel with the larger weight goes to the requested level.
+    #[serde(default = "full_weight")]
+    pub white_weight: f32,
+    #[serde(default = "full_weight")]
+    pub color_weight: f32,
+}
+
+fn full_weight() -> f32 {
+    1.
+}
+
+/// What the source of a program for an ESP strip is written in.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub enum ProgramLanguage {
+    Rust,
+    AssemblyScript,
+}
+
+/// How a group is presented to Google during SYNC.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub enum GroupRole {
+    /// Only controllable through this API.
+    Hidden,
+    Exposed {
+        name: Option<String>,
+        room_hint: Option<String>,
+    },
+}
+
+impl Default for GroupRole {
+    fn default() -> Self {
+        GroupRole::Exposed {
+            name: None,
+            room_hint: None,
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
 pub enum State {
     Off,
     Rgb { red: u8, green: u8, blue: u8 },
     White { temp: u32 },
+    Mixed,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub enum Color {
+    Rgb {
+        red: u8,
+        green: u8,
+        blue: u8,
+    },
+    White {
+        temp: u32,
+    },
+    /// A color saved with `SaveColor`, such as "movie amber", looked up
+    /// whenever it is used.
+    Named {
+        name: String,
+    },
+}
+
+/// State applied whenever a light is switched on from off.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct PowerOnDefaults {
+    pub brightness: Option<u8>,
+    pub color: Option<Color>,
+}
+
+/// Restrictions on who may change a light, and when.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct Policy {
+    /// Ignores voice commands while still accepting the API.
+    #[serde(default)]
+    pub locked: bool,
+    #[serde(default)]
+    pub quiet_hours: Option<QuietHours>,
+}
+
+/// A daily window in which only the override token may change a light.
+/// Times are minutes after midnight; a window whose `start` is after its
+/// `end` runs past midnight.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct QuietHours {
+    pub start: u16,
+    pub end: u16,
+    #[serde(default)]
+    pub utc_offset_minutes: i16,
+}
+
+/// The layout of an addressable LED strip.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct StripConfig {
+    /// Number of pixels.
+    pub length: u16,
+    /// The channels of each pixel in the order the strip expects them, e.g.
+    /// `GRB`, `GRBW`, or `RGBCW` with `C` the cool white.
+    pub order: String,
+    /// The most current the strip's supply can provide, which frames are
+    /// dimmed to stay within.
+    #[serde(default)]
+    pub max_milliamps: Option<u32>,
+    /// Draw of one channel of one pixel at full level, 20 if unset.
+    #[serde(default)]
+    pub milliamps_per_channel: Option<u32>,
+}
+
+/// One light's part in a scene, started `delay_ms` after the scene begins
+/// and faded in over `transition_ms`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct SceneEntry {
+    pub light: LightId,
+    pub on: bool,
+    pub brightness: Option<u8>,
+    pub color: Option<Color>,
+    #[serde(default)]
+    pub transition_ms: u64,
+    #[serde(default)]
+    pub delay_ms: u64,
 }
 
 #[derive(Deserialize, Serialize, Debug)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
 pub struct Light {
-    pub id: String,
+    pub id: LightId,
     pub state: State,
+    /// What last changed the light, unknown until something does after the
+    /// bridge starts.
+    #[serde(default)]
+    pub source: Option<Source>,
+}
+
+/// Where a change to a light came from.
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub enum Source {
+    /// Google, a SmartThings hub or an app using the Hue bridge emulator.
+    Assistant,
+    /// A request made with the API token.
+    Api,
+    /// A request made with the override token.
+    Override,
+    Mqtt,
+    /// A PC's peripherals, mirrored through OpenRGB.
+    OpenRgb,
+    /// A rule run on a reading from `sensor`.
+    Rule {
+        sensor: String,
+    },
+    /// An auto-off timer.
+    Timer,
+    /// A seasonal effect shown on the dates it's configured for.
+    Season {
+        effect: String,
+    },
+    /// The device itself, such as a wall switch or the vendor's app.
+    Device,
 }
 
 pub struct Enumerate;
@@ -45,11 +537,12 @@ impl IntoRequest for Enumerate {
 }
 
 pub struct AddLightToGroup {
-    pub light: String,
-    pub group: String,
+    pub light: LightId,
+    pub group: GroupId,
 }
 
 #[derive(Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
 pub struct AddLightToGroupResponse;
 
 impl IntoRequest for AddLightToGroup {
@@ -64,11 +557,12 @@ impl IntoRequest for AddLightToGroup {
 }
 
 pub struct RemoveLightFromGroup {
-    pub light: String,
-    pub group: String,
+    pub light: LightId,
+    pub group: GroupId,
 }
 
 #[derive(Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
 pub struct RemoveLightFromGroupResponse;
 
 impl IntoRequest for RemoveLightFromGroup {
@@ -83,11 +577,12 @@ impl IntoRequest for RemoveLightFromGroup {
 }
 
 pub struct MakeGroup {
-    pub lights: Vec<String>,
-    pub id: String,
+    pub lights: Vec<LightId>,
+    pub id: GroupId,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
 pub struct MakeGroupResponse;
 
 impl IntoRequest for MakeGroup {
@@ -101,31 +596,1110 @@ impl IntoRequest for MakeGroup {
     }
 }
 
-#[derive(Deserialize, Serialize, Debug)]
-pub struct CheckAuthResponse;
+pub struct MakeComposite {
+    pub id: CompositeId,
+    pub composite: Composite,
+}
 
-pub struct CheckAuth;
+#[derive(Serialize, Deserialize, Debug)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct MakeCompositeResponse;
 
-impl IntoRequest for CheckAuth {
-    type Response = CheckAuthResponse;
+impl IntoRequest for MakeComposite {
+    type Response = MakeCompositeResponse;
 
     fn into_request(self) -> Request {
-        Request::CheckAuth
+        Request::MakeComposite {
+            id: self.id,
+            composite: self.composite,
+        }
     }
 }
 
-pub trait IntoRequest {
-    type Response: for<'de> Deserialize<'de>;
+pub struct RescanIntegration {
+    pub name: String,
+}
 
-    fn into_request(self) -> Request;
+#[derive(Serialize, Deserialize, Debug)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct RescanIntegrationResponse {
+    pub added: Vec<LightId>,
 }
 
-pub async fn request<T: IntoRequest>(key: &str, request: T) -> Result<T::Response, surf::Error> {
-    surf::post(format!(
-        "https://lightsmanager.syntacticsugarglider.com/api/{}",
-        key
-    ))
-    .body(surf::Body::from_json(&request.into_request())?)
-    .recv_json()
-    .await
+impl IntoRequest for RescanIntegration {
+    type Response = RescanIntegrationResponse;
+
+    fn into_request(self) -> Request {
+        Request::RescanIntegration { name: self.name }
+    }
+}
+
+pub struct SetGroupRole {
+    pub group: GroupId,
+    pub role: GroupRole,
+}
+
+#[derive(Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct SetGroupRoleResponse;
+
+impl IntoRequest for SetGroupRole {
+    type Response = SetGroupRoleResponse;
+
+    fn into_request(self) -> Request {
+        Request::SetGroupRole {
+            group: self.group,
+            role: self.role,
+        }
+    }
+}
+
+pub struct SetPowerOnDefaults {
+    pub light: LightId,
+    pub defaults: PowerOnDefaults,
+}
+
+pub struct SetGroupBrightnessMode {
+    pub group: GroupId,
+    pub mode: BrightnessMode,
+}
+
+#[derive(Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct SetGroupBrightnessModeResponse;
+
+impl IntoRequest for SetGroupBrightnessMode {
+    type Response = SetGroupBrightnessModeResponse;
+
+    fn into_request(self) -> Request {
+        Request::SetGroupBrightnessMode {
+            group: self.group,
+            mode: self.mode,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct SetPowerOnDefaultsResponse;
+
+impl IntoRequest for SetPowerOnDefaults {
+    type Response = SetPowerOnDefaultsResponse;
+
+    fn into_request(self) -> Request {
+        Request::SetPowerOnDefaults {
+            light: self.light,
+            defaults: self.defaults,
+        }
+    }
+}
+
+pub struct SetAutoOff {
+    pub light: LightId,
+    pub after_secs: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct SetAutoOffResponse;
+
+impl IntoRequest for SetAutoOff {
+    type Response = SetAutoOffResponse;
+
+    fn into_request(self) -> Request {
+        Request::SetAutoOff {
+            light: self.light,
+            after_secs: self.after_secs,
+        }
+    }
+}
+
+pub struct RunScene {
+    pub entries: Vec<SceneEntry>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct RunSceneResponse;
+
+impl IntoRequest for RunScene {
+    type Response = RunSceneResponse;
+
+    fn into_request(self) -> Request {
+        Request::RunScene {
+            entries: self.entries,
+        }
+    }
+}
+
+pub struct SnapshotScene {
+    pub name: String,
+    pub lights: Vec<LightId>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct SnapshotSceneResponse {
+    /// What was saved, also usable with `RunScene`.
+    pub entries: Vec<SceneEntry>,
+}
+
+impl IntoRequest for SnapshotScene {
+    type Response = SnapshotSceneResponse;
+
+    fn into_request(self) -> Request {
+        Request::SnapshotScene {
+            name: self.name,
+            lights: self.lights,
+        }
+    }
+}
+
+pub struct RecallScene {
+    pub name: String,
+}
+
+#[derive(Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct RecallSceneResponse;
+
+impl IntoRequest for RecallScene {
+    type Response = RecallSceneResponse;
+
+    fn into_request(self) -> Request {
+        Request::RecallScene { name: self.name }
+    }
+}
+
+pub struct SunTimes;
+
+/// Today's solar events as unix timestamps, absent when the sun doesn't
+/// cross the relevant altitude.
+#[derive(Serialize, Deserialize, Debug)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct SunTimesResponse {
+    pub dawn: Option<u64>,
+    pub sunrise: Option<u64>,
+    pub sunset: Option<u64>,
+    pub dusk: Option<u64>,
+}
+
+impl IntoRequest for SunTimes {
+    type Response = SunTimesResponse;
+
+    fn into_request(self) -> Request {
+        Request::SunTimes
+    }
+}
+
+/// A state to hold for a while before reverting to whatever it replaced.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct TemporaryState {
+    pub on: bool,
+    pub brightness: u8,
+    pub color: Option<Color>,
+}
+
+pub struct SetTemporary {
+    pub light: LightId,
+    pub state: TemporaryState,
+    pub duration_secs: u64,
+}
+
+#[derive(Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct SetTemporaryResponse;
+
+impl IntoRequest for SetTemporary {
+    type Response = SetTemporaryResponse;
+
+    fn into_request(self) -> Request {
+        Request::SetTemporary {
+            light: self.light,
+            state: self.state,
+            duration_secs: self.duration_secs,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub enum Pattern {
+    Flash,
+    Pulse,
+}
+
+pub struct Notify {
+    pub lights: Vec<LightId>,
+    pub color: Color,
+    pub pattern: Pattern,
+    pub cycles: u32,
+}
+
+#[derive(Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct NotifyResponse;
+
+impl IntoRequest for Notify {
+    type Response = NotifyResponse;
+
+    fn into_request(self) -> Request {
+        Request::Notify {
+            lights: self.lights,
+            color: self.color,
+            pattern: self.pattern,
+            cycles: self.cycles,
+        }
+    }
+}
+
+/// One stored item, its contents base64 encoded.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct ArchiveEntry {
+    pub namespace: String,
+    pub key: String,
+    pub data: String,
+}
+
+/// A snapshot of everything the server persists.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub enum Archive {
+    Plain {
+        entries: Vec<ArchiveEntry>,
+    },
+    /// The plain entries as JSON, encrypted with a key derived from a
+    /// passphrase and base64 encoded.
+    Encrypted {
+        data: String,
+    },
+}
+
+pub struct ExportState {
+    pub passphrase: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct ExportStateResponse {
+    pub archive: Archive,
+}
+
+impl IntoRequest for ExportState {
+    type Response = ExportStateResponse;
+
+    fn into_request(self) -> Request {
+        Request::ExportState {
+            passphrase: self.passphrase,
+        }
+    }
+}
+
+pub struct ImportState {
+    pub archive: Archive,
+    pub passphrase: Option<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct ImportStateResponse;
+
+impl IntoRequest for ImportState {
+    type Response = ImportStateResponse;
+
+    fn into_request(self) -> Request {
+        Request::ImportState {
+            archive: self.archive,
+            passphrase: self.passphrase,
+        }
+    }
+}
+
+/// A device the bridge has seen, connected or not.
+#[derive(Serialize, Deserialize, Debug)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct RegisteredDevice {
+    pub id: LightId,
+    pub name: String,
+    pub integration: String,
+    pub room: Option<String>,
+    #[serde(default)]
+    pub structure: Option<String>,
+    /// Whether Google is told about the device.
+    #[serde(default = "exposed")]
+    pub exposed: bool,
+    pub online: bool,
+    /// Unix timestamp of the last time its integration reported it.
+    pub last_seen: u64,
+}
+
+fn exposed() -> bool {
+    true
+}
+
+pub struct ListDevices;
+
+#[derive(Serialize, Deserialize, Debug)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct ListDevicesResponse {
+    pub devices: Vec<RegisteredDevice>,
+}
+
+impl IntoRequest for ListDevices {
+    type Response = ListDevicesResponse;
+
+    fn into_request(self) -> Request {
+        Request::ListDevices
+    }
+}
+
+pub struct SetRoom {
+    pub light: LightId,
+    pub room: Option<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct SetRoomResponse;
+
+impl IntoRequest for SetRoom {
+    type Response = SetRoomResponse;
+
+    fn into_request(self) -> Request {
+        Request::SetRoom {
+            light: self.light,
+            room: self.room,
+        }
+    }
+}
+
+pub struct SetStructure {
+    pub light: LightId,
+    pub structure: Option<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct SetStructureResponse;
+
+impl IntoRequest for SetStructure {
+    type Response = SetStructureResponse;
+
+    fn into_request(self) -> Request {
+        Request::SetStructure {
+            light: self.light,
+            structure: self.structure,
+        }
+    }
+}
+
+pub struct SetExposed {
+    pub light: LightId,
+    pub exposed: bool,
+}
+
+#[derive(Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct SetExposedResponse;
+
+impl IntoRequest for SetExposed {
+    type Response = SetExposedResponse;
+
+    fn into_request(self) -> Request {
+        Request::SetExposed {
+            light: self.light,
+            exposed: self.exposed,
+        }
+    }
+}
+
+pub struct ListStructure {
+    pub structure: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct ListStructureResponse {
+    pub devices: Vec<RegisteredDevice>,
+}
+
+impl IntoRequest for ListStructure {
+    type Response = ListStructureResponse;
+
+    fn into_request(self) -> Request {
+        Request::ListStructure {
+            structure: self.structure,
+        }
+    }
+}
+
+pub struct Simulate {
+    pub lights: u8,
+}
+
+#[derive(Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct SimulateResponse;
+
+impl IntoRequest for Simulate {
+    type Response = SimulateResponse;
+
+    fn into_request(self) -> Request {
+        Request::Simulate {
+            lights: self.lights,
+        }
+    }
+}
+
+pub struct PressLinkButton;
+
+#[derive(Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct PressLinkButtonResponse;
+
+impl IntoRequest for PressLinkButton {
+    type Response = PressLinkButtonResponse;
+
+    fn into_request(self) -> Request {
+        Request::PressLinkButton
+    }
+}
+
+pub struct ForgetDevice {
+    pub light: LightId,
+}
+
+#[derive(Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct ForgetDeviceResponse;
+
+impl IntoRequest for ForgetDevice {
+    type Response = ForgetDeviceResponse;
+
+    fn into_request(self) -> Request {
+        Request::ForgetDevice { light: self.light }
+    }
+}
+
+pub struct Undo {
+    pub light: LightId,
+}
+
+#[derive(Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct UndoResponse;
+
+impl IntoRequest for Undo {
+    type Response = UndoResponse;
+
+    fn into_request(self) -> Request {
+        Request::Undo { light: self.light }
+    }
+}
+
+pub struct SetPolicy {
+    pub light: LightId,
+    pub policy: Policy,
+}
+
+#[derive(Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct SetPolicyResponse;
+
+impl IntoRequest for SetPolicy {
+    type Response = SetPolicyResponse;
+
+    fn into_request(self) -> Request {
+        Request::SetPolicy {
+            light: self.light,
+            policy: self.policy,
+        }
+    }
+}
+
+pub struct SetStrip {
+    pub light: LightId,
+    pub strip: StripConfig,
+}
+
+#[derive(Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct SetStripResponse;
+
+impl IntoRequest for SetStrip {
+    type Response = SetStripResponse;
+
+    fn into_request(self) -> Request {
+        Request::SetStrip {
+            light: self.light,
+            strip: self.strip,
+        }
+    }
+}
+
+/// How a device's recent commands went, slowest first in
+/// `DeviceStatsResponse`.
+#[derive(Serialize, Deserialize, Debug)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct DeviceStatistics {
+    pub id: LightId,
+    pub name: String,
+    pub integration: String,
+    /// How many commands the figures cover.
+    pub commands: u32,
+    /// Fraction of them that succeeded, from 0 to 1.
+    pub success_rate: f32,
+    pub timeouts: u32,
+    /// Latency percentiles in milliseconds, `None` before any command.
+    pub p50_ms: Option<u32>,
+    pub p90_ms: Option<u32>,
+    pub p99_ms: Option<u32>,
+}
+
+pub struct DeviceStats;
+
+#[derive(Serialize, Deserialize, Debug)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct DeviceStatsResponse {
+    pub devices: Vec<DeviceStatistics>,
+}
+
+impl IntoRequest for DeviceStats {
+    type Response = DeviceStatsResponse;
+
+    fn into_request(self) -> Request {
+        Request::DeviceStats
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct CheckAuthResponse;
+
+pub struct CheckAuth;
+
+impl IntoRequest for CheckAuth {
+    type Response = CheckAuthResponse;
+
+    fn into_request(self) -> Request {
+        Request::CheckAuth
+    }
+}
+
+pub trait IntoRequest {
+    type Response: for<'de> Deserialize<'de>;
+
+    fn into_request(self) -> Request;
+}
+
+#[cfg(any(feature = "native", feature = "wasm"))]
+pub async fn request<T: IntoRequest>(key: &str, request: T) -> Result<T::Response, surf::Error> {
+    surf::post(format!(
+        "https://lightsmanager.syntacticsugarglider.com/api/{}",
+        key
+    ))
+    .body(surf::Body::from_json(&request.into_request())?)
+    .recv_json()
+    .await
+}
+
+pub struct ProgramUpload {
+    pub light: LightId,
+    pub program: String,
+}
+
+#[derive(Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct ProgramUploadResponse;
+
+impl IntoRequest for ProgramUpload {
+    type Response = ProgramUploadResponse;
+
+    fn into_request(self) -> Request {
+        Request::ProgramUpload {
+            light: self.light,
+            program: self.program,
+        }
+    }
+}
+
+pub struct CompileProgram {
+    pub light: LightId,
+    pub language: ProgramLanguage,
+    pub source: String,
+}
+
+#[derive(Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct CompileProgramResponse {
+    /// Size of the compiled program.
+    pub size: u64,
+}
+
+impl IntoRequest for CompileProgram {
+    type Response = CompileProgramResponse;
+
+    fn into_request(self) -> Request {
+        Request::CompileProgram {
+            light: self.light,
+            language: self.language,
+            source: self.source,
+        }
+    }
+}
+
+pub struct RawWrite {
+    pub light: LightId,
+    pub frame: String,
+}
+
+#[derive(Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct RawWriteResponse;
+
+impl IntoRequest for RawWrite {
+    type Response = RawWriteResponse;
+
+    fn into_request(self) -> Request {
+        Request::RawWrite {
+            light: self.light,
+            frame: self.frame,
+        }
+    }
+}
+
+pub struct UploadInit {
+    pub light: LightId,
+    pub size: u64,
+    pub sha256: String,
+}
+
+#[derive(Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct UploadInitResponse {
+    pub upload: String,
+    /// Bytes already received, from which to continue.
+    pub received: u64,
+}
+
+impl IntoRequest for UploadInit {
+    type Response = UploadInitResponse;
+
+    fn into_request(self) -> Request {
+        Request::UploadInit {
+            light: self.light,
+            size: self.size,
+            sha256: self.sha256,
+        }
+    }
+}
+
+pub struct UploadAppend {
+    pub upload: String,
+    pub offset: u64,
+    pub chunk: String,
+    pub sha256: String,
+}
+
+#[derive(Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct UploadAppendResponse {
+    pub received: u64,
+}
+
+impl IntoRequest for UploadAppend {
+    type Response = UploadAppendResponse;
+
+    fn into_request(self) -> Request {
+        Request::UploadAppend {
+            upload: self.upload,
+            offset: self.offset,
+            chunk: self.chunk,
+            sha256: self.sha256,
+        }
+    }
+}
+
+pub struct UploadCommit {
+    pub upload: String,
+}
+
+#[derive(Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct UploadCommitResponse;
+
+impl IntoRequest for UploadCommit {
+    type Response = UploadCommitResponse;
+
+    fn into_request(self) -> Request {
+        Request::UploadCommit {
+            upload: self.upload,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub enum SensorKind {
+    Motion,
+    Contact,
+    Button,
+    Climate,
+    /// Conditions outside, from a weather service rather than a device.
+    Weather,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub enum Press {
+    Click,
+    DoubleClick,
+    Hold,
+}
+
+/// What an input device reported.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub enum SensorReading {
+    Motion {
+        occupied: bool,
+    },
+    Contact {
+        open: bool,
+    },
+    /// A press of one of the buttons on a remote or wall switch, numbered
+    /// as the device numbers them.
+    Button {
+        button: u32,
+        press: Press,
+    },
+    /// Either measurement may be left out by sensors that only take one, or
+    /// that report them separately.
+    Climate {
+        celsius: Option<f32>,
+        /// Relative humidity in percent.
+        humidity: Option<f32>,
+    },
+    Weather {
+        condition: WeatherCondition,
+        celsius: Option<f32>,
+        /// Whether it is forecast to freeze soon.
+        frost: bool,
+    },
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub enum WeatherCondition {
+    Clear,
+    Cloudy,
+    Fog,
+    Drizzle,
+    Rain,
+    Snow,
+    Thunderstorm,
+}
+
+impl SensorReading {
+    pub fn kind(&self) -> SensorKind {
+        match self {
+            SensorReading::Motion { .. } => SensorKind::Motion,
+            SensorReading::Contact { .. } => SensorKind::Contact,
+            SensorReading::Button { .. } => SensorKind::Button,
+            SensorReading::Climate { .. } => SensorKind::Climate,
+            SensorReading::Weather { .. } => SensorKind::Weather,
+        }
+    }
+
+    /// Whether the reading is something that happened, such as a button
+    /// press, rather than a state that holds until the next one.
+    pub fn momentary(&self) -> bool {
+        matches!(self, SensorReading::Button { .. })
+    }
+}
+
+/// An input device the bridge has heard from.
+#[derive(Serialize, Deserialize, Debug)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct Sensor {
+    pub id: SensorId,
+    pub name: String,
+    pub kind: SensorKind,
+    pub room: Option<String>,
+    /// Whether Google is told about the sensor.
+    pub exposed: bool,
+    /// The last reading, `None` until it reports after a restart.
+    pub reading: Option<SensorReading>,
+    /// Unix timestamp of the last time it reported.
+    pub last_seen: u64,
+}
+
+pub struct ReportSensor {
+    pub sensor: SensorId,
+    pub reading: SensorReading,
+}
+
+#[derive(Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct ReportSensorResponse;
+
+impl IntoRequest for ReportSensor {
+    type Response = ReportSensorResponse;
+
+    fn into_request(self) -> Request {
+        Request::ReportSensor {
+            sensor: self.sensor,
+            reading: self.reading,
+        }
+    }
+}
+
+pub struct ListSensors;
+
+#[derive(Serialize, Deserialize, Debug)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct ListSensorsResponse {
+    pub sensors: Vec<Sensor>,
+}
+
+impl IntoRequest for ListSensors {
+    type Response = ListSensorsResponse;
+
+    fn into_request(self) -> Request {
+        Request::ListSensors
+    }
+}
+
+pub struct SetSensorExposed {
+    pub sensor: SensorId,
+    pub exposed: bool,
+}
+
+#[derive(Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct SetSensorExposedResponse;
+
+impl IntoRequest for SetSensorExposed {
+    type Response = SetSensorExposedResponse;
+
+    fn into_request(self) -> Request {
+        Request::SetSensorExposed {
+            sensor: self.sensor,
+            exposed: self.exposed,
+        }
+    }
+}
+
+/// A climate sensor's measurements at one time.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct ClimateSample {
+    /// Unix timestamp.
+    pub at: u64,
+    pub celsius: Option<f32>,
+    pub humidity: Option<f32>,
+}
+
+pub struct SensorHistory {
+    pub sensor: SensorId,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct SensorHistoryResponse {
+    /// Oldest first.
+    pub samples: Vec<ClimateSample>,
+}
+
+impl IntoRequest for SensorHistory {
+    type Response = SensorHistoryResponse;
+
+    fn into_request(self) -> Request {
+        Request::SensorHistory {
+            sensor: self.sensor,
+        }
+    }
+}
+
+pub struct LearnCode {
+    pub blaster: String,
+    pub name: String,
+    pub rf: bool,
+}
+
+#[derive(Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct LearnCodeResponse;
+
+impl IntoRequest for LearnCode {
+    type Response = LearnCodeResponse;
+
+    fn into_request(self) -> Request {
+        Request::LearnCode {
+            blaster: self.blaster,
+            name: self.name,
+            rf: self.rf,
+        }
+    }
+}
+
+pub struct ListCodes;
+
+#[derive(Serialize, Deserialize, Debug)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct ListCodesResponse {
+    /// Sorted by name.
+    pub codes: Vec<String>,
+}
+
+impl IntoRequest for ListCodes {
+    type Response = ListCodesResponse;
+
+    fn into_request(self) -> Request {
+        Request::ListCodes
+    }
+}
+
+pub struct DeleteCode {
+    pub name: String,
+}
+
+#[derive(Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct DeleteCodeResponse;
+
+impl IntoRequest for DeleteCode {
+    type Response = DeleteCodeResponse;
+
+    fn into_request(self) -> Request {
+        Request::DeleteCode { name: self.name }
+    }
+}
+
+pub struct SaveColor {
+    pub name: String,
+    pub color: Color,
+}
+
+#[derive(Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct SaveColorResponse;
+
+impl IntoRequest for SaveColor {
+    type Response = SaveColorResponse;
+
+    fn into_request(self) -> Request {
+        Request::SaveColor {
+            name: self.name,
+            color: self.color,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct NamedColor {
+    pub name: String,
+    /// Never itself `Named`.
+    pub color: Color,
+}
+
+pub struct ListColors;
+
+#[derive(Serialize, Deserialize, Debug)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct ListColorsResponse {
+    /// Sorted by name.
+    pub colors: Vec<NamedColor>,
+}
+
+impl IntoRequest for ListColors {
+    type Response = ListColorsResponse;
+
+    fn into_request(self) -> Request {
+        Request::ListColors
+    }
+}
+
+pub struct DeleteColor {
+    pub name: String,
+}
+
+#[derive(Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct DeleteColorResponse;
+
+impl IntoRequest for DeleteColor {
+    type Response = DeleteColorResponse;
+
+    fn into_request(self) -> Request {
+        Request::DeleteColor { name: self.name }
+    }
+}
+
+pub struct SetOverrideHold {
+    pub light: LightId,
+    pub hold_secs: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct SetOverrideHoldResponse;
+
+impl IntoRequest for SetOverrideHold {
+    type Response = SetOverrideHoldResponse;
+
+    fn into_request(self) -> Request {
+        Request::SetOverrideHold {
+            light: self.light,
+            hold_secs: self.hold_secs,
+        }
+    }
+}
+
+pub struct ClearOverride {
+    pub light: LightId,
+}
+
+#[derive(Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct ClearOverrideResponse;
+
+impl IntoRequest for ClearOverride {
+    type Response = ClearOverrideResponse;
+
+    fn into_request(self) -> Request {
+        Request::ClearOverride { light: self.light }
+    }
+}
+
+/// Messages on `/events` other than lights.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub enum Event {
+    Transfer(TransferProgress),
+}
+
+/// How a program transfer to a strip is going.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct TransferProgress {
+    pub light: LightId,
+    /// Bytes the strip has received.
+    pub bytes: u64,
+    pub state: TransferState,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub enum TransferState {
+    /// Waiting for a free slot or for the bandwidth caps.
+    Queued,
+    Sending,
+    Done,
+    Failed {
+        error: String,
+    },
 }
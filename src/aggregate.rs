@@ -0,0 +1,190 @@
+use std::{
+    collections::BTreeSet,
+    future::Future,
+    sync::{Arc, Mutex},
+};
+
+use async_lock::RwLock;
+use futures::future::{join_all, BoxFuture};
+use lazy_static::lazy_static;
+
+use crate::{registry::Registry, App, Brightness, Color, Error, LightError, PowerState, Role};
+
+const ALL_LIGHTS: &str = "All lights";
+
+lazy_static! {
+    /// Rooms that currently have a light of their own.
+    static ref ROOMS: Mutex<BTreeSet<String>> = Mutex::new(BTreeSet::new());
+}
+
+enum Span {
+    Everything,
+    Room(String),
+}
+
+/// Every device, or every device in a room, as one light, so turning them
+/// all off is a single command fanned out through the usual rate limits.
+/// Membership follows devices as they are found and moved between rooms.
+struct Aggregate {
+    span: Span,
+    devices: Arc<Mutex<BTreeSet<String>>>,
+    registry: Arc<Registry>,
+    exposed: bool,
+    app: Arc<RwLock<App>>,
+}
+
+impl Aggregate {
+    async fn new(app: &Arc<RwLock<App>>, span: Span, exposed: bool) -> Self {
+        let (devices, registry) = {
+            let app = app.read().await;
+            (app.devices.clone(), app.registry.clone())
+        };
+        Aggregate {
+            span,
+            devices,
+            registry,
+            exposed,
+            app: app.clone(),
+        }
+    }
+
+    /// Runs a command on every member found since startup, returning the
+    /// first failure.
+    async fn fan_out<F, R>(&self, command: F) -> Result<(), LightError>
+    where
+        F: Fn(Arc<RwLock<App>>, String) -> R,
+        R: Future<Output = Result<(), Error>>,
+    {
+        let members = {
+            let app = self.app.read().await;
+            self.member_ids()
+                .into_iter()
+                .filter(|id| app.light(id).map_or(false, |light| light.light().online()))
+                .collect::<Vec<_>>()
+        };
+        join_all(members.into_iter().map(|id| command(self.app.clone(), id)))
+            .await
+            .into_iter()
+            .find_map(|result| result.err())
+            .map_or(Ok(()), |e| Err(e.into()))
+    }
+
+    fn member_ids(&self) -> Vec<String> {
+        let devices = self.devices.lock().unwrap();
+        match &self.span {
+            Span::Everything => devices.iter().cloned().collect(),
+            Span::Room(room) => devices
+                .iter()
+                .filter(|id| {
+                    self.registry
+                        .get(id)
+                        .map_or(false, |device| device.room.as_ref() == Some(room))
+                })
+                .cloned()
+                .collect(),
+        }
+    }
+}
+
+fn room_id(room: &str) -> String {
+    format!("Room {}", room)
+}
+
+impl crate::Light for Aggregate {
+    fn name(&self) -> String {
+        match &self.span {
+            Span::Everything => ALL_LIGHTS.to_owned(),
+            Span::Room(room) => room.clone(),
+        }
+    }
+
+    fn vendor(&self) -> &'static str {
+        "group"
+    }
+
+    fn unique_id<'a>(&'a self) -> BoxFuture<'a, Result<String, LightError>> {
+        Box::pin(async move {
+            Ok(match &self.span {
+                Span::Everything => ALL_LIGHTS.to_owned(),
+                Span::Room(room) => room_id(room),
+            })
+        })
+    }
+
+    fn members(&self) -> Option<Vec<String>> {
+        Some(self.member_ids())
+    }
+
+    // Google already groups devices by their room hint, so only the light
+    // for everything is ever offered to it.
+    fn role(&self) -> Role {
+        if self.exposed {
+            Role::Named {
+                name: self.name(),
+                room_hint: None,
+            }
+        } else {
+            Role::Hidden
+        }
+    }
+
+    fn set_power_state<'a>(&'a self, state: PowerState) -> BoxFuture<'a, Result<(), LightError>> {
+        Box::pin(
+            self.fan_out(
+                move |app, id| async move { app.read().await.set_state(&id, state).await },
+            ),
+        )
+    }
+
+    fn set_brightness<'a>(
+        &'a self,
+        brightness: Brightness,
+    ) -> BoxFuture<'a, Result<(), LightError>> {
+        Box::pin(self.fan_out(move |app, id| async move {
+            app.read().await.set_brightness(&id, brightness).await
+        }))
+    }
+
+    fn set_color<'a>(&'a self, color: Color) -> BoxFuture<'a, Result<(), LightError>> {
+        Box::pin(
+            self.fan_out(
+                move |app, id| async move { app.read().await.set_color(&id, color).await },
+            ),
+        )
+    }
+}
+
+/// Adds the built-in light spanning every device, which Google only sees if
+/// `exposed` is set, along with one for each room.
+pub async fn add_all_lights(app: &Arc<RwLock<App>>, exposed: bool) {
+    let light = Aggregate::new(app, Span::Everything, exposed).await;
+    app.write().await.push_light(light).await;
+    sync_rooms(app).await;
+}
+
+/// Adds a light for each room devices are assigned to, and drops those of
+/// rooms left empty.
+pub(crate) async fn sync_rooms(app: &Arc<RwLock<App>>) {
+    let rooms = app
+        .read()
+        .await
+        .registry
+        .devices()
+        .into_iter()
+        .filter_map(|(_, device)| device.room)
+        .collect::<BTreeSet<_>>();
+    let (added, removed) = {
+        let mut current = ROOMS.lock().unwrap();
+        let added = rooms.difference(&current).cloned().collect::<Vec<_>>();
+        let removed = current.difference(&rooms).cloned().collect::<Vec<_>>();
+        *current = rooms;
+        (added, removed)
+    };
+    for room in added {
+        let light = Aggregate::new(app, Span::Room(room), false).await;
+        app.write().await.push_light(light).await;
+    }
+    for room in removed {
+        app.write().await.remove(&room_id(&room));
+    }
+}
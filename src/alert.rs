@@ -0,0 +1,88 @@
+use std::{sync::Arc, time::Duration};
+
+use async_io::Timer;
+use async_lock::RwLock;
+use futures::future::join_all;
+
+use crate::{App, Brightness, Color, Error, LightState, PowerState};
+
+const FLASH: Duration = Duration::from_millis(400);
+const PULSE_STEP: Duration = Duration::from_millis(50);
+const PULSE_STEPS: u8 = 10;
+
+#[derive(Clone, Copy, Debug)]
+pub(crate) enum Pattern {
+    /// Full brightness on, then off.
+    Flash,
+    /// Fades up to full brightness and back down.
+    Pulse,
+}
+
+async fn cycle(app: &RwLock<App>, id: &str, color: Color, pattern: Pattern) -> Result<(), Error> {
+    match pattern {
+        Pattern::Flash => {
+            let state = LightState {
+                on: true,
+                brightness: 255,
+                color: Some(color),
+            };
+            app.read().await.apply(id, state).await?;
+            Timer::after(FLASH).await;
+            app.read().await.set_state(id, PowerState::Off).await?;
+            Timer::after(FLASH).await;
+        }
+        Pattern::Pulse => {
+            let state = LightState {
+                on: true,
+                brightness: 0,
+                color: Some(color),
+            };
+            app.read().await.apply(id, state).await?;
+            let steps = (1..=PULSE_STEPS).chain((0..PULSE_STEPS).rev());
+            for step in steps {
+                let brightness = Brightness::from_scale(step as u32, PULSE_STEPS as u32);
+                app.read().await.set_brightness(id, brightness).await?;
+                Timer::after(PULSE_STEP).await;
+            }
+        }
+    }
+    Ok(())
+}
+
+async fn alert_light(
+    app: &RwLock<App>,
+    id: &str,
+    color: Color,
+    pattern: Pattern,
+    cycles: u32,
+) -> Result<(), Error> {
+    let token = app.read().await.begin_hold(id)?;
+    let mut result = Ok(());
+    for _ in 0..cycles {
+        result = cycle(app, id, color, pattern).await;
+        if result.is_err() {
+            break;
+        }
+    }
+    let app = app.read().await;
+    app.settle_hold(id, token);
+    result.and(app.release_hold(id, token).await)
+}
+
+/// Runs `pattern` on every light at once, then puts each back the way it was.
+pub(crate) async fn alert(
+    app: Arc<RwLock<App>>,
+    lights: Vec<String>,
+    color: Color,
+    pattern: Pattern,
+    cycles: u32,
+) -> Result<(), Error> {
+    join_all(
+        lights
+            .iter()
+            .map(|id| alert_light(&app, id, color, pattern, cycles)),
+    )
+    .await
+    .into_iter()
+    .collect()
+}
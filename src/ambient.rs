@@ -0,0 +1,331 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    convert::TryInto,
+    io,
+    net::UdpSocket,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use async_io::{Async, Timer};
+use async_lock::RwLock;
+use futures::future::{join_all, select};
+use serde::Deserialize;
+
+use crate::App;
+
+const MAGIC: &[u8] = b"LA";
+const VERSION: u8 = 1;
+/// Set on the last packet a client sends before it stops capturing.
+const FLAG_END: u8 = 1;
+const HEADER: usize = 14;
+
+/// Strips following the edges of a screen, from colors a capture client on
+/// the computer sends, from `ambient.toml`.
+#[derive(Deserialize)]
+pub struct AmbientConfig {
+    #[serde(default = "default_port")]
+    pub port: u16,
+    /// How much of each new color a pixel takes on per frame, from 0 to 1,
+    /// with 1 not smoothing at all.
+    #[serde(default = "default_smoothing")]
+    pub smoothing: f32,
+    /// How long the screen takes to show a frame after it is captured,
+    /// which strips wait for, less the time frames take to arrive.
+    #[serde(default)]
+    pub latency_ms: u64,
+    /// How long after the last frame strips go back to the state they were
+    /// last set to.
+    #[serde(default = "default_timeout_ms")]
+    pub timeout_ms: u64,
+    pub segments: Vec<AmbientSegment>,
+}
+
+fn default_port() -> u16 {
+    7331
+}
+
+fn default_smoothing() -> f32 {
+    0.5
+}
+
+fn default_timeout_ms() -> u64 {
+    2000
+}
+
+/// Pixels of a strip and the zones they show, which are numbered clockwise
+/// from the top left corner of the screen. A segment running against the
+/// zones, such as one along the bottom edge from left to right, lists its
+/// first zone last.
+#[derive(Deserialize)]
+pub struct AmbientSegment {
+    pub light: String,
+    #[serde(default)]
+    pub start: usize,
+    pub length: usize,
+    pub zones: (u16, u16),
+}
+
+impl AmbientSegment {
+    /// The color of each pixel, blended between the nearest zones.
+    fn colors<'a>(&'a self, zones: &'a [[f32; 3]]) -> impl Iterator<Item = [f32; 3]> + 'a {
+        let (first, last) = (self.zones.0 as f32, self.zones.1 as f32);
+        let steps = self.length.saturating_sub(1).max(1) as f32;
+        (0..self.length).map(move |pixel| {
+            let position = first + (last - first) * pixel as f32 / steps;
+            let zone = |index: f32| zones.get(index as usize).copied().unwrap_or([0., 0., 0.]);
+            let (below, above) = (zone(position.floor()), zone(position.ceil()));
+            let mix = position.fract();
+            let mut color = [0.; 3];
+            for channel in 0..3 {
+                color[channel] = below[channel] * (1. - mix) + above[channel] * mix;
+            }
+            color
+        })
+    }
+}
+
+/// A frame of edge colors. Packets are laid out as:
+///
+/// | bytes | field                                        |
+/// |-------|----------------------------------------------|
+/// | 2     | `LA`                                         |
+/// | 1     | version, 1                                   |
+/// | 1     | flags, with bit 0 set on the last packet     |
+/// | 4     | sequence number                              |
+/// | 4     | capture time in milliseconds, by any clock   |
+/// | 2     | number of zones                              |
+/// | 3 × n | red, green and blue of each zone             |
+///
+/// with numbers big endian.
+struct Packet {
+    sequence: u32,
+    captured: u32,
+    end: bool,
+    zones: Vec<[f32; 3]>,
+}
+
+fn parse(data: &[u8]) -> Option<Packet> {
+    if data.len() < HEADER || &data[..2] != MAGIC || data[2] != VERSION {
+        return None;
+    }
+    let count = u16::from_be_bytes(data[12..14].try_into().ok()?) as usize;
+    let zones = data
+        .get(HEADER..HEADER + count * 3)?
+        .chunks(3)
+        .map(|zone| [zone[0] as f32, zone[1] as f32, zone[2] as f32])
+        .collect();
+    Some(Packet {
+        sequence: u32::from_be_bytes(data[4..8].try_into().ok()?),
+        captured: u32::from_be_bytes(data[8..12].try_into().ok()?),
+        end: data[3] & FLAG_END != 0,
+        zones,
+    })
+}
+
+/// One pixel's levels in a strip's channel order, leaving white channels
+/// dark.
+fn pixel(order: &str, [r, g, b]: [f32; 3]) -> impl Iterator<Item = u8> + '_ {
+    order.chars().map(move |channel| {
+        let level = match channel.to_ascii_uppercase() {
+            'R' => r,
+            'G' => g,
+            'B' => b,
+            _ => 0.,
+        };
+        level.round().max(0.).min(255.) as u8
+    })
+}
+
+/// The client's clock relative to ours, and the frames waiting to be shown.
+struct Stream {
+    /// Our time, in milliseconds since `epoch`, minus the client's time of
+    /// the packet that arrived fastest.
+    offset: u32,
+    sequence: u32,
+    last: Instant,
+    pending: VecDeque<(Instant, Vec<[f32; 3]>)>,
+    /// The colors last shown, by zone, which new ones are blended into.
+    shown: Vec<[f32; 3]>,
+}
+
+struct Receiver {
+    config: AmbientConfig,
+    epoch: Instant,
+    stream: Option<Stream>,
+}
+
+impl Receiver {
+    fn millis(&self, now: Instant) -> u32 {
+        now.duration_since(self.epoch).as_millis() as u32
+    }
+    /// Queues a frame to be shown once the screen shows it, returning
+    /// whether the stream ended.
+    fn receive(&mut self, packet: Packet, now: Instant) -> bool {
+        if packet.end {
+            return self.stream.take().is_some();
+        }
+        let ours = self.millis(now);
+        let latency = Duration::from_millis(self.config.latency_ms);
+        let stream = self.stream.get_or_insert_with(|| Stream {
+            offset: ours.wrapping_sub(packet.captured),
+            sequence: packet.sequence.wrapping_sub(1),
+            last: now,
+            pending: VecDeque::new(),
+            shown: vec![],
+        });
+        if (packet.sequence.wrapping_sub(stream.sequence) as i32) <= 0 {
+            return false;
+        }
+        stream.sequence = packet.sequence;
+        stream.last = now;
+        // A packet that arrived faster than any before it means the others
+        // spent that much longer on the way.
+        let transit = ours.wrapping_sub(packet.captured.wrapping_add(stream.offset)) as i32;
+        if transit < 0 {
+            stream.offset = ours.wrapping_sub(packet.captured);
+        }
+        let transit = Duration::from_millis(transit.max(0) as u64);
+        let due = now + latency.checked_sub(transit).unwrap_or_default();
+        stream.pending.push_back((due, packet.zones));
+        false
+    }
+    /// The frames of every strip whose turn has come, or `None` if nothing
+    /// changed.
+    fn frames(&mut self, app: &App, now: Instant) -> Option<HashMap<String, Vec<u8>>> {
+        let smoothing = self.config.smoothing.max(0.).min(1.);
+        let stream = self.stream.as_mut()?;
+        let mut latest = None;
+        while stream.pending.front().map_or(false, |(due, _)| *due <= now) {
+            latest = stream.pending.pop_front().map(|(_, zones)| zones);
+        }
+        let zones = latest?;
+        if stream.shown.len() != zones.len() {
+            stream.shown = zones.clone();
+        }
+        for (shown, zone) in stream.shown.iter_mut().zip(&zones) {
+            for channel in 0..3 {
+                shown[channel] += (zone[channel] - shown[channel]) * smoothing;
+            }
+        }
+        let mut frames = HashMap::new();
+        for segment in &self.config.segments {
+            let strip = match app
+                .registry
+                .get(&segment.light)
+                .and_then(|device| device.strip)
+            {
+                Some(strip) => strip,
+                None => continue,
+            };
+            let width = strip.order.len();
+            let frame = frames
+                .entry(segment.light.clone())
+                .or_insert_with(|| vec![0; strip.length as usize * width]);
+            for (index, color) in segment.colors(&stream.shown).enumerate() {
+                let offset = (segment.start + index) * width;
+                if let Some(levels) = frame.get_mut(offset..offset + width) {
+                    for (level, value) in levels.iter_mut().zip(pixel(&strip.order, color)) {
+                        *level = value;
+                    }
+                }
+            }
+        }
+        Some(frames)
+    }
+    /// When the next frame is due, or when the stream times out.
+    fn wake(&self, now: Instant) -> Instant {
+        let timeout = Duration::from_millis(self.config.timeout_ms);
+        match &self.stream {
+            Some(stream) => stream
+                .pending
+                .front()
+                .map_or(stream.last + timeout, |(due, _)| *due),
+            None => now + timeout,
+        }
+    }
+    fn expired(&mut self, now: Instant) -> bool {
+        let timeout = Duration::from_millis(self.config.timeout_ms);
+        let expired = self
+            .stream
+            .as_ref()
+            .map_or(false, |stream| now.duration_since(stream.last) >= timeout);
+        if expired {
+            self.stream = None;
+        }
+        expired
+    }
+}
+
+async fn receive(app: &RwLock<App>, config: AmbientConfig) -> io::Result<()> {
+    let socket = Async::<UdpSocket>::bind(([0, 0, 0, 0], config.port))?;
+    let lights = {
+        let mut lights = config
+            .segments
+            .iter()
+            .map(|segment| segment.light.clone())
+            .collect::<Vec<_>>();
+        lights.sort();
+        lights.dedup();
+        lights
+    };
+    let mut receiver = Receiver {
+        config,
+        epoch: Instant::now(),
+        stream: None,
+    };
+    let mut failing = false;
+    let mut buf = [0; 2048];
+    loop {
+        let wake = receiver.wake(Instant::now());
+        select(Box::pin(socket.readable()), Box::pin(Timer::at(wake))).await;
+        let mut ended = false;
+        loop {
+            match socket.get_ref().recv_from(&mut buf) {
+                Ok((len, _)) => {
+                    if let Some(packet) = parse(&buf[..len]) {
+                        ended |= receiver.receive(packet, Instant::now());
+                    }
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) => return Err(e),
+            }
+        }
+        let now = Instant::now();
+        ended |= receiver.expired(now);
+        let app = app.read().await;
+        if let Some(frames) = receiver.frames(&app, now) {
+            let results = join_all(
+                frames
+                    .iter()
+                    .map(|(light, frame)| app.write_frame(light, frame)),
+            )
+            .await;
+            // Logged once each time writes start failing, not every frame.
+            match results.into_iter().find_map(Result::err) {
+                Some(e) if !failing => {
+                    eprintln!("failed to write screen colors: {}", e);
+                    failing = true;
+                }
+                Some(_) => {}
+                None => failing = false,
+            }
+        }
+        if ended {
+            for light in &lights {
+                if let Err(e) = app.reapply(light).await {
+                    eprintln!("failed to restore {} after screen capture: {}", light, e);
+                }
+            }
+        }
+    }
+}
+
+/// Receives edge colors from a screen capture client and shows them on the
+/// strip segments around the screen, until the client stops and the strips
+/// go back to their usual control.
+pub async fn ambient(app: Arc<RwLock<App>>, config: AmbientConfig) {
+    if let Err(e) = receive(&app, config).await {
+        eprintln!("screen capture input stopped: {}", e);
+    }
+}
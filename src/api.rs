@@ -1,131 +1,1057 @@
-use std::{collections::HashMap, sync::Arc};
+use std::{
+    collections::HashMap,
+    convert::Infallible,
+    sync::{self, Arc},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
 
-use futures::{future::join_all, stream::iter, StreamExt};
+use async_lock::{Mutex, RwLock};
+use futures::{
+    future::join_all,
+    stream::{iter, once, Stream},
+    StreamExt,
+};
 use lazy_static::lazy_static;
-use lights_api::{Light, Request, State};
-use smol::lock::{Mutex, RwLock};
-use warp::{filters::BoxedFilter, Filter, Reply};
+use lights_api::{
+    BrightnessMode, EnumerateItem, GroupId, GroupRole, Light, LightId, Request, Source, State,
+};
+use serde::{Deserialize, Serialize};
+use warp::{filters::BoxedFilter, http::StatusCode, hyper::Body, Filter, Reply};
 
-use crate::{App, Color};
+use crate::{
+    aggregate::sync_rooms,
+    alert::{alert, Pattern},
+    backup::{export_state, import_state},
+    compile::{compile_program, CompileError},
+    composite::{make_composite, restore_composites},
+    hue::press_link_button,
+    integrations::broadlink_rm::learn_code,
+    scene::{run_scene, snapshot, SceneEntry},
+    sensor::report_sensor,
+    storage::{storage, valid, Store},
+    temporary::hold,
+    tuya_rescan,
+    ui::{scope, Scope},
+    upload::commit_upload,
+    vault::credential,
+    App, Color, LightState, LightWrapper, Role, SolarEvent,
+};
 
 lazy_static! {
-    static ref GROUPS: Mutex<HashMap<String, Arc<Group>>> = Mutex::new(HashMap::new());
+    static ref GROUPS: Mutex<HashMap<GroupId, Arc<Group>>> = Mutex::new(HashMap::new());
 }
 
 pub fn api(app: Arc<RwLock<App>>) -> BoxedFilter<(impl Reply,)> {
     let api = warp::path!("api" / String)
         .and(warp::body::json())
-        .and_then(move |token, request: Request| {
+        .and_then({
             let app = app.clone();
-            async move {
-                Ok::<_, core::convert::Infallible>(if token == env!("API_AUTH_TOKEN") {
-                    match request {
-                        Request::Enumerate => warp::reply::json({
-                            let app = app.read().await;
-                            let lights = app
-                                .lights()
-                                .map(|item| Light {
-                                    id: item.id(),
-                                    state: if item.is_on() {
-                                        match item.rgb_color() {
-                                            Color::White { temperature } => {
-                                                State::White { temp: temperature }
-                                            }
-                                            Color::Rgb { r, g, b } => State::Rgb {
-                                                red: r,
-                                                green: g,
-                                                blue: b,
-                                            },
+            move |token: String, request: Request| {
+                let app = app.clone();
+                async move {
+                    let active = app.read().await.check_active();
+                    let mut status = StatusCode::OK;
+                    let reply = match scope(&token) {
+                        // Only the active instance may change what's stored.
+                        Some(_) if active.is_err() && !request.read_only() => {
+                            warp::reply::json(&crate::Error::Standby.to_string())
+                        }
+                        Some(scope) if scope.writable() || request.read_only() => match request {
+                            Request::Enumerate => {
+                                warp::reply::json(&enumerate(&*app.read().await).await)
+                            }
+                            Request::CheckAuth => warp::reply::json(&lights_api::CheckAuthResponse),
+                            Request::MakeGroup { lights, id } => {
+                                match make_group(&app, id, lights).await {
+                                    Ok(()) => warp::reply::json(&lights_api::MakeGroupResponse),
+                                    Err(e) => warp::reply::json(&e),
+                                }
+                            }
+                            Request::AddLightToGroup { light, group } => {
+                                match add_to_group(&group, light).await {
+                                    Ok(()) => {
+                                        warp::reply::json(&lights_api::AddLightToGroupResponse)
+                                    }
+                                    Err(e) => warp::reply::json(&e),
+                                }
+                            }
+                            Request::RescanIntegration { name } => {
+                                match rescan(&app, &name).await {
+                                    Ok(added) => {
+                                        warp::reply::json(&lights_api::RescanIntegrationResponse {
+                                            added,
+                                        })
+                                    }
+                                    Err(e) => warp::reply::json(&e),
+                                }
+                            }
+                            Request::SetGroupRole { group, role } => {
+                                match set_group_role(&*app.read().await, &group, role).await {
+                                    Ok(()) => warp::reply::json(&lights_api::SetGroupRoleResponse),
+                                    Err(e) => warp::reply::json(&e),
+                                }
+                            }
+                            Request::SetGroupBrightnessMode { group, mode } => {
+                                match set_group_brightness_mode(&group, mode).await {
+                                    Ok(()) => warp::reply::json(
+                                        &lights_api::SetGroupBrightnessModeResponse,
+                                    ),
+                                    Err(e) => warp::reply::json(&e),
+                                }
+                            }
+                            Request::SetPowerOnDefaults { light, defaults } => {
+                                let lights_api::PowerOnDefaults { brightness, color } = defaults;
+                                let app = app.read().await;
+                                let result = color
+                                    .map(|color| app.resolve_color(color))
+                                    .transpose()
+                                    .and_then(|color| {
+                                        let defaults = crate::PowerOnDefaults { brightness, color };
+                                        app.set_defaults(light.as_str(), defaults)
+                                    });
+                                match result {
+                                    Ok(()) => {
+                                        warp::reply::json(&lights_api::SetPowerOnDefaultsResponse)
+                                    }
+                                    Err(e) => warp::reply::json(&e.to_string()),
+                                }
+                            }
+                            Request::SetAutoOff { light, after_secs } => {
+                                let after = after_secs.map(Duration::from_secs);
+                                match app.read().await.set_auto_off(light.as_str(), after) {
+                                    Ok(()) => warp::reply::json(&lights_api::SetAutoOffResponse),
+                                    Err(e) => warp::reply::json(&e.to_string()),
+                                }
+                            }
+                            Request::RunScene { entries } => {
+                                let source = scope.origin().into();
+                                match start_scene(&app, entries, scope, source).await {
+                                    Ok(()) => warp::reply::json(&lights_api::RunSceneResponse),
+                                    Err(e) => warp::reply::json(&e),
+                                }
+                            }
+                            Request::SnapshotScene { name, lights } => {
+                                match snapshot_scene(&app, &name, lights).await {
+                                    Ok(entries) => {
+                                        warp::reply::json(&lights_api::SnapshotSceneResponse {
+                                            entries,
+                                        })
+                                    }
+                                    Err(e) => warp::reply::json(&e),
+                                }
+                            }
+                            Request::RecallScene { name } => match scenes().get(&name) {
+                                Ok(Some(entries)) => {
+                                    let source = scope.origin().into();
+                                    match start_scene(&app, entries, scope, source).await {
+                                        Ok(()) => {
+                                            warp::reply::json(&lights_api::RecallSceneResponse)
                                         }
-                                    } else {
-                                        State::Off
-                                    },
-                                })
-                                .collect();
-                            &lights_api::EnumerateResponse {
+                                        Err(e) => warp::reply::json(&e),
+                                    }
+                                }
+                                Ok(None) => warp::reply::json(&format!("no scene `{}`", name)),
+                                Err(e) => warp::reply::json(&e.to_string()),
+                            },
+                            Request::SunTimes => match sun_times(&*app.read().await) {
+                                Some(times) => warp::reply::json(&times),
+                                None => warp::reply::json(&"location not configured"),
+                            },
+                            Request::SetTemporary {
+                                light,
+                                state,
+                                duration_secs,
+                            } => {
+                                let lights_api::TemporaryState {
+                                    on,
+                                    brightness,
+                                    color,
+                                } = state;
+                                let color = match color {
+                                    Some(color) => app.read().await.resolve_color(color).map(Some),
+                                    None => Ok(None),
+                                };
+                                let permitted =
+                                    app.read().await.permit(light.as_str(), scope.origin());
+                                match async {
+                                    permitted?;
+                                    let state = LightState {
+                                        on,
+                                        brightness,
+                                        color: color?,
+                                    };
+                                    hold(
+                                        app.clone(),
+                                        light.0,
+                                        state,
+                                        Duration::from_secs(duration_secs),
+                                        scope.origin().into(),
+                                    )
+                                    .await
+                                }
+                                .await
+                                .map_err(|e| e.to_string())
+                                {
+                                    Ok(()) => warp::reply::json(&lights_api::SetTemporaryResponse),
+                                    Err(e) => warp::reply::json(&e),
+                                }
+                            }
+                            Request::Notify {
                                 lights,
-                                groups: iter(GROUPS.lock().await.iter())
-                                    .then(|(id, group)| async move {
-                                        lights_api::Group {
-                                            name: format!("Group {}", id),
-                                            lights: group.lights.lock().await.clone(),
+                                color: alert_color,
+                                pattern,
+                                cycles,
+                            } => {
+                                let pattern = match pattern {
+                                    lights_api::Pattern::Flash => Pattern::Flash,
+                                    lights_api::Pattern::Pulse => Pattern::Pulse,
+                                };
+                                let permitted = app
+                                    .read()
+                                    .await
+                                    .permit_all(lights.iter().map(LightId::as_str), scope.origin());
+                                match async {
+                                    permitted.map_err(|e| e.to_string())?;
+                                    let color = app
+                                        .read()
+                                        .await
+                                        .resolve_color(alert_color)
+                                        .map_err(|e| e.to_string())?;
+                                    start_alert(&app, lights, color, pattern, cycles).await
+                                }
+                                .await
+                                {
+                                    Ok(()) => warp::reply::json(&lights_api::NotifyResponse),
+                                    Err(e) => warp::reply::json(&e),
+                                }
+                            }
+                            Request::ExportState { passphrase } => {
+                                match export_state(passphrase).await {
+                                    Ok(archive) => {
+                                        warp::reply::json(&lights_api::ExportStateResponse {
+                                            archive,
+                                        })
+                                    }
+                                    Err(e) => warp::reply::json(&e.to_string()),
+                                }
+                            }
+                            Request::ImportState {
+                                archive,
+                                passphrase,
+                            } => match import_state(archive, passphrase).await {
+                                Ok(()) => {
+                                    app.write().await.reload();
+                                    restore_groups(&app).await;
+                                    warp::reply::json(&lights_api::ImportStateResponse)
+                                }
+                                Err(e) => warp::reply::json(&e.to_string()),
+                            },
+                            Request::ListDevices => {
+                                let devices = registered_devices(&*app.read().await, None);
+                                warp::reply::json(&lights_api::ListDevicesResponse { devices })
+                            }
+                            Request::ListStructure { structure } => {
+                                let devices =
+                                    registered_devices(&*app.read().await, Some(&structure));
+                                warp::reply::json(&lights_api::ListStructureResponse { devices })
+                            }
+                            Request::Simulate { lights } => {
+                                app.write().await.simulate(lights).await;
+                                warp::reply::json(&lights_api::SimulateResponse)
+                            }
+                            Request::PressLinkButton => {
+                                press_link_button();
+                                warp::reply::json(&lights_api::PressLinkButtonResponse)
+                            }
+                            Request::SetStructure { light, structure } => {
+                                match app.read().await.set_structure(light.as_str(), structure) {
+                                    Ok(()) => warp::reply::json(&lights_api::SetStructureResponse),
+                                    Err(e) => warp::reply::json(&e.to_string()),
+                                }
+                            }
+                            Request::SetExposed { light, exposed } => {
+                                match app.read().await.set_exposed(light.as_str(), exposed) {
+                                    Ok(()) => warp::reply::json(&lights_api::SetExposedResponse),
+                                    Err(e) => warp::reply::json(&e.to_string()),
+                                }
+                            }
+                            Request::SetRoom { light, room } => {
+                                let result = app.read().await.set_room(light.as_str(), room);
+                                match result {
+                                    Ok(()) => {
+                                        sync_rooms(&app).await;
+                                        warp::reply::json(&lights_api::SetRoomResponse)
+                                    }
+                                    Err(e) => warp::reply::json(&e.to_string()),
+                                }
+                            }
+                            Request::ForgetDevice { light } => {
+                                let result = app.write().await.forget(light.as_str());
+                                match result {
+                                    Ok(()) => {
+                                        sync_rooms(&app).await;
+                                        warp::reply::json(&lights_api::ForgetDeviceResponse)
+                                    }
+                                    Err(e) => warp::reply::json(&e.to_string()),
+                                }
+                            }
+                            Request::MakeComposite { id, composite } => {
+                                match make_composite(&app, id, composite).await {
+                                    Ok(()) => warp::reply::json(&lights_api::MakeCompositeResponse),
+                                    Err(e) => warp::reply::json(&e),
+                                }
+                            }
+                            Request::Undo { light } => {
+                                let app = app.read().await;
+                                match async {
+                                    app.permit(light.as_str(), scope.origin())?;
+                                    app.undo(light.as_str()).await?;
+                                    app.attribute(light.as_str(), &scope.origin().into());
+                                    Ok::<_, crate::Error>(())
+                                }
+                                .await
+                                {
+                                    Ok(()) => warp::reply::json(&lights_api::UndoResponse),
+                                    Err(e) => warp::reply::json(&e.to_string()),
+                                }
+                            }
+                            Request::SetPolicy { light, policy } => {
+                                match app.read().await.set_policy(light.as_str(), policy) {
+                                    Ok(()) => warp::reply::json(&lights_api::SetPolicyResponse),
+                                    Err(e) => warp::reply::json(&e.to_string()),
+                                }
+                            }
+                            Request::ProgramUpload { light, program } => {
+                                let result = match base64::decode(&program) {
+                                    Ok(program) => {
+                                        let transfer = app
+                                            .read()
+                                            .await
+                                            .program_transfer(light.as_str(), &program);
+                                        match transfer {
+                                            Ok(transfer) => transfer.send(&program).await,
+                                            Err(e) => Err(e),
                                         }
+                                    }
+                                    Err(e) => Err(crate::Error::Payload(e.to_string())),
+                                };
+                                match result {
+                                    Ok(()) => warp::reply::json(&lights_api::ProgramUploadResponse),
+                                    Err(e) => warp::reply::json(&e.to_string()),
+                                }
+                            }
+                            Request::CompileProgram {
+                                light,
+                                language,
+                                source,
+                            } => match compile_program(language.into(), source).await {
+                                Ok(program) => {
+                                    let transfer =
+                                        app.read().await.program_transfer(light.as_str(), &program);
+                                    let result = match transfer {
+                                        Ok(transfer) => transfer.send(&program).await,
+                                        Err(e) => Err(e),
+                                    };
+                                    match result {
+                                        Ok(()) => {
+                                            warp::reply::json(&lights_api::CompileProgramResponse {
+                                                size: program.len() as u64,
+                                            })
+                                        }
+                                        Err(e) => {
+                                            status = deploy_status(&e);
+                                            warp::reply::json(&e.to_string())
+                                        }
+                                    }
+                                }
+                                Err(e) => {
+                                    status = compile_status(&e);
+                                    warp::reply::json(&e.to_string())
+                                }
+                            },
+                            Request::RawWrite { light, frame } => {
+                                let result = match base64::decode(&frame) {
+                                    Ok(frame) => {
+                                        app.read().await.write_frame(light.as_str(), &frame).await
+                                    }
+                                    Err(e) => Err(crate::Error::Payload(e.to_string())),
+                                };
+                                match result {
+                                    Ok(()) => warp::reply::json(&lights_api::RawWriteResponse),
+                                    Err(e) => warp::reply::json(&e.to_string()),
+                                }
+                            }
+                            Request::UploadInit {
+                                light,
+                                size,
+                                sha256,
+                            } => match app.read().await.start_upload(light.as_str(), size, &sha256)
+                            {
+                                Ok((upload, received)) => {
+                                    warp::reply::json(&lights_api::UploadInitResponse {
+                                        upload,
+                                        received,
                                     })
-                                    .collect()
-                                    .await,
-                            }
-                        }),
-                        Request::CheckAuth => warp::reply::json(&lights_api::CheckAuthResponse),
-                        Request::MakeGroup { lights, id } => {
-                            let group = Arc::new(Group {
-                                name: format!("Group {}", id),
-                                lights: Mutex::new(lights),
-                                app: app.clone(),
-                                id: id.clone(),
-                            });
-                            app.write().await.push_light(group.clone()).await;
-                            GROUPS.lock().await.insert(id.clone(), group);
-                            warp::reply::json(&lights_api::MakeGroupResponse)
-                        }
-                        Request::AddLightToGroup { light, group } => {
-                            GROUPS
-                                .lock()
+                                }
+                                Err(e) => warp::reply::json(&e.to_string()),
+                            },
+                            Request::UploadAppend {
+                                upload,
+                                offset,
+                                chunk,
+                                sha256,
+                            } => match app
+                                .read()
                                 .await
-                                .get(&group)
-                                .unwrap()
-                                .lights
-                                .lock()
-                                .await
-                                .push(light);
-                            warp::reply::json(&lights_api::AddLightToGroupResponse)
-                        }
-                        Request::RemoveLightFromGroup { light, group } => {
-                            let lights = GROUPS.lock().await;
-                            let mut lights = lights.get(&group).unwrap().lights.lock().await;
-                            let idx = lights.iter().position(|item| item == &light).unwrap();
-                            lights.remove(idx);
-                            warp::reply::json(&lights_api::RemoveLightFromGroupResponse)
-                        }
-                    }
-                } else {
-                    warp::reply::json(&format!("bad auth"))
-                })
+                                .append_upload(&upload, offset, &chunk, &sha256)
+                            {
+                                Ok(received) => {
+                                    warp::reply::json(&lights_api::UploadAppendResponse {
+                                        received,
+                                    })
+                                }
+                                Err(e) => warp::reply::json(&e.to_string()),
+                            },
+                            Request::UploadCommit { upload } => {
+                                match commit_upload(&app, &upload).await {
+                                    Ok(()) => warp::reply::json(&lights_api::UploadCommitResponse),
+                                    Err(e) => warp::reply::json(&e.to_string()),
+                                }
+                            }
+                            Request::ReportSensor { sensor, reading } => {
+                                report_sensor(&app, sensor.as_str(), reading).await;
+                                warp::reply::json(&lights_api::ReportSensorResponse)
+                            }
+                            Request::ListSensors => {
+                                warp::reply::json(&lights_api::ListSensorsResponse {
+                                    sensors: app.read().await.sensors(),
+                                })
+                            }
+                            Request::SetSensorExposed { sensor, exposed } => {
+                                match app
+                                    .read()
+                                    .await
+                                    .set_sensor_exposed(sensor.as_str(), exposed)
+                                {
+                                    Ok(()) => {
+                                        warp::reply::json(&lights_api::SetSensorExposedResponse)
+                                    }
+                                    Err(e) => warp::reply::json(&e.to_string()),
+                                }
+                            }
+                            Request::SensorHistory { sensor } => {
+                                match app.read().await.climate_history(sensor.as_str()) {
+                                    Ok(samples) => {
+                                        warp::reply::json(&lights_api::SensorHistoryResponse {
+                                            samples,
+                                        })
+                                    }
+                                    Err(e) => warp::reply::json(&e.to_string()),
+                                }
+                            }
+                            Request::LearnCode { blaster, name, rf } => {
+                                match learn_code(&app, &blaster, &name, rf).await {
+                                    Ok(()) => warp::reply::json(&lights_api::LearnCodeResponse),
+                                    Err(e) => warp::reply::json(&e.to_string()),
+                                }
+                            }
+                            Request::ListCodes => match app.read().await.codes() {
+                                Ok(codes) => {
+                                    warp::reply::json(&lights_api::ListCodesResponse { codes })
+                                }
+                                Err(e) => warp::reply::json(&e.to_string()),
+                            },
+                            Request::DeleteCode { name } => {
+                                match app.read().await.delete_code(&name) {
+                                    Ok(()) => warp::reply::json(&lights_api::DeleteCodeResponse),
+                                    Err(e) => warp::reply::json(&e.to_string()),
+                                }
+                            }
+                            Request::SaveColor { name, color } => {
+                                match app.read().await.save_color(&name, color) {
+                                    Ok(()) => warp::reply::json(&lights_api::SaveColorResponse),
+                                    Err(e) => warp::reply::json(&e.to_string()),
+                                }
+                            }
+                            Request::ListColors => {
+                                warp::reply::json(&lights_api::ListColorsResponse {
+                                    colors: app.read().await.colors(),
+                                })
+                            }
+                            Request::DeleteColor { name } => {
+                                match app.read().await.delete_color(&name) {
+                                    Ok(()) => warp::reply::json(&lights_api::DeleteColorResponse),
+                                    Err(e) => warp::reply::json(&e.to_string()),
+                                }
+                            }
+                            Request::SetOverrideHold { light, hold_secs } => {
+                                let hold = hold_secs.map(Duration::from_secs);
+                                match app.read().await.set_override_hold(light.as_str(), hold) {
+                                    Ok(()) => {
+                                        warp::reply::json(&lights_api::SetOverrideHoldResponse)
+                                    }
+                                    Err(e) => warp::reply::json(&e.to_string()),
+                                }
+                            }
+                            Request::ClearOverride { light } => {
+                                match app.read().await.clear_override(light.as_str()) {
+                                    Ok(()) => warp::reply::json(&lights_api::ClearOverrideResponse),
+                                    Err(e) => warp::reply::json(&e.to_string()),
+                                }
+                            }
+                            Request::SetStrip { light, strip } => {
+                                match app.read().await.set_strip(light.as_str(), strip) {
+                                    Ok(()) => warp::reply::json(&lights_api::SetStripResponse),
+                                    Err(e) => warp::reply::json(&e.to_string()),
+                                }
+                            }
+                            Request::DeviceStats => {
+                                warp::reply::json(&lights_api::DeviceStatsResponse {
+                                    devices: app.read().await.device_stats(),
+                                })
+                            }
+                            Request::RemoveLightFromGroup { light, group } => {
+                                match remove_from_group(&group, &light).await {
+                                    Ok(()) => {
+                                        warp::reply::json(&lights_api::RemoveLightFromGroupResponse)
+                                    }
+                                    Err(e) => warp::reply::json(&e),
+                                }
+                            }
+                        },
+                        Some(_) => warp::reply::json(&format!("read-only token")),
+                        None => warp::reply::json(&format!("bad auth")),
+                    };
+                    Ok::<_, Infallible>(warp::reply::with_status(reply, status))
+                }
+            }
+        })
+        .map(Reply::into_response);
+    let stream = warp::path!("api" / String / "enumerate")
+        .and(warp::get())
+        .map(move |token: String| match scope(&token) {
+            Some(_) => {
+                let lines = enumerate_stream(app.clone()).map(|item| {
+                    let mut line = serde_json::to_vec(&item).unwrap();
+                    line.push(b'\n');
+                    Ok::<_, Infallible>(line)
+                });
+                warp::http::Response::builder()
+                    .header("content-type", "application/x-ndjson")
+                    .body(Body::wrap_stream(lines))
+                    .unwrap()
+                    .into_response()
             }
+            None => warp::reply::json(&format!("bad auth")).into_response(),
         });
-    api.boxed()
+    api.or(stream).unify().boxed()
+}
+
+fn compile_status(error: &CompileError) -> StatusCode {
+    match error {
+        CompileError::TooLarge => StatusCode::PAYLOAD_TOO_LARGE,
+        CompileError::Io(_) | CompileError::Unsandboxed => StatusCode::INTERNAL_SERVER_ERROR,
+        CompileError::Forbidden(_)
+        | CompileError::TimedOut
+        | CompileError::Failed(_)
+        | CompileError::Abi(_) => StatusCode::UNPROCESSABLE_ENTITY,
+    }
+}
+
+fn deploy_status(error: &crate::Error) -> StatusCode {
+    match error {
+        crate::Error::Absent => StatusCode::NOT_FOUND,
+        crate::Error::Payload(_) => StatusCode::PAYLOAD_TOO_LARGE,
+        crate::Error::Unsupported => StatusCode::UNPROCESSABLE_ENTITY,
+        crate::Error::Policy(_) => StatusCode::FORBIDDEN,
+        crate::Error::Standby => StatusCode::SERVICE_UNAVAILABLE,
+        _ => StatusCode::BAD_GATEWAY,
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct StoredGroup {
+    lights: Vec<LightId>,
+    #[serde(default)]
+    role: GroupRole,
+    #[serde(default)]
+    brightness_mode: BrightnessMode,
+}
+
+fn groups() -> Store<StoredGroup> {
+    storage().store("groups")
+}
+
+fn persist(group: &Group) {
+    let stored = StoredGroup {
+        lights: group.lights.lock().unwrap().clone(),
+        role: group.role.lock().unwrap().clone(),
+        brightness_mode: *group.brightness_mode.lock().unwrap(),
+    };
+    if let Err(e) = groups().put(group.id.as_str(), &stored) {
+        eprintln!("failed to persist group `{}`: {}", group.id, e);
+    }
+}
+
+async fn insert_group(app: &Arc<RwLock<App>>, id: GroupId, stored: StoredGroup) -> Arc<Group> {
+    let group = Arc::new(Group {
+        name: format!("Group {}", id),
+        lights: sync::Mutex::new(stored.lights),
+        role: sync::Mutex::new(stored.role),
+        brightness_mode: sync::Mutex::new(stored.brightness_mode),
+        app: app.clone(),
+        id: id.clone(),
+    });
+    app.write().await.push_light(group.clone()).await;
+    GROUPS.lock().await.insert(id, group.clone());
+    group
+}
+
+/// Recreates the groups and composite lights saved by earlier runs.
+pub async fn restore_groups(app: &Arc<RwLock<App>>) {
+    restore_composites(app).await;
+    let store = groups();
+    let ids = match store.keys() {
+        Ok(ids) => ids,
+        Err(e) => {
+            eprintln!("failed to list stored groups: {}", e);
+            return;
+        }
+    };
+    for id in ids {
+        match store.get(&id) {
+            Ok(Some(stored)) => {
+                insert_group(app, GroupId(id), stored).await;
+            }
+            Ok(None) => {}
+            Err(e) => eprintln!("failed to load group `{}`: {}", id, e),
+        }
+    }
+}
+
+pub(crate) async fn make_group(
+    app: &Arc<RwLock<App>>,
+    id: GroupId,
+    lights: Vec<LightId>,
+) -> Result<(), String> {
+    if !valid(id.as_str()) {
+        return Err(format!("invalid group id `{}`", id));
+    }
+    let stored = StoredGroup {
+        lights,
+        role: GroupRole::default(),
+        brightness_mode: BrightnessMode::default(),
+    };
+    persist(&*insert_group(app, id, stored).await);
+    Ok(())
+}
+
+fn unknown_group(group: &GroupId) -> String {
+    format!("unknown group `{}`", group)
+}
+
+pub(crate) async fn add_to_group(group: &GroupId, light: LightId) -> Result<(), String> {
+    let groups = GROUPS.lock().await;
+    let group = groups.get(group).ok_or_else(|| unknown_group(group))?;
+    group.lights.lock().unwrap().push(light);
+    persist(group);
+    Ok(())
+}
+
+pub(crate) async fn remove_from_group(group: &GroupId, light: &LightId) -> Result<(), String> {
+    let groups = GROUPS.lock().await;
+    let entry = groups.get(group).ok_or_else(|| unknown_group(group))?;
+    {
+        let mut lights = entry.lights.lock().unwrap();
+        let idx = lights
+            .iter()
+            .position(|item| item == light)
+            .ok_or_else(|| format!("`{}` is not in group `{}`", light, group))?;
+        lights.remove(idx);
+    }
+    persist(entry);
+    Ok(())
+}
+
+pub(crate) async fn set_group_role(
+    app: &App,
+    group: &GroupId,
+    role: GroupRole,
+) -> Result<(), String> {
+    let groups = GROUPS.lock().await;
+    let group = groups.get(group).ok_or_else(|| unknown_group(group))?;
+    *group.role.lock().unwrap() = role;
+    persist(group);
+    app.sync.schedule();
+    Ok(())
+}
+
+pub(crate) async fn set_group_brightness_mode(
+    group: &GroupId,
+    mode: BrightnessMode,
+) -> Result<(), String> {
+    let groups = GROUPS.lock().await;
+    let group = groups.get(group).ok_or_else(|| unknown_group(group))?;
+    *group.brightness_mode.lock().unwrap() = mode;
+    persist(group);
+    Ok(())
+}
+
+/// Asks an integration for its current devices and adds any that aren't
+/// already known or have stopped responding, returning their ids.
+pub(crate) async fn rescan(app: &RwLock<App>, name: &str) -> Result<Vec<LightId>, String> {
+    let lights = match name {
+        "tuya" => match (credential("TUYA_USER"), credential("TUYA_PASS")) {
+            (Some(user), Some(pass)) => tuya_rescan(user, pass).await.map_err(|e| e.to_string()),
+            _ => Err("tuya credentials not configured".to_owned()),
+        },
+        _ => Err(format!("unknown integration `{}`", name)),
+    }?;
+    let mut app = app.write().await;
+    let mut added = vec![];
+    for light in lights {
+        if let Ok(id) = crate::Light::unique_id(&light).await {
+            if app.light(&id).map_or(true, |known| !known.online()) {
+                app.push_light(light).await;
+                added.push(LightId(id));
+            }
+        }
+    }
+    Ok(added)
+}
+
+pub(crate) fn scenes() -> Store<Vec<lights_api::SceneEntry>> {
+    storage().store("scenes")
+}
+
+pub(crate) async fn start_scene(
+    app: &Arc<RwLock<App>>,
+    entries: Vec<lights_api::SceneEntry>,
+    scope: Scope,
+    source: Source,
+) -> Result<(), String> {
+    let entries = {
+        let app = app.read().await;
+        entries
+            .into_iter()
+            .map(|entry| {
+                Ok(SceneEntry {
+                    light: entry.light.0,
+                    on: entry.on,
+                    brightness: entry.brightness,
+                    color: entry
+                        .color
+                        .map(|color| app.resolve_color(color))
+                        .transpose()?,
+                    transition: Duration::from_millis(entry.transition_ms),
+                    delay: Duration::from_millis(entry.delay_ms),
+                })
+            })
+            .collect::<Result<Vec<_>, crate::Error>>()
+            .map_err(|e| e.to_string())?
+    };
+    let lights = entries.iter().map(|entry| entry.light.as_str());
+    let permitted = app.read().await.permit_all(lights, scope.origin());
+    async {
+        permitted?;
+        run_scene(app.clone(), entries, source).await
+    }
+    .await
+    .map_err(|e| e.to_string())
+}
+
+async fn snapshot_scene(
+    app: &Arc<RwLock<App>>,
+    name: &str,
+    lights: Vec<LightId>,
+) -> Result<Vec<lights_api::SceneEntry>, String> {
+    if !valid(name) {
+        return Err(format!("invalid scene name `{}`", name));
+    }
+    let lights = lights.into_iter().map(|light| light.0).collect::<Vec<_>>();
+    let entries = snapshot(app, &lights)
+        .await
+        .map_err(|e| e.to_string())?
+        .into_iter()
+        .map(|entry| lights_api::SceneEntry {
+            light: LightId(entry.light),
+            on: entry.on,
+            brightness: entry.brightness,
+            color: entry.color.map(api_color),
+            transition_ms: 0,
+            delay_ms: 0,
+        })
+        .collect::<Vec<_>>();
+    scenes().put(name, &entries).map_err(|e| e.to_string())?;
+    Ok(entries)
+}
+
+pub(crate) fn sun_times(app: &App) -> Option<lights_api::SunTimesResponse> {
+    let location = app.location()?;
+    let now = SystemTime::now();
+    let at = |event| {
+        location
+            .event_on(event, now)
+            .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+            .map(|time| time.as_secs())
+    };
+    Some(lights_api::SunTimesResponse {
+        dawn: at(SolarEvent::Dawn),
+        sunrise: at(SolarEvent::Sunrise),
+        sunset: at(SolarEvent::Sunset),
+        dusk: at(SolarEvent::Dusk),
+    })
+}
+
+/// Checks that every light exists, then runs the alert in the background.
+pub(crate) async fn start_alert(
+    app: &Arc<RwLock<App>>,
+    lights: Vec<LightId>,
+    color: Color,
+    pattern: Pattern,
+    cycles: u32,
+) -> Result<(), String> {
+    let lights = lights.into_iter().map(|light| light.0).collect::<Vec<_>>();
+    {
+        let app = app.read().await;
+        if let Some(id) = lights.iter().find(|id| app.light(id).is_none()) {
+            return Err(format!("unknown light `{}`", id));
+        }
+    }
+    let task = alert(app.clone(), lights, color, pattern, cycles);
+    app.read().await.spawner.spawn(Box::pin(async move {
+        if let Err(e) = task.await {
+            eprintln!("alert failed: {:?}", e);
+        }
+    }));
+    Ok(())
+}
+
+pub(crate) fn api_color(color: Color) -> lights_api::Color {
+    match color {
+        Color::Rgb { r, g, b } => lights_api::Color::Rgb {
+            red: r,
+            green: g,
+            blue: b,
+        },
+        Color::White { temperature } => lights_api::Color::White { temp: temperature },
+    }
+}
+
+pub(crate) fn light_state(app: &App, light: &LightWrapper) -> Light {
+    let state = app.state(light);
+    Light {
+        id: LightId(light.id()),
+        state: if state.on {
+            match state.color {
+                Some(Color::White { temperature }) => State::White { temp: temperature },
+                Some(Color::Rgb { r, g, b }) => State::Rgb {
+                    red: r,
+                    green: g,
+                    blue: b,
+                },
+                None => State::Mixed,
+            }
+        } else {
+            State::Off
+        },
+        source: app.source(light),
+    }
+}
+
+/// Every registered device, or those assigned to `structure`.
+fn registered_devices(app: &App, structure: Option<&str>) -> Vec<lights_api::RegisteredDevice> {
+    app.registry
+        .devices()
+        .into_iter()
+        .filter(|(_, device)| {
+            structure.map_or(true, |structure| {
+                device.structure.as_deref() == Some(structure)
+            })
+        })
+        .map(|(id, device)| lights_api::RegisteredDevice {
+            online: app.light(&id).map_or(false, |known| known.online()),
+            id: LightId(id),
+            name: device.name,
+            integration: device.vendor,
+            room: device.room,
+            structure: device.structure,
+            exposed: device.exposed,
+            last_seen: device.last_seen,
+        })
+        .collect()
+}
+
+fn effects(app: &App) -> Vec<lights_api::Choice> {
+    let all = app
+        .lights()
+        .map(|light| LightId(light.id()))
+        .collect::<Vec<_>>();
+    [Pattern::Flash, Pattern::Pulse]
+        .iter()
+        .map(|pattern| lights_api::Choice {
+            name: format!("{:?}", pattern),
+            lights: all.clone(),
+        })
+        .collect()
+}
+
+fn programs(app: &App) -> Vec<lights_api::Choice> {
+    let strips = app
+        .lights()
+        .filter(|light| light.light().vendor() == "esp")
+        .map(|light| LightId(light.id()))
+        .collect::<Vec<_>>();
+    let programs = storage().blobs("programs").keys().unwrap_or_default();
+    programs
+        .into_iter()
+        .map(|name| lights_api::Choice {
+            name,
+            lights: strips.clone(),
+        })
+        .collect()
+}
+
+async fn group_list() -> Vec<lights_api::Group> {
+    iter(GROUPS.lock().await.iter())
+        .then(|(id, group)| async move {
+            lights_api::Group {
+                name: format!("Group {}", id),
+                lights: group.lights.lock().unwrap().clone(),
+                role: group.role.lock().unwrap().clone(),
+                brightness_mode: *group.brightness_mode.lock().unwrap(),
+            }
+        })
+        .collect()
+        .await
+}
+
+pub(crate) async fn enumerate(app: &App) -> lights_api::EnumerateResponse {
+    lights_api::EnumerateResponse {
+        effects: effects(app),
+        programs: programs(app),
+        lights: app.lights().map(|light| light_state(app, light)).collect(),
+        groups: group_list().await,
+    }
+}
+
+/// `Enumerate` one item at a time, so lights are sent before waiting on the
+/// group lock.
+fn enumerate_stream(app: Arc<RwLock<App>>) -> impl Stream<Item = EnumerateItem> {
+    let lights = {
+        let app = app.clone();
+        async move {
+            let app = app.read().await;
+            app.lights()
+                .map(|light| EnumerateItem::Light(light_state(&app, light)))
+                .collect::<Vec<_>>()
+        }
+    };
+    let groups = async {
+        group_list()
+            .await
+            .into_iter()
+            .map(EnumerateItem::Group)
+            .collect()
+    };
+    let choices = async move {
+        let app = app.read().await;
+        effects(&app)
+            .into_iter()
+            .map(EnumerateItem::Effect)
+            .chain(programs(&app).into_iter().map(EnumerateItem::Program))
+            .collect()
+    };
+    once(lights)
+        .chain(once(groups))
+        .chain(once(choices))
+        .flat_map(iter)
 }
 
 pub struct Group {
     name: String,
-    lights: Mutex<Vec<String>>,
-    id: String,
+    lights: sync::Mutex<Vec<LightId>>,
+    role: sync::Mutex<GroupRole>,
+    brightness_mode: sync::Mutex<BrightnessMode>,
+    id: GroupId,
     app: Arc<RwLock<App>>,
 }
 
+impl Group {
+    /// The level each member should go to for a group brightness command.
+    async fn brightness_targets(&self, brightness: u8) -> Vec<(LightId, u8)> {
+        let members = self.lights.lock().unwrap().clone();
+        let mode = *self.brightness_mode.lock().unwrap();
+        let app = self.app.read().await;
+        let current = members
+            .iter()
+            .map(|id| app.light(id.as_str()).map_or(0, |light| light.brightness()))
+            .collect::<Vec<_>>();
+        let average =
+            current.iter().map(|level| *level as f32).sum::<f32>() / current.len().max(1) as f32;
+        members
+            .into_iter()
+            .zip(current)
+            .map(|(id, level)| match mode {
+                BrightnessMode::Proportional if average > 0. => {
+                    let scaled = level as f32 * brightness as f32 / average;
+                    (id, scaled.round().min(255.) as u8)
+                }
+                _ => (id, brightness),
+            })
+            .collect()
+    }
+}
+
 impl crate::Light for Group {
     fn name(&self) -> String {
         self.name.clone()
     }
 
+    fn vendor(&self) -> &'static str {
+        "group"
+    }
+
     fn unique_id<'a>(
         &'a self,
-    ) -> futures::future::BoxFuture<'a, Result<String, Box<dyn std::error::Error + Send>>> {
+    ) -> futures::future::BoxFuture<'a, Result<String, crate::LightError>> {
         Box::pin(async move { Ok(format!("Group {}", self.id)) })
     }
 
+    fn members(&self) -> Option<Vec<String>> {
+        Some(
+            self.lights
+                .lock()
+                .unwrap()
+                .iter()
+                .map(|light| light.0.clone())
+                .collect(),
+        )
+    }
+
+    fn role(&self) -> Role {
+        match self.role.lock().unwrap().clone() {
+            GroupRole::Hidden => Role::Hidden,
+            GroupRole::Exposed { name, room_hint } => Role::Named {
+                name: name.unwrap_or_else(|| self.name.clone()),
+                room_hint,
+            },
+        }
+    }
+
     fn set_power_state<'a>(
         &'a self,
         state: crate::PowerState,
-    ) -> futures::future::BoxFuture<'a, Result<(), Box<dyn std::error::Error + Send>>> {
+    ) -> futures::future::BoxFuture<'a, Result<(), crate::LightError>> {
         Box::pin(async move {
-            if let Some(e) = join_all(self.lights.lock().await.iter().cloned().map(|light| {
+            if let Some(e) = join_all(self.members().unwrap_or_default().into_iter().map(|light| {
                 let app = self.app.clone();
                 async move {
                     app.read()
                         .await
                         .set_state(&*light, state)
                         .await
-                        .map_err(|e| Box::new(e) as Box<dyn std::error::Error + Send>)
+                        .map_err(crate::LightError::from)
                 }
             }))
             .await
@@ -141,17 +1067,18 @@ impl crate::Light for Group {
 
     fn set_brightness<'a>(
         &'a self,
-        brightness: u8,
-    ) -> futures::future::BoxFuture<'a, Result<(), Box<dyn std::error::Error + Send>>> {
+        brightness: crate::Brightness,
+    ) -> futures::future::BoxFuture<'a, Result<(), crate::LightError>> {
         Box::pin(async move {
-            if let Some(e) = join_all(self.lights.lock().await.iter().cloned().map(|light| {
+            let targets = self.brightness_targets(brightness.into()).await;
+            if let Some(e) = join_all(targets.into_iter().map(|(light, brightness)| {
                 let app = self.app.clone();
                 async move {
                     app.read()
                         .await
-                        .set_brightness(&*light, brightness)
+                        .set_brightness(light.as_str(), brightness.into())
                         .await
-                        .map_err(|e| Box::new(e) as Box<dyn std::error::Error + Send>)
+                        .map_err(crate::LightError::from)
                 }
             }))
             .await
@@ -168,16 +1095,16 @@ impl crate::Light for Group {
     fn set_color<'a>(
         &'a self,
         color: Color,
-    ) -> futures::future::BoxFuture<'a, Result<(), Box<dyn std::error::Error + Send>>> {
+    ) -> futures::future::BoxFuture<'a, Result<(), crate::LightError>> {
         Box::pin(async move {
-            if let Some(e) = join_all(self.lights.lock().await.iter().cloned().map(|light| {
+            if let Some(e) = join_all(self.members().unwrap_or_default().into_iter().map(|light| {
                 let app = self.app.clone();
                 async move {
                     app.read()
                         .await
                         .set_color(&*light, color)
                         .await
-                        .map_err(|e| Box::new(e) as Box<dyn std::error::Error + Send>)
+                        .map_err(crate::LightError::from)
                 }
             }))
             .await
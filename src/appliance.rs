@@ -0,0 +1,68 @@
+use std::sync::atomic::Ordering;
+
+use crate::{App, DeviceKind, Error, Id, Thermostat, ThermostatMode};
+
+/// The last settings a fan or thermostat accepted, unknown until one is
+/// sent.
+#[derive(Clone, Copy, Default)]
+pub(crate) struct ApplianceState {
+    pub(crate) fan_speed: Option<u8>,
+    pub(crate) thermostat: Option<Thermostat>,
+}
+
+impl App {
+    pub(crate) async fn set_fan_speed(&self, id: &str, percent: u8) -> Result<(), Error> {
+        let wrapper = self.by_id.get(&Id(id.into())).ok_or(Error::Absent)?;
+        if wrapper.light().kind() != DeviceKind::Fan {
+            return Err(Error::Unsupported);
+        }
+        let percent = percent.min(100);
+        self.dispatch(wrapper, wrapper.light().set_fan_speed(percent))
+            .await?;
+        wrapper.appliance.lock().unwrap().fan_speed = Some(percent);
+        wrapper.revision.fetch_add(1, Ordering::SeqCst);
+        self.notify(&wrapper.id);
+        Ok(())
+    }
+    /// Changes a thermostat's mode, its setpoint or both, keeping whichever
+    /// isn't given as it was. Setpoints are clamped to the device's range.
+    pub(crate) async fn set_thermostat(
+        &self,
+        id: &str,
+        mode: Option<ThermostatMode>,
+        celsius: Option<f32>,
+    ) -> Result<(), Error> {
+        let wrapper = self.by_id.get(&Id(id.into())).ok_or(Error::Absent)?;
+        let (modes, (min, max)) = match wrapper.light().kind() {
+            DeviceKind::Thermostat { modes, range } => (modes, range),
+            _ => return Err(Error::Unsupported),
+        };
+        let current = wrapper.appliance.lock().unwrap().thermostat;
+        // A setpoint sent before any mode starts whatever the device does
+        // first other than switching off.
+        let mode = mode
+            .or_else(|| current.map(|current| current.mode))
+            .or_else(|| {
+                modes
+                    .iter()
+                    .copied()
+                    .find(|mode| *mode != ThermostatMode::Off)
+            })
+            .unwrap_or(ThermostatMode::Off);
+        if !modes.contains(&mode) {
+            return Err(Error::Unsupported);
+        }
+        let celsius = celsius
+            .or_else(|| current.map(|current| current.celsius))
+            .unwrap_or((min + max) / 2.)
+            .max(min)
+            .min(max);
+        let thermostat = Thermostat { mode, celsius };
+        self.dispatch(wrapper, wrapper.light().set_thermostat(thermostat))
+            .await?;
+        wrapper.appliance.lock().unwrap().thermostat = Some(thermostat);
+        wrapper.revision.fetch_add(1, Ordering::SeqCst);
+        self.notify(&wrapper.id);
+        Ok(())
+    }
+}
@@ -0,0 +1,103 @@
+use std::{
+    f64::consts::PI,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use serde::{Deserialize, Serialize};
+
+const SECS_PER_DAY: f64 = 86400.;
+const UNIX_EPOCH_JULIAN: f64 = 2440587.5;
+const J2000: f64 = 2451545.;
+
+/// Where the lights are, as read from `location.toml`. Longitude is positive
+/// east of Greenwich.
+#[derive(Clone, Copy, Serialize, Deserialize)]
+pub struct Location {
+    pub latitude: f64,
+    pub longitude: f64,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SolarEvent {
+    /// Start of civil twilight.
+    Dawn,
+    Sunrise,
+    Sunset,
+    /// End of civil twilight.
+    Dusk,
+}
+
+impl SolarEvent {
+    fn altitude(self) -> f64 {
+        match self {
+            SolarEvent::Sunrise | SolarEvent::Sunset => -0.833,
+            SolarEvent::Dawn | SolarEvent::Dusk => -6.,
+        }
+    }
+}
+
+fn to_julian(time: SystemTime) -> f64 {
+    let secs = match time.duration_since(UNIX_EPOCH) {
+        Ok(since) => since.as_secs_f64(),
+        Err(e) => -e.duration().as_secs_f64(),
+    };
+    secs / SECS_PER_DAY + UNIX_EPOCH_JULIAN
+}
+
+fn from_julian(julian: f64) -> SystemTime {
+    let secs = (julian - UNIX_EPOCH_JULIAN) * SECS_PER_DAY;
+    if secs >= 0. {
+        UNIX_EPOCH + Duration::from_secs_f64(secs)
+    } else {
+        UNIX_EPOCH - Duration::from_secs_f64(-secs)
+    }
+}
+
+impl Location {
+    /// Time of `event` on the solar day containing `day`, or `None` if the sun
+    /// never crosses the relevant altitude that day (polar day or night).
+    pub fn event_on(&self, event: SolarEvent, day: SystemTime) -> Option<SystemTime> {
+        let radians = PI / 180.;
+        let cycle = (to_julian(day) - J2000 - 0.0009 + self.longitude / 360.).round();
+        let mean = cycle + 0.0009 - self.longitude / 360.;
+        let anomaly = (357.5291 + 0.98560028 * mean).rem_euclid(360.) * radians;
+        let center =
+            1.9148 * anomaly.sin() + 0.02 * (2. * anomaly).sin() + 0.0003 * (3. * anomaly).sin();
+        let ecliptic = (anomaly / radians + center + 180. + 102.9372).rem_euclid(360.) * radians;
+        let transit = J2000 + mean + 0.0053 * anomaly.sin() - 0.0069 * (2. * ecliptic).sin();
+        let declination = (ecliptic.sin() * (23.4397 * radians).sin()).asin();
+        let latitude = self.latitude * radians;
+        let hour_angle = ((event.altitude() * radians).sin() - latitude.sin() * declination.sin())
+            / (latitude.cos() * declination.cos());
+        if hour_angle.abs() > 1. {
+            return None;
+        }
+        let offset = hour_angle.acos() / radians / 360.;
+        Some(from_julian(match event {
+            SolarEvent::Dawn | SolarEvent::Sunrise => transit - offset,
+            SolarEvent::Sunset | SolarEvent::Dusk => transit + offset,
+        }))
+    }
+
+    /// The first occurrence of `event`, shifted by `offset_secs` (negative for
+    /// "30 minutes before sunset"), that falls after `after`.
+    pub fn next_event(
+        &self,
+        event: SolarEvent,
+        offset_secs: i64,
+        after: SystemTime,
+    ) -> Option<SystemTime> {
+        let day = Duration::from_secs(SECS_PER_DAY as u64);
+        (0..3)
+            .filter_map(|days| self.event_on(event, after - day + day * days))
+            .filter_map(|time| {
+                if offset_secs >= 0 {
+                    time.checked_add(Duration::from_secs(offset_secs as u64))
+                } else {
+                    time.checked_sub(Duration::from_secs((-offset_secs) as u64))
+                }
+            })
+            .find(|time| *time > after)
+    }
+}
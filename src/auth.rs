@@ -1,8 +1,23 @@
 use http::Uri;
+use lazy_static::lazy_static;
 use serde::{Deserialize, Serialize};
-use std::convert::TryFrom;
+use std::{convert::TryFrom, sync::Arc, time::Duration};
 use warp::{filters::BoxedFilter, Filter, Reply};
 
+use crate::Health;
+
+const TOKEN_LIFETIME: Duration = Duration::from_secs(360);
+
+lazy_static! {
+    static ref ACCESS_TOKEN: String = uuid::Uuid::new_v4().to_string();
+}
+
+/// Whether `token` was issued by the account linking flow, which Google and
+/// SmartThings share.
+pub(crate) fn valid_token(token: &str) -> bool {
+    token == *ACCESS_TOKEN
+}
+
 #[derive(Deserialize, Debug)]
 struct TokenQuery {
     client_id: String,
@@ -31,8 +46,8 @@ struct OauthQuery {
     scope: Option<String>,
 }
 
-pub fn auth() -> BoxedFilter<(impl Reply,)> {
-    let authorization_code = uuid::Uuid::new_v4().to_string();
+pub fn auth(health: Arc<Health>) -> BoxedFilter<(impl Reply,)> {
+    let authorization_code = ACCESS_TOKEN.clone();
     let access_token = authorization_code.clone();
     let refresh_token = access_token.clone();
 
@@ -53,11 +68,12 @@ pub fn auth() -> BoxedFilter<(impl Reply,)> {
         auth.and(warp::path("token"))
             .and(warp::body::form())
             .map(move |_: TokenQuery| {
+                health.token_issued("google", TOKEN_LIFETIME);
                 warp::reply::json(&TokenResponse {
                     token_type: "Bearer".to_owned(),
                     access_token: access_token.clone(),
                     refresh_token: refresh_token.clone(),
-                    expires_in: 360,
+                    expires_in: TOKEN_LIFETIME.as_secs() as u32,
                 })
             });
     let auth = auth_init.or(auth_token);
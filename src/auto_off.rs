@@ -0,0 +1,118 @@
+use std::{
+    collections::HashMap,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use async_io::Timer;
+use async_lock::RwLock;
+use lights_api::Source;
+
+use crate::{
+    storage::{storage, Store},
+    App, Error, Id, PowerState,
+};
+
+const TICK: Duration = Duration::from_secs(1);
+
+/// Seconds after being switched on that a light switches itself off.
+fn timers() -> Store<u64> {
+    storage().store("auto-off")
+}
+
+/// The configured timers, and when each light that is on with one is due
+/// to go off.
+#[derive(Default)]
+pub(crate) struct AutoOff {
+    after: HashMap<String, Duration>,
+    armed: HashMap<String, Instant>,
+}
+
+impl App {
+    /// Loads the auto-off timers saved by earlier runs.
+    pub(crate) fn restore_auto_off(&self) {
+        let store = timers();
+        let ids = match store.keys() {
+            Ok(ids) => ids,
+            Err(e) => {
+                eprintln!("failed to list stored auto-off timers: {}", e);
+                return;
+            }
+        };
+        let mut auto_off = self.auto_off.lock().unwrap();
+        auto_off.after.clear();
+        for id in ids {
+            match store.get(&id) {
+                Ok(Some(secs)) => {
+                    auto_off.after.insert(id, Duration::from_secs(secs));
+                }
+                Ok(None) => {}
+                Err(e) => eprintln!("failed to load auto-off timer for `{}`: {}", id, e),
+            }
+        }
+    }
+    /// Has a light switch off `after` each time it is switched on, or
+    /// never if `None`. Takes effect from the next time it comes on.
+    pub(crate) fn set_auto_off(&self, id: &str, after: Option<Duration>) -> Result<(), Error> {
+        if !self.by_id.contains_key(&Id(id.into())) {
+            return Err(Error::Absent);
+        }
+        let stored = match after {
+            Some(after) => timers().put(id, &after.as_secs()),
+            None => timers().remove(id),
+        };
+        if let Err(e) = stored {
+            eprintln!("failed to persist auto-off timer for `{}`: {}", id, e);
+        }
+        let mut auto_off = self.auto_off.lock().unwrap();
+        match after {
+            Some(after) => {
+                auto_off.after.insert(id.to_owned(), after);
+            }
+            None => {
+                auto_off.after.remove(id);
+                auto_off.armed.remove(id);
+            }
+        }
+        Ok(())
+    }
+    /// Starts the light's timer, if it has one, as it comes on.
+    pub(crate) fn arm_auto_off(&self, id: &str) {
+        let mut auto_off = self.auto_off.lock().unwrap();
+        if let Some(after) = auto_off.after.get(id).copied() {
+            auto_off.armed.insert(id.to_owned(), Instant::now() + after);
+        }
+    }
+    pub(crate) fn disarm_auto_off(&self, id: &str) {
+        self.auto_off.lock().unwrap().armed.remove(id);
+    }
+    fn due_auto_off(&self, now: Instant) -> Vec<String> {
+        let mut auto_off = self.auto_off.lock().unwrap();
+        let due = auto_off
+            .armed
+            .iter()
+            .filter(|(_, deadline)| **deadline <= now)
+            .map(|(id, _)| id.clone())
+            .collect::<Vec<_>>();
+        for id in &due {
+            auto_off.armed.remove(id);
+        }
+        due
+    }
+}
+
+/// Switches lights off once their auto-off timers run out.
+pub async fn auto_off(app: Arc<RwLock<App>>) {
+    app.read().await.restore_auto_off();
+    loop {
+        Timer::after(TICK).await;
+        let due = app.read().await.due_auto_off(Instant::now());
+        for id in due {
+            let app = app.read().await;
+            match app.set_state(&id, PowerState::Off).await {
+                Ok(()) => app.attribute(&id, &Source::Timer),
+                Err(e) => eprintln!("failed to switch off {} on its timer: {}", id, e),
+            }
+        }
+    }
+}
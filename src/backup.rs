@@ -0,0 +1,148 @@
+use lights_api::{Archive, ArchiveEntry};
+use openssl::{
+    hash::MessageDigest,
+    pkcs5::pbkdf2_hmac,
+    rand::rand_bytes,
+    symm::{decrypt_aead, encrypt_aead, Cipher},
+};
+use thiserror::Error;
+
+use crate::{
+    programs::blocking,
+    storage::{storage, valid, StorageError},
+};
+
+// Encrypted archives are the salt, the nonce and the tag, then the
+// ciphertext.
+const SALT_LEN: usize = 16;
+const NONCE_LEN: usize = 12;
+const TAG_LEN: usize = 16;
+const KEY_LEN: usize = 32;
+const ITERATIONS: usize = 600_000;
+
+#[derive(Debug, Error)]
+pub enum BackupError {
+    #[error(transparent)]
+    Storage(#[from] StorageError),
+    #[error("malformed archive: {0}")]
+    Format(#[from] serde_json::Error),
+    #[error("malformed archive data: {0}")]
+    Encoding(#[from] base64::DecodeError),
+    #[error("archive is encrypted and needs a passphrase")]
+    Encrypted,
+    #[error("wrong passphrase or corrupted archive")]
+    Passphrase,
+    #[error("archive contains an invalid entry name `{0}`")]
+    Name(String),
+    #[error("encryption failed: {0}")]
+    Crypto(#[from] openssl::error::ErrorStack),
+}
+
+/// Stretches a passphrase into a key, slowly so that guessing is costly.
+fn key(passphrase: &str, salt: &[u8]) -> Result<[u8; KEY_LEN], BackupError> {
+    let mut key = [0; KEY_LEN];
+    pbkdf2_hmac(
+        passphrase.as_bytes(),
+        salt,
+        ITERATIONS,
+        MessageDigest::sha256(),
+        &mut key,
+    )?;
+    Ok(key)
+}
+
+fn encrypt(passphrase: &str, plain: &[u8]) -> Result<Vec<u8>, BackupError> {
+    let mut salt = [0; SALT_LEN];
+    rand_bytes(&mut salt)?;
+    let mut nonce = [0; NONCE_LEN];
+    rand_bytes(&mut nonce)?;
+    let mut tag = [0; TAG_LEN];
+    let ciphertext = encrypt_aead(
+        Cipher::aes_256_gcm(),
+        &key(passphrase, &salt)?,
+        Some(&nonce),
+        &[],
+        plain,
+        &mut tag,
+    )?;
+    let mut data = salt.to_vec();
+    data.extend_from_slice(&nonce);
+    data.extend_from_slice(&tag);
+    data.extend(ciphertext);
+    Ok(data)
+}
+
+fn decrypt(passphrase: &str, data: &[u8]) -> Result<Vec<u8>, BackupError> {
+    if data.len() < SALT_LEN + NONCE_LEN + TAG_LEN {
+        return Err(BackupError::Passphrase);
+    }
+    let (salt, rest) = data.split_at(SALT_LEN);
+    let (nonce, rest) = rest.split_at(NONCE_LEN);
+    let (tag, ciphertext) = rest.split_at(TAG_LEN);
+    decrypt_aead(
+        Cipher::aes_256_gcm(),
+        &key(passphrase, salt)?,
+        Some(nonce),
+        &[],
+        ciphertext,
+        tag,
+    )
+    .map_err(|_| BackupError::Passphrase)
+}
+
+/// Snapshots the data directory, encrypting it if a passphrase is given.
+/// Deriving the key takes a while, so it runs off the executor.
+pub(crate) async fn export_state(passphrase: Option<String>) -> Result<Archive, BackupError> {
+    blocking(move || export(passphrase.as_deref())).await
+}
+
+fn export(passphrase: Option<&str>) -> Result<Archive, BackupError> {
+    let entries: Vec<_> = storage()
+        .entries()?
+        .into_iter()
+        .map(|(namespace, key, data)| ArchiveEntry {
+            namespace,
+            key,
+            data: base64::encode(data),
+        })
+        .collect();
+    Ok(match passphrase {
+        Some(passphrase) => Archive::Encrypted {
+            data: base64::encode(encrypt(passphrase, &serde_json::to_vec(&entries)?)?),
+        },
+        None => Archive::Plain { entries },
+    })
+}
+
+/// Writes every entry of an archive into the data directory, replacing
+/// items with the same name and leaving everything else in place.
+pub(crate) async fn import_state(
+    archive: Archive,
+    passphrase: Option<String>,
+) -> Result<(), BackupError> {
+    blocking(move || import(archive, passphrase.as_deref())).await
+}
+
+fn import(archive: Archive, passphrase: Option<&str>) -> Result<(), BackupError> {
+    let entries = match archive {
+        Archive::Plain { entries } => entries,
+        Archive::Encrypted { data } => {
+            let passphrase = passphrase.ok_or(BackupError::Encrypted)?;
+            let plain = decrypt(passphrase, &base64::decode(data)?)?;
+            serde_json::from_slice(&plain)?
+        }
+    };
+    let mut decoded = Vec::with_capacity(entries.len());
+    for entry in entries {
+        for name in &[&entry.namespace, &entry.key] {
+            if !valid(name) {
+                return Err(BackupError::Name((*name).clone()));
+            }
+        }
+        decoded.push((entry.namespace, entry.key, base64::decode(entry.data)?));
+    }
+    for (namespace, key, data) in decoded {
+        storage().blobs(&namespace).put(&key, &data)?;
+    }
+    Ok(())
+}
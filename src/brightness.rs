@@ -0,0 +1,95 @@
+use std::ops::RangeInclusive;
+
+/// A light's brightness, from 0 to 255 as lights are set to. Converting to
+/// and from another scale, such as Google's percent, rounds to the nearest
+/// step, so that a value of a scale with at most 256 steps comes back from a
+/// round trip as it went in.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug, Default)]
+pub struct Brightness(u8);
+
+impl Brightness {
+    pub const MAX: Brightness = Brightness(u8::MAX);
+
+    pub fn from_percent(percent: u32) -> Self {
+        Brightness::from_scale(percent, 100)
+    }
+    pub fn percent(self) -> u8 {
+        self.to_scale(100) as u8
+    }
+    /// From a step of a scale running from 0 to `max`.
+    pub fn from_scale(value: u32, max: u32) -> Self {
+        Brightness::from_range(value, 0..=max)
+    }
+    pub fn to_scale(self, max: u32) -> u32 {
+        self.to_range(0..=max)
+    }
+    /// From a step of a scale starting above 0, such as that of devices
+    /// whose darkest setting is still on. Values outside it are clamped.
+    pub fn from_range(value: u32, range: RangeInclusive<u32>) -> Self {
+        let (start, end) = (*range.start(), *range.end());
+        let span = end.saturating_sub(start) as u64;
+        if span == 0 {
+            return Brightness::MAX;
+        }
+        let steps = (value.max(start).min(end) - start) as u64;
+        Brightness(((steps * 255 + span / 2) / span) as u8)
+    }
+    pub fn to_range(self, range: RangeInclusive<u32>) -> u32 {
+        let (start, end) = (*range.start(), *range.end());
+        let span = end.saturating_sub(start) as u64;
+        start + ((self.0 as u64 * span + 127) / 255) as u32
+    }
+}
+
+impl From<u8> for Brightness {
+    fn from(level: u8) -> Self {
+        Brightness(level)
+    }
+}
+
+impl From<Brightness> for u8 {
+    fn from(brightness: Brightness) -> Self {
+        brightness.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Brightness;
+
+    #[test]
+    fn percents_round_trip() {
+        for percent in 0..=100 {
+            assert_eq!(Brightness::from_percent(percent).percent() as u32, percent);
+        }
+    }
+
+    #[test]
+    fn device_scales_round_trip() {
+        // Hue's `bri` and WiZ's `dimming`, which have fewer steps than a
+        // brightness, keep every value they send.
+        for range in &[1..=254, 10..=100] {
+            for value in range.clone() {
+                let brightness = Brightness::from_range(value, range.clone());
+                assert_eq!(brightness.to_range(range.clone()), value);
+            }
+        }
+        // Tuya's data point has more, so every brightness is kept instead.
+        for level in 0..=u8::MAX {
+            let brightness = Brightness::from(level);
+            assert_eq!(
+                Brightness::from_range(brightness.to_range(10..=1000), 10..=1000),
+                brightness
+            );
+        }
+    }
+
+    #[test]
+    fn ranges_keep_their_ends() {
+        assert_eq!(Brightness::from_range(0, 10..=1000), Brightness::from(0));
+        assert_eq!(Brightness::from_range(2000, 10..=1000), Brightness::MAX);
+        assert_eq!(Brightness::MAX.to_range(1..=254), 254);
+        assert_eq!(Brightness::from(0).to_range(1..=254), 1);
+        assert_eq!(Brightness::from_range(5, 5..=5), Brightness::MAX);
+    }
+}
@@ -0,0 +1,135 @@
+use std::{
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use async_io::Timer;
+use async_lock::RwLock;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::{
+    storage::{storage, Store},
+    App, Id,
+};
+
+const LEASE_KEY: &str = "lease";
+/// How long after writing the lease it is read back, so that of two
+/// instances taking an expired lease at once only the last writer goes on.
+const SETTLE: Duration = Duration::from_millis(500);
+
+/// Two or more instances sharing a data directory, from `cluster.toml`.
+/// Whichever holds the lease controls devices, and the rest stand by until
+/// it stops renewing.
+#[derive(Deserialize)]
+pub struct ClusterConfig {
+    /// Names this instance in the lease, letting it take the lease straight
+    /// back after a restart. A new one is made up on each run if unset.
+    #[serde(default)]
+    pub id: Option<String>,
+    /// Seconds a lease lasts without being renewed.
+    #[serde(default = "default_lease_secs")]
+    pub lease_secs: u64,
+}
+
+fn default_lease_secs() -> u64 {
+    15
+}
+
+#[derive(Serialize, Deserialize)]
+struct Lease {
+    holder: String,
+    /// Unix time in milliseconds.
+    expires: u64,
+}
+
+fn store() -> Store<Lease> {
+    storage().store("cluster")
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|since| since.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+/// Takes or renews the lease if it is free or already ours, returning
+/// whether it is ours afterwards.
+async fn contend(id: &str, lease: Duration) -> bool {
+    let store = store();
+    let held = match store.get(LEASE_KEY) {
+        Ok(held) => held,
+        Err(e) => {
+            eprintln!("failed to read cluster lease: {}", e);
+            return false;
+        }
+    };
+    let renewing = match &held {
+        Some(held) if held.holder == id => true,
+        Some(held) if held.expires > now() => return false,
+        _ => false,
+    };
+    let ours = Lease {
+        holder: id.to_owned(),
+        expires: now() + lease.as_millis() as u64,
+    };
+    if let Err(e) = store.put(LEASE_KEY, &ours) {
+        eprintln!("failed to write cluster lease: {}", e);
+        return false;
+    }
+    if renewing {
+        return true;
+    }
+    Timer::after(SETTLE).await;
+    matches!(store.get(LEASE_KEY), Ok(Some(held)) if held.holder == id)
+}
+
+impl App {
+    /// Picks up what was stored behind this instance's back, by the
+    /// previously active instance before taking over from it or by an
+    /// imported archive.
+    pub(crate) fn reload(&mut self) {
+        self.registry.reload();
+        for (id, device) in self.registry.devices() {
+            if !self.by_id.contains_key(&Id(id.clone())) {
+                self.insert_placeholder(id, &device);
+            }
+        }
+        for light in self.lights() {
+            self.remember(&light.id(), light.light());
+        }
+        self.restore_policies();
+        self.restore_auto_off();
+        self.restore_override_holds();
+    }
+}
+
+/// Stands this instance by until it holds the cluster lease, then keeps the
+/// lease renewed. Losing it, such as after a long pause, stands it by again.
+pub async fn cluster(app: Arc<RwLock<App>>, config: ClusterConfig) {
+    let id = config
+        .id
+        .unwrap_or_else(|| Uuid::new_v4().to_hyphenated().to_string());
+    let lease = Duration::from_secs(config.lease_secs.max(1));
+    let health = app.read().await.health();
+    let mut active = false;
+    health.set_standby(true);
+    app.read().await.registry.set_standby(true);
+    loop {
+        let ours = contend(&id, lease).await;
+        if ours != active {
+            active = ours;
+            if active {
+                eprintln!("took the cluster lease, now active");
+                app.write().await.reload();
+            } else {
+                eprintln!("lost the cluster lease, standing by");
+            }
+            app.read().await.registry.set_standby(!active);
+            health.set_standby(!active);
+        }
+        // Renewed well before expiry, while standbys check as often.
+        Timer::after(lease / 3).await;
+    }
+}
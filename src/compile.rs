@@ -0,0 +1,396 @@
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+    thread,
+    time::{Duration, Instant},
+};
+
+use lights_api::ProgramLanguage;
+use thiserror::Error;
+use uuid::Uuid;
+
+use crate::{integrations::esp_host::validate, programs::blocking, storage::storage};
+
+const BUILDS: &str = "builds";
+const TIMEOUT: Duration = Duration::from_secs(60);
+const MAX_SOURCE: usize = 256 * 1024;
+// Identifiers of what would let a program read files or the environment of
+// the server while it is compiled. The sandbox keeps those out of reach
+// anyway, this refuses such programs with a clearer error.
+const FORBIDDEN: &[&str] = &[
+    "include",
+    "include_str",
+    "include_bytes",
+    "env",
+    "option_env",
+    "path",
+];
+// Read-only in the default sandbox, for the compilers and what they link.
+const SYSTEM: &[&str] = &["/usr", "/bin", "/lib", "/lib64", "/etc/ld.so.cache"];
+
+#[derive(Debug, Error)]
+pub(crate) enum CompileError {
+    #[error("io error: {0}")]
+    Io(#[from] io::Error),
+    #[error("source too large")]
+    TooLarge,
+    #[error("`{0}` isn't allowed in programs")]
+    Forbidden(&'static str),
+    #[error("no sandbox to compile in, install bwrap or set LIGHTS_COMPILE_SANDBOX")]
+    Unsandboxed,
+    #[error("compilation timed out")]
+    TimedOut,
+    #[error("compilation failed:\n{0}")]
+    Failed(String),
+    #[error("program doesn't fit the strip ABI: {0}")]
+    Abi(io::Error),
+}
+
+#[derive(Clone, Copy, Debug)]
+pub(crate) enum Language {
+    Rust,
+    AssemblyScript,
+}
+
+impl From<ProgramLanguage> for Language {
+    fn from(language: ProgramLanguage) -> Self {
+        match language {
+            ProgramLanguage::Rust => Language::Rust,
+            ProgramLanguage::AssemblyScript => Language::AssemblyScript,
+        }
+    }
+}
+
+impl Language {
+    fn extension(self) -> &'static str {
+        match self {
+            Language::Rust => "rs",
+            Language::AssemblyScript => "ts",
+        }
+    }
+
+    /// The compiler invocation, overridable with `LIGHTS_RUSTC` and
+    /// `LIGHTS_ASC` for bundled toolchains, and run in `dir` under the
+    /// sandbox.
+    fn command(self, dir: &Path, source: &Path, output: &Path) -> Command {
+        let compiler = match self {
+            Language::Rust => std::env::var("LIGHTS_RUSTC").unwrap_or_else(|_| "rustc".into()),
+            Language::AssemblyScript => {
+                std::env::var("LIGHTS_ASC").unwrap_or_else(|_| "asc".into())
+            }
+        };
+        let mut command = sandbox(&compiler, dir);
+        match self {
+            Language::Rust => {
+                command.args(&[
+                    "--edition=2018",
+                    "--crate-type=cdylib",
+                    "--target=wasm32-unknown-unknown",
+                    "-Copt-level=s",
+                    "-Cpanic=abort",
+                ]);
+                command.arg("-o").arg(output).arg(source);
+            }
+            Language::AssemblyScript => {
+                command.arg(source).arg("--optimize").arg("-o").arg(output);
+            }
+        }
+        // Nothing from the server's environment but what finds the
+        // toolchain reaches the compiler.
+        command.env_clear();
+        for name in &["PATH", "HOME", "RUSTUP_HOME", "RUSTUP_TOOLCHAIN"] {
+            if let Ok(value) = std::env::var(name) {
+                command.env(name, value);
+            }
+        }
+        command
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::piped());
+        command
+    }
+}
+
+/// The command in `LIGHTS_COMPILE_SANDBOX` if set, and otherwise `bwrap`
+/// with no network, nothing of the filesystem readable but the system
+/// directories and toolchain, and nothing writable but the scratch directory.
+fn sandbox(compiler: &str, dir: &Path) -> Command {
+    if let Ok(sandbox) = std::env::var("LIGHTS_COMPILE_SANDBOX") {
+        let mut sandbox = sandbox.split_whitespace();
+        if let Some(program) = sandbox.next() {
+            let mut command = Command::new(program);
+            command.args(sandbox).arg(compiler);
+            return command;
+        }
+    }
+    let mut command = Command::new("bwrap");
+    command.args(&["--unshare-all", "--die-with-parent", "--new-session"]);
+    let home = std::env::var_os("HOME").map(PathBuf::from);
+    let rustup = std::env::var_os("RUSTUP_HOME")
+        .map(PathBuf::from)
+        .or_else(|| home.as_ref().map(|home| home.join(".rustup")));
+    // Only the binaries of the cargo home, which holds registry credentials.
+    let cargo = std::env::var_os("CARGO_HOME")
+        .map(PathBuf::from)
+        .or_else(|| home.as_ref().map(|home| home.join(".cargo")))
+        .map(|cargo| cargo.join("bin"));
+    // A bundled toolchain given by path is read from its root.
+    let toolchain = Path::new(compiler)
+        .parent()
+        .and_then(Path::parent)
+        .filter(|root| root.is_absolute())
+        .map(Path::to_path_buf);
+    let readable = SYSTEM
+        .iter()
+        .map(PathBuf::from)
+        .chain(rustup)
+        .chain(cargo)
+        .chain(toolchain);
+    for path in readable {
+        command.arg("--ro-bind-try").arg(&path).arg(&path);
+    }
+    command.args(&["--proc", "/proc", "--dev", "/dev", "--tmpfs", "/tmp"]);
+    command.arg("--bind").arg(dir).arg(dir);
+    command.arg("--chdir").arg(dir);
+    command.arg(compiler);
+    command
+}
+
+fn run(mut command: Command) -> Result<(), CompileError> {
+    let mut child = command.spawn().map_err(|e| match e.kind() {
+        io::ErrorKind::NotFound => CompileError::Unsandboxed,
+        _ => e.into(),
+    })?;
+    // Drained as it comes so a chatty compiler can't block on a full pipe.
+    let stderr = child.stderr.take().map(|mut pipe| {
+        thread::spawn(move || {
+            let mut stderr = String::new();
+            let _ = io::Read::read_to_string(&mut pipe, &mut stderr);
+            stderr
+        })
+    });
+    let started = Instant::now();
+    loop {
+        if let Some(status) = child.try_wait()? {
+            let stderr = stderr
+                .and_then(|reader| reader.join().ok())
+                .unwrap_or_default();
+            return if status.success() {
+                Ok(())
+            } else {
+                Err(CompileError::Failed(stderr))
+            };
+        }
+        if started.elapsed() > TIMEOUT {
+            let _ = child.kill();
+            let _ = child.wait();
+            return Err(CompileError::TimedOut);
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+}
+
+fn build(language: Language, source: String) -> Result<Vec<u8>, CompileError> {
+    let dir = storage().namespace(BUILDS).join(Uuid::new_v4().to_string());
+    fs::create_dir_all(&dir)?;
+    let source_path = dir.join(format!("program.{}", language.extension()));
+    let output = dir.join("program.wasm");
+    let result = fs::write(&source_path, source)
+        .map_err(CompileError::from)
+        .and_then(|()| {
+            let mut command = language.command(&dir, &source_path, &output);
+            command.current_dir(&dir);
+            run(command)
+        })
+        .and_then(|()| Ok(fs::read(&output)?));
+    let _ = fs::remove_dir_all(&dir);
+    result
+}
+
+/// The identifiers of Rust source, leaving out comments and the contents of
+/// literals so that neither can hide one from the check.
+fn identifiers(source: &str) -> Vec<String> {
+    let chars = source.chars().collect::<Vec<_>>();
+    let mut identifiers = vec![];
+    let mut at = 0;
+    while at < chars.len() {
+        let next = chars.get(at + 1).copied();
+        match chars[at] {
+            '/' if next == Some('/') => {
+                while at < chars.len() && chars[at] != '\n' {
+                    at += 1;
+                }
+            }
+            '/' if next == Some('*') => {
+                // Block comments nest.
+                let mut depth = 0;
+                while at < chars.len() {
+                    match (chars[at], chars.get(at + 1)) {
+                        ('/', Some('*')) => {
+                            depth += 1;
+                            at += 2;
+                        }
+                        ('*', Some('/')) => {
+                            depth -= 1;
+                            at += 2;
+                            if depth == 0 {
+                                break;
+                            }
+                        }
+                        _ => at += 1,
+                    }
+                }
+            }
+            '"' => at = skip_string(&chars, at + 1, None),
+            // A character literal, or the start of a lifetime whose name is
+            // read as an identifier.
+            '\'' if next == Some('\\') => {
+                at += 3;
+                while at < chars.len() && chars[at] != '\'' {
+                    at += 1;
+                }
+                at += 1;
+            }
+            '\'' if chars.get(at + 2) == Some(&'\'') => at += 3,
+            c if c.is_alphanumeric() || c == '_' => {
+                let start = at;
+                while at < chars.len() && (chars[at].is_alphanumeric() || chars[at] == '_') {
+                    at += 1;
+                }
+                let word = chars[start..at].iter().collect::<String>();
+                if word == "r" || word == "br" {
+                    let hashes = chars[at..].iter().take_while(|c| **c == '#').count();
+                    if chars.get(at + hashes) == Some(&'"') {
+                        at = skip_string(&chars, at + hashes + 1, Some(hashes));
+                        continue;
+                    }
+                    // A raw identifier, read next without its prefix.
+                    if word == "r" && hashes == 1 {
+                        at += 1;
+                        continue;
+                    }
+                }
+                if !c.is_numeric() {
+                    identifiers.push(word);
+                }
+            }
+            _ => at += 1,
+        }
+    }
+    identifiers
+}
+
+/// The index after a string literal whose contents start at `at`, raw with
+/// that many `#`s if `raw` is given.
+fn skip_string(chars: &[char], mut at: usize, raw: Option<usize>) -> usize {
+    while at < chars.len() {
+        match (chars[at], raw) {
+            ('\\', None) => at += 2,
+            ('"', None) => return at + 1,
+            ('"', Some(hashes)) => {
+                let closing = chars[at + 1..].iter().take_while(|c| **c == '#').count();
+                if closing >= hashes {
+                    return at + 1 + hashes;
+                }
+                at += 1;
+            }
+            _ => at += 1,
+        }
+    }
+    at
+}
+
+/// Compiles a program for an ESP strip and checks it against the strip ABI.
+/// The compiler runs sandboxed with a clean environment in a scratch
+/// directory and is killed if it takes too long.
+pub(crate) async fn compile_program(
+    language: Language,
+    source: String,
+) -> Result<Vec<u8>, CompileError> {
+    if source.len() > MAX_SOURCE {
+        return Err(CompileError::TooLarge);
+    }
+    if let Language::Rust = language {
+        let identifiers = identifiers(&source);
+        if let Some(forbidden) = FORBIDDEN
+            .iter()
+            .find(|name| identifiers.iter().any(|identifier| identifier == *name))
+        {
+            return Err(CompileError::Forbidden(forbidden));
+        }
+    }
+    let binary = blocking(move || build(language, source)).await?;
+    validate(&binary).map_err(CompileError::Abi)?;
+    Ok(binary)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{compile_program, identifiers, CompileError, Language, FORBIDDEN};
+
+    fn forbidden(source: &str) -> bool {
+        let result = futures::executor::block_on(compile_program(Language::Rust, source.into()));
+        matches!(result, Err(CompileError::Forbidden(_)))
+    }
+
+    #[test]
+    fn reads_identifiers_in_code() {
+        assert_eq!(
+            identifiers("fn tick(t: u32) -> u32 { t + 1 }"),
+            ["fn", "tick", "t", "u32", "u32", "t"]
+        );
+        assert_eq!(identifiers("let r#path = 'a';"), ["let", "path"]);
+        assert_eq!(
+            identifiers("fn f<'a>(x: &'a u8) {}"),
+            ["fn", "f", "a", "x", "a", "u8"]
+        );
+    }
+
+    #[test]
+    fn skips_comments_and_literals() {
+        let source = r####"
+            // include_str!("/etc/passwd")
+            /* env!("HOME") /* nested */ path */
+            const A: &str = "include_bytes!(\"secret\")";
+            const B: &str = r#"option_env!("KEY") " still "#;
+            const C: &[u8] = br##"include!("x")"##;
+            const D: char = '"';
+            const E: char = '\'';
+        "####;
+        let identifiers = identifiers(source);
+        assert_eq!(
+            identifiers,
+            [
+                "const", "A", "str", "const", "B", "str", "const", "C", "u8", "const", "D", "char",
+                "const", "E", "char"
+            ]
+        );
+    }
+
+    #[test]
+    fn refuses_real_uses() {
+        assert!(forbidden(r#"const S: &str = include_str!("/etc/passwd");"#));
+        assert!(forbidden(r#"const S: &str = env!("HOME");"#));
+        assert!(forbidden(r#"#[path = "/etc/passwd"] mod m;"#));
+        assert!(forbidden("const S: &str = r#include_bytes!(\"x\");"));
+        // A closing quote in a comment doesn't end the string early.
+        assert!(forbidden(
+            "const S: &str = \"// \"; const T: &str = env!(\"HOME\");"
+        ));
+    }
+
+    #[test]
+    fn allows_mentions() {
+        // Checked without compiling, which would build in storage.
+        for source in &[
+            "// env!(\"HOME\")\nfn f() {}",
+            "const S: &str = \"include_str!\";",
+        ] {
+            let identifiers = identifiers(source);
+            assert!(!FORBIDDEN
+                .iter()
+                .any(|name| identifiers.iter().any(|identifier| identifier == name)));
+        }
+    }
+}
@@ -0,0 +1,143 @@
+use std::sync::Arc;
+
+use async_lock::RwLock;
+use futures::future::{join, BoxFuture};
+use lights_api::{Composite, CompositeId};
+
+use crate::{
+    storage::{storage, valid, Store},
+    App, Brightness, Color, LightError, PowerState,
+};
+
+fn composites() -> Store<Composite> {
+    storage().store("composites")
+}
+
+/// Presents a white channel and a color channel as a single light.
+pub struct CompositeLight {
+    id: CompositeId,
+    channels: Composite,
+    app: Arc<RwLock<App>>,
+}
+
+impl CompositeLight {
+    /// The level each channel goes to for a brightness command.
+    fn levels(&self, brightness: u8) -> (u8, u8) {
+        let white = self.channels.white_weight.max(0.);
+        let color = self.channels.color_weight.max(0.);
+        let max = white.max(color);
+        if max.is_nan() || max <= 0. {
+            return (brightness, brightness);
+        }
+        let level = |weight: f32| (brightness as f32 * weight / max).round() as u8;
+        (level(white), level(color))
+    }
+}
+
+fn first_error(
+    (white, color): (Result<(), crate::Error>, Result<(), crate::Error>),
+) -> Result<(), LightError> {
+    white.and(color).map_err(LightError::from)
+}
+
+impl crate::Light for CompositeLight {
+    fn name(&self) -> String {
+        format!("Composite {}", self.id)
+    }
+
+    fn vendor(&self) -> &'static str {
+        "composite"
+    }
+
+    fn unique_id<'a>(&'a self) -> BoxFuture<'a, Result<String, LightError>> {
+        Box::pin(async move { Ok(format!("Composite {}", self.id)) })
+    }
+
+    fn set_power_state<'a>(&'a self, state: PowerState) -> BoxFuture<'a, Result<(), LightError>> {
+        Box::pin(async move {
+            let app = self.app.read().await;
+            first_error(
+                join(
+                    app.set_state(self.channels.white.as_str(), state),
+                    app.set_state(self.channels.color.as_str(), state),
+                )
+                .await,
+            )
+        })
+    }
+
+    fn set_brightness<'a>(
+        &'a self,
+        brightness: Brightness,
+    ) -> BoxFuture<'a, Result<(), LightError>> {
+        Box::pin(async move {
+            let (white, color) = self.levels(brightness.into());
+            let app = self.app.read().await;
+            first_error(
+                join(
+                    app.set_brightness(self.channels.white.as_str(), white.into()),
+                    app.set_brightness(self.channels.color.as_str(), color.into()),
+                )
+                .await,
+            )
+        })
+    }
+
+    fn set_color<'a>(&'a self, color: Color) -> BoxFuture<'a, Result<(), LightError>> {
+        Box::pin(async move {
+            let channel = match color {
+                Color::Rgb { .. } => &self.channels.color,
+                Color::White { .. } => &self.channels.white,
+            };
+            self.app
+                .read()
+                .await
+                .set_color(channel.as_str(), color)
+                .await
+                .map_err(LightError::from)
+        })
+    }
+}
+
+async fn insert_composite(app: &Arc<RwLock<App>>, id: CompositeId, channels: Composite) {
+    let light = CompositeLight {
+        id,
+        channels,
+        app: app.clone(),
+    };
+    app.write().await.push_light(light).await;
+}
+
+pub(crate) async fn make_composite(
+    app: &Arc<RwLock<App>>,
+    id: CompositeId,
+    channels: Composite,
+) -> Result<(), String> {
+    if !valid(id.as_str()) {
+        return Err(format!("invalid composite id `{}`", id));
+    }
+    composites()
+        .put(id.as_str(), &channels)
+        .map_err(|e| format!("failed to persist composite `{}`: {}", id, e))?;
+    insert_composite(app, id, channels).await;
+    Ok(())
+}
+
+/// Recreates the composite lights saved by earlier runs.
+pub(crate) async fn restore_composites(app: &Arc<RwLock<App>>) {
+    let store = composites();
+    let ids = match store.keys() {
+        Ok(ids) => ids,
+        Err(e) => {
+            eprintln!("failed to list stored composites: {}", e);
+            return;
+        }
+    };
+    for id in ids {
+        match store.get(&id) {
+            Ok(Some(channels)) => insert_composite(app, CompositeId(id), channels).await,
+            Ok(None) => {}
+            Err(e) => eprintln!("failed to load composite `{}`: {}", id, e),
+        }
+    }
+}
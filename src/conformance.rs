@@ -0,0 +1,193 @@
+use std::sync::Arc;
+
+use async_lock::RwLock;
+use futures::future::BoxFuture;
+use serde_json::Value;
+use thiserror::Error;
+
+use crate::{
+    fulfill::{fulfill, FulfillmentRequest},
+    hook,
+    integrations::mock::MockLight,
+    App, Spawner,
+};
+
+/// Fulfillment requests with the responses expected of the mock lights.
+/// They are modeled on the examples in Google's reference rather than
+/// captured from Google, so they pin down what is answered today and should
+/// give way to captured exchanges.
+const FIXTURES: &[(&str, &str, &str)] = &[
+    (
+        "sync",
+        include_str!("../fixtures/fulfill/sync.request.json"),
+        include_str!("../fixtures/fulfill/sync.response.json"),
+    ),
+    (
+        "execute",
+        include_str!("../fixtures/fulfill/execute.request.json"),
+        include_str!("../fixtures/fulfill/execute.response.json"),
+    ),
+    (
+        "query",
+        include_str!("../fixtures/fulfill/query.request.json"),
+        include_str!("../fixtures/fulfill/query.response.json"),
+    ),
+    (
+        "unknown_intent",
+        include_str!("../fixtures/fulfill/unknown_intent.request.json"),
+        include_str!("../fixtures/fulfill/unknown_intent.response.json"),
+    ),
+    (
+        "unsupported_command",
+        include_str!("../fixtures/fulfill/unsupported_command.request.json"),
+        include_str!("../fixtures/fulfill/unsupported_command.response.json"),
+    ),
+    (
+        "malformed_execute",
+        include_str!("../fixtures/fulfill/malformed_execute.request.json"),
+        include_str!("../fixtures/fulfill/malformed_execute.response.json"),
+    ),
+];
+
+/// Requests as Google documents them, which must fit the models here
+/// without leaving anything out.
+const SCHEMA_FIXTURES: &[(&str, &str)] = &[
+    ("sync", include_str!("../fixtures/schema/sync.request.json")),
+    (
+        "query",
+        include_str!("../fixtures/schema/query.request.json"),
+    ),
+    (
+        "execute_light",
+        include_str!("../fixtures/schema/execute_light.request.json"),
+    ),
+    (
+        "execute_temperature",
+        include_str!("../fixtures/schema/execute_temperature.request.json"),
+    ),
+    (
+        "execute_appliance",
+        include_str!("../fixtures/schema/execute_appliance.request.json"),
+    ),
+    (
+        "disconnect",
+        include_str!("../fixtures/schema/disconnect.request.json"),
+    ),
+];
+
+const WEBHOOK_FIXTURES: &[(&str, &str)] = &[(
+    "webhook",
+    include_str!("../fixtures/schema/webhook.request.json"),
+)];
+
+#[derive(Debug, Error)]
+pub enum SelftestError {
+    #[error("fixture `{0}` is invalid: {1}")]
+    Fixture(&'static str, serde_json::Error),
+    #[error("fixture `{fixture}` mismatch\nexpected: {expected}\nactual: {actual}")]
+    Mismatch {
+        fixture: &'static str,
+        expected: Value,
+        actual: Value,
+    },
+    #[error("fixture `{fixture}` doesn't fit its model: {problem}")]
+    Schema {
+        fixture: &'static str,
+        problem: String,
+    },
+}
+
+pub(crate) struct InertSpawner;
+
+impl Spawner for InertSpawner {
+    fn spawn(&self, _: BoxFuture<'static, ()>) {}
+}
+
+// SYNC device lists come out of a HashMap, so sort anything keyed by id
+// before comparing against the golden responses.
+fn normalize(value: &mut Value) {
+    match value {
+        Value::Array(items) => {
+            items.iter_mut().for_each(normalize);
+            items.sort_by(|a, b| {
+                let id = |item: &Value| item.get("id").map(Value::to_string);
+                id(a).cmp(&id(b))
+            });
+        }
+        Value::Object(map) => map.values_mut().for_each(normalize),
+        _ => {}
+    }
+}
+
+fn schema_error(fixture: &'static str, unknown: Vec<String>) -> Result<(), SelftestError> {
+    if unknown.is_empty() {
+        return Ok(());
+    }
+    Err(SelftestError::Schema {
+        fixture,
+        problem: format!("unknown fields {}", unknown.join(", ")),
+    })
+}
+
+/// Reads each documented request into the models here, failing on fields
+/// they leave out, payloads that don't fit and commands read as
+/// unsupported.
+fn check_schemas() -> Result<(), SelftestError> {
+    for (name, request) in SCHEMA_FIXTURES {
+        let request: FulfillmentRequest =
+            serde_json::from_str(request).map_err(|e| SelftestError::Fixture(name, e))?;
+        let unknown = request.check().map_err(|problem| SelftestError::Schema {
+            fixture: name,
+            problem,
+        })?;
+        schema_error(name, unknown)?;
+    }
+    for (name, request) in WEBHOOK_FIXTURES {
+        let request = serde_json::from_str(request).map_err(|e| SelftestError::Fixture(name, e))?;
+        let unknown = hook::unknown_fields(request).map_err(|e| SelftestError::Fixture(name, e))?;
+        schema_error(name, unknown)?;
+    }
+    Ok(())
+}
+
+/// Checks the payload models against Google's documented requests, then
+/// replays the recorded Google fulfillment fixtures against an `App` of mock
+/// lights and checks each response against its golden copy.
+pub async fn selftest() -> Result<(), SelftestError> {
+    check_schemas()?;
+    let mut app = App::with_spawner(InertSpawner);
+    app.set_strict_payloads(true);
+    app.push_lights(vec![MockLight::new(1), MockLight::new(2)])
+        .await;
+    let app = Arc::new(RwLock::new(app));
+    for (name, request, response) in FIXTURES {
+        let request = serde_json::from_str(request).map_err(|e| SelftestError::Fixture(name, e))?;
+        let mut expected: Value =
+            serde_json::from_str(response).map_err(|e| SelftestError::Fixture(name, e))?;
+        let mut actual = serde_json::to_value(fulfill(request, &app).await)
+            .map_err(|e| SelftestError::Fixture(name, e))?;
+        normalize(&mut expected);
+        normalize(&mut actual);
+        if expected != actual {
+            return Err(SelftestError::Mismatch {
+                fixture: name,
+                expected,
+                actual,
+            });
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn fixtures_match_golden_responses() {
+        futures::executor::block_on(super::selftest()).unwrap();
+    }
+
+    #[test]
+    fn fixtures_fit_payload_models() {
+        super::check_schemas().unwrap();
+    }
+}
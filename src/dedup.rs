@@ -0,0 +1,69 @@
+use std::time::{Duration, Instant};
+
+use crate::{forwards, App, LightWrapper};
+
+/// How long after a light accepts a command an identical one is skipped,
+/// unless configured for the light's vendor.
+pub(crate) const DEFAULT_DEDUP_WINDOW: Duration = Duration::from_secs(2);
+
+#[derive(Clone, Copy)]
+pub(crate) enum Command {
+    Power,
+    Brightness,
+    Color,
+}
+
+/// When a light last accepted each kind of command.
+#[derive(Default)]
+pub(crate) struct Confirmed {
+    power: Option<Instant>,
+    brightness: Option<Instant>,
+    color: Option<Instant>,
+}
+
+impl Confirmed {
+    fn at(&mut self, command: Command) -> &mut Option<Instant> {
+        match command {
+            Command::Power => &mut self.power,
+            Command::Brightness => &mut self.brightness,
+            Command::Color => &mut self.color,
+        }
+    }
+}
+
+impl App {
+    /// Skips commands repeating what lights have just accepted, such as
+    /// retried EXECUTE requests, for `window` after they accepted it. A zero
+    /// window sends every command.
+    pub fn set_default_dedup_window(&mut self, window: Duration) {
+        self.default_dedup = window;
+    }
+    pub fn set_dedup_window<T: Into<String>>(&mut self, vendor: T, window: Duration) {
+        self.dedup.insert(vendor.into(), window);
+    }
+    /// Whether a command can be skipped, given whether the cached state is
+    /// already what it sets. Lights that pass commands on to others leave it
+    /// to them.
+    pub(crate) fn duplicate(&self, wrapper: &LightWrapper, command: Command, same: bool) -> bool {
+        let light = wrapper.light();
+        if !same || forwards(light) {
+            return false;
+        }
+        let window = *self
+            .dedup
+            .get(light.vendor())
+            .unwrap_or(&self.default_dedup);
+        wrapper
+            .confirmed
+            .lock()
+            .unwrap()
+            .at(command)
+            .map_or(false, |confirmed| confirmed.elapsed() < window)
+    }
+}
+
+impl LightWrapper {
+    pub(crate) fn confirm(&self, command: Command) {
+        *self.confirmed.lock().unwrap().at(command) = Some(Instant::now());
+    }
+}
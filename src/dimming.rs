@@ -0,0 +1,66 @@
+use serde::Deserialize;
+
+/// How a requested brightness maps to the level sent to a device, so that
+/// half brightness looks half as bright on hardware that dims linearly.
+#[derive(Clone, Copy, Debug, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DimmingCurve {
+    /// Sends the requested level unchanged, for devices that already dim
+    /// perceptually.
+    Linear,
+    /// Raises the requested fraction to this power, 2.2 being typical.
+    Gamma(f32),
+    /// Treats the requested level as CIE 1976 lightness.
+    CieLightness,
+}
+
+impl Default for DimmingCurve {
+    fn default() -> Self {
+        DimmingCurve::Linear
+    }
+}
+
+fn level(fraction: f32) -> u8 {
+    (fraction * 255.).round().max(0.).min(255.) as u8
+}
+
+impl DimmingCurve {
+    /// The device level for a requested brightness. Anything above zero
+    /// stays above zero, so a dim light isn't switched off by rounding.
+    pub(crate) fn apply(self, brightness: u8) -> u8 {
+        let fraction = brightness as f32 / 255.;
+        let output = match self {
+            DimmingCurve::Linear => return brightness,
+            DimmingCurve::Gamma(gamma) => level(fraction.powf(gamma)),
+            DimmingCurve::CieLightness => {
+                let lightness = fraction * 100.;
+                level(if lightness > 8. {
+                    ((lightness + 16.) / 116.).powi(3)
+                } else {
+                    lightness / 903.3
+                })
+            }
+        };
+        if brightness > 0 {
+            output.max(1)
+        } else {
+            0
+        }
+    }
+    /// The requested brightness a device level corresponds to, for state the
+    /// device reports itself.
+    pub(crate) fn invert(self, level: u8) -> u8 {
+        let fraction = level as f32 / 255.;
+        match self {
+            DimmingCurve::Linear => level,
+            DimmingCurve::Gamma(gamma) => self::level(fraction.powf(1. / gamma)),
+            DimmingCurve::CieLightness => self::level(
+                if fraction > 0.008856 {
+                    116. * fraction.cbrt() - 16.
+                } else {
+                    903.3 * fraction
+                } / 100.,
+            ),
+        }
+    }
+}
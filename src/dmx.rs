@@ -0,0 +1,294 @@
+use std::{
+    collections::HashMap,
+    io,
+    net::{Ipv4Addr, UdpSocket},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use async_io::{Async, Timer};
+use async_lock::RwLock;
+use futures::future::{join_all, select};
+use serde::Deserialize;
+
+use crate::App;
+
+const ARTNET_PORT: u16 = 6454;
+const SACN_PORT: u16 = 5568;
+const ARTNET_HEADER: &[u8] = b"Art-Net\0";
+const ARTNET_DMX: u16 = 0x5000;
+const SACN_IDENTIFIER: &[u8] = b"ASC-E1.17\0\0\0";
+/// Set in the options of the last packet a source sends before it stops.
+const SACN_TERMINATED: u8 = 0x40;
+const UNIVERSE_CHANNELS: usize = 512;
+
+/// Strips driven by lighting software, such as xLights or QLC+, sending
+/// Art-Net or sACN (E1.31), from `dmx.toml`.
+#[derive(Deserialize)]
+pub struct DmxConfig {
+    /// How long after the last packet a strip goes back to the state it was
+    /// last set to, such as by Google.
+    #[serde(default = "default_timeout_ms")]
+    pub timeout_ms: u64,
+    pub strips: Vec<DmxStrip>,
+}
+
+fn default_timeout_ms() -> u64 {
+    2500
+}
+
+/// Where a strip's frame is in the universes, which are numbered as the
+/// packets carry them for both protocols.
+#[derive(Deserialize)]
+pub struct DmxStrip {
+    pub light: String,
+    pub universe: u16,
+    /// The DMX address of the strip's first channel, from 1.
+    #[serde(default = "default_address")]
+    pub address: u16,
+    /// Bytes in a frame, the strip's length times the channels of each
+    /// pixel.
+    pub channels: usize,
+    /// Channels used of each universe before the frame continues in the
+    /// next, 510 by default so that RGB pixels aren't split.
+    #[serde(default = "default_universe_size")]
+    pub universe_size: usize,
+}
+
+fn default_address() -> u16 {
+    1
+}
+
+fn default_universe_size() -> usize {
+    510
+}
+
+impl DmxStrip {
+    /// Offset of the first channel in the first universe.
+    fn start(&self) -> usize {
+        self.address.max(1) as usize - 1
+    }
+    fn universe_size(&self) -> usize {
+        self.universe_size.max(1).min(UNIVERSE_CHANNELS)
+    }
+    fn universes(&self) -> impl Iterator<Item = u16> {
+        let size = self.universe_size();
+        let count = (self.start() + self.channels + size - 1) / size;
+        self.universe..self.universe.saturating_add(count as u16)
+    }
+    fn last_universe(&self) -> u16 {
+        self.universes().last().unwrap_or(self.universe)
+    }
+    fn frame(&self, universes: &HashMap<u16, Vec<u8>>) -> Vec<u8> {
+        let mut frame = Vec::with_capacity(self.channels);
+        let mut offset = self.start();
+        for universe in self.universes() {
+            let data = universes.get(&universe).map_or(&[][..], Vec::as_slice);
+            let end = self
+                .universe_size()
+                .min(offset + self.channels - frame.len());
+            frame.extend((offset..end).map(|channel| data.get(channel).copied().unwrap_or(0)));
+            offset = 0;
+        }
+        frame
+    }
+}
+
+/// The universe and channel levels of a DMX packet in either protocol, and
+/// whether its source has stopped.
+fn parse(packet: &[u8]) -> Option<(u16, &[u8], bool)> {
+    if packet.starts_with(ARTNET_HEADER) {
+        if packet.len() < 18 || u16::from_le_bytes([packet[8], packet[9]]) != ARTNET_DMX {
+            return None;
+        }
+        let universe = u16::from_le_bytes([packet[14], packet[15]]) & 0x7fff;
+        let length = u16::from_be_bytes([packet[16], packet[17]]) as usize;
+        return Some((universe, packet.get(18..18 + length)?, false));
+    }
+    if packet.get(4..16) != Some(SACN_IDENTIFIER) || packet.len() < 126 {
+        return None;
+    }
+    // Root, framing and DMP layer vectors for DMX data, and a null start
+    // code; other packets, such as universe discovery, are ignored.
+    if packet[18..22] != [0, 0, 0, 4] || packet[40..44] != [0, 0, 0, 2] || packet[117] != 2 {
+        return None;
+    }
+    if packet[125] != 0 {
+        return None;
+    }
+    let terminated = packet[112] & SACN_TERMINATED != 0;
+    let universe = u16::from_be_bytes([packet[113], packet[114]]);
+    let count = u16::from_be_bytes([packet[123], packet[124]]) as usize;
+    Some((universe, packet.get(126..125 + count)?, terminated))
+}
+
+/// A strip receiving frames, and whether the last write to it failed, so
+/// that failures are only logged once.
+struct Stream {
+    last: Instant,
+    failing: bool,
+}
+
+struct Receiver {
+    config: DmxConfig,
+    universes: HashMap<u16, Vec<u8>>,
+    streams: HashMap<String, Stream>,
+}
+
+impl Receiver {
+    /// Records a packet and returns the strips whose frames it completes,
+    /// or those it stops.
+    fn receive(&mut self, packet: &[u8]) -> (Vec<(String, Vec<u8>)>, Vec<String>) {
+        let (universe, data, terminated) = match parse(packet) {
+            Some(parsed) => parsed,
+            None => return (vec![], vec![]),
+        };
+        let touched = self
+            .config
+            .strips
+            .iter()
+            .filter(|strip| strip.universes().any(|spanned| spanned == universe));
+        if terminated {
+            let streams = &mut self.streams;
+            let stopped = touched
+                .filter(|strip| streams.remove(&strip.light).is_some())
+                .map(|strip| strip.light.clone())
+                .collect();
+            return (vec![], stopped);
+        }
+        let levels = self
+            .universes
+            .entry(universe)
+            .or_insert_with(|| vec![0; UNIVERSE_CHANNELS]);
+        let len = data.len().min(UNIVERSE_CHANNELS);
+        levels[..len].copy_from_slice(&data[..len]);
+        let frames = touched
+            .filter(|strip| strip.last_universe() == universe)
+            .map(|strip| (strip.light.clone(), strip.frame(&self.universes)))
+            .collect();
+        (frames, vec![])
+    }
+    fn expired(&mut self) -> Vec<String> {
+        let timeout = Duration::from_millis(self.config.timeout_ms);
+        let expired = self
+            .streams
+            .iter()
+            .filter(|(_, stream)| stream.last.elapsed() >= timeout)
+            .map(|(light, _)| light.clone())
+            .collect::<Vec<_>>();
+        for light in &expired {
+            self.streams.remove(light);
+        }
+        expired
+    }
+}
+
+/// Every packet waiting on `socket`, read without blocking.
+fn drain(socket: &Async<UdpSocket>, buf: &mut [u8]) -> io::Result<Vec<Vec<u8>>> {
+    let mut packets = vec![];
+    loop {
+        match socket.get_ref().recv_from(buf) {
+            Ok((len, _)) => packets.push(buf[..len].to_vec()),
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(packets),
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Puts strips back to the state they were last set to.
+async fn restore(app: &RwLock<App>, lights: Vec<String>) {
+    let app = app.read().await;
+    join_all(lights.iter().map(|id| {
+        let app = &app;
+        async move {
+            if let Err(e) = app.reapply(id).await {
+                eprintln!("failed to restore {} after its stream: {}", id, e);
+            }
+        }
+    }))
+    .await;
+}
+
+async fn receive(app: &RwLock<App>, config: DmxConfig) -> io::Result<()> {
+    let artnet = Async::<UdpSocket>::bind(([0, 0, 0, 0], ARTNET_PORT))?;
+    let sacn = Async::<UdpSocket>::bind(([0, 0, 0, 0], SACN_PORT))?;
+    // sACN is usually multicast, to a group for each universe.
+    for strip in &config.strips {
+        for universe in strip.universes() {
+            let [high, low] = universe.to_be_bytes();
+            let group = Ipv4Addr::new(239, 255, high, low);
+            if let Err(e) = sacn
+                .get_ref()
+                .join_multicast_v4(&group, &Ipv4Addr::UNSPECIFIED)
+            {
+                eprintln!("failed to join sACN universe {}: {}", universe, e);
+            }
+        }
+    }
+    let check = Duration::from_millis(config.timeout_ms / 4).max(Duration::from_millis(100));
+    let mut receiver = Receiver {
+        config,
+        universes: HashMap::new(),
+        streams: HashMap::new(),
+    };
+    let mut buf = [0; 1024];
+    loop {
+        select(
+            Box::pin(select(
+                Box::pin(artnet.readable()),
+                Box::pin(sacn.readable()),
+            )),
+            Box::pin(Timer::after(check)),
+        )
+        .await;
+        // Only the latest frame of each strip is written, so that strips
+        // slower than the stream drop frames rather than fall behind.
+        let mut frames = HashMap::new();
+        let mut stopped = vec![];
+        for packet in drain(&artnet, &mut buf)?
+            .into_iter()
+            .chain(drain(&sacn, &mut buf)?)
+        {
+            let (completed, terminated) = receiver.receive(&packet);
+            frames.extend(completed);
+            stopped.extend(terminated);
+        }
+        stopped.extend(receiver.expired());
+        let now = Instant::now();
+        let results = {
+            let app = app.read().await;
+            join_all(frames.iter().map(|(light, frame)| {
+                let app = &app;
+                async move { (light, app.write_frame(light, frame).await) }
+            }))
+            .await
+        };
+        for (light, result) in results {
+            let stream = receiver.streams.entry(light.clone()).or_insert(Stream {
+                last: now,
+                failing: false,
+            });
+            stream.last = now;
+            match result {
+                Ok(()) => stream.failing = false,
+                Err(e) => {
+                    if !stream.failing {
+                        eprintln!("failed to write stream to {}: {}", light, e);
+                    }
+                    stream.failing = true;
+                }
+            }
+        }
+        if !stopped.is_empty() {
+            restore(app, stopped).await;
+        }
+    }
+}
+
+/// Listens for Art-Net and sACN and shows the frames they carry on strips,
+/// until each stream stops and its strip goes back to its usual control.
+pub async fn dmx(app: Arc<RwLock<App>>, config: DmxConfig) {
+    if let Err(e) = receive(&app, config).await {
+        eprintln!("DMX input stopped: {}", e);
+    }
+}
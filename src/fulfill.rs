@@ -1,58 +1,286 @@
-use std::collections::HashMap;
+use std::{
+    borrow::Cow,
+    collections::HashMap,
+    convert::TryFrom,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
 
-use serde::{Deserialize, Serialize};
+use async_lock::RwLock;
+use futures::{
+    channel::oneshot,
+    future::{FutureExt, Shared},
+};
+use lights_api::{SensorKind, SensorReading};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::{json, Map, Value};
 
-use crate::{App, Color};
+use crate::{
+    intent::{self, DeviceCommand, DeviceQuery, DeviceSync},
+    policy::Origin,
+    registry::RegisteredSensor,
+    request_sync::report_state,
+    schema::{self, Read},
+    App, Brightness, Color, DeviceKind, Error, Light, LightError, ThermostatMode,
+};
 
-#[derive(Deserialize, Debug)]
+const COLOR_SETTING: &str = "action.devices.traits.ColorSetting";
+const OCCUPANCY_SENSING: &str = "action.devices.traits.OccupancySensing";
+const OPEN_CLOSE: &str = "action.devices.traits.OpenClose";
+const ON_OFF: &str = "action.devices.traits.OnOff";
+const FAN_SPEED: &str = "action.devices.traits.FanSpeed";
+const TEMPERATURE_SETTING: &str = "action.devices.traits.TemperatureSetting";
+
+/// How long an EXECUTE response is kept to answer Google's retries of the
+/// request with.
+const REPLAY_WINDOW: Duration = Duration::from_secs(60);
+
+const COMMAND_PREFIX: &str = "action.devices.commands.";
+const EXECUTE: &str = "action.devices.EXECUTE";
+const QUERY: &str = "action.devices.QUERY";
+
+const LIGHT_TRAITS: &[&str] = &[ON_OFF, COLOR_SETTING, "action.devices.traits.Brightness"];
+
+/// The traits a light is synced with by default.
+pub(crate) fn light_traits(light: &dyn Light) -> Vec<String> {
+    default_traits(&light.kind(), light.supports_color())
+}
+
+fn default_traits(kind: &DeviceKind, supports_color: bool) -> Vec<String> {
+    let traits = match kind {
+        DeviceKind::Light => LIGHT_TRAITS,
+        DeviceKind::Switch => &[ON_OFF],
+        DeviceKind::Fan => &[ON_OFF, FAN_SPEED],
+        DeviceKind::Thermostat { .. } => &[TEMPERATURE_SETTING],
+    };
+    traits
+        .iter()
+        .filter(|t| supports_color || **t != COLOR_SETTING)
+        .map(|t| (*t).to_owned())
+        .collect()
+}
+
+#[derive(Deserialize, Serialize, Debug)]
 struct Input {
     intent: String,
-    payload: Option<IntentPayload>,
+    /// Read once the intent says what it should be, so that a payload is
+    /// never taken for another intent's by its shape.
+    #[serde(default)]
+    payload: Option<Value>,
+}
+
+impl Input {
+    fn read(&self, index: usize) -> Result<Read<IntentPayload>, serde_json::Error> {
+        let payload = self.payload.clone().unwrap_or_default();
+        let path = format!("inputs[{}].payload", index);
+        match self.intent.as_str() {
+            EXECUTE => schema::read(payload, &path).map(|read| read.map(IntentPayload::Execute)),
+            QUERY => schema::read(payload, &path).map(|read| read.map(IntentPayload::Query)),
+            _ => Ok(Read {
+                value: IntentPayload::Empty,
+                unknown: vec![],
+            }),
+        }
+    }
 }
 
-#[derive(Debug, Deserialize)]
-#[serde(untagged)]
 enum IntentPayload {
-    Execute { commands: Vec<Command> },
-    Query { devices: Vec<CommandDevice> },
+    Execute(ExecutePayload),
+    Query(QueryPayload),
+    /// For intents that carry nothing, such as SYNC.
+    Empty,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+struct ExecutePayload {
+    commands: Vec<Command>,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, Serialize)]
+struct QueryPayload {
+    devices: Vec<CommandDevice>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
 struct CommandDevice {
     id: String,
+    /// Given back as it was synced, which no device here sets.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    custom_data: Option<Value>,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, Serialize)]
 struct Command {
     devices: Vec<CommandDevice>,
     execution: Vec<CommandCommand>,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Deserialize)]
+struct RawCommand {
+    command: String,
+    #[serde(default)]
+    params: Value,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(from = "RawCommand")]
 struct CommandCommand {
     command: String,
     params: CommandParams,
 }
 
-#[derive(Debug, Deserialize)]
+impl From<RawCommand> for CommandCommand {
+    fn from(raw: RawCommand) -> Self {
+        CommandCommand {
+            params: CommandParams::read(&raw.command, raw.params),
+            command: raw.command,
+        }
+    }
+}
+
+/// Params as each command has them. Written back out untagged, as Google
+/// sends them.
+#[derive(Debug, Serialize, Clone)]
 #[serde(untagged)]
 enum CommandParams {
-    OnOff { on: bool },
-    Brightness { brightness: u8 },
-    Color { color: QueryColor },
+    OnOff {
+        on: bool,
+    },
+    Brightness {
+        brightness: u8,
+    },
+    Color {
+        color: QueryColor,
+    },
+    FanSpeed {
+        #[serde(rename = "fanSpeedPercent")]
+        fan_speed_percent: u8,
+    },
+    Setpoint {
+        #[serde(rename = "thermostatTemperatureSetpoint")]
+        thermostat_temperature_setpoint: f32,
+    },
+    Mode {
+        #[serde(rename = "thermostatMode")]
+        thermostat_mode: ThermostatMode,
+    },
+    Unsupported(Value),
 }
 
-#[derive(Deserialize, Debug)]
+impl CommandParams {
+    /// Reads params by the command they came with rather than by their
+    /// shape, leaving any that don't fit it unsupported.
+    fn read(command: &str, params: Value) -> Self {
+        fn field<T: DeserializeOwned>(params: &Value, name: &str) -> Option<T> {
+            T::deserialize(params.get(name)?).ok()
+        }
+        let read = match command.strip_prefix(COMMAND_PREFIX) {
+            Some("OnOff") => field(&params, "on").map(|on| CommandParams::OnOff { on }),
+            Some("BrightnessAbsolute") => field(&params, "brightness")
+                .map(|brightness| CommandParams::Brightness { brightness }),
+            Some("ColorAbsolute") => {
+                field(&params, "color").map(|color| CommandParams::Color { color })
+            }
+            Some("SetFanSpeed") => field(&params, "fanSpeedPercent")
+                .map(|fan_speed_percent| CommandParams::FanSpeed { fan_speed_percent }),
+            Some("ThermostatTemperatureSetpoint") => {
+                field(&params, "thermostatTemperatureSetpoint").map(
+                    |thermostat_temperature_setpoint| CommandParams::Setpoint {
+                        thermostat_temperature_setpoint,
+                    },
+                )
+            }
+            Some("ThermostatSetMode") => field(&params, "thermostatMode")
+                .map(|thermostat_mode| CommandParams::Mode { thermostat_mode }),
+            _ => None,
+        };
+        read.unwrap_or(CommandParams::Unsupported(params))
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug)]
 #[serde(rename_all = "camelCase")]
+struct Envelope {
+    request_id: String,
+    inputs: Vec<Input>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(try_from = "Value")]
 pub struct FulfillmentRequest {
     request_id: String,
     inputs: Vec<Input>,
+    /// Fields outside the intents' payloads that the models here don't know.
+    unknown: Vec<String>,
+}
+
+impl TryFrom<Value> for FulfillmentRequest {
+    type Error = serde_json::Error;
+
+    fn try_from(raw: Value) -> Result<Self, Self::Error> {
+        let Read { value, unknown } = schema::read::<Envelope>(raw, "")?;
+        Ok(FulfillmentRequest {
+            request_id: value.request_id,
+            inputs: value.inputs,
+            unknown,
+        })
+    }
+}
+
+impl FulfillmentRequest {
+    /// Reads each input's payload, returning `None` for those that don't fit
+    /// their intent, along with every field the models here don't know.
+    fn read(&self) -> (Vec<Option<IntentPayload>>, Vec<String>) {
+        let mut unknown = self.unknown.clone();
+        let payloads = self
+            .inputs
+            .iter()
+            .enumerate()
+            .map(|(index, input)| match input.read(index) {
+                Ok(read) => {
+                    unknown.extend(read.unknown);
+                    Some(read.value)
+                }
+                Err(e) => {
+                    eprintln!("malformed {} payload: {}", input.intent, e);
+                    None
+                }
+            })
+            .collect();
+        (payloads, unknown)
+    }
+    /// Fields the models here don't know, or why a payload doesn't fit its
+    /// intent, for checking them against Google's format.
+    pub(crate) fn check(&self) -> Result<Vec<String>, String> {
+        let mut unknown = self.unknown.clone();
+        for (index, input) in self.inputs.iter().enumerate() {
+            let read = input
+                .read(index)
+                .map_err(|e| format!("{} payload: {}", input.intent, e))?;
+            if let IntentPayload::Execute(payload) = &read.value {
+                for command in payload
+                    .commands
+                    .iter()
+                    .flat_map(|command| &command.execution)
+                {
+                    if let CommandParams::Unsupported(_) = command.params {
+                        return Err(format!("unsupported command {}", command.command));
+                    }
+                }
+            }
+            unknown.extend(read.unknown);
+        }
+        Ok(unknown)
+    }
 }
 
 #[derive(Serialize)]
 #[serde(rename_all = "camelCase")]
 pub struct FulfillmentResponse {
     request_id: String,
+    /// Left out for DISCONNECT, which expects no payload.
+    #[serde(skip_serializing_if = "Option::is_none")]
     payload: Option<Payload>,
 }
 
@@ -62,22 +290,37 @@ pub struct FulfillmentResponse {
 enum Payload {
     Sync {
         agent_user_id: String,
-        devices: Vec<Device>,
+        devices: Vec<Value>,
     },
     Query {
         agent_user_id: String,
-        devices: HashMap<String, QueryDevice>,
+        devices: Map<String, Value>,
     },
     Execute {
         commands: Vec<ExecCommand>,
     },
+    #[serde(rename_all = "camelCase")]
+    Error {
+        error_code: String,
+    },
+}
+
+impl Payload {
+    fn error(code: &str) -> Self {
+        Payload::Error {
+            error_code: code.to_owned(),
+        }
+    }
 }
 
 #[derive(Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
 struct ExecCommand {
     ids: Vec<String>,
     status: String,
     states: ExecStates,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error_code: Option<String>,
 }
 
 #[derive(Serialize, Clone)]
@@ -86,12 +329,22 @@ struct ExecStates {
 }
 
 #[derive(Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
 struct QueryDevice {
-    status: String,
+    /// Left out of report-state, which has no status.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    status: Option<String>,
     online: bool,
     brightness: u8,
     on: bool,
-    color: QueryColor,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    color: Option<QueryColor>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    current_fan_speed_percent: Option<u8>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    thermostat_mode: Option<ThermostatMode>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    thermostat_temperature_setpoint: Option<f32>,
 }
 
 #[derive(Serialize, Clone, Debug, Deserialize)]
@@ -100,11 +353,13 @@ struct QueryDevice {
 enum QueryColor {
     White {
         temperature: u32,
+        #[serde(default)]
         name: String,
     },
     Rgb {
         #[serde(rename = "spectrumRGB")]
         spectrum_rgb: u32,
+        #[serde(default)]
         name: String,
     },
 }
@@ -117,15 +372,39 @@ struct Device {
     ty: String,
     traits: Vec<String>,
     name: Name,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    room_hint: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    structure_hint: Option<String>,
     will_report_state: bool,
+    /// Lets the device's notifications be announced, which the user can turn
+    /// off per device in the Home app.
+    notification_supported_by_agent: bool,
     attributes: DeviceAttributes,
 }
 
 #[derive(Serialize, Clone)]
 #[serde(rename_all = "camelCase")]
 struct DeviceAttributes {
-    color_model: String,
-    color_temperature_range: ColorTemperatureRange,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    color_model: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    color_temperature_range: Option<ColorTemperatureRange>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    supports_fan_speed_percent: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    available_thermostat_modes: Option<Vec<ThermostatMode>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    thermostat_temperature_unit: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    thermostat_temperature_range: Option<ThermostatTemperatureRange>,
+}
+
+#[derive(Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
+struct ThermostatTemperatureRange {
+    min_threshold_celsius: f32,
+    max_threshold_celsius: f32,
 }
 
 #[derive(Serialize, Clone)]
@@ -140,148 +419,598 @@ struct Name {
     name: String,
 }
 
-pub async fn fulfill(request: FulfillmentRequest, app: &App) -> FulfillmentResponse {
-    let mut payload = None;
-    for input in &request.inputs {
+fn error_code(error: &Error) -> &'static str {
+    match error {
+        Error::Absent => "deviceNotFound",
+        Error::Light(LightError::Offline) => "deviceOffline",
+        Error::Light(LightError::AuthExpired) => "authFailure",
+        Error::Light(LightError::RateLimited)
+        | Error::Light(LightError::TimedOut)
+        | Error::Standby => "transientError",
+        Error::Light(LightError::Protocol(_)) => "protocolError",
+        Error::Light(LightError::Other(_))
+        | Error::NothingToUndo
+        | Error::InvalidConfig(_)
+        | Error::Payload(_) => "hardError",
+        Error::Policy(_) => "actionNotAvailable",
+        Error::Unsupported => "functionNotSupported",
+    }
+}
+
+/// Google's commands in the form every assistant shares. Anything
+/// unsupported has been turned away already.
+fn device_commands(execution: &[CommandCommand]) -> Vec<DeviceCommand> {
+    execution
+        .iter()
+        .filter_map(|command| match &command.params {
+            CommandParams::OnOff { on } => Some(DeviceCommand::Power(*on)),
+            CommandParams::Brightness { brightness } => Some(DeviceCommand::Brightness(
+                Brightness::from_percent(*brightness as u32),
+            )),
+            CommandParams::Color {
+                color: QueryColor::Rgb { spectrum_rgb, .. },
+            } => {
+                let color = format!("{:06X}", spectrum_rgb)
+                    .as_bytes()
+                    .chunks(2)
+                    .map(|byte| {
+                        u8::from_str_radix(&format!("{}{}", byte[0] as char, byte[1] as char), 16)
+                            .unwrap()
+                    })
+                    .collect::<Vec<_>>();
+                Some(DeviceCommand::Color(Color::Rgb {
+                    r: color[0],
+                    g: color[1],
+                    b: color[2],
+                }))
+            }
+            CommandParams::Color {
+                color: QueryColor::White { temperature, .. },
+            } => Some(DeviceCommand::Color(Color::White {
+                temperature: *temperature,
+            })),
+            CommandParams::FanSpeed { fan_speed_percent } => {
+                Some(DeviceCommand::FanSpeed(*fan_speed_percent))
+            }
+            CommandParams::Setpoint {
+                thermostat_temperature_setpoint,
+            } => Some(DeviceCommand::Setpoint(*thermostat_temperature_setpoint)),
+            CommandParams::Mode { thermostat_mode } => Some(DeviceCommand::Mode(*thermostat_mode)),
+            CommandParams::Unsupported(_) => None,
+        })
+        .collect()
+}
+
+/// The error code for an execution, if it uses a command nothing here
+/// understands.
+fn unsupported(execution: &[CommandCommand]) -> Option<&'static str> {
+    execution.iter().find_map(|command| match &command.params {
+        CommandParams::Unsupported(params) => {
+            eprintln!("unsupported command {} with {}", command.command, params);
+            Some("functionNotSupported")
+        }
+        _ => None,
+    })
+}
+
+/// The commands an EXECUTE intent is about to run on one device, as sent by
+/// Google. Hooks may change them before they run.
+pub struct Execution {
+    pub device: String,
+    /// Command names, such as `action.devices.commands.OnOff`, with their
+    /// params.
+    pub commands: Vec<(String, Value)>,
+}
+
+/// What an execute hook decided about an [`Execution`].
+pub enum Outcome {
+    /// Runs the commands, including any changes made to them.
+    Proceed,
+    /// Reports success without running anything, for devices the hook
+    /// handles itself.
+    Handled,
+    /// Fails the device with a Google error code, such as `deviceTurnedOff`.
+    Reject(String),
+}
+
+/// How long EXECUTE waits on each command before answering `PENDING`, by
+/// command name, such as `action.devices.commands.OnOff`.
+#[derive(Default)]
+pub(crate) struct Budgets {
+    commands: HashMap<String, Duration>,
+    default: Option<Duration>,
+}
+
+impl Budgets {
+    /// The tightest budget of any command in an execution, or `None` to
+    /// wait for it however long it takes.
+    fn of(&self, execution: &[CommandCommand]) -> Option<Duration> {
+        execution
+            .iter()
+            .filter_map(|command| {
+                self.commands
+                    .get(&command.command)
+                    .copied()
+                    .or(self.default)
+            })
+            .min()
+    }
+}
+
+type Reply = Shared<oneshot::Receiver<Option<Payload>>>;
+
+/// EXECUTE requests answered or being answered lately, by structure and
+/// request id, so that a retry gets the response of the first attempt
+/// rather than running the commands again.
+#[derive(Default)]
+pub(crate) struct Replies {
+    recent: Mutex<HashMap<(Option<String>, String), (Instant, Reply)>>,
+}
+
+impl Replies {
+    /// The reply to an earlier attempt at the request, or where to send
+    /// this one's for later attempts. An attempt abandoned before it
+    /// answered leaves the request to whichever claims it next.
+    fn claim(
+        &self,
+        key: (Option<String>, String),
+    ) -> Result<Reply, oneshot::Sender<Option<Payload>>> {
+        let mut recent = self.recent.lock().unwrap();
+        recent.retain(|_, (received, _)| received.elapsed() < REPLAY_WINDOW);
+        if let Some((_, reply)) = recent.get(&key) {
+            if !matches!(reply.peek(), Some(Err(_))) {
+                return Ok(reply.clone());
+            }
+        }
+        let (sender, receiver) = oneshot::channel();
+        recent.insert(key, (Instant::now(), receiver.shared()));
+        Err(sender)
+    }
+}
+
+type ExecuteHook = Box<dyn Fn(&mut Execution) -> Outcome + Send + Sync>;
+type SyncHook = Box<dyn Fn(&mut Vec<Value>) + Send + Sync>;
+type QueryHook = Box<dyn Fn(&[String], &mut Map<String, Value>) + Send + Sync>;
+
+/// Callbacks run around fulfillment, in the order they were added.
+#[derive(Default)]
+pub(crate) struct Hooks {
+    execute: Vec<ExecuteHook>,
+    sync: Vec<SyncHook>,
+    query: Vec<QueryHook>,
+}
+
+impl Hooks {
+    fn execute<'a>(
+        &self,
+        device: &str,
+        execution: &'a [CommandCommand],
+    ) -> (Outcome, Cow<'a, [CommandCommand]>) {
+        if self.execute.is_empty() {
+            return (Outcome::Proceed, Cow::Borrowed(execution));
+        }
+        let mut request = Execution {
+            device: device.to_owned(),
+            commands: execution
+                .iter()
+                .map(|command| {
+                    (
+                        command.command.clone(),
+                        serde_json::to_value(&command.params).unwrap_or_default(),
+                    )
+                })
+                .collect(),
+        };
+        for hook in &self.execute {
+            match hook(&mut request) {
+                Outcome::Proceed => {}
+                outcome => return (outcome, Cow::Borrowed(execution)),
+            }
+        }
+        let execution = request
+            .commands
+            .into_iter()
+            .map(|(command, params)| CommandCommand {
+                params: CommandParams::read(&command, params),
+                command,
+            })
+            .collect();
+        (Outcome::Proceed, Cow::Owned(execution))
+    }
+}
+
+impl App {
+    /// Adds a callback run before each device's EXECUTE commands, which can
+    /// change them, answer for the device or refuse them.
+    pub fn on_execute<F: Fn(&mut Execution) -> Outcome + Send + Sync + 'static>(
+        &mut self,
+        hook: F,
+    ) {
+        self.hooks.execute.push(Box::new(hook));
+    }
+    /// Adds a callback that can change the devices in a SYNC response, given
+    /// as Google's device objects, such as to add virtual devices.
+    pub fn on_sync<F: Fn(&mut Vec<Value>) + Send + Sync + 'static>(&mut self, hook: F) {
+        self.hooks.sync.push(Box::new(hook));
+    }
+    /// Adds a callback that can change the states in a QUERY response, keyed
+    /// by device id, given the ids Google asked for.
+    pub fn on_query<F: Fn(&[String], &mut Map<String, Value>) + Send + Sync + 'static>(
+        &mut self,
+        hook: F,
+    ) {
+        self.hooks.query.push(Box::new(hook));
+    }
+    /// Answers EXECUTE commands still running after `budget` with `PENDING`,
+    /// reporting their final state to Google once they finish.
+    pub fn set_execute_budget<T: Into<String>>(&mut self, command: T, budget: Duration) {
+        self.budgets.commands.insert(command.into(), budget);
+    }
+    pub fn set_default_execute_budget(&mut self, budget: Duration) {
+        self.budgets.default = Some(budget);
+    }
+    /// Answers fulfillment requests with fields nothing here knows with
+    /// `protocolError`, rather than logging them and going on, to catch
+    /// changes to Google's format before they're misread.
+    pub fn set_strict_payloads(&mut self, strict: bool) {
+        self.strict_payloads = strict;
+    }
+}
+
+fn query_state(query: &DeviceQuery, status: Option<String>) -> QueryDevice {
+    QueryDevice {
+        online: query.online,
+        brightness: query.brightness.percent(),
+        on: query.on,
+        status,
+        color: query.color.map(|color| QueryColor::Rgb {
+            name: "".to_owned(),
+            spectrum_rgb: color.to_spectrum(),
+        }),
+        current_fan_speed_percent: query.fan_speed,
+        thermostat_mode: query.thermostat.map(|thermostat| thermostat.mode),
+        thermostat_temperature_setpoint: query.thermostat.map(|thermostat| thermostat.celsius),
+    }
+}
+
+fn device(app: &App, device: DeviceSync) -> Device {
+    let ty = match device.kind {
+        DeviceKind::Light => "action.devices.types.LIGHT",
+        DeviceKind::Switch => "action.devices.types.SWITCH",
+        DeviceKind::Fan => "action.devices.types.FAN",
+        DeviceKind::Thermostat { .. } => "action.devices.types.THERMOSTAT",
+    };
+    Device {
+        traits: app
+            .registry
+            .get(&device.id)
+            .map(|registered| registered.traits)
+            .filter(|traits| !traits.is_empty())
+            .unwrap_or_else(|| default_traits(&device.kind, device.supports_color)),
+        ty: ty.into(),
+        attributes: attributes(&device),
+        id: device.id,
+        name: Name { name: device.name },
+        room_hint: device.room_hint,
+        structure_hint: device.structure,
+        // Only commands answered as pending are followed by a state report,
+        // not every change, so Google has to keep querying lights.
+        will_report_state: false,
+        notification_supported_by_agent: true,
+    }
+}
+
+fn attributes(device: &DeviceSync) -> DeviceAttributes {
+    let mut attributes = DeviceAttributes {
+        color_model: None,
+        color_temperature_range: None,
+        supports_fan_speed_percent: None,
+        available_thermostat_modes: None,
+        thermostat_temperature_unit: None,
+        thermostat_temperature_range: None,
+    };
+    match &device.kind {
+        DeviceKind::Light if device.supports_color => {
+            attributes.color_model = Some("rgb".to_owned());
+            attributes.color_temperature_range = Some(ColorTemperatureRange {
+                temperature_min_k: *device.temperatures.start(),
+                temperature_max_k: *device.temperatures.end(),
+            });
+        }
+        DeviceKind::Light | DeviceKind::Switch => {}
+        DeviceKind::Fan => attributes.supports_fan_speed_percent = Some(true),
+        DeviceKind::Thermostat { modes, range } => {
+            attributes.available_thermostat_modes = Some(modes.clone());
+            attributes.thermostat_temperature_unit = Some("C".to_owned());
+            attributes.thermostat_temperature_range = Some(ThermostatTemperatureRange {
+                min_threshold_celsius: range.0,
+                max_threshold_celsius: range.1,
+            });
+        }
+    }
+    attributes
+}
+
+/// How a sensor is listed to Google. Buttons aren't, having no state to
+/// show, and neither are climate sensors or the weather.
+fn sensor_device(id: String, sensor: RegisteredSensor) -> Option<Value> {
+    let (traits, attributes) = match sensor.kind {
+        SensorKind::Motion => (
+            OCCUPANCY_SENSING,
+            json!({ "occupancySensorConfiguration": [{ "occupancySensorType": "PIR" }] }),
+        ),
+        SensorKind::Contact => (
+            OPEN_CLOSE,
+            json!({ "discreteOnlyOpenClose": true, "queryOnlyOpenClose": true }),
+        ),
+        SensorKind::Button | SensorKind::Climate | SensorKind::Weather => return None,
+    };
+    Some(json!({
+        "id": id,
+        "type": "action.devices.types.SENSOR",
+        "traits": [traits],
+        "name": { "name": sensor.name },
+        "roomHint": sensor.room,
+        "willReportState": true,
+        "notificationSupportedByAgent": true,
+        "attributes": attributes,
+    }))
+}
+
+/// A sensor's state as Google expects it, offline until it has reported
+/// since startup.
+pub(crate) fn sensor_state(reading: Option<SensorReading>) -> Value {
+    match reading {
+        Some(SensorReading::Motion { occupied }) => json!({
+            "online": true,
+            "occupancy": if occupied { "OCCUPIED" } else { "UNOCCUPIED" },
+        }),
+        Some(SensorReading::Contact { open }) => json!({
+            "online": true,
+            "openPercent": if open { 100 } else { 0 },
+        }),
+        Some(SensorReading::Button { .. })
+        | Some(SensorReading::Climate { .. })
+        | Some(SensorReading::Weather { .. }) => json!({ "online": true }),
+        None => json!({ "online": false }),
+    }
+}
+
+/// Reports the state a command answered as pending left a light in.
+async fn report(state: DeviceQuery) {
+    let value = match serde_json::to_value(query_state(&state, None)) {
+        Ok(value) => value,
+        Err(_) => return,
+    };
+    if let Err(e) = report_state(&state.id, value).await {
+        eprintln!("failed to report state of {}: {:?}", state.id, e);
+    }
+}
+
+fn pending(id: &str) -> ExecCommand {
+    ExecCommand {
+        ids: vec![id.to_owned()],
+        status: "PENDING".to_owned(),
+        states: ExecStates { online: true },
+        error_code: None,
+    }
+}
+
+fn exec_command(id: &str, online: bool, error_code: Option<String>) -> ExecCommand {
+    ExecCommand {
+        ids: vec![id.to_owned()],
+        status: if error_code.is_none() {
+            "SUCCESS"
+        } else {
+            "ERROR"
+        }
+        .to_owned(),
+        states: ExecStates { online },
+        error_code,
+    }
+}
+
+pub async fn fulfill(
+    request: FulfillmentRequest,
+    shared: &Arc<RwLock<App>>,
+) -> FulfillmentResponse {
+    fulfill_structure(request, shared, None).await
+}
+
+/// Fulfills requests of a Google account linked to a single structure, which
+/// only sees and controls the devices in it.
+pub async fn fulfill_structure(
+    request: FulfillmentRequest,
+    shared: &Arc<RwLock<App>>,
+    structure: Option<&str>,
+) -> FulfillmentResponse {
+    let execute = request
+        .inputs
+        .first()
+        .map_or(false, |input| input.intent == "action.devices.EXECUTE");
+    let payload = if execute {
+        let key = (structure.map(str::to_owned), request.request_id.clone());
+        loop {
+            let claim = shared.read().await.replies.claim(key.clone());
+            match claim {
+                Ok(reply) => match reply.await {
+                    Ok(payload) => break payload,
+                    // Abandoned, so claimed again, for only one of the
+                    // attempts waiting on it to run the commands.
+                    Err(_) => continue,
+                },
+                Err(sender) => {
+                    let payload = respond(&request, shared, structure).await;
+                    let _ = sender.send(payload.clone());
+                    break payload;
+                }
+            }
+        }
+    } else {
+        respond(&request, shared, structure).await
+    };
+    FulfillmentResponse {
+        request_id: request.request_id,
+        payload,
+    }
+}
+
+async fn respond(
+    request: &FulfillmentRequest,
+    shared: &Arc<RwLock<App>>,
+    structure: Option<&str>,
+) -> Option<Payload> {
+    let app = shared.read().await;
+    let app = &*app;
+    let mut payload = Some(Payload::error("protocolError"));
+    let (payloads, unknown) = request.read();
+    if !unknown.is_empty() {
+        eprintln!(
+            "warning: fulfillment request has unknown fields: {}",
+            unknown.join(", ")
+        );
+        if app.strict_payloads {
+            return payload;
+        }
+    }
+    for (input, read) in request.inputs.iter().zip(payloads) {
         if input.intent == "action.devices.SYNC" {
+            let mut devices = intent::sync(app)
+                .into_iter()
+                .filter(|sync| intent::visible(app, &sync.id, structure))
+                .map(|sync| device(app, sync))
+                .filter_map(|device| serde_json::to_value(device).ok())
+                .collect::<Vec<_>>();
+            // Sensors aren't assigned to structures, so only accounts that
+            // see every structure are told about them.
+            if structure.is_none() {
+                devices.extend(
+                    app.registry
+                        .sensors()
+                        .into_iter()
+                        .filter(|(_, sensor)| sensor.exposed)
+                        .filter_map(|(id, sensor)| sensor_device(id, sensor)),
+                );
+            }
+            for hook in &app.hooks.sync {
+                hook(&mut devices);
+            }
             payload = Some(Payload::Sync {
                 agent_user_id: "haha.yes".to_owned(),
-                devices: app
-                    .lights()
-                    .map(|light| Device {
-                        id: light.id(),
-                        ty: "action.devices.types.LIGHT".into(),
-                        traits: vec![
-                            "action.devices.traits.OnOff".into(),
-                            "action.devices.traits.ColorSetting".into(),
-                            "action.devices.traits.Brightness".into(),
-                        ],
-                        name: Name { name: light.name() },
-                        will_report_state: false,
-                        attributes: DeviceAttributes {
-                            color_model: "rgb".to_owned(),
-                            color_temperature_range: ColorTemperatureRange {
-                                temperature_min_k: 2000,
-                                temperature_max_k: 7500,
-                            },
-                        },
-                    })
-                    .collect(),
+                devices,
             });
             break;
-        } else if input.intent == "action.devices.EXECUTE" {
-            let mut exec_commands = vec![];
-            if let Some(payload) = &input.payload {
-                if let IntentPayload::Execute { commands } = payload {
-                    for command in commands {
-                        exec_commands.push(ExecCommand {
-                            ids: command.devices.iter().map(|item| item.id.clone()).collect(),
-                            status: "SUCCESS".to_owned(),
-                            states: ExecStates { online: true },
-                        });
-                        for device in &command.devices {
-                            for command in &command.execution {
-                                match &command.params {
-                                    CommandParams::OnOff { on } => {
-                                        let _ = app.set_state(&device.id, (*on).into()).await;
-                                    }
-                                    CommandParams::Brightness { brightness } => {
-                                        if let Ok(_) = app
-                                            .set_brightness(
-                                                &device.id,
-                                                ((*brightness as f32 / 100.) * 255.) as u8,
-                                            )
-                                            .await
-                                        {
-                                            let _ = app.set_state(&device.id, true.into()).await;
-                                        }
-                                    }
-                                    CommandParams::Color { color } => {
-                                        if let Ok(_) = match color {
-                                            QueryColor::Rgb { spectrum_rgb, .. } => {
-                                                let color = format!("{:06X}", spectrum_rgb)
-                                                    .as_bytes()
-                                                    .chunks(2)
-                                                    .map(|byte| {
-                                                        u8::from_str_radix(
-                                                            &format!(
-                                                                "{}{}",
-                                                                byte[0] as char, byte[1] as char
-                                                            ),
-                                                            16,
-                                                        )
-                                                        .unwrap()
-                                                    })
-                                                    .collect::<Vec<_>>();
-                                                app.set_color(
-                                                    &device.id,
-                                                    Color::Rgb {
-                                                        r: color[0],
-                                                        g: color[1],
-                                                        b: color[2],
-                                                    },
-                                                )
-                                                .await
-                                            }
-                                            QueryColor::White { temperature, .. } => {
-                                                let temperature = *temperature;
-                                                app.set_color(
-                                                    &device.id,
-                                                    Color::White { temperature },
-                                                )
-                                                .await
-                                            }
-                                        } {
-                                            let _ = app.set_state(&device.id, true.into()).await;
-                                        }
+        } else if input.intent == EXECUTE {
+            if let Some(IntentPayload::Execute(ExecutePayload { commands })) = read {
+                let mut exec_commands = vec![];
+                for command in &commands {
+                    for device in &command.devices {
+                        if !intent::visible(app, &device.id, structure) {
+                            exec_commands.push(exec_command(
+                                &device.id,
+                                false,
+                                Some("deviceNotFound".to_owned()),
+                            ));
+                            continue;
+                        }
+                        let online = || app.light(&device.id).map_or(false, |light| light.online());
+                        let execution = match app.hooks.execute(&device.id, &command.execution) {
+                            (Outcome::Proceed, execution) => execution,
+                            (Outcome::Handled, _) => {
+                                exec_commands.push(exec_command(&device.id, true, None));
+                                continue;
+                            }
+                            (Outcome::Reject(code), _) => {
+                                exec_commands.push(exec_command(&device.id, online(), Some(code)));
+                                continue;
+                            }
+                        };
+                        if let Some(code) = unsupported(&execution) {
+                            exec_commands.push(exec_command(
+                                &device.id,
+                                online(),
+                                Some(code.to_owned()),
+                            ));
+                            continue;
+                        }
+                        let commands = device_commands(&execution);
+                        let result = match app.budgets.of(&execution) {
+                            Some(budget) => {
+                                match intent::execute_within(
+                                    shared,
+                                    app,
+                                    &device.id,
+                                    commands,
+                                    Origin::Assistant,
+                                    budget,
+                                    report,
+                                )
+                                .await
+                                {
+                                    Some(result) => result,
+                                    None => {
+                                        exec_commands.push(pending(&device.id));
+                                        continue;
                                     }
                                 }
                             }
-                        }
+                            None => {
+                                intent::execute(app, &device.id, &commands, Origin::Assistant).await
+                            }
+                        };
+                        exec_commands.push(exec_command(
+                            &device.id,
+                            result.as_ref().err().map_or(true, intent::reachable),
+                            result.err().map(|e| error_code(&e).to_owned()),
+                        ));
                     }
                 }
+                payload = Some(Payload::Execute {
+                    commands: exec_commands,
+                });
             }
-            payload = Some(Payload::Execute {
-                commands: exec_commands,
-            });
             break;
-        } else if input.intent == "action.devices.QUERY" {
-            if let Some(loc_payload) = &input.payload {
-                if let IntentPayload::Query { devices } = loc_payload {
-                    payload = Some(Payload::Query {
-                        agent_user_id: "haha.yes".to_owned(),
-                        devices: app
-                            .lights()
-                            .filter_map(|device| {
-                                let id = device.id();
-                                if devices.iter().any(|dev| dev.id == id) {
-                                    Some((
-                                        id,
-                                        QueryDevice {
-                                            online: true,
-                                            brightness: ((device.brightness() as f32 / 255.) * 100.)
-                                                as u8,
-                                            on: device.is_on(),
-                                            status: "SUCCESS".to_owned(),
-                                            color: QueryColor::Rgb {
-                                                name: "".to_owned(),
-                                                spectrum_rgb: device.color(),
-                                            },
-                                        },
-                                    ))
-                                } else {
-                                    None
-                                }
-                            })
-                            .collect(),
-                    });
+        } else if input.intent == QUERY {
+            if let Some(IntentPayload::Query(QueryPayload { devices })) = read {
+                let requested = devices
+                    .iter()
+                    .filter(|device| intent::visible(app, &device.id, structure))
+                    .map(|device| device.id.clone())
+                    .collect::<Vec<_>>();
+                let mut states = intent::query(app, &requested)
+                    .into_iter()
+                    .filter_map(|query| {
+                        let state = query_state(&query, Some("SUCCESS".to_owned()));
+                        Some((query.id, serde_json::to_value(state).ok()?))
+                    })
+                    .collect::<Map<_, _>>();
+                for id in &requested {
+                    if app
+                        .registry
+                        .sensor(id)
+                        .map_or(false, |sensor| sensor.exposed)
+                    {
+                        let mut state = sensor_state(app.sensor_reading(id));
+                        state["status"] = json!("SUCCESS");
+                        states.insert(id.clone(), state);
+                    }
+                }
+                for hook in &app.hooks.query {
+                    hook(&requested, &mut states);
                 }
+                payload = Some(Payload::Query {
+                    agent_user_id: "haha.yes".to_owned(),
+                    devices: states,
+                });
             }
             break;
+        } else if input.intent == "action.devices.DISCONNECT" {
+            payload = None;
+            break;
+        } else {
+            payload = Some(Payload::error("notSupported"));
+            break;
         }
     }
-    FulfillmentResponse {
-        request_id: request.request_id,
-        payload,
-    }
+    payload
 }
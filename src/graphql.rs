@@ -0,0 +1,313 @@
+use std::{convert::Infallible, sync::Arc};
+
+use async_graphql::{
+    Context, EmptyMutation, InputObject, Object, Result, Schema, SimpleObject, Subscription,
+};
+use async_lock::RwLock;
+use futures::{future::ready, Stream, StreamExt};
+use lights_api::LightId;
+use warp::{filters::BoxedFilter, Filter, Reply};
+
+use crate::{
+    api::{add_to_group, enumerate, make_group, remove_from_group, sun_times},
+    policy::Origin,
+    ui::{authorized, authorized_scope, viewer, Scope},
+    App, Color, LightWrapper, PowerState,
+};
+
+type LightsSchema = Schema<Query, Mutation, Updates>;
+/// Served to the guest token, which can't change anything.
+type GuestSchema = Schema<Query, EmptyMutation, Updates>;
+
+#[derive(SimpleObject)]
+struct LightColor {
+    red: u8,
+    green: u8,
+    blue: u8,
+    /// Kelvin, present only for white light.
+    temperature: Option<u32>,
+}
+
+#[derive(SimpleObject)]
+struct Light {
+    id: String,
+    name: String,
+    vendor: String,
+    on: bool,
+    brightness: u8,
+    /// Absent when the members of a group disagree.
+    color: Option<LightColor>,
+}
+
+#[derive(SimpleObject)]
+struct Group {
+    name: String,
+    lights: Vec<String>,
+}
+
+#[derive(SimpleObject)]
+struct SunTimes {
+    dawn: Option<u64>,
+    sunrise: Option<u64>,
+    sunset: Option<u64>,
+    dusk: Option<u64>,
+}
+
+/// A color to set, either a white temperature or an RGB triple.
+#[derive(InputObject)]
+struct ColorInput {
+    red: Option<u8>,
+    green: Option<u8>,
+    blue: Option<u8>,
+    temperature: Option<u32>,
+}
+
+impl From<ColorInput> for Color {
+    fn from(input: ColorInput) -> Self {
+        match input.temperature {
+            Some(temperature) => Color::White { temperature },
+            None => Color::Rgb {
+                r: input.red.unwrap_or(0),
+                g: input.green.unwrap_or(0),
+                b: input.blue.unwrap_or(0),
+            },
+        }
+    }
+}
+
+fn light(app: &App, wrapper: &LightWrapper) -> Light {
+    let state = app.state(wrapper);
+    Light {
+        id: wrapper.id(),
+        name: wrapper.name(),
+        vendor: wrapper.light().vendor().to_owned(),
+        on: state.on,
+        brightness: state.brightness,
+        color: state.color.map(|color| {
+            let (red, green, blue) = color.to_rgb();
+            LightColor {
+                red,
+                green,
+                blue,
+                temperature: match color {
+                    Color::White { temperature } => Some(temperature),
+                    Color::Rgb { .. } => None,
+                },
+            }
+        }),
+    }
+}
+
+fn app<'a>(ctx: &Context<'a>) -> &'a Arc<RwLock<App>> {
+    ctx.data_unchecked::<Arc<RwLock<App>>>()
+}
+
+/// Where a mutation came from, given with each request over HTTP. Those
+/// sent over the subscription socket are taken for plain API calls.
+fn origin(ctx: &Context<'_>) -> Origin {
+    *ctx.data_unchecked::<Origin>()
+}
+
+/// The app for a mutation, which is refused while on standby.
+async fn writable<'a>(ctx: &Context<'a>) -> Result<&'a Arc<RwLock<App>>> {
+    let app = app(ctx);
+    app.read().await.check_active().map_err(|e| e.to_string())?;
+    Ok(app)
+}
+
+async fn current(app: &RwLock<App>, id: &str) -> Result<Light> {
+    let app = app.read().await;
+    app.light(id)
+        .map(|wrapper| light(&app, wrapper))
+        .ok_or_else(|| format!("unknown light `{}`", id).into())
+}
+
+struct Query;
+
+#[Object]
+impl Query {
+    async fn lights(&self, ctx: &Context<'_>) -> Vec<Light> {
+        let app = app(ctx).read().await;
+        app.lights().map(|wrapper| light(&app, wrapper)).collect()
+    }
+
+    async fn light(&self, ctx: &Context<'_>, id: String) -> Option<Light> {
+        current(app(ctx), &id).await.ok()
+    }
+
+    async fn groups(&self, ctx: &Context<'_>) -> Vec<Group> {
+        enumerate(&*app(ctx).read().await)
+            .await
+            .groups
+            .into_iter()
+            .map(|group| Group {
+                name: group.name,
+                lights: group.lights.into_iter().map(|light| light.0).collect(),
+            })
+            .collect()
+    }
+
+    async fn sun_times(&self, ctx: &Context<'_>) -> Option<SunTimes> {
+        sun_times(&*app(ctx).read().await).map(|times| SunTimes {
+            dawn: times.dawn,
+            sunrise: times.sunrise,
+            sunset: times.sunset,
+            dusk: times.dusk,
+        })
+    }
+}
+
+struct Mutation;
+
+#[Object]
+impl Mutation {
+    async fn set_power(&self, ctx: &Context<'_>, id: String, on: bool) -> Result<Light> {
+        let app = writable(ctx).await?;
+        let origin = origin(ctx);
+        app.read()
+            .await
+            .permit(&id, origin)
+            .map_err(|e| e.to_string())?;
+        app.read()
+            .await
+            .set_state(&id, PowerState::from(on))
+            .await
+            .map_err(|e| e.to_string())?;
+        app.read().await.attribute(&id, &origin.into());
+        current(app, &id).await
+    }
+
+    async fn set_brightness(&self, ctx: &Context<'_>, id: String, brightness: u8) -> Result<Light> {
+        let app = writable(ctx).await?;
+        let origin = origin(ctx);
+        app.read()
+            .await
+            .permit(&id, origin)
+            .map_err(|e| e.to_string())?;
+        app.read()
+            .await
+            .set_brightness(&id, brightness.into())
+            .await
+            .map_err(|e| e.to_string())?;
+        app.read().await.attribute(&id, &origin.into());
+        current(app, &id).await
+    }
+
+    async fn set_color(&self, ctx: &Context<'_>, id: String, color: ColorInput) -> Result<Light> {
+        let app = writable(ctx).await?;
+        let origin = origin(ctx);
+        app.read()
+            .await
+            .permit(&id, origin)
+            .map_err(|e| e.to_string())?;
+        app.read()
+            .await
+            .set_color(&id, color.into())
+            .await
+            .map_err(|e| e.to_string())?;
+        app.read().await.attribute(&id, &origin.into());
+        current(app, &id).await
+    }
+
+    async fn make_group(&self, ctx: &Context<'_>, id: String, lights: Vec<String>) -> Result<bool> {
+        make_group(
+            writable(ctx).await?,
+            id.into(),
+            lights.into_iter().map(LightId).collect(),
+        )
+        .await?;
+        Ok(true)
+    }
+
+    async fn add_light_to_group(
+        &self,
+        ctx: &Context<'_>,
+        light: String,
+        group: String,
+    ) -> Result<bool> {
+        writable(ctx).await?;
+        add_to_group(&group.into(), light.into()).await?;
+        Ok(true)
+    }
+
+    async fn remove_light_from_group(
+        &self,
+        ctx: &Context<'_>,
+        light: String,
+        group: String,
+    ) -> Result<bool> {
+        writable(ctx).await?;
+        remove_from_group(&group.into(), &light.into()).await?;
+        Ok(true)
+    }
+}
+
+struct Updates;
+
+#[Subscription]
+impl Updates {
+    /// Every light whose state changes, as it changes.
+    async fn light_changed(&self, ctx: &Context<'_>) -> impl Stream<Item = Light> {
+        let app = app(ctx).clone();
+        let changes = app.read().await.subscribe();
+        changes
+            .then(move |id| {
+                let app = app.clone();
+                async move { current(&app, &id).await.ok() }
+            })
+            .filter_map(ready)
+    }
+}
+
+/// Serves queries and mutations as `POST /graphql`, and subscriptions as a
+/// WebSocket on the same path, both authenticated with a bearer token in the
+/// `Authorization` header. The guest token gets a schema without mutations.
+pub fn graphql(app: Arc<RwLock<App>>) -> BoxedFilter<(impl Reply,)> {
+    let schema: LightsSchema = Schema::build(Query, Mutation, Updates)
+        .data(app.clone())
+        .data(Origin::Api)
+        .finish();
+    let guest_schema: GuestSchema = Schema::build(Query, EmptyMutation, Updates)
+        .data(app)
+        .finish();
+    let query = warp::post()
+        .and(authorized_scope())
+        .and(async_graphql_warp::graphql(schema.clone()))
+        .and_then(
+            |scope: Scope, (schema, request): (LightsSchema, async_graphql::Request)| async move {
+                let request = request.data(scope.origin());
+                Ok::<_, Infallible>(async_graphql_warp::Response::from(
+                    schema.execute(request).await,
+                ))
+            },
+        );
+    let guest_query = warp::post()
+        .and(viewer())
+        .and(async_graphql_warp::graphql(guest_schema.clone()))
+        .and_then(
+            |(schema, request): (GuestSchema, async_graphql::Request)| async move {
+                Ok::<_, Infallible>(async_graphql_warp::Response::from(
+                    schema.execute(request).await,
+                ))
+            },
+        );
+    let subscription = authorized()
+        .and(async_graphql_warp::graphql_subscription(schema))
+        .map(Reply::into_response);
+    let guest_subscription = viewer()
+        .and(async_graphql_warp::graphql_subscription(guest_schema))
+        .map(Reply::into_response);
+    warp::path("graphql")
+        .and(warp::path::end())
+        .and(
+            query
+                .map(Reply::into_response)
+                .or(guest_query.map(Reply::into_response))
+                .unify()
+                .or(subscription)
+                .unify()
+                .or(guest_subscription)
+                .unify(),
+        )
+        .boxed()
+}
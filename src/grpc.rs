@@ -0,0 +1,407 @@
+use std::{sync::Arc, time::Duration};
+
+use async_lock::RwLock;
+use futures::{
+    channel::mpsc::{unbounded, UnboundedReceiver},
+    StreamExt,
+};
+use lights_api::{GroupRole, LightId};
+use lights_grpc::{
+    color::Kind,
+    lights_server::{Lights, LightsServer},
+    Empty, EnumerateRequest, EnumerateResponse, GroupMembership, MakeGroupRequest, NotifyRequest,
+    RescanIntegrationRequest, RescanIntegrationResponse, Rgb, RunSceneRequest,
+    SetBrightnessRequest, SetColorRequest, SetGroupRoleRequest, SetPowerOnDefaultsRequest,
+    SetPowerRequest, SetTemporaryRequest, SunTimesRequest, SunTimesResponse, WatchStateRequest,
+};
+use tonic::{Request, Response, Status};
+
+use crate::{
+    alert::Pattern,
+    api::{
+        add_to_group, enumerate, make_group, remove_from_group, rescan, set_group_role,
+        start_alert, sun_times,
+    },
+    policy::Origin,
+    scene::{run_scene, SceneEntry},
+    temporary::hold,
+    ui::scope,
+    App, Color, Error, LightError, LightState, LightWrapper, PowerOnDefaults,
+};
+
+fn status(error: Error) -> Status {
+    match error {
+        Error::Absent => Status::not_found(error.to_string()),
+        Error::Light(LightError::Offline) => Status::unavailable(error.to_string()),
+        Error::Light(LightError::AuthExpired) => Status::unauthenticated(error.to_string()),
+        Error::Light(LightError::RateLimited) => Status::resource_exhausted(error.to_string()),
+        Error::Light(LightError::TimedOut) => Status::deadline_exceeded(error.to_string()),
+        Error::Light(_) => Status::internal(error.to_string()),
+        Error::NothingToUndo => Status::failed_precondition(error.to_string()),
+        Error::Policy(_) => Status::permission_denied(error.to_string()),
+        Error::InvalidConfig(_) => Status::invalid_argument(error.to_string()),
+        Error::Standby => Status::unavailable(error.to_string()),
+        Error::Unsupported => Status::unimplemented(error.to_string()),
+        Error::Payload(_) => Status::invalid_argument(error.to_string()),
+    }
+}
+
+fn to_color(color: Option<lights_grpc::Color>) -> Option<Color> {
+    match color?.kind? {
+        Kind::Rgb(Rgb { red, green, blue }) => Some(Color::Rgb {
+            r: red.min(255) as u8,
+            g: green.min(255) as u8,
+            b: blue.min(255) as u8,
+        }),
+        Kind::Temperature(temperature) => Some(Color::White { temperature }),
+    }
+}
+
+fn from_color(color: Color) -> lights_grpc::Color {
+    lights_grpc::Color {
+        kind: Some(match color {
+            Color::Rgb { r, g, b } => Kind::Rgb(Rgb {
+                red: r as u32,
+                green: g as u32,
+                blue: b as u32,
+            }),
+            Color::White { temperature } => Kind::Temperature(temperature),
+        }),
+    }
+}
+
+fn light(app: &App, light: &LightWrapper) -> lights_grpc::Light {
+    let state = app.state(light);
+    lights_grpc::Light {
+        id: light.id(),
+        on: state.on,
+        brightness: state.brightness as u32,
+        color: state.color.map(from_color),
+    }
+}
+
+fn token<T>(request: &Request<T>) -> Option<&str> {
+    request
+        .metadata()
+        .get("authorization")?
+        .to_str()
+        .ok()?
+        .strip_prefix("Bearer ")
+}
+
+fn brightness(value: u32) -> u8 {
+    value.min(255) as u8
+}
+
+struct Service {
+    app: Arc<RwLock<App>>,
+}
+
+impl Service {
+    /// Refuses requests made with the read-only guest token, and any change
+    /// while on standby, returning where the others came from.
+    async fn writable<T>(&self, request: &Request<T>) -> Result<Origin, Status> {
+        let origin = match token(request).and_then(scope) {
+            Some(scope) if scope.writable() => scope.origin(),
+            _ => return Err(Status::permission_denied("read-only token")),
+        };
+        self.app.read().await.check_active().map_err(status)?;
+        Ok(origin)
+    }
+}
+
+#[tonic::async_trait]
+impl Lights for Service {
+    async fn enumerate(
+        &self,
+        _: Request<EnumerateRequest>,
+    ) -> Result<Response<EnumerateResponse>, Status> {
+        let app = self.app.read().await;
+        let groups = enumerate(&app).await.groups;
+        Ok(Response::new(EnumerateResponse {
+            lights: app.lights().map(|wrapper| light(&app, wrapper)).collect(),
+            groups: groups
+                .into_iter()
+                .map(|group| lights_grpc::Group {
+                    name: group.name,
+                    lights: group.lights.into_iter().map(|light| light.0).collect(),
+                })
+                .collect(),
+        }))
+    }
+
+    async fn set_power(
+        &self,
+        request: Request<SetPowerRequest>,
+    ) -> Result<Response<Empty>, Status> {
+        let origin = self.writable(&request).await?;
+        let request = request.into_inner();
+        let app = self.app.read().await;
+        app.permit(&request.light, origin).map_err(status)?;
+        app.set_state(&request.light, request.on.into())
+            .await
+            .map_err(status)?;
+        app.attribute(&request.light, &origin.into());
+        Ok(Response::new(Empty {}))
+    }
+
+    async fn set_brightness(
+        &self,
+        request: Request<SetBrightnessRequest>,
+    ) -> Result<Response<Empty>, Status> {
+        let origin = self.writable(&request).await?;
+        let request = request.into_inner();
+        let app = self.app.read().await;
+        app.permit(&request.light, origin).map_err(status)?;
+        app.set_brightness(&request.light, brightness(request.brightness).into())
+            .await
+            .map_err(status)?;
+        app.attribute(&request.light, &origin.into());
+        Ok(Response::new(Empty {}))
+    }
+
+    async fn set_color(
+        &self,
+        request: Request<SetColorRequest>,
+    ) -> Result<Response<Empty>, Status> {
+        let origin = self.writable(&request).await?;
+        let request = request.into_inner();
+        let color =
+            to_color(request.color).ok_or_else(|| Status::invalid_argument("missing color"))?;
+        let app = self.app.read().await;
+        app.permit(&request.light, origin).map_err(status)?;
+        app.set_color(&request.light, color).await.map_err(status)?;
+        app.attribute(&request.light, &origin.into());
+        Ok(Response::new(Empty {}))
+    }
+
+    async fn make_group(
+        &self,
+        request: Request<MakeGroupRequest>,
+    ) -> Result<Response<Empty>, Status> {
+        self.writable(&request).await?;
+        let request = request.into_inner();
+        make_group(
+            &self.app,
+            request.id.into(),
+            request.lights.into_iter().map(LightId).collect(),
+        )
+        .await
+        .map_err(Status::invalid_argument)?;
+        Ok(Response::new(Empty {}))
+    }
+
+    async fn add_light_to_group(
+        &self,
+        request: Request<GroupMembership>,
+    ) -> Result<Response<Empty>, Status> {
+        self.writable(&request).await?;
+        let request = request.into_inner();
+        add_to_group(&request.group.into(), request.light.into())
+            .await
+            .map_err(Status::not_found)?;
+        Ok(Response::new(Empty {}))
+    }
+
+    async fn remove_light_from_group(
+        &self,
+        request: Request<GroupMembership>,
+    ) -> Result<Response<Empty>, Status> {
+        self.writable(&request).await?;
+        let request = request.into_inner();
+        remove_from_group(&request.group.into(), &request.light.into())
+            .await
+            .map_err(Status::not_found)?;
+        Ok(Response::new(Empty {}))
+    }
+
+    async fn set_group_role(
+        &self,
+        request: Request<SetGroupRoleRequest>,
+    ) -> Result<Response<Empty>, Status> {
+        self.writable(&request).await?;
+        let request = request.into_inner();
+        let role = if request.hidden {
+            GroupRole::Hidden
+        } else {
+            GroupRole::Exposed {
+                name: Some(request.name).filter(|name| !name.is_empty()),
+                room_hint: Some(request.room_hint).filter(|room| !room.is_empty()),
+            }
+        };
+        set_group_role(&*self.app.read().await, &request.group.into(), role)
+            .await
+            .map_err(Status::not_found)?;
+        Ok(Response::new(Empty {}))
+    }
+
+    async fn rescan_integration(
+        &self,
+        request: Request<RescanIntegrationRequest>,
+    ) -> Result<Response<RescanIntegrationResponse>, Status> {
+        self.writable(&request).await?;
+        let added = rescan(&self.app, &request.into_inner().name)
+            .await
+            .map_err(Status::failed_precondition)?;
+        Ok(Response::new(RescanIntegrationResponse {
+            added: added.into_iter().map(|light| light.0).collect(),
+        }))
+    }
+
+    async fn set_power_on_defaults(
+        &self,
+        request: Request<SetPowerOnDefaultsRequest>,
+    ) -> Result<Response<Empty>, Status> {
+        self.writable(&request).await?;
+        let request = request.into_inner();
+        let defaults = PowerOnDefaults {
+            brightness: if request.has_brightness {
+                Some(brightness(request.brightness))
+            } else {
+                None
+            },
+            color: to_color(request.color),
+        };
+        self.app
+            .read()
+            .await
+            .set_defaults(&request.light, defaults)
+            .map_err(status)?;
+        Ok(Response::new(Empty {}))
+    }
+
+    async fn run_scene(
+        &self,
+        request: Request<RunSceneRequest>,
+    ) -> Result<Response<Empty>, Status> {
+        let origin = self.writable(&request).await?;
+        let entries: Vec<_> = request
+            .into_inner()
+            .entries
+            .into_iter()
+            .map(|entry| SceneEntry {
+                light: entry.light,
+                on: entry.on,
+                brightness: if entry.has_brightness {
+                    Some(brightness(entry.brightness))
+                } else {
+                    None
+                },
+                color: to_color(entry.color),
+                transition: Duration::from_millis(entry.transition_ms),
+                delay: Duration::from_millis(entry.delay_ms),
+            })
+            .collect();
+        self.app
+            .read()
+            .await
+            .permit_all(entries.iter().map(|entry| entry.light.as_str()), origin)
+            .map_err(status)?;
+        run_scene(self.app.clone(), entries, origin.into())
+            .await
+            .map_err(status)?;
+        Ok(Response::new(Empty {}))
+    }
+
+    async fn set_temporary(
+        &self,
+        request: Request<SetTemporaryRequest>,
+    ) -> Result<Response<Empty>, Status> {
+        let origin = self.writable(&request).await?;
+        let request = request.into_inner();
+        self.app
+            .read()
+            .await
+            .permit(&request.light, origin)
+            .map_err(status)?;
+        let state = LightState {
+            on: request.on,
+            brightness: brightness(request.brightness),
+            color: to_color(request.color),
+        };
+        hold(
+            self.app.clone(),
+            request.light,
+            state,
+            Duration::from_secs(request.duration_secs),
+            origin.into(),
+        )
+        .await
+        .map_err(status)?;
+        Ok(Response::new(Empty {}))
+    }
+
+    async fn notify(&self, request: Request<NotifyRequest>) -> Result<Response<Empty>, Status> {
+        let origin = self.writable(&request).await?;
+        let request = request.into_inner();
+        self.app
+            .read()
+            .await
+            .permit_all(request.lights.iter().map(String::as_str), origin)
+            .map_err(status)?;
+        let pattern = match lights_grpc::Pattern::from_i32(request.pattern) {
+            Some(lights_grpc::Pattern::Pulse) => Pattern::Pulse,
+            _ => Pattern::Flash,
+        };
+        let color =
+            to_color(request.color).ok_or_else(|| Status::invalid_argument("missing color"))?;
+        let lights = request.lights.into_iter().map(LightId).collect();
+        start_alert(&self.app, lights, color, pattern, request.cycles)
+            .await
+            .map_err(Status::not_found)?;
+        Ok(Response::new(Empty {}))
+    }
+
+    async fn sun_times(
+        &self,
+        _: Request<SunTimesRequest>,
+    ) -> Result<Response<SunTimesResponse>, Status> {
+        let times = sun_times(&*self.app.read().await)
+            .ok_or_else(|| Status::failed_precondition("location not configured"))?;
+        Ok(Response::new(SunTimesResponse {
+            dawn: times.dawn.unwrap_or(0),
+            sunrise: times.sunrise.unwrap_or(0),
+            sunset: times.sunset.unwrap_or(0),
+            dusk: times.dusk.unwrap_or(0),
+        }))
+    }
+
+    type WatchStateStream = UnboundedReceiver<Result<lights_grpc::Light, Status>>;
+
+    async fn watch_state(
+        &self,
+        _: Request<WatchStateRequest>,
+    ) -> Result<Response<Self::WatchStateStream>, Status> {
+        let (sender, receiver) = unbounded();
+        let app = self.app.clone();
+        let (mut changes, spawner) = {
+            let app = app.read().await;
+            for wrapper in app.lights() {
+                let _ = sender.unbounded_send(Ok(light(&app, wrapper)));
+            }
+            (app.subscribe(), app.spawner.clone())
+        };
+        spawner.spawn(Box::pin(async move {
+            while let Some(id) = changes.next().await {
+                let app = app.read().await;
+                if let Some(wrapper) = app.light(&id) {
+                    if sender.unbounded_send(Ok(light(&app, wrapper))).is_err() {
+                        break;
+                    }
+                }
+            }
+        }));
+        Ok(Response::new(receiver))
+    }
+}
+
+/// The gRPC control service, authenticated with the same tokens as the JSON
+/// API passed as `authorization: Bearer <token>` metadata.
+pub fn grpc(app: Arc<RwLock<App>>) -> LightsServer<impl Lights> {
+    LightsServer::with_interceptor(Service { app }, |request: Request<()>| {
+        match token(&request).and_then(scope) {
+            Some(_) => Ok(request),
+            None => Err(Status::unauthenticated("bad auth")),
+        }
+    })
+}
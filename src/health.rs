@@ -0,0 +1,323 @@
+use std::{
+    collections::HashMap,
+    fmt::Write,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use async_lock::RwLock;
+use lights_api::{DeviceStatistics, SensorReading};
+use serde::Serialize;
+use warp::{filters::BoxedFilter, http::StatusCode, Filter, Reply};
+
+use crate::{ui::authorized, App};
+
+/// Progress of an integration's device discovery.
+#[derive(Clone, Serialize)]
+#[serde(rename_all = "snake_case", tag = "state", content = "error")]
+pub enum Discovery {
+    Running,
+    Complete,
+    Failed(String),
+}
+
+/// Liveness information gathered from the parts of the bridge that talk to
+/// the outside world.
+#[derive(Default)]
+pub struct Health {
+    integrations: Mutex<HashMap<String, Discovery>>,
+    tokens: Mutex<HashMap<String, SystemTime>>,
+    last_sync: Mutex<Option<SystemTime>>,
+    sync_error: Mutex<Option<String>>,
+    pending_syncs: AtomicUsize,
+    standby: AtomicBool,
+}
+
+impl Health {
+    pub fn report_discovery<T: Into<String>>(&self, integration: T, discovery: Discovery) {
+        self.integrations
+            .lock()
+            .unwrap()
+            .insert(integration.into(), discovery);
+    }
+    pub(crate) fn token_issued(&self, name: &str, lifetime: Duration) {
+        self.tokens
+            .lock()
+            .unwrap()
+            .insert(name.to_owned(), SystemTime::now() + lifetime);
+    }
+    pub(crate) fn sync_queued(&self) {
+        self.pending_syncs.fetch_add(1, Ordering::SeqCst);
+    }
+    pub(crate) fn sync_dequeued(&self) {
+        self.pending_syncs.fetch_sub(1, Ordering::SeqCst);
+    }
+    /// Records how the last sync request went, which only succeeded if
+    /// Google accepted it.
+    pub(crate) fn sync_finished(&self, result: Result<(), String>) {
+        if result.is_ok() {
+            *self.last_sync.lock().unwrap() = Some(SystemTime::now());
+        }
+        *self.sync_error.lock().unwrap() = result.err();
+    }
+    pub(crate) fn set_standby(&self, standby: bool) {
+        self.standby.store(standby, Ordering::SeqCst);
+    }
+    /// Whether another instance in the cluster holds the lease.
+    pub(crate) fn standby(&self) -> bool {
+        self.standby.load(Ordering::SeqCst)
+    }
+    fn healthy(&self) -> bool {
+        self.sync_error.lock().unwrap().is_none()
+            && self
+                .integrations
+                .lock()
+                .unwrap()
+                .values()
+                .all(|discovery| !matches!(discovery, Discovery::Failed(_)))
+    }
+}
+
+fn timestamp(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|since| since.as_secs())
+        .unwrap_or(0)
+}
+
+#[derive(Serialize)]
+struct IntegrationStatus {
+    discovery: Option<Discovery>,
+    devices: usize,
+}
+
+#[derive(Serialize)]
+struct Status {
+    healthy: bool,
+    integrations: HashMap<String, IntegrationStatus>,
+    /// Unix timestamp of the last HomeGraph sync request that succeeded.
+    last_sync: Option<u64>,
+    sync_failing: bool,
+    /// Why the last sync request failed, such as Google rejecting it.
+    sync_error: Option<String>,
+    pending_syncs: usize,
+    standby: bool,
+    /// Commands waiting on or holding a rate limit slot, by vendor.
+    command_queues: HashMap<String, usize>,
+    /// Unix timestamps at which issued tokens stop being valid.
+    token_expiry: HashMap<String, u64>,
+}
+
+fn status(app: &App) -> Status {
+    let health = &app.health;
+    let mut integrations: HashMap<_, _> = health
+        .integrations
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|(name, discovery)| {
+            (
+                name.clone(),
+                IntegrationStatus {
+                    discovery: Some(discovery.clone()),
+                    devices: 0,
+                },
+            )
+        })
+        .collect();
+    for wrapper in app.lights() {
+        integrations
+            .entry(wrapper.light().vendor().to_owned())
+            .or_insert(IntegrationStatus {
+                discovery: None,
+                devices: 0,
+            })
+            .devices += 1;
+    }
+    Status {
+        healthy: health.healthy(),
+        integrations,
+        last_sync: health.last_sync.lock().unwrap().map(timestamp),
+        sync_failing: health.sync_error.lock().unwrap().is_some(),
+        sync_error: health.sync_error.lock().unwrap().clone(),
+        pending_syncs: health.pending_syncs.load(Ordering::SeqCst),
+        standby: health.standby(),
+        command_queues: app
+            .limiters
+            .iter()
+            .map(|(vendor, limiter)| (vendor.clone(), limiter.queued()))
+            .collect(),
+        token_expiry: health
+            .tokens
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(name, expiry)| (name.clone(), timestamp(*expiry)))
+            .collect(),
+    }
+}
+
+fn label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+fn sample(metrics: &mut String, name: &str, device: &DeviceStatistics, extra: &str, value: f64) {
+    let _ = writeln!(
+        metrics,
+        "{}{{device=\"{}\",integration=\"{}\"{}}} {}",
+        name,
+        label(device.id.as_str()),
+        label(&device.integration),
+        extra,
+        value
+    );
+}
+
+/// Per-device command statistics and climate sensor readings in the
+/// Prometheus text format.
+fn metrics(app: &App) -> String {
+    let devices = app.device_stats();
+    let mut metrics = String::new();
+    let gauges: &[(&str, &str, fn(&DeviceStatistics) -> f64)] = &[
+        (
+            "lights_device_commands",
+            "Recent commands the statistics cover.",
+            |device| device.commands as f64,
+        ),
+        (
+            "lights_device_success_ratio",
+            "Fraction of recent commands that succeeded.",
+            |device| device.success_rate as f64,
+        ),
+        (
+            "lights_device_timeouts",
+            "Recent commands that timed out.",
+            |device| device.timeouts as f64,
+        ),
+    ];
+    for (name, help, value) in gauges {
+        let _ = writeln!(metrics, "# HELP {} {}\n# TYPE {} gauge", name, help, name);
+        for device in &devices {
+            sample(&mut metrics, name, device, "", value(device));
+        }
+    }
+    let name = "lights_device_latency_seconds";
+    let _ = writeln!(
+        metrics,
+        "# HELP {} Latency of recent commands.\n# TYPE {} summary",
+        name, name
+    );
+    for device in &devices {
+        let quantiles = [
+            ("0.5", device.p50_ms),
+            ("0.9", device.p90_ms),
+            ("0.99", device.p99_ms),
+        ];
+        for (quantile, ms) in &quantiles {
+            if let Some(ms) = ms {
+                let extra = format!(",quantile=\"{}\"", quantile);
+                sample(&mut metrics, name, device, &extra, *ms as f64 / 1000.);
+            }
+        }
+    }
+    let climate = app
+        .sensors()
+        .into_iter()
+        .filter_map(|sensor| match sensor.reading {
+            Some(SensorReading::Climate { celsius, humidity }) => {
+                Some((sensor.id, [celsius, humidity]))
+            }
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    let gauges = [
+        (
+            "lights_sensor_temperature_celsius",
+            "Last temperature a climate sensor reported.",
+        ),
+        (
+            "lights_sensor_humidity_percent",
+            "Last relative humidity a climate sensor reported.",
+        ),
+    ];
+    for (index, (name, help)) in gauges.iter().enumerate() {
+        let _ = writeln!(metrics, "# HELP {} {}\n# TYPE {} gauge", name, help, name);
+        for (sensor, values) in &climate {
+            if let Some(value) = values[index] {
+                let _ = writeln!(
+                    metrics,
+                    "{}{{sensor=\"{}\"}} {}",
+                    name,
+                    label(sensor.as_str()),
+                    value
+                );
+            }
+        }
+    }
+    metrics
+}
+
+/// `GET /healthz` answers 503 while any integration has failed discovery or
+/// HomeGraph syncs are failing, and 409 on a standby instance so that load
+/// balancers only send traffic to the active one. `GET /status` reports the details and
+/// `GET /metrics` exports per-device command statistics and climate readings
+/// for Prometheus.
+pub fn health(app: Arc<RwLock<App>>) -> BoxedFilter<(impl Reply,)> {
+    let healthz = warp::path("healthz").and(warp::path::end()).and_then({
+        let app = app.clone();
+        move || {
+            let app = app.clone();
+            async move {
+                let health = app.read().await.health();
+                let (reply, code) = if health.standby() {
+                    ("standby", StatusCode::CONFLICT)
+                } else if health.healthy() {
+                    ("ok", StatusCode::OK)
+                } else {
+                    ("unhealthy", StatusCode::SERVICE_UNAVAILABLE)
+                };
+                Ok::<_, core::convert::Infallible>(warp::reply::with_status(reply, code))
+            }
+        }
+    });
+    let metrics = warp::path("metrics")
+        .and(warp::path::end())
+        .and(authorized())
+        .and_then({
+            let app = app.clone();
+            move || {
+                let app = app.clone();
+                async move {
+                    Ok::<_, core::convert::Infallible>(warp::reply::with_header(
+                        metrics(&*app.read().await),
+                        "content-type",
+                        "text/plain; version=0.0.4",
+                    ))
+                }
+            }
+        });
+    let status = warp::path("status")
+        .and(warp::path::end())
+        .and(authorized())
+        .and_then(move || {
+            let app = app.clone();
+            async move {
+                Ok::<_, core::convert::Infallible>(warp::reply::json(&status(&*app.read().await)))
+            }
+        });
+    warp::get()
+        .and(
+            healthz
+                .map(Reply::into_response)
+                .or(status.map(Reply::into_response))
+                .unify()
+                .or(metrics.map(Reply::into_response))
+                .unify(),
+        )
+        .boxed()
+}
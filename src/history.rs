@@ -0,0 +1,54 @@
+use std::{
+    collections::VecDeque,
+    time::{Duration, Instant},
+};
+
+use crate::{App, Error, Id, LightState};
+
+/// How many steps back a light can be undone.
+const DEPTH: usize = 16;
+/// Changes closer together than this are undone as one step, so a command
+/// that sets power, color and brightness, or a whole fade, reverts at once.
+const BURST: Duration = Duration::from_secs(2);
+
+/// The states a light had before its recent changes, newest last.
+#[derive(Default)]
+pub(crate) struct History {
+    saved: VecDeque<LightState>,
+    last_change: Option<Instant>,
+}
+
+impl History {
+    /// Notes a change the device accepted, given the state from before it.
+    pub(crate) fn record(&mut self, before: LightState) {
+        let now = Instant::now();
+        if self
+            .last_change
+            .map_or(true, |last| now.duration_since(last) > BURST)
+        {
+            if self.saved.len() == DEPTH {
+                self.saved.pop_front();
+            }
+            self.saved.push_back(before);
+        }
+        self.last_change = Some(now);
+    }
+}
+
+impl App {
+    /// Puts a light back the way it was before its most recent change.
+    pub(crate) async fn undo(&self, id: &str) -> Result<(), Error> {
+        let wrapper = self.by_id.get(&Id(id.into())).ok_or(Error::Absent)?;
+        let (saved, depth) = {
+            let mut history = wrapper.history.lock().unwrap();
+            let saved = history.saved.pop_back().ok_or(Error::NothingToUndo)?;
+            (saved, history.saved.len())
+        };
+        let result = self.apply(id, saved).await;
+        // Reverting isn't itself a step to undo.
+        let mut history = wrapper.history.lock().unwrap();
+        history.saved.truncate(depth);
+        history.last_change = None;
+        result
+    }
+}
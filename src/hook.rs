@@ -1,18 +1,31 @@
+use std::{collections::HashMap, sync::Arc};
+
+use async_lock::RwLock;
 use serde::{Deserialize, Serialize};
+use serde_json::Value;
 use warp::Rejection;
 
+use crate::{
+    intent::{execute, DeviceCommand},
+    policy::Origin,
+    schema::{self, Read},
+    storage::storage,
+    App,
+};
+
 impl warp::reject::Reject for SerdeRejection {}
 
 #[derive(Debug)]
 struct SerdeRejection(serde_json::Error);
 
-#[derive(Deserialize)]
+#[derive(Deserialize, Serialize)]
 #[serde(rename_all = "snake_case")]
 enum HandlerNameRaw {
     RunProgram,
+    SetColor,
 }
 
-#[derive(Deserialize)]
+#[derive(Deserialize, Serialize)]
 struct HookHandler {
     name: HandlerNameRaw,
 }
@@ -20,6 +33,7 @@ struct HookHandler {
 #[derive(Debug, Clone)]
 enum HandlerCommand {
     RunProgram { program: String },
+    SetColor { light: String, color: String },
 }
 
 impl<'de> Deserialize<'de> for HookData {
@@ -27,12 +41,20 @@ impl<'de> Deserialize<'de> for HookData {
     where
         D: serde::Deserializer<'de>,
     {
+        let raw = Value::deserialize(deserializer)?;
+        let Read { value, unknown } = schema::read(raw, "").map_err(serde::de::Error::custom)?;
+        if !unknown.is_empty() {
+            eprintln!(
+                "warning: webhook request has unknown fields: {}",
+                unknown.join(", ")
+            );
+        }
         let HookRequest {
-            session: HookSession { id },
+            session: HookRequestSession { id, .. },
             intent,
             handler: HookHandler { name },
-            scene,
-        } = HookRequest::deserialize(deserializer)?;
+            ..
+        } = value;
         Ok(HookData {
             session: id,
             command: match name {
@@ -42,11 +64,25 @@ impl<'de> Deserialize<'de> for HookData {
                         .ok_or(serde::de::Error::custom(format!("`program` param invalid")))?;
                     HandlerCommand::RunProgram { program }
                 }
+                HandlerNameRaw::SetColor => {
+                    let light = intent
+                        .param_as_str("light")
+                        .ok_or(serde::de::Error::custom(format!("`light` param invalid")))?;
+                    let color = intent
+                        .param_as_str("color")
+                        .ok_or(serde::de::Error::custom(format!("`color` param invalid")))?;
+                    HandlerCommand::SetColor { light, color }
+                }
             },
         })
     }
 }
 
+/// Fields of a webhook request that the models here don't know.
+pub(crate) fn unknown_fields(raw: Value) -> Result<Vec<String>, serde_json::Error> {
+    schema::read::<HookRequest>(raw, "").map(|read| read.unknown)
+}
+
 #[derive(Debug, Clone)]
 pub struct HookData {
     session: SessionId,
@@ -63,22 +99,64 @@ struct SlotValue {
     value: String,
 }
 
-#[derive(Deserialize, Debug)]
+#[derive(Deserialize, Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct HookRequestSession {
+    id: SessionId,
+    #[serde(default)]
+    params: Value,
+    #[serde(default)]
+    type_overrides: Vec<Value>,
+    #[serde(default)]
+    language_code: Option<String>,
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
 struct HookScene {
+    #[serde(default)]
+    name: Option<String>,
+    #[serde(default)]
+    slot_filling_status: Option<String>,
+    #[serde(default)]
     slots: serde_json::Value,
+    #[serde(default)]
+    next: Option<Value>,
 }
 
-#[derive(Deserialize, Debug)]
+#[derive(Deserialize, Serialize, Debug)]
+struct IntentParameter {
+    #[serde(default)]
+    original: Option<String>,
+    resolved: Value,
+}
+
+#[derive(Deserialize, Serialize, Debug)]
 struct HookIntent {
-    params: serde_json::Value,
+    #[serde(default)]
+    name: Option<String>,
+    #[serde(default)]
+    params: HashMap<String, IntentParameter>,
+    #[serde(default)]
+    query: Option<String>,
 }
 
-#[derive(Deserialize)]
+/// A conversation webhook request, modelled in full so that fields Google
+/// adds are noticed. Those about who the conversation is with aren't read.
+#[derive(Deserialize, Serialize)]
 struct HookRequest {
     handler: HookHandler,
-    session: HookSession,
+    session: HookRequestSession,
     scene: HookScene,
     intent: HookIntent,
+    #[serde(default)]
+    user: Value,
+    #[serde(default)]
+    home: Value,
+    #[serde(default)]
+    device: Value,
+    #[serde(default)]
+    context: Value,
 }
 
 impl HookScene {
@@ -111,9 +189,7 @@ impl HookIntent {
     fn param_as_str(&self, param: &str) -> Option<String> {
         self.params
             .get(param)
-            .map(|item| item.get("resolved"))
-            .flatten()
-            .map(|item| item.as_str())
+            .map(|item| item.resolved.as_str())
             .flatten()
             .map(str::to_owned)
     }
@@ -193,36 +269,71 @@ struct HookResponseSession {
     id: SessionId,
 }
 
+fn type_override<I: IntoIterator<Item = T>, T: AsRef<str>>(name: &str, entries: I) -> TypeOverride {
+    TypeOverride {
+        name: name.into(),
+        type_override_mode: TypeOverrideMode::TypeReplace,
+        synonym: TypeSynonym {
+            entries: entries
+                .into_iter()
+                .map(|entry| {
+                    let name = entry.as_ref().to_owned();
+                    TypeEntry {
+                        name: name.to_owned(),
+                        synonyms: vec![name.to_lowercase().into()],
+                    }
+                })
+                .collect(),
+        },
+    }
+}
+
 impl HookResponseBuilder {
-    fn build<I: IntoIterator<Item = T>, T: AsRef<str>>(self, programs: I) -> HookResponse {
+    /// Offers the stored programs and saved colors as the values of the
+    /// `program` and `color` types.
+    fn build<I: IntoIterator<Item = T>, T: AsRef<str>>(
+        self,
+        programs: I,
+        colors: Vec<String>,
+    ) -> HookResponse {
         HookResponse {
             session: HookResponseSession {
                 id: self.session.id,
-                type_overrides: vec![TypeOverride {
-                    name: "program".into(),
-                    type_override_mode: TypeOverrideMode::TypeReplace,
-                    synonym: TypeSynonym {
-                        entries: programs
-                            .into_iter()
-                            .map(|light| {
-                                let name = light.as_ref().to_owned();
-                                TypeEntry {
-                                    name: name.to_owned(),
-                                    synonyms: vec![name.to_lowercase().into()],
-                                }
-                            })
-                            .collect(),
-                    },
-                }],
+                type_overrides: vec![
+                    type_override("program", programs),
+                    type_override("color", colors),
+                ],
             },
             prompt: self.prompt,
         }
     }
 }
 
-pub async fn hook(input: HookData) -> Result<String, Rejection> {
+/// Sets a light, found by id or name, to a saved color.
+async fn set_color(app: &App, light: &str, color: &str) -> String {
+    let color = match app.registry.color(color) {
+        Some(color) => color,
+        None => return format!("There's no color called {}.", color),
+    };
+    let id = app
+        .lights()
+        .find(|wrapper| wrapper.id() == light || wrapper.name().eq_ignore_ascii_case(light))
+        .map(|wrapper| wrapper.id());
+    let id = match id {
+        Some(id) => id,
+        None => return format!("There's no light called {}.", light),
+    };
+    match execute(app, &id, &[DeviceCommand::Color(color)], Origin::Assistant).await {
+        Ok(()) => format!("Done."),
+        Err(e) => format!("That didn't work: {}.", e),
+    }
+}
+
+pub async fn hook(app: Arc<RwLock<App>>, input: HookData) -> Result<String, Rejection> {
     let command = input.command;
     let session = input.session;
+    let programs = storage().blobs("programs");
+    let app = app.read().await;
     serde_json::to_string(
         &session
             .make_response(&match command {
@@ -232,7 +343,7 @@ pub async fn hook(input: HookData) -> Result<String, Rejection> {
                         std::env::var("ESP_AUTH_TOKEN").unwrap_or("".into())
                     ))
                     .body(surf::Body::from_bytes(
-                        std::fs::read(format!("programs/{}.wasm", program)).unwrap_or(vec![]),
+                        programs.get(&program).ok().flatten().unwrap_or(vec![]),
                     ))
                     .send()
                     .await
@@ -242,26 +353,15 @@ pub async fn hook(input: HookData) -> Result<String, Rejection> {
                         format!("Something went wrong lol")
                     }
                 }
-                _ => format!("Something went wrong lmao"),
+                HandlerCommand::SetColor { light, color } => set_color(&app, &light, &color).await,
             })
             .build(
-                std::fs::read_dir("./programs")
-                    .unwrap()
+                programs.keys().unwrap_or(vec![]),
+                app.registry
+                    .colors()
                     .into_iter()
-                    .map(|path| {
-                        format!(
-                            "{}",
-                            path.unwrap()
-                                .path()
-                                .file_name()
-                                .unwrap()
-                                .to_str()
-                                .unwrap()
-                                .split('.')
-                                .next()
-                                .unwrap()
-                        )
-                    }),
+                    .map(|(name, _)| name)
+                    .collect(),
             ),
     )
     .map_err(|e| warp::reject::custom(SerdeRejection(e)))
@@ -0,0 +1,694 @@
+use std::{
+    collections::BTreeMap,
+    convert::Infallible,
+    io,
+    net::{Ipv4Addr, SocketAddr, UdpSocket},
+    ops::RangeInclusive,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use async_io::Async;
+use async_lock::RwLock;
+use bytes::Bytes;
+use futures::future::join;
+use lazy_static::lazy_static;
+use serde::Deserialize;
+use serde_json::{json, Map, Value};
+use uuid::Uuid;
+use warp::{filters::BoxedFilter, http::Method, path::Tail, Filter, Reply};
+
+use crate::{
+    forwards,
+    intent::{self, DeviceCommand, DeviceQuery, DeviceSync},
+    policy::Origin,
+    storage::{storage, Store},
+    App, Brightness, Color, DeviceKind, DEFAULT_TEMPERATURE,
+};
+
+const SSDP_PORT: u16 = 1900;
+const SSDP_GROUP: Ipv4Addr = Ipv4Addr::new(239, 255, 255, 250);
+const API_VERSION: &str = "1.41.0";
+const SOFTWARE_VERSION: &str = "1941132080";
+
+/// A Hue bridge emulator for apps and remotes, such as Logitech Harmony,
+/// that only control lights through a bridge on the LAN, from `hue.toml`.
+#[derive(Deserialize, Clone)]
+pub struct HueConfig {
+    /// The address the bridge listens on and apps are told to connect to.
+    pub ip: Ipv4Addr,
+    /// Harmony hubs only try port 80.
+    #[serde(default = "default_port")]
+    pub port: u16,
+    /// Any stable MAC address, which the bridge id is derived from.
+    pub mac: String,
+    /// How long apps can pair after the link button is pressed with the
+    /// `PressLinkButton` request.
+    #[serde(default = "default_pairing_secs")]
+    pub pairing_secs: u64,
+}
+
+lazy_static! {
+    /// When the link button was last pressed.
+    static ref PRESSED: Mutex<Option<Instant>> = Mutex::new(None);
+}
+
+/// Opens the bridge to pairing for the configured time.
+pub(crate) fn press_link_button() {
+    *PRESSED.lock().unwrap() = Some(Instant::now());
+}
+
+fn default_port() -> u16 {
+    80
+}
+
+fn default_pairing_secs() -> u64 {
+    5 * 60
+}
+
+/// Paired apps, by the username they were given, with the device type they
+/// paired as.
+fn users() -> Store<String> {
+    storage().store("hue-users")
+}
+
+/// The light behind each number the Hue API addresses lights by.
+fn numbers() -> Store<String> {
+    storage().store("hue-lights")
+}
+
+struct Bridge {
+    config: HueConfig,
+    numbers: Mutex<BTreeMap<u32, String>>,
+}
+
+impl Bridge {
+    fn new(config: HueConfig) -> Self {
+        let mut loaded = BTreeMap::new();
+        let store = numbers();
+        match store.keys() {
+            Ok(keys) => {
+                for key in keys {
+                    match (key.parse(), store.get(&key)) {
+                        (Ok(number), Ok(Some(id))) => {
+                            loaded.insert(number, id);
+                        }
+                        (_, Err(e)) => eprintln!("failed to load hue light {}: {}", key, e),
+                        _ => {}
+                    }
+                }
+            }
+            Err(e) => eprintln!("failed to list stored hue lights: {}", e),
+        }
+        Bridge {
+            config,
+            numbers: Mutex::new(loaded),
+        }
+    }
+    fn mac(&self) -> String {
+        self.config.mac.to_lowercase()
+    }
+    fn serial(&self) -> String {
+        self.mac().replace(':', "")
+    }
+    /// The MAC address with `FFFE` in the middle, as real bridges report.
+    fn bridge_id(&self) -> String {
+        let serial = self.serial().to_uppercase();
+        let middle = serial.len().min(6);
+        format!("{}FFFE{}", &serial[..middle], &serial[middle..])
+    }
+    fn udn(&self) -> String {
+        format!("uuid:2f402f80-da50-11e1-9b23-{}", self.serial())
+    }
+    fn pairing(&self) -> bool {
+        let window = Duration::from_secs(self.config.pairing_secs);
+        PRESSED
+            .lock()
+            .unwrap()
+            .map_or(false, |pressed| pressed.elapsed() < window)
+    }
+    fn paired(&self, user: &str) -> bool {
+        matches!(users().get(user), Ok(Some(_)))
+    }
+    /// Every light with its number, numbering lights the first time they're
+    /// listed so that numbers stay the same across restarts.
+    fn lights(&self, app: &App) -> Vec<(u32, DeviceSync)> {
+        let mut numbers = self.numbers.lock().unwrap();
+        let mut lights = intent::sync(app)
+            .into_iter()
+            .filter(|device| device.kind == DeviceKind::Light)
+            .map(|device| {
+                let existing = numbers
+                    .iter()
+                    .find(|(_, id)| **id == device.id)
+                    .map(|(number, _)| *number);
+                let number = existing.unwrap_or_else(|| {
+                    let number = numbers.keys().next_back().map_or(1, |last| last + 1);
+                    if let Err(e) = self::numbers().put(&number.to_string(), &device.id) {
+                        eprintln!("failed to persist hue light {}: {}", number, e);
+                    }
+                    numbers.insert(number, device.id.clone());
+                    number
+                });
+                (number, device)
+            })
+            .collect::<Vec<_>>();
+        lights.sort_by_key(|(number, _)| *number);
+        lights
+    }
+    fn short_config(&self) -> Value {
+        json!({
+            "name": "lights",
+            "datastoreversion": "98",
+            "swversion": SOFTWARE_VERSION,
+            "apiversion": API_VERSION,
+            "mac": self.mac(),
+            "bridgeid": self.bridge_id(),
+            "factorynew": false,
+            "replacesbridgeid": null,
+            "modelid": "BSB002",
+            "starterkitid": "",
+        })
+    }
+    fn config(&self) -> Value {
+        let mut config = self.short_config();
+        let whitelist = users()
+            .keys()
+            .unwrap_or_default()
+            .into_iter()
+            .filter_map(|user| {
+                let name = users().get(&user).ok()??;
+                Some((user, json!({ "name": name })))
+            })
+            .collect::<Map<_, _>>();
+        config.as_object_mut().unwrap().extend(
+            json!({
+                "zigbeechannel": 15,
+                "dhcp": true,
+                "ipaddress": self.config.ip.to_string(),
+                "netmask": "255.255.255.0",
+                "gateway": self.config.ip.to_string(),
+                "proxyaddress": "none",
+                "proxyport": 0,
+                "timezone": "UTC",
+                "linkbutton": self.pairing(),
+                "portalservices": false,
+                "whitelist": whitelist,
+            })
+            .as_object()
+            .unwrap()
+            .clone(),
+        );
+        config
+    }
+    fn description(&self) -> String {
+        format!(
+            r#"<?xml version="1.0" encoding="UTF-8" ?>
+<root xmlns="urn:schemas-upnp-org:device-1-0">
+<specVersion><major>1</major><minor>0</minor></specVersion>
+<URLBase>http://{ip}:{port}/</URLBase>
+<device>
+<deviceType>urn:schemas-upnp-org:device:Basic:1</deviceType>
+<friendlyName>lights ({ip})</friendlyName>
+<manufacturer>Royal Philips Electronics</manufacturer>
+<manufacturerURL>http://www.philips.com</manufacturerURL>
+<modelDescription>Philips hue Personal Wireless Lighting</modelDescription>
+<modelName>Philips hue bridge 2015</modelName>
+<modelNumber>BSB002</modelNumber>
+<modelURL>http://www.meethue.com</modelURL>
+<serialNumber>{serial}</serialNumber>
+<UDN>{udn}</UDN>
+<presentationURL>index.html</presentationURL>
+</device>
+</root>
+"#,
+            ip = self.config.ip,
+            port = self.config.port,
+            serial = self.serial(),
+            udn = self.udn(),
+        )
+    }
+}
+
+fn error(kind: u32, address: &str, description: impl ToString) -> Value {
+    json!([{
+        "error": {
+            "type": kind,
+            "address": address,
+            "description": description.to_string(),
+        }
+    }])
+}
+
+fn rgb(hue: f64, saturation: f64) -> Color {
+    let chroma = saturation;
+    let x = chroma * (1. - ((hue / 60.) % 2. - 1.).abs());
+    let (r, g, b) = match (hue / 60.) as u32 {
+        0 => (chroma, x, 0.),
+        1 => (x, chroma, 0.),
+        2 => (0., chroma, x),
+        3 => (0., x, chroma),
+        4 => (x, 0., chroma),
+        _ => (chroma, 0., x),
+    };
+    let m = 1. - chroma;
+    let channel = |value: f64| ((value + m) * 255.).round() as u8;
+    Color::Rgb {
+        r: channel(r),
+        g: channel(g),
+        b: channel(b),
+    }
+}
+
+/// Hue in degrees and saturation from 0 to 1.
+fn hue_saturation(color: Color) -> (f64, f64) {
+    let (r, g, b) = color.to_rgb();
+    let r = r as f64 / 255.;
+    let g = g as f64 / 255.;
+    let b = b as f64 / 255.;
+    let cmax = r.max(g.max(b));
+    let diff = cmax - r.min(g.min(b));
+    let hue = if diff == 0. {
+        0.
+    } else if cmax == r {
+        (60. * ((g - b) / diff) + 360.) % 360.
+    } else if cmax == g {
+        60. * ((b - r) / diff) + 120.
+    } else {
+        60. * ((r - g) / diff) + 240.
+    };
+    (hue, if cmax == 0. { 0. } else { diff / cmax })
+}
+
+/// A CIE xy point at full brightness, using the wide gamut conversion
+/// Philips documents for its lights.
+fn xy_rgb(x: f64, y: f64) -> Color {
+    let y = y.max(0.0001);
+    let (big_x, big_y, big_z) = (x / y, 1., (1. - x - y) / y);
+    let r = big_x * 1.656492 - big_y * 0.354851 - big_z * 0.255038;
+    let g = -big_x * 0.707196 + big_y * 1.655397 + big_z * 0.036152;
+    let b = big_x * 0.051713 - big_y * 0.121364 + big_z * 1.011530;
+    let max = r.max(g.max(b)).max(0.0001);
+    let channel = |value: f64| {
+        let value = (value / max).max(0.);
+        let value = if value <= 0.0031308 {
+            12.92 * value
+        } else {
+            1.055 * value.powf(1. / 2.4) - 0.055
+        };
+        (value.min(1.) * 255.).round() as u8
+    };
+    Color::Rgb {
+        r: channel(r),
+        g: channel(g),
+        b: channel(b),
+    }
+}
+
+fn rgb_xy(color: Color) -> (f64, f64) {
+    let (r, g, b) = color.to_rgb();
+    let linear = |value: u8| {
+        let value = value as f64 / 255.;
+        if value > 0.04045 {
+            ((value + 0.055) / 1.055).powf(2.4)
+        } else {
+            value / 12.92
+        }
+    };
+    let (r, g, b) = (linear(r), linear(g), linear(b));
+    let x = r * 0.664511 + g * 0.154324 + b * 0.162028;
+    let y = r * 0.283881 + g * 0.668433 + b * 0.047685;
+    let z = r * 0.000088 + g * 0.072310 + b * 0.986039;
+    let sum = x + y + z;
+    if sum == 0. {
+        // The white point, for black.
+        (0.3127, 0.329)
+    } else {
+        (x / sum, y / sum)
+    }
+}
+
+/// Hue brightness runs from 1 to 254.
+const BRI: RangeInclusive<u32> = 1..=254;
+
+fn light_state(query: &DeviceQuery) -> Value {
+    let mut state = json!({
+        "on": query.on,
+        "bri": query.brightness.to_range(BRI),
+        "alert": "none",
+        "reachable": query.online,
+    });
+    if query.supports_color {
+        let color = query.color.unwrap_or(Color::White {
+            temperature: DEFAULT_TEMPERATURE,
+        });
+        let (hue, saturation) = hue_saturation(color);
+        let (x, y) = rgb_xy(color);
+        let (colormode, ct) = match color.mireds() {
+            Some(ct) => ("ct", ct),
+            None => ("hs", 366),
+        };
+        state.as_object_mut().unwrap().extend(
+            json!({
+                "hue": (hue / 360. * 65535.).round() as u16,
+                "sat": (saturation * 254.).round() as u8,
+                "xy": [x, y],
+                "ct": ct,
+                "effect": "none",
+                "colormode": colormode,
+            })
+            .as_object()
+            .unwrap()
+            .clone(),
+        );
+    }
+    state
+}
+
+fn light(number: u32, device: &DeviceSync, query: &DeviceQuery) -> Value {
+    let (kind, model) = if device.supports_color {
+        ("Extended color light", "LCT015")
+    } else {
+        ("Dimmable light", "LWB010")
+    };
+    json!({
+        "state": light_state(query),
+        "type": kind,
+        "name": device.name,
+        "modelid": model,
+        "manufacturername": "Philips",
+        "uniqueid": format!(
+            "00:17:88:01:{:02x}:{:02x}:{:02x}:{:02x}-0b",
+            (number >> 24) as u8,
+            (number >> 16) as u8,
+            (number >> 8) as u8,
+            number as u8,
+        ),
+        "swversion": "1.46.13_r26312",
+    })
+}
+
+fn lights(app: &App, bridge: &Bridge) -> Value {
+    bridge
+        .lights(app)
+        .into_iter()
+        .filter_map(|(number, device)| {
+            let query = intent::query_device(app, app.light(&device.id)?);
+            Some((number.to_string(), light(number, &device, &query)))
+        })
+        .collect::<Map<_, _>>()
+        .into()
+}
+
+/// A state change in the form every assistant shares, given the light's
+/// current color for changes to only its hue or saturation.
+fn device_commands(body: &Value, current: Option<Color>) -> Vec<DeviceCommand> {
+    let mut commands = vec![];
+    if let Some(on) = body["on"].as_bool() {
+        commands.push(DeviceCommand::Power(on));
+        if !on {
+            return commands;
+        }
+    }
+    if let Some(bri) = body["bri"].as_u64() {
+        let bri = bri.min(*BRI.end() as u64) as u32;
+        commands.push(DeviceCommand::Brightness(Brightness::from_range(bri, BRI)));
+    }
+    let (hue, saturation) = current.map_or((0., 0.), hue_saturation);
+    let xy = body["xy"]
+        .as_array()
+        .and_then(|xy| Some((xy.get(0)?.as_f64()?, xy.get(1)?.as_f64()?)));
+    if let Some((x, y)) = xy {
+        commands.push(DeviceCommand::Color(xy_rgb(x, y)));
+    } else if let Some(ct) = body["ct"].as_u64() {
+        let ct = ct.min(u32::MAX as u64) as u32;
+        commands.push(DeviceCommand::Color(Color::from_mireds(ct)));
+    } else if body["hue"].is_u64() || body["sat"].is_u64() {
+        let hue = body["hue"]
+            .as_u64()
+            .map_or(hue, |hue| hue.min(65535) as f64 / 65535. * 360.);
+        let saturation = body["sat"]
+            .as_u64()
+            .map_or(saturation, |sat| sat.min(254) as f64 / 254.);
+        commands.push(DeviceCommand::Color(rgb(hue, saturation)));
+    }
+    commands
+}
+
+/// Applies a state change to the lights behind `address` and acknowledges
+/// each attribute in it, as bridges do.
+async fn set_state(app: &App, ids: &[String], address: &str, body: &Value) -> Value {
+    let changes = match body.as_object() {
+        Some(changes) => changes,
+        None => return error(2, address, "body contains invalid json"),
+    };
+    let mut failed = None;
+    for id in ids {
+        let current = app
+            .light(id)
+            .and_then(|light| intent::query_device(app, light).color);
+        let commands = device_commands(body, current);
+        // Apps on the LAN aren't an assistant, so what they send counts as
+        // an API call.
+        if let Err(e) = intent::execute(app, id, &commands, Origin::Api).await {
+            eprintln!("hue command for {} failed: {}", id, e);
+            failed = Some(e);
+        }
+    }
+    // Every light is tried, and any failure reported once they all were.
+    if let Some(e) = failed {
+        return error(901, address, e);
+    }
+    // Turning a light off leaves the rest of the change unapplied.
+    let off = body["on"].as_bool() == Some(false);
+    changes
+        .iter()
+        .filter(|(key, _)| !off || *key == "on")
+        .map(|(key, value)| json!({ "success": { format!("{}/{}", address, key): value } }))
+        .collect::<Vec<_>>()
+        .into()
+}
+
+async fn respond(app: &App, bridge: &Bridge, method: &Method, path: &str, body: &[u8]) -> Value {
+    let segments = path
+        .split('/')
+        .filter(|segment| !segment.is_empty())
+        .collect::<Vec<_>>();
+    let body = serde_json::from_slice::<Value>(body).unwrap_or(Value::Null);
+    let (user, rest) = match (method, segments.split_first()) {
+        (&Method::POST, None) => {
+            if !bridge.pairing() {
+                return error(101, "", "link button not pressed");
+            }
+            if let Err(e) = app.check_active() {
+                return error(901, "", e);
+            }
+            let user = Uuid::new_v4().to_simple().to_string();
+            let name = body["devicetype"].as_str().unwrap_or("unknown");
+            if let Err(e) = users().put(&user, &name.to_owned()) {
+                return error(901, "", e);
+            }
+            return json!([{ "success": { "username": user } }]);
+        }
+        (_, None) => {
+            return error(
+                4,
+                "/",
+                format!("method, {}, not available for resource, /", method),
+            )
+        }
+        (_, Some((user, rest))) => (*user, rest),
+    };
+    if !bridge.paired(user) {
+        // Apps check whether an address is a bridge, before pairing, with
+        // any username.
+        return match rest {
+            ["config"] => bridge.short_config(),
+            _ if user == "config" => bridge.short_config(),
+            _ => error(1, &format!("/{}", rest.join("/")), "unauthorized user"),
+        };
+    }
+    // Group 0 holds every light, leaving out groups and composites, which
+    // would only send their members the same command again.
+    let members = || {
+        bridge.lights(app).into_iter().filter(|(_, device)| {
+            app.light(&device.id)
+                .map_or(false, |light| !forwards(light.light()))
+        })
+    };
+    let everything = || members().map(|(_, device)| device.id).collect::<Vec<_>>();
+    match (method, rest) {
+        (&Method::GET, []) => json!({
+            "lights": lights(app, bridge),
+            "groups": {},
+            "config": bridge.config(),
+            "schedules": {},
+            "scenes": {},
+            "rules": {},
+            "sensors": {},
+            "resourcelinks": {},
+        }),
+        (&Method::GET, ["config"]) => bridge.config(),
+        (&Method::GET, ["lights"]) => lights(app, bridge),
+        (&Method::GET, ["lights", number]) => {
+            let found = bridge
+                .lights(app)
+                .into_iter()
+                .find(|(n, _)| n.to_string() == *number);
+            match found.and_then(|(n, device)| Some((n, app.light(&device.id)?, device))) {
+                Some((n, wrapper, device)) => {
+                    light(n, &device, &intent::query_device(app, wrapper))
+                }
+                None => error(
+                    3,
+                    &format!("/lights/{}", number),
+                    format!("resource, /lights/{}, not available", number),
+                ),
+            }
+        }
+        (&Method::PUT, ["lights", number, "state"]) => {
+            let found = bridge
+                .lights(app)
+                .into_iter()
+                .find(|(n, _)| n.to_string() == *number);
+            let address = format!("/lights/{}/state", number);
+            match found {
+                Some((_, device)) => set_state(app, &[device.id], &address, &body).await,
+                None => error(
+                    3,
+                    &address,
+                    format!("resource, /lights/{}, not available", number),
+                ),
+            }
+        }
+        (&Method::GET, ["groups"]) => json!({}),
+        // Group 0 is every light, and the only group kept.
+        (&Method::GET, ["groups", "0"]) => {
+            let lights = members().collect::<Vec<_>>();
+            let any_on = lights.iter().any(|(_, device)| {
+                app.light(&device.id)
+                    .map_or(false, |light| intent::query_device(app, light).on)
+            });
+            json!({
+                "name": "Group 0",
+                "lights": lights
+                    .iter()
+                    .map(|(number, _)| number.to_string())
+                    .collect::<Vec<_>>(),
+                "type": "LightGroup",
+                "state": { "any_on": any_on, "all_on": false },
+                "action": { "on": any_on },
+            })
+        }
+        (&Method::PUT, ["groups", "0", "action"]) => {
+            set_state(app, &everything(), "/groups/0/action", &body).await
+        }
+        (&Method::GET, [kind]) => match *kind {
+            "schedules" | "scenes" | "rules" | "sensors" | "resourcelinks" => json!({}),
+            _ => error(
+                3,
+                &format!("/{}", kind),
+                format!("resource, /{}, not available", kind),
+            ),
+        },
+        _ => {
+            let address = format!("/{}", rest.join("/"));
+            error(
+                4,
+                &address,
+                format!(
+                    "method, {}, not available for resource, {}",
+                    method, address
+                ),
+            )
+        }
+    }
+}
+
+fn routes(app: Arc<RwLock<App>>, bridge: Arc<Bridge>) -> BoxedFilter<(impl Reply,)> {
+    let description = warp::get().and(warp::path!("description.xml")).map({
+        let bridge = bridge.clone();
+        move || warp::reply::with_header(bridge.description(), "content-type", "text/xml")
+    });
+    let api = warp::path("api")
+        .and(warp::method())
+        .and(warp::path::tail())
+        .and(warp::body::bytes())
+        .and_then(move |method: Method, tail: Tail, body: Bytes| {
+            let app = app.clone();
+            let bridge = bridge.clone();
+            async move {
+                let app = app.read().await;
+                let response = respond(&app, &bridge, &method, tail.as_str(), &body).await;
+                Ok::<_, Infallible>(warp::reply::json(&response))
+            }
+        });
+    description.or(api).boxed()
+}
+
+/// Answers SSDP searches so that apps find the bridge.
+async fn advertise(bridge: &Bridge) -> io::Result<()> {
+    let socket = Async::<UdpSocket>::bind(([0, 0, 0, 0], SSDP_PORT))?;
+    socket
+        .get_ref()
+        .join_multicast_v4(&SSDP_GROUP, &bridge.config.ip)?;
+    let mut buf = [0; 2048];
+    loop {
+        let (len, peer) = socket.recv_from(&mut buf).await?;
+        let request = String::from_utf8_lossy(&buf[..len]);
+        if !request.starts_with("M-SEARCH") || !request.contains("ssdp:discover") {
+            continue;
+        }
+        let target = request
+            .lines()
+            .find_map(|line| {
+                let (name, value) = line.split_at(line.find(':')?);
+                if name.trim().eq_ignore_ascii_case("st") {
+                    Some(value[1..].trim().to_owned())
+                } else {
+                    None
+                }
+            })
+            .unwrap_or_default();
+        let target = match target.as_str() {
+            "ssdp:all" | "upnp:rootdevice" => "upnp:rootdevice",
+            "urn:schemas-upnp-org:device:basic:1" | "urn:schemas-upnp-org:device:Basic:1" => {
+                "urn:schemas-upnp-org:device:basic:1"
+            }
+            _ => continue,
+        };
+        let response = format!(
+            "HTTP/1.1 200 OK\r\n\
+             HOST: 239.255.255.250:1900\r\n\
+             CACHE-CONTROL: max-age=100\r\n\
+             EXT:\r\n\
+             LOCATION: http://{}:{}/description.xml\r\n\
+             SERVER: Linux/3.14.0 UPnP/1.0 IpBridge/{}\r\n\
+             hue-bridgeid: {}\r\n\
+             ST: {}\r\n\
+             USN: {}::{}\r\n\r\n",
+            bridge.config.ip,
+            bridge.config.port,
+            API_VERSION,
+            bridge.bridge_id(),
+            target,
+            bridge.udn(),
+            target,
+        );
+        socket.send_to(response.as_bytes(), peer).await?;
+    }
+}
+
+/// Serves enough of the Hue API for apps to list and control lights, and
+/// advertises it over SSDP.
+pub async fn hue(app: Arc<RwLock<App>>, config: HueConfig) {
+    let bridge = Arc::new(Bridge::new(config));
+    let addr = SocketAddr::from((bridge.config.ip, bridge.config.port));
+    let server = warp::serve(routes(app, bridge.clone())).run(addr);
+    let discovery = async {
+        if let Err(e) = advertise(&bridge).await {
+            eprintln!("hue bridge discovery stopped: {}", e);
+        }
+    };
+    join(server, discovery).await;
+}
@@ -1,11 +1,12 @@
-use crate::PowerState;
+use super::{mac_address, vendor_error};
+use crate::{Brightness, LightError, PowerState};
+use async_lock::Mutex;
 use futures::{
     future::{BoxFuture, Either},
     TryFutureExt,
 };
-use smol::lock::Mutex;
 use std::{
-    error::Error,
+    ops::RangeInclusive,
     sync::atomic::{AtomicUsize, Ordering},
 };
 
@@ -13,9 +14,28 @@ static COUNT: AtomicUsize = AtomicUsize::new(1);
 
 use lights_broadlink::{Color, Connection};
 
+const MIN_KELVIN: u32 = 2700;
+const MAX_KELVIN: u32 = 6500;
+
+/// How white color temperatures are sent to a bulb.
+#[derive(Clone, Copy)]
+pub enum WhiteMode {
+    /// Send the temperature in Kelvin, clamped to the bulb's supported range.
+    Kelvin,
+    /// Send a 0-100 warm-to-cool mix, for bulbs that only blend two white
+    /// channels.
+    Mix,
+    /// Approximate the temperature with the RGB channels.
+    Rgb,
+}
+
 pub struct BroadlinkLight {
     name: String,
     light: Mutex<Connection>,
+    mac: Option<String>,
+    /// The address-based id used while the MAC address was unknown.
+    legacy_id: Option<String>,
+    white_mode: WhiteMode,
 }
 
 impl crate::Light for BroadlinkLight {
@@ -23,69 +43,106 @@ impl crate::Light for BroadlinkLight {
         self.name.clone()
     }
 
+    fn vendor(&self) -> &'static str {
+        "broadlink"
+    }
+
     fn set_power_state<'a>(
         &'a self,
         state: crate::PowerState,
-    ) -> BoxFuture<'a, Result<(), Box<dyn Error + Send>>> {
+    ) -> BoxFuture<'a, Result<(), LightError>> {
         let fut = match state {
             PowerState::On => Either::Left(async move { self.light.lock().await.turn_on().await }),
             PowerState::Off => {
                 Either::Right(async move { self.light.lock().await.turn_off().await })
             }
         };
-        Box::pin(fut.map_err(|e| Box::new(e) as Box<dyn Error + Send>))
+        Box::pin(fut.map_err(vendor_error))
     }
 
     fn set_brightness<'a>(
         &'a self,
-        brightness: u8,
-    ) -> BoxFuture<'a, Result<(), Box<dyn Error + Send>>> {
+        brightness: Brightness,
+    ) -> BoxFuture<'a, Result<(), LightError>> {
         Box::pin(async move {
             self.light
                 .lock()
                 .await
-                .set_brightness(brightness)
+                .set_brightness(brightness.into())
                 .await
-                .map_err(|e| Box::new(e) as Box<dyn Error + Send>)
+                .map_err(vendor_error)
         })
     }
 
-    fn set_color<'a>(
-        &'a self,
-        color: crate::Color,
-    ) -> BoxFuture<'a, Result<(), Box<dyn Error + Send>>> {
+    fn set_color<'a>(&'a self, color: crate::Color) -> BoxFuture<'a, Result<(), LightError>> {
         Box::pin(async move {
+            let color = match color {
+                crate::Color::Rgb { r, g, b } => Color::Rgb {
+                    red: r,
+                    green: g,
+                    blue: b,
+                },
+                crate::Color::White { temperature } => {
+                    let clamped = temperature.max(MIN_KELVIN).min(MAX_KELVIN);
+                    match self.white_mode {
+                        WhiteMode::Kelvin => Color::White {
+                            temperature: clamped,
+                        },
+                        WhiteMode::Mix => Color::White {
+                            temperature: (clamped - MIN_KELVIN) * 100 / (MAX_KELVIN - MIN_KELVIN),
+                        },
+                        WhiteMode::Rgb => {
+                            let (red, green, blue) = color.to_rgb();
+                            Color::Rgb { red, green, blue }
+                        }
+                    }
+                }
+            };
             self.light
                 .lock()
                 .await
-                .set_color(match color {
-                    crate::Color::Rgb { r, g, b } => Color::Rgb {
-                        red: r,
-                        green: g,
-                        blue: b,
-                    },
-                    crate::Color::White { temperature } => Color::White { temperature },
-                })
+                .set_color(color)
                 .await
-                .map_err(|e| Box::new(e) as Box<dyn Error + Send>)
+                .map_err(vendor_error)
         })
     }
 
-    fn unique_id<'a>(&'a self) -> BoxFuture<'a, Result<String, Box<dyn Error + Send>>> {
+    fn unique_id<'a>(&'a self) -> BoxFuture<'a, Result<String, LightError>> {
         Box::pin(async move {
-            Ok(format!(
-                "Broadlink Light {}",
-                self.light.lock().await.addr()
-            ))
+            Ok(match &self.mac {
+                Some(mac) => format!("Broadlink Light {}", mac),
+                None => format!("Broadlink Light {}", self.light.lock().await.addr()),
+            })
         })
     }
+
+    fn legacy_ids(&self) -> Vec<String> {
+        self.legacy_id.iter().cloned().collect()
+    }
+
+    fn color_temperature_range(&self) -> RangeInclusive<u32> {
+        MIN_KELVIN..=MAX_KELVIN
+    }
 }
 
 impl BroadlinkLight {
     pub fn new(light: Connection) -> Self {
+        let mac = mac_address(light.addr());
         BroadlinkLight {
             name: format!("Aliexpress Light {}", COUNT.fetch_add(1, Ordering::SeqCst)),
+            legacy_id: mac
+                .as_ref()
+                .map(|_| format!("Broadlink Light {}", light.addr())),
+            mac,
             light: Mutex::new(light),
+            white_mode: WhiteMode::Kelvin,
         }
     }
+    pub fn mac(&self) -> Option<&str> {
+        self.mac.as_deref()
+    }
+    pub fn with_white_mode(mut self, white_mode: WhiteMode) -> Self {
+        self.white_mode = white_mode;
+        self
+    }
 }
@@ -0,0 +1,584 @@
+use std::{
+    collections::HashMap,
+    io,
+    net::{IpAddr, SocketAddr, UdpSocket},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use aes::Aes128;
+use async_io::{Async, Timer};
+use async_lock::{Mutex, RwLock};
+use block_modes::{block_padding::NoPadding, BlockMode, Cbc};
+use futures::{
+    future::{select, BoxFuture, Either},
+    pin_mut,
+};
+use serde::Deserialize;
+use thiserror::Error;
+
+use crate::{
+    storage::{storage, Blobs, StorageError},
+    App, Brightness, Color, DeviceKind, Light, LightError, PowerState, Thermostat, ThermostatMode,
+};
+
+const VENDOR: &str = "broadlink-rm";
+const PORT: u16 = 80;
+const TIMEOUT: Duration = Duration::from_secs(2);
+/// How long a remote has to be pointed at the blaster once learning starts,
+/// and for RF remotes, how long its button has to be held for the sweep.
+const LEARN_WINDOW: Duration = Duration::from_secs(30);
+const LEARN_POLL: Duration = Duration::from_secs(1);
+
+const DEFAULT_KEY: [u8; 16] = [
+    0x09, 0x76, 0x28, 0x34, 0x3f, 0xe9, 0x9e, 0x23, 0x76, 0x5c, 0x15, 0x13, 0xac, 0xcf, 0x8b, 0x02,
+];
+const IV: [u8; 16] = [
+    0x56, 0x2e, 0x17, 0x99, 0x6d, 0x09, 0x3d, 0x28, 0xdd, 0xb3, 0xba, 0x69, 0x5a, 0x2e, 0x6f, 0x58,
+];
+const MAGIC: [u8; 8] = [0x5a, 0xa5, 0xaa, 0x55, 0x5a, 0xa5, 0xaa, 0x55];
+const HEADER: usize = 0x38;
+
+const AUTHENTICATE: u16 = 0x65;
+const COMMAND: u16 = 0x6a;
+
+const SEND_DATA: u32 = 0x02;
+const ENTER_LEARNING: u32 = 0x03;
+const CHECK_DATA: u32 = 0x04;
+const SWEEP_FREQUENCY: u32 = 0x19;
+const CHECK_FREQUENCY: u32 = 0x1a;
+const FIND_RF_PACKET: u32 = 0x1b;
+const CANCEL_SWEEP: u32 = 0x1e;
+
+type Aes128Cbc = Cbc<Aes128, NoPadding>;
+
+/// Broadlink RM blasters and the devices they drive by replaying learned
+/// codes, as listed in `broadlink.toml`.
+#[derive(Deserialize)]
+pub struct RmConfig {
+    #[serde(default, rename = "blaster")]
+    pub blasters: Vec<RmBlasterConfig>,
+    #[serde(default, rename = "device")]
+    pub devices: Vec<RmDeviceConfig>,
+}
+
+#[derive(Deserialize)]
+pub struct RmBlasterConfig {
+    pub name: String,
+    pub ip: IpAddr,
+    /// Such as `34:ea:34:01:02:03`.
+    pub mac: String,
+    /// For the RM4 family, which frames commands differently.
+    #[serde(default)]
+    pub rm4: bool,
+}
+
+/// A device controlled through a blaster. Its codes are learned with the
+/// `LearnCode` request under names starting with `codes`: `<codes> on` and
+/// `<codes> off` for every kind, `<codes> up` and `<codes> down` for
+/// dimmers, `<codes> speed 1` onwards for fans, and `<codes> <mode>
+/// <degrees>`, such as `ac cool 24`, for thermostats.
+#[derive(Deserialize)]
+pub struct RmDeviceConfig {
+    pub name: String,
+    pub blaster: String,
+    pub codes: String,
+    #[serde(default)]
+    pub kind: RmDeviceKind,
+    /// How many presses of `<codes> up` take a dimmer from its lowest level
+    /// to its highest.
+    #[serde(default)]
+    pub steps: u8,
+    /// Milliseconds between the presses of a dimmer, for devices that miss
+    /// presses sent too quickly.
+    #[serde(default = "default_press_interval")]
+    pub press_interval_ms: u64,
+    /// The number of speed codes a fan has.
+    #[serde(default)]
+    pub speeds: u8,
+    /// The modes a thermostat has codes for, including `off`.
+    #[serde(default)]
+    pub modes: Vec<ThermostatMode>,
+    /// The setpoints a thermostat has codes for, in whole degrees Celsius.
+    #[serde(default = "default_range")]
+    pub range: (f32, f32),
+}
+
+fn default_press_interval() -> u64 {
+    300
+}
+
+fn default_range() -> (f32, f32) {
+    (16., 30.)
+}
+
+#[derive(Deserialize, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum RmDeviceKind {
+    /// Only switched on and off, such as a TV.
+    Switch,
+    /// Synced as a light whose brightness is reached by pressing up or down
+    /// as many times as it takes, such as a lamp with IR dimming or a
+    /// soundbar's volume. Long runs of presses may need a longer timeout
+    /// for `broadlink-rm` in `timeouts.toml`.
+    Dimmer,
+    Fan,
+    Thermostat,
+}
+
+impl Default for RmDeviceKind {
+    fn default() -> Self {
+        RmDeviceKind::Switch
+    }
+}
+
+#[derive(Debug, Error)]
+pub(crate) enum BlasterError {
+    #[error("io error: {0}")]
+    Io(#[from] io::Error),
+    #[error("blaster answered with error {0}")]
+    Device(i16),
+    #[error("malformed reply from blaster")]
+    Malformed,
+    #[error("no code `{0}` has been learned")]
+    Unlearned(String),
+    #[error("failed to access codes: {0}")]
+    Storage(#[from] StorageError),
+}
+
+impl From<BlasterError> for LightError {
+    fn from(error: BlasterError) -> Self {
+        match error {
+            BlasterError::Io(error) => error.into(),
+            BlasterError::Storage(error) => LightError::other(error),
+            error => LightError::Protocol(error.to_string()),
+        }
+    }
+}
+
+/// Learned codes by name, shared by every blaster.
+fn codes() -> Blobs {
+    storage().blobs("broadlink")
+}
+
+/// Whether `name` can be stored as a code, which keeps it a plain file name.
+fn valid_name(name: &str) -> bool {
+    !name.trim().is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == ' ' || c == '-' || c == '_')
+}
+
+fn parse_mac(mac: &str) -> Option<[u8; 6]> {
+    let mut bytes = [0; 6];
+    let mut parts = mac.split(|c| c == ':' || c == '-');
+    for byte in &mut bytes {
+        *byte = u8::from_str_radix(parts.next()?, 16).ok()?;
+    }
+    parts.next().map_or(Some(bytes), |_| None)
+}
+
+fn checksum(data: &[u8]) -> u16 {
+    data.iter()
+        .fold(0xbeafu16, |sum, byte| sum.wrapping_add(*byte as u16))
+}
+
+fn cipher(key: &[u8; 16]) -> Aes128Cbc {
+    Aes128Cbc::new_var(key, &IV).expect("key and iv are a block long")
+}
+
+/// The key and id handed out when authenticating, along with the counter
+/// every packet carries.
+struct Session {
+    key: [u8; 16],
+    id: u32,
+    count: u16,
+    authenticated: bool,
+}
+
+/// An RM blaster on the LAN, spoken to over Broadlink's encrypted UDP
+/// protocol.
+pub(crate) struct Blaster {
+    addr: SocketAddr,
+    mac: [u8; 6],
+    rm4: bool,
+    session: Mutex<Session>,
+}
+
+impl Blaster {
+    fn new(config: &RmBlasterConfig) -> Result<Self, crate::Error> {
+        let mac = parse_mac(&config.mac).ok_or_else(|| {
+            crate::Error::InvalidConfig(format!("invalid mac address `{}`", config.mac))
+        })?;
+        Ok(Blaster {
+            addr: SocketAddr::new(config.ip, PORT),
+            mac,
+            rm4: config.rm4,
+            session: Mutex::new(Session {
+                key: DEFAULT_KEY,
+                id: 0,
+                count: 0,
+                authenticated: false,
+            }),
+        })
+    }
+
+    fn packet(&self, session: &mut Session, ty: u16, payload: &[u8]) -> Vec<u8> {
+        session.count = session.count.wrapping_add(1) | 0x8000;
+        let mut payload = payload.to_vec();
+        payload.resize((payload.len() + 15) / 16 * 16, 0);
+        let mut packet = vec![0; HEADER];
+        packet[..8].copy_from_slice(&MAGIC);
+        let device_type: u16 = if self.rm4 { 0x51da } else { 0x2712 };
+        packet[0x24..0x26].copy_from_slice(&device_type.to_le_bytes());
+        packet[0x26..0x28].copy_from_slice(&ty.to_le_bytes());
+        packet[0x28..0x2a].copy_from_slice(&session.count.to_le_bytes());
+        let mut mac = self.mac;
+        mac.reverse();
+        packet[0x2a..0x30].copy_from_slice(&mac);
+        packet[0x30..0x34].copy_from_slice(&session.id.to_le_bytes());
+        packet[0x34..0x36].copy_from_slice(&checksum(&payload).to_le_bytes());
+        packet.extend(cipher(&session.key).encrypt_vec(&payload));
+        let sum = checksum(&packet);
+        packet[0x20..0x22].copy_from_slice(&sum.to_le_bytes());
+        packet
+    }
+
+    async fn exchange(&self, packet: &[u8]) -> io::Result<Vec<u8>> {
+        let socket = Async::<UdpSocket>::bind(([0, 0, 0, 0], 0))?;
+        socket.send_to(packet, self.addr).await?;
+        let receive = async {
+            let mut reply = vec![0; 2048];
+            let (len, _) = socket.recv_from(&mut reply).await?;
+            reply.truncate(len);
+            Ok(reply)
+        };
+        let timeout = Timer::after(TIMEOUT);
+        pin_mut!(receive);
+        match select(receive, timeout).await {
+            Either::Left((result, _)) => result,
+            Either::Right(_) => Err(io::ErrorKind::TimedOut.into()),
+        }
+    }
+
+    /// Sends a packet and decrypts the payload of the reply.
+    async fn send(
+        &self,
+        session: &mut Session,
+        ty: u16,
+        payload: &[u8],
+    ) -> Result<Vec<u8>, BlasterError> {
+        let packet = self.packet(session, ty, payload);
+        let reply = self.exchange(&packet).await?;
+        if reply.len() < HEADER || (reply.len() - HEADER) % 16 != 0 {
+            return Err(BlasterError::Malformed);
+        }
+        let error = i16::from_le_bytes([reply[0x22], reply[0x23]]);
+        if error != 0 {
+            return Err(BlasterError::Device(error));
+        }
+        cipher(&session.key)
+            .decrypt_vec(&reply[HEADER..])
+            .map_err(|_| BlasterError::Malformed)
+    }
+
+    async fn authenticate(&self, session: &mut Session) -> Result<(), BlasterError> {
+        session.key = DEFAULT_KEY;
+        session.id = 0;
+        let mut payload = vec![0; 0x50];
+        payload[0x04..0x14].copy_from_slice(&[0x31; 16]);
+        payload[0x1e] = 0x01;
+        payload[0x2d] = 0x01;
+        payload[0x30..0x36].copy_from_slice(b"lights");
+        let reply = self.send(session, AUTHENTICATE, &payload).await?;
+        if reply.len() < 0x14 {
+            return Err(BlasterError::Malformed);
+        }
+        session.id = u32::from_le_bytes([reply[0], reply[1], reply[2], reply[3]]);
+        session.key.copy_from_slice(&reply[0x04..0x14]);
+        session.authenticated = true;
+        Ok(())
+    }
+
+    /// Runs a command, authenticating first if needed, and returns the data
+    /// it answered with.
+    async fn command(&self, command: u32, data: &[u8]) -> Result<Vec<u8>, BlasterError> {
+        let mut session = self.session.lock().await;
+        if !session.authenticated {
+            self.authenticate(&mut session).await?;
+        }
+        let mut payload = vec![];
+        if self.rm4 {
+            payload.extend_from_slice(&(data.len() as u16 + 4).to_le_bytes());
+        }
+        payload.extend_from_slice(&command.to_le_bytes());
+        payload.extend_from_slice(data);
+        let result = self.send(&mut session, COMMAND, &payload).await;
+        // A blaster that restarted no longer knows the session, so the next
+        // command starts a new one. Checking for a learned code fails until
+        // there is one, which says nothing about the session.
+        match result {
+            Err(BlasterError::Io(_)) => session.authenticated = false,
+            Err(BlasterError::Device(_)) if command != CHECK_DATA => session.authenticated = false,
+            _ => {}
+        }
+        let reply = result?;
+        let skip = if self.rm4 { 6 } else { 4 };
+        Ok(reply.get(skip..).unwrap_or_default().to_vec())
+    }
+
+    /// Sends a stored code.
+    async fn replay(&self, name: &str) -> Result<(), BlasterError> {
+        let code = codes()
+            .get(name)?
+            .ok_or_else(|| BlasterError::Unlearned(name.to_owned()))?;
+        self.command(SEND_DATA, &code).await?;
+        Ok(())
+    }
+
+    /// Polls `command` until it succeeds or the learning window is over.
+    async fn poll(&self, command: u32) -> Result<Option<Vec<u8>>, BlasterError> {
+        let start = Instant::now();
+        while start.elapsed() < LEARN_WINDOW {
+            Timer::after(LEARN_POLL).await;
+            match self.command(command, &[]).await {
+                Ok(reply) => return Ok(Some(reply)),
+                // Nothing has been received yet.
+                Err(BlasterError::Device(_)) => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(None)
+    }
+
+    /// Waits for a remote's code, sweeping for its frequency first if it is
+    /// an RF remote.
+    async fn learn(&self, rf: bool) -> Result<Option<Vec<u8>>, BlasterError> {
+        if !rf {
+            self.command(ENTER_LEARNING, &[]).await?;
+            return self.poll(CHECK_DATA).await;
+        }
+        self.command(SWEEP_FREQUENCY, &[]).await?;
+        let start = Instant::now();
+        let found = loop {
+            if start.elapsed() >= LEARN_WINDOW {
+                break false;
+            }
+            Timer::after(LEARN_POLL).await;
+            if self.command(CHECK_FREQUENCY, &[]).await?.first() == Some(&1) {
+                break true;
+            }
+        };
+        if !found {
+            self.command(CANCEL_SWEEP, &[]).await?;
+            return Ok(None);
+        }
+        self.command(FIND_RF_PACKET, &[]).await?;
+        self.poll(CHECK_DATA).await
+    }
+}
+
+/// A TV, dimmer, fan or air conditioner whose commands are replayed
+/// through a blaster.
+pub struct RmDevice {
+    config: RmDeviceConfig,
+    blaster: Arc<Blaster>,
+    /// The step a dimmer was left at, unknown until it is first dimmed or
+    /// after presses fail.
+    level: Mutex<Option<u8>>,
+}
+
+impl RmDevice {
+    fn code(&self, suffix: &str) -> String {
+        format!("{} {}", self.config.codes, suffix)
+    }
+
+    fn send<'a>(&'a self, code: String) -> BoxFuture<'a, Result<(), LightError>> {
+        Box::pin(async move { Ok(self.blaster.replay(&code).await?) })
+    }
+
+    async fn press(&self, suffix: &str, times: u8) -> Result<(), BlasterError> {
+        let code = self.code(suffix);
+        let interval = Duration::from_millis(self.config.press_interval_ms);
+        for _ in 0..times {
+            self.blaster.replay(&code).await?;
+            Timer::after(interval).await;
+        }
+        Ok(())
+    }
+
+    /// Presses up or down from the step the dimmer was left at to the one
+    /// closest to `brightness`. Not knowing where it was, it is first taken
+    /// all the way down.
+    async fn dim(&self, brightness: Brightness) -> Result<(), BlasterError> {
+        let steps = self.config.steps;
+        let target = brightness.to_scale(steps as u32) as u8;
+        let mut level = self.level.lock().await;
+        let current = match level.take() {
+            Some(current) => current,
+            None => {
+                self.press("down", steps).await?;
+                0
+            }
+        };
+        if target > current {
+            self.press("up", target - current).await?;
+        } else {
+            self.press("down", current - target).await?;
+        }
+        *level = Some(target);
+        Ok(())
+    }
+}
+
+impl Light for RmDevice {
+    fn name(&self) -> String {
+        self.config.name.clone()
+    }
+
+    fn vendor(&self) -> &'static str {
+        VENDOR
+    }
+
+    fn unique_id<'a>(&'a self) -> BoxFuture<'a, Result<String, LightError>> {
+        Box::pin(async move { Ok(format!("Broadlink RM {}", self.config.codes)) })
+    }
+
+    fn set_power_state<'a>(&'a self, state: PowerState) -> BoxFuture<'a, Result<(), LightError>> {
+        self.send(self.code(match state {
+            PowerState::On => "on",
+            PowerState::Off => "off",
+        }))
+    }
+
+    fn set_brightness<'a>(
+        &'a self,
+        brightness: Brightness,
+    ) -> BoxFuture<'a, Result<(), LightError>> {
+        Box::pin(async move {
+            match self.config.kind {
+                RmDeviceKind::Dimmer if self.config.steps > 0 => Ok(self.dim(brightness).await?),
+                RmDeviceKind::Dimmer => Err(LightError::Protocol("no dimming steps".to_owned())),
+                _ => Err(LightError::Protocol("not a light".to_owned())),
+            }
+        })
+    }
+
+    fn set_color<'a>(&'a self, _: Color) -> BoxFuture<'a, Result<(), LightError>> {
+        Box::pin(async { Err(LightError::Protocol("not a light".to_owned())) })
+    }
+
+    fn supports_color(&self) -> bool {
+        false
+    }
+
+    fn kind(&self) -> DeviceKind {
+        match self.config.kind {
+            RmDeviceKind::Switch => DeviceKind::Switch,
+            RmDeviceKind::Dimmer => DeviceKind::Light,
+            RmDeviceKind::Fan => DeviceKind::Fan,
+            RmDeviceKind::Thermostat => DeviceKind::Thermostat {
+                modes: self.config.modes.clone(),
+                range: self.config.range,
+            },
+        }
+    }
+
+    fn set_fan_speed<'a>(&'a self, percent: u8) -> BoxFuture<'a, Result<(), LightError>> {
+        let speeds = self.config.speeds as u32;
+        if speeds == 0 {
+            return Box::pin(async { Err(LightError::Protocol("no speed codes".to_owned())) });
+        }
+        let speed = (percent as u32 * speeds + 99) / 100;
+        if speed == 0 {
+            return self.send(self.code("off"));
+        }
+        self.send(self.code(&format!("speed {}", speed)))
+    }
+
+    fn set_thermostat<'a>(
+        &'a self,
+        thermostat: Thermostat,
+    ) -> BoxFuture<'a, Result<(), LightError>> {
+        if thermostat.mode == ThermostatMode::Off {
+            return self.send(self.code("off"));
+        }
+        let mode = serde_json::to_value(thermostat.mode)
+            .ok()
+            .and_then(|mode| mode.as_str().map(str::to_owned))
+            .unwrap_or_default();
+        self.send(self.code(&format!("{} {}", mode, thermostat.celsius.round() as i32)))
+    }
+}
+
+impl App {
+    /// Adds the blasters in `config` and the devices they drive. Devices of
+    /// a blaster that isn't listed are left out.
+    pub async fn add_blasters(&mut self, config: RmConfig) -> Result<(), crate::Error> {
+        let mut blasters = HashMap::new();
+        for blaster in &config.blasters {
+            blasters.insert(blaster.name.clone(), Arc::new(Blaster::new(blaster)?));
+        }
+        let mut devices = vec![];
+        for device in config.devices {
+            match blasters.get(&device.blaster) {
+                Some(blaster) => devices.push(RmDevice {
+                    blaster: blaster.clone(),
+                    config: device,
+                    level: Mutex::new(None),
+                }),
+                None => eprintln!(
+                    "no blaster `{}` for {}, leaving it out",
+                    device.blaster, device.name
+                ),
+            }
+        }
+        self.blasters.extend(blasters);
+        self.push_lights(devices).await;
+        Ok(())
+    }
+    /// The names of every learned code, sorted.
+    pub(crate) fn codes(&self) -> Result<Vec<String>, crate::Error> {
+        let mut names = codes().keys().map_err(LightError::other)?;
+        names.sort();
+        Ok(names)
+    }
+    pub(crate) fn delete_code(&self, name: &str) -> Result<(), crate::Error> {
+        if !valid_name(name) || !self.codes()?.iter().any(|code| code == name) {
+            return Err(crate::Error::Absent);
+        }
+        codes().remove(name).map_err(LightError::other)?;
+        Ok(())
+    }
+}
+
+/// Puts a blaster in learning mode and stores the code it picks up under
+/// `name`. The app is only locked to find the blaster, since learning waits
+/// on someone pressing a button.
+pub(crate) async fn learn_code(
+    app: &Arc<RwLock<App>>,
+    blaster: &str,
+    name: &str,
+    rf: bool,
+) -> Result<(), crate::Error> {
+    if !valid_name(name) {
+        return Err(crate::Error::Payload(format!(
+            "code names are letters, digits, spaces, `-` and `_`, not `{}`",
+            name
+        )));
+    }
+    let blaster = app
+        .read()
+        .await
+        .blasters
+        .get(blaster)
+        .cloned()
+        .ok_or(crate::Error::Absent)?;
+    let code = blaster
+        .learn(rf)
+        .await
+        .map_err(LightError::from)?
+        .ok_or(LightError::TimedOut)?;
+    codes().put(name, &code).map_err(LightError::other)?;
+    Ok(())
+}
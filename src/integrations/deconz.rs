@@ -0,0 +1,518 @@
+use crate::{
+    reciprocal,
+    vault::{vault, VaultError},
+    Brightness, Color, LightError, PowerState, ReportedState, DEFAULT_TEMPERATURES,
+};
+use async_io::Async;
+use async_tungstenite::{client_async, tungstenite::Message};
+use futures::{
+    channel::mpsc::{unbounded, UnboundedReceiver, UnboundedSender},
+    future::BoxFuture,
+    StreamExt,
+};
+use lights_api::{Press, SensorReading};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::{
+    collections::HashMap,
+    net::{TcpStream, ToSocketAddrs},
+    ops::RangeInclusive,
+    sync::{Arc, Mutex},
+};
+use thiserror::Error;
+
+const API_KEY: &str = "DECONZ_API_KEY";
+// deCONZ answers a pairing attempt with this while the gateway is locked.
+const LINK_BUTTON_NOT_PRESSED: u64 = 101;
+
+/// Address of the deCONZ REST API, as listed in `deconz.toml`.
+#[derive(Deserialize)]
+pub struct DeconzConfig {
+    pub host: String,
+    #[serde(default = "default_port")]
+    pub port: u16,
+}
+
+fn default_port() -> u16 {
+    80
+}
+
+#[derive(Debug, Error)]
+pub enum DeconzError {
+    #[error("http error: {0}")]
+    Http(String),
+    #[error("websocket error: {0}")]
+    WebSocket(#[from] async_tungstenite::tungstenite::Error),
+    #[error("io error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error(transparent)]
+    Vault(#[from] VaultError),
+    #[error("gateway is locked, unlock it with \"Authenticate app\" in Phoscon")]
+    Locked,
+    #[error("gateway error: {0}")]
+    Gateway(String),
+}
+
+impl From<surf::Error> for DeconzError {
+    fn from(error: surf::Error) -> Self {
+        DeconzError::Http(error.to_string())
+    }
+}
+
+impl From<DeconzError> for LightError {
+    fn from(error: DeconzError) -> Self {
+        match error {
+            DeconzError::Io(error) => error.into(),
+            DeconzError::Gateway(message) => LightError::Protocol(message),
+            error => LightError::other(error),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct Config {
+    bridgeid: String,
+    websocketport: u16,
+}
+
+#[derive(Deserialize)]
+struct LightInfo {
+    name: String,
+    uniqueid: String,
+    #[serde(default)]
+    hascolor: bool,
+    #[serde(default)]
+    state: Value,
+    /// Mireds.
+    ctmin: Option<u32>,
+    ctmax: Option<u32>,
+}
+
+impl LightInfo {
+    fn temperatures(&self) -> Option<RangeInclusive<u32>> {
+        match (self.ctmin, self.ctmax) {
+            (Some(min), Some(max)) if min > 0 && max >= min => {
+                Some(reciprocal(max)..=reciprocal(min))
+            }
+            _ => None,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct GroupInfo {
+    name: String,
+    #[serde(default)]
+    lights: Vec<String>,
+}
+
+/// Errors come back as a list of `{"error": {"type", "description"}}`
+/// objects with a 200 status.
+fn check(response: Value) -> Result<Value, DeconzError> {
+    let error = response
+        .as_array()
+        .and_then(|items| items.iter().find_map(|item| item.get("error")));
+    match error {
+        Some(error) => Err(DeconzError::Gateway(
+            error["description"]
+                .as_str()
+                .unwrap_or("unknown error")
+                .to_owned(),
+        )),
+        None => Ok(response),
+    }
+}
+
+/// Asks a gateway unlocked from Phoscon for an API key and saves it.
+pub async fn deconz_pair(config: &DeconzConfig) -> Result<String, DeconzError> {
+    let response: Value = surf::post(format!("http://{}:{}/api", config.host, config.port))
+        .body(json!({ "devicetype": "lights" }))
+        .recv_json()
+        .await?;
+    let first = &response[0];
+    if first["error"]["type"].as_u64() == Some(LINK_BUTTON_NOT_PRESSED) {
+        return Err(DeconzError::Locked);
+    }
+    let key = check(response)?[0]["success"]["username"]
+        .as_str()
+        .ok_or_else(|| DeconzError::Gateway("pairing returned no key".to_owned()))?
+        .to_owned();
+    vault().put(API_KEY, key.as_bytes())?;
+    Ok(key)
+}
+
+/// A paired deCONZ gateway.
+pub struct DeconzBridge {
+    base: String,
+    host: String,
+    id: String,
+    websocket_port: u16,
+    /// Unique ids of lights by gateway id, for resolving pushed events and
+    /// group members.
+    ids: Mutex<HashMap<String, String>>,
+    reachable: Mutex<HashMap<String, bool>>,
+    listeners: Mutex<Vec<UnboundedSender<(String, ReportedState)>>>,
+    sensor_listeners: Mutex<Vec<UnboundedSender<(String, SensorReading)>>>,
+}
+
+impl DeconzBridge {
+    /// Connects with the saved API key, pairing first if there isn't one.
+    pub async fn connect(config: &DeconzConfig) -> Result<Arc<Self>, DeconzError> {
+        let key = match vault().get(API_KEY)? {
+            Some(key) => String::from_utf8_lossy(&key).into_owned(),
+            None => deconz_pair(config).await?,
+        };
+        let base = format!("http://{}:{}/api/{}", config.host, config.port, key);
+        let gateway: Value = surf::get(format!("{}/config", base)).recv_json().await?;
+        let gateway: Config = serde_json::from_value(check(gateway)?)
+            .map_err(|e| DeconzError::Gateway(format!("malformed config: {}", e)))?;
+        Ok(Arc::new(DeconzBridge {
+            base,
+            host: config.host.clone(),
+            id: gateway.bridgeid,
+            websocket_port: gateway.websocketport,
+            ids: Mutex::new(HashMap::new()),
+            reachable: Mutex::new(HashMap::new()),
+            listeners: Mutex::new(vec![]),
+            sensor_listeners: Mutex::new(vec![]),
+        }))
+    }
+
+    async fn get<T: serde::de::DeserializeOwned>(&self, path: &str) -> Result<T, DeconzError> {
+        let response: Value = surf::get(format!("{}/{}", self.base, path))
+            .recv_json()
+            .await?;
+        serde_json::from_value(check(response)?)
+            .map_err(|e| DeconzError::Gateway(format!("malformed {}: {}", path, e)))
+    }
+
+    async fn put(&self, path: &str, body: Value) -> Result<(), DeconzError> {
+        let response: Value = surf::put(format!("{}/{}", self.base, path))
+            .body(body)
+            .recv_json()
+            .await?;
+        check(response).map(|_| ())
+    }
+
+    /// Every Zigbee light on the gateway, followed by its groups.
+    pub async fn lights(self: &Arc<Self>) -> Result<Vec<DeconzLight>, DeconzError> {
+        let lights: HashMap<String, LightInfo> = self.get("lights").await?;
+        let groups: HashMap<String, GroupInfo> = self.get("groups").await?;
+        let mut ids = self.ids.lock().unwrap();
+        let mut reachable = self.reachable.lock().unwrap();
+        let mut found = vec![];
+        for (id, light) in &lights {
+            ids.insert(id.clone(), light.uniqueid.clone());
+            reachable.insert(
+                id.clone(),
+                light.state["reachable"].as_bool().unwrap_or(true),
+            );
+            found.push(DeconzLight {
+                bridge: self.clone(),
+                name: light.name.clone(),
+                resource: Resource::Light(id.clone()),
+                unique_id: format!("deCONZ Light {}", light.uniqueid),
+                color: light.hascolor,
+                temperatures: light.temperatures().unwrap_or(DEFAULT_TEMPERATURES),
+            });
+        }
+        for (id, group) in groups {
+            // Groups owned by switches and sensors come back empty.
+            if group.lights.is_empty() {
+                continue;
+            }
+            let members = group
+                .lights
+                .iter()
+                .filter_map(|light| ids.get(light))
+                .map(|uniqueid| format!("deCONZ Light {}", uniqueid))
+                .collect();
+            found.push(DeconzLight {
+                bridge: self.clone(),
+                name: group.name,
+                unique_id: format!("deCONZ Group {}-{}", self.id, id),
+                color: group
+                    .lights
+                    .iter()
+                    .any(|light| lights.get(light).map_or(false, |light| light.hascolor)),
+                // The range every member can show.
+                temperatures: group
+                    .lights
+                    .iter()
+                    .filter_map(|light| lights.get(light)?.temperatures())
+                    .fold(None, |common: Option<RangeInclusive<u32>>, range| {
+                        Some(match common {
+                            Some(common) => {
+                                *common.start().max(range.start())..=*common.end().min(range.end())
+                            }
+                            None => range,
+                        })
+                    })
+                    .filter(|range| !range.is_empty())
+                    .unwrap_or(DEFAULT_TEMPERATURES),
+                resource: Resource::Group(id, members),
+            });
+        }
+        Ok(found)
+    }
+
+    /// Returns a stream of the changes the gateway pushes for its lights,
+    /// with the id of the light that changed.
+    pub fn updates(&self) -> UnboundedReceiver<(String, ReportedState)> {
+        let (sender, receiver) = unbounded();
+        self.listeners.lock().unwrap().push(sender);
+        receiver
+    }
+
+    /// Returns a stream of what the gateway's motion sensors, contact
+    /// sensors and switches report, with the id of the sensor.
+    pub fn sensor_readings(&self) -> UnboundedReceiver<(String, SensorReading)> {
+        let (sender, receiver) = unbounded();
+        self.sensor_listeners.lock().unwrap().push(sender);
+        receiver
+    }
+
+    /// Follows the gateway's event websocket until it closes.
+    pub async fn run(&self) -> Result<(), DeconzError> {
+        let addr = (self.host.as_str(), self.websocket_port)
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| DeconzError::Gateway(format!("unknown host {}", self.host)))?;
+        let stream = Async::<TcpStream>::connect(addr).await?;
+        let (mut events, _) = client_async(
+            format!("ws://{}:{}", self.host, self.websocket_port),
+            stream,
+        )
+        .await?;
+        while let Some(message) = events.next().await {
+            if let Message::Text(text) = message? {
+                if let Ok(event) = serde_json::from_str::<Value>(&text) {
+                    self.dispatch(&event);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn dispatch(&self, event: &Value) {
+        if event["e"] == "changed" && event["r"] == "sensors" {
+            return self.dispatch_sensor(event);
+        }
+        if event["e"] != "changed" || event["r"] != "lights" {
+            return;
+        }
+        let light = match event["id"].as_str() {
+            Some(light) => light,
+            None => return,
+        };
+        let state = &event["state"];
+        if !state.is_object() {
+            return;
+        }
+        if let Some(reachable) = state["reachable"].as_bool() {
+            self.reachable
+                .lock()
+                .unwrap()
+                .insert(light.to_owned(), reachable);
+        }
+        let uniqueid = match self.ids.lock().unwrap().get(light) {
+            Some(uniqueid) => uniqueid.clone(),
+            None => return,
+        };
+        let update = (
+            format!("deCONZ Light {}", uniqueid),
+            ReportedState {
+                on: state["on"].as_bool(),
+                brightness: state["bri"].as_u64().map(|bri| bri.min(255) as u8),
+                color: color(state),
+            },
+        );
+        self.listeners
+            .lock()
+            .unwrap()
+            .retain(|listener| listener.unbounded_send(update.clone()).is_ok());
+    }
+
+    fn dispatch_sensor(&self, event: &Value) {
+        let uniqueid = match event["uniqueid"].as_str() {
+            Some(uniqueid) => uniqueid,
+            None => return,
+        };
+        let reading = match sensor_reading(&event["state"]) {
+            Some(reading) => reading,
+            None => return,
+        };
+        let update = (format!("deCONZ Sensor {}", uniqueid), reading);
+        self.sensor_listeners
+            .lock()
+            .unwrap()
+            .retain(|listener| listener.unbounded_send(update.clone()).is_ok());
+    }
+}
+
+// Switches report `buttonevent` as the button number times 1000 plus what
+// happened to it, of which presses and releases on their own are left out.
+fn sensor_reading(state: &Value) -> Option<SensorReading> {
+    if let Some(occupied) = state["presence"].as_bool() {
+        return Some(SensorReading::Motion { occupied });
+    }
+    if let Some(open) = state["open"].as_bool() {
+        return Some(SensorReading::Contact { open });
+    }
+    let event = state["buttonevent"].as_u64()?;
+    let press = match event % 1000 {
+        1 => Press::Hold,
+        2 => Press::Click,
+        4 => Press::DoubleClick,
+        _ => return None,
+    };
+    Some(SensorReading::Button {
+        button: (event / 1000) as u32,
+        press,
+    })
+}
+
+// Only the color mode the light is in is meaningful, the other fields keep
+// stale values.
+fn color(state: &Value) -> Option<Color> {
+    match state["colormode"].as_str()? {
+        "ct" => Some(Color::from_mireds(
+            state["ct"].as_u64().filter(|ct| *ct > 0)? as u32,
+        )),
+        "hs" => {
+            let hue = state["hue"].as_u64()? as f64 / 65535. * 360.;
+            let saturation = state["sat"].as_u64()? as f64 / 255.;
+            Some(rgb(hue, saturation))
+        }
+        _ => None,
+    }
+}
+
+fn rgb(hue: f64, saturation: f64) -> Color {
+    let chroma = saturation;
+    let x = chroma * (1. - ((hue / 60.) % 2. - 1.).abs());
+    let (r, g, b) = match (hue / 60.) as u32 {
+        0 => (chroma, x, 0.),
+        1 => (x, chroma, 0.),
+        2 => (0., chroma, x),
+        3 => (0., x, chroma),
+        4 => (x, 0., chroma),
+        _ => (chroma, 0., x),
+    };
+    let m = 1. - chroma;
+    let channel = |value: f64| ((value + m) * 255.) as u8;
+    Color::Rgb {
+        r: channel(r),
+        g: channel(g),
+        b: channel(b),
+    }
+}
+
+fn hue_saturation(r: u8, g: u8, b: u8) -> (u16, u8) {
+    let r = r as f64 / 255.;
+    let g = g as f64 / 255.;
+    let b = b as f64 / 255.;
+    let cmax = r.max(g.max(b));
+    let diff = cmax - r.min(g.min(b));
+    let hue = if diff == 0. {
+        0.
+    } else if cmax == r {
+        (60. * ((g - b) / diff) + 360.) % 360.
+    } else if cmax == g {
+        60. * ((b - r) / diff) + 120.
+    } else {
+        60. * ((r - g) / diff) + 240.
+    };
+    let saturation = if cmax == 0. { 0. } else { diff / cmax };
+    ((hue / 360. * 65535.) as u16, (saturation * 255.) as u8)
+}
+
+enum Resource {
+    Light(String),
+    /// Gateway id and the unique ids of the member lights.
+    Group(String, Vec<String>),
+}
+
+/// A Zigbee light or group behind a deCONZ gateway.
+pub struct DeconzLight {
+    bridge: Arc<DeconzBridge>,
+    name: String,
+    resource: Resource,
+    unique_id: String,
+    color: bool,
+    temperatures: RangeInclusive<u32>,
+}
+
+impl DeconzLight {
+    async fn command(&self, body: Value) -> Result<(), LightError> {
+        let path = match &self.resource {
+            Resource::Light(id) => format!("lights/{}/state", id),
+            Resource::Group(id, _) => format!("groups/{}/action", id),
+        };
+        self.bridge.put(&path, body).await.map_err(LightError::from)
+    }
+}
+
+impl crate::Light for DeconzLight {
+    fn name(&self) -> String {
+        self.name.clone()
+    }
+
+    fn vendor(&self) -> &'static str {
+        "deconz"
+    }
+
+    fn unique_id<'a>(&'a self) -> BoxFuture<'a, Result<String, LightError>> {
+        Box::pin(async move { Ok(self.unique_id.clone()) })
+    }
+
+    fn set_power_state<'a>(&'a self, state: PowerState) -> BoxFuture<'a, Result<(), LightError>> {
+        Box::pin(self.command(json!({ "on": matches!(state, PowerState::On) })))
+    }
+
+    fn set_brightness<'a>(
+        &'a self,
+        brightness: Brightness,
+    ) -> BoxFuture<'a, Result<(), LightError>> {
+        Box::pin(self.command(json!({ "bri": u8::from(brightness) })))
+    }
+
+    fn set_color<'a>(&'a self, color: Color) -> BoxFuture<'a, Result<(), LightError>> {
+        let body = match color {
+            Color::Rgb { r, g, b } => {
+                let (hue, sat) = hue_saturation(r, g, b);
+                json!({ "hue": hue, "sat": sat })
+            }
+            Color::White { .. } => json!({ "ct": color.mireds() }),
+        };
+        Box::pin(self.command(body))
+    }
+
+    fn members(&self) -> Option<Vec<String>> {
+        match &self.resource {
+            Resource::Light(_) => None,
+            Resource::Group(_, members) => Some(members.clone()),
+        }
+    }
+
+    fn online(&self) -> bool {
+        match &self.resource {
+            Resource::Light(id) => *self
+                .bridge
+                .reachable
+                .lock()
+                .unwrap()
+                .get(id)
+                .unwrap_or(&true),
+            Resource::Group(..) => true,
+        }
+    }
+
+    fn supports_color(&self) -> bool {
+        self.color
+    }
+
+    fn color_temperature_range(&self) -> RangeInclusive<u32> {
+        self.temperatures.clone()
+    }
+}
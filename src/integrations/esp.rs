@@ -1,40 +1,290 @@
-use crate::{Color, PowerState};
+use super::{
+    esp_host::{inject, HostParameters},
+    mac_address,
+};
+use crate::{Brightness, Color, Error, LightError, PowerState, DEFAULT_TEMPERATURES};
+use async_lock::Mutex;
 use futures::{
     future::{BoxFuture, Either},
     TryFutureExt,
 };
-use smol::lock::Mutex;
+use lights_api::StripConfig;
 use std::{
-    error::Error,
+    collections::HashMap,
+    convert::TryFrom,
     io,
     net::IpAddr,
-    sync::atomic::{AtomicUsize, Ordering},
+    ops::RangeInclusive,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
 };
 
 static COUNT: AtomicUsize = AtomicUsize::new(1);
 
+const WARM_KELVIN: u32 = 2700;
+const COOL_KELVIN: u32 = 6500;
+/// Draw of a single channel of a single pixel at full level, typical of
+/// WS2812 and SK6812 pixels.
+const MILLIAMPS_PER_CHANNEL: u32 = 20;
+/// What each pixel's controller draws even when dark.
+const IDLE_MILLIAMPS: f32 = 1.;
+
 use lights_esp_strip::Light;
 
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Channel {
+    Red,
+    Green,
+    Blue,
+    /// The warm white, or the only white on RGBW strips.
+    White,
+    Cool,
+}
+
+/// The channels of each pixel in the order a strip expects them.
+#[derive(Clone, Debug)]
+struct ChannelOrder(Vec<Channel>);
+
+impl TryFrom<&str> for ChannelOrder {
+    type Error = String;
+
+    fn try_from(order: &str) -> Result<Self, Self::Error> {
+        let channels = order
+            .chars()
+            .map(|channel| match channel.to_ascii_uppercase() {
+                'R' => Ok(Channel::Red),
+                'G' => Ok(Channel::Green),
+                'B' => Ok(Channel::Blue),
+                'W' => Ok(Channel::White),
+                'C' => Ok(Channel::Cool),
+                other => Err(format!("unknown channel `{}` in `{}`", other, order)),
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        if channels.is_empty() {
+            return Err("empty channel order".to_owned());
+        }
+        for channel in &channels {
+            if channels.iter().filter(|other| *other == channel).count() > 1 {
+                return Err(format!("repeated channel in `{}`", order));
+            }
+        }
+        Ok(ChannelOrder(channels))
+    }
+}
+
+impl ChannelOrder {
+    fn has(&self, channel: Channel) -> bool {
+        self.0.contains(&channel)
+    }
+
+    /// One pixel's levels, using the white channels for white colors where
+    /// the strip has them.
+    fn pixel(&self, color: Color, brightness: u8) -> Vec<u8> {
+        let (mut red, mut green, mut blue, mut warm, mut cool) = (0., 0., 0., 0., 0.);
+        match color {
+            Color::White { temperature } if self.has(Channel::White) && self.has(Channel::Cool) => {
+                let clamped = temperature.max(WARM_KELVIN).min(COOL_KELVIN);
+                let mix = (clamped - WARM_KELVIN) as f32 / (COOL_KELVIN - WARM_KELVIN) as f32;
+                // Mixed so the stronger channel is always at full level.
+                let scale = mix.max(1. - mix);
+                cool = mix / scale;
+                warm = (1. - mix) / scale;
+            }
+            Color::White { .. } if self.has(Channel::White) => warm = 1.,
+            Color::White { .. } if self.has(Channel::Cool) => cool = 1.,
+            color => {
+                let (r, g, b) = color.to_rgb();
+                red = r as f32 / 255.;
+                green = g as f32 / 255.;
+                blue = b as f32 / 255.;
+            }
+        }
+        self.0
+            .iter()
+            .map(|channel| {
+                let level = match channel {
+                    Channel::Red => red,
+                    Channel::Green => green,
+                    Channel::Blue => blue,
+                    Channel::White => warm,
+                    Channel::Cool => cool,
+                };
+                (level * brightness as f32).round() as u8
+            })
+            .collect()
+    }
+}
+
+/// The most current a strip may draw, and how its draw is estimated.
+#[derive(Clone, Copy, Debug)]
+struct PowerBudget {
+    max_milliamps: u32,
+    per_channel: u32,
+}
+
+impl PowerBudget {
+    /// Dims a frame evenly until its estimated draw fits the budget, going
+    /// dark if the idle draw alone exceeds it.
+    fn limit(&self, frame: &mut [u8], pixels: usize) {
+        let idle = pixels as f32 * IDLE_MILLIAMPS;
+        let lit =
+            frame.iter().map(|level| *level as f32).sum::<f32>() / 255. * self.per_channel as f32;
+        let available = self.max_milliamps as f32 - idle;
+        if idle + lit <= self.max_milliamps as f32 {
+            return;
+        }
+        let scale = (available / lit).max(0.);
+        for level in frame {
+            *level = (*level as f32 * scale) as u8;
+        }
+    }
+}
+
+/// A strip driven with whole frames over the write protocol, rather than
+/// the firmware's RGB color command.
+#[derive(Clone, Debug)]
+struct Strip {
+    order: ChannelOrder,
+    length: usize,
+    budget: Option<PowerBudget>,
+}
+
+impl TryFrom<&StripConfig> for Strip {
+    type Error = String;
+
+    fn try_from(config: &StripConfig) -> Result<Self, Self::Error> {
+        if config.length == 0 {
+            return Err("strip has no pixels".to_owned());
+        }
+        Ok(Strip {
+            order: ChannelOrder::try_from(config.order.as_str())?,
+            length: config.length as usize,
+            budget: config.max_milliamps.map(|max_milliamps| PowerBudget {
+                max_milliamps,
+                per_channel: config
+                    .milliamps_per_channel
+                    .unwrap_or(MILLIAMPS_PER_CHANNEL),
+            }),
+        })
+    }
+}
+
+impl Strip {
+    fn frame(&self, color: Color, brightness: u8) -> Vec<u8> {
+        let mut frame = self.order.pixel(color, brightness).repeat(self.length);
+        self.limit(&mut frame);
+        frame
+    }
+
+    /// Refuses frames laid out for a different strip.
+    fn check(&self, frame: &[u8]) -> Result<(), String> {
+        let expected = self.length * self.order.0.len();
+        if frame.len() != expected {
+            return Err(format!(
+                "frame of {} bytes for a strip expecting {}",
+                frame.len(),
+                expected
+            ));
+        }
+        Ok(())
+    }
+
+    fn limit(&self, frame: &mut [u8]) {
+        if let Some(budget) = self.budget {
+            budget.limit(frame, self.length);
+        }
+    }
+}
+
 struct LightData {
     light: Light,
     brightness: u8,
-    color: (u8, u8, u8),
+    color: Color,
 }
 
 pub struct EspLight {
     name: String,
     data: Mutex<LightData>,
+    strip: std::sync::Mutex<Option<Strip>>,
+    /// The chip's MAC, as seen by the bridge when the strip connected.
+    mac: Option<String>,
+    /// The address based id strips had before they were identified by MAC.
+    legacy_id: Option<String>,
 }
 
 impl EspLight {
     pub async fn addr(&self) -> Result<IpAddr, io::Error> {
         self.data.lock().await.light.addr()
     }
+    pub fn mac(&self) -> Option<&str> {
+        self.mac.as_deref()
+    }
+    /// Programs the strip, logging rather than returning failures.
     pub async fn try_program(&self, binary: &[u8]) {
-        let _ = self.data.lock().await.light.program(binary).await;
+        if let Err(e) = self.program(binary).await {
+            eprintln!("failed to program {}: {}", self.name, e);
+        }
     }
+    /// Writes a frame to the strip, logging rather than returning failures.
     pub async fn try_write(&self, binary: &[u8]) {
-        let _ = self.data.lock().await.light.write(binary).await;
+        if let Err(e) = self.write(binary).await {
+            eprintln!("failed to write to {}: {}", self.name, e);
+        }
+    }
+    async fn program(&self, binary: &[u8]) -> Result<(), Error> {
+        let mut data = self.data.lock().await;
+        let parameters = HostParameters {
+            strip_length: self
+                .strip
+                .lock()
+                .unwrap()
+                .as_ref()
+                .map_or(0, |strip| strip.length as u32),
+            color: data.color.to_rgb(),
+            brightness: data.brightness,
+        };
+        let program = inject(binary, &parameters).map_err(|e| Error::Payload(e.to_string()))?;
+        data.light
+            .program(&program)
+            .await
+            .map_err(|e| Error::Light(e.into()))
+    }
+    async fn write(&self, binary: &[u8]) -> Result<(), Error> {
+        let strip = self.strip.lock().unwrap().clone();
+        let mut frame = binary.to_vec();
+        if let Some(strip) = strip {
+            strip.check(&frame).map_err(Error::Payload)?;
+            strip.limit(&mut frame);
+        }
+        self.data
+            .lock()
+            .await
+            .light
+            .write(&frame)
+            .await
+            .map_err(|e| Error::Light(e.into()))
+    }
+    async fn show(&self, data: &mut LightData) -> Result<(), LightError> {
+        let strip = self.strip.lock().unwrap().clone();
+        let result = match strip {
+            Some(strip) => {
+                let frame = strip.frame(data.color, data.brightness);
+                data.light.write(&frame).await
+            }
+            None => {
+                let (r, g, b) = data.color.to_rgb();
+                let ratio = data.brightness as f32 / 255.;
+                let color = (
+                    (r as f32 * ratio) as u8,
+                    (g as f32 * ratio) as u8,
+                    (b as f32 * ratio) as u8,
+                );
+                data.light.set_color(color).await
+            }
+        };
+        result.map_err(LightError::from)
     }
 }
 
@@ -43,10 +293,14 @@ impl crate::Light for EspLight {
         self.name.clone()
     }
 
+    fn vendor(&self) -> &'static str {
+        "esp"
+    }
+
     fn set_power_state<'a>(
         &'a self,
         state: crate::PowerState,
-    ) -> BoxFuture<'a, Result<(), Box<dyn Error + Send>>> {
+    ) -> BoxFuture<'a, Result<(), LightError>> {
         let fut = match state {
             PowerState::On => {
                 Either::Left(async move { self.data.lock().await.light.turn_on().await })
@@ -55,100 +309,125 @@ impl crate::Light for EspLight {
                 Either::Right(async move { self.data.lock().await.light.turn_off().await })
             }
         };
-        Box::pin(fut.map_err(|e| Box::new(e) as Box<dyn Error + Send>))
+        Box::pin(fut.map_err(LightError::from))
     }
 
     fn set_brightness<'a>(
         &'a self,
-        brightness: u8,
-    ) -> BoxFuture<'a, Result<(), Box<dyn Error + Send>>> {
+        brightness: Brightness,
+    ) -> BoxFuture<'a, Result<(), LightError>> {
         Box::pin(async move {
             let mut data = self.data.lock().await;
-            let ratio = brightness as f32 / 255.;
-            data.brightness = brightness;
-            let color = (
-                (data.color.0 as f32 * ratio) as u8,
-                (data.color.1 as f32 * ratio) as u8,
-                (data.color.2 as f32 * ratio) as u8,
-            );
-            data.light
-                .set_color(color)
-                .await
-                .map_err(|e| Box::new(e) as Box<dyn Error + Send>)
+            data.brightness = brightness.into();
+            self.show(&mut data).await
         })
     }
 
-    fn set_color<'a>(
-        &'a self,
-        color: crate::Color,
-    ) -> BoxFuture<'a, Result<(), Box<dyn Error + Send>>> {
+    fn set_color<'a>(&'a self, color: crate::Color) -> BoxFuture<'a, Result<(), LightError>> {
         Box::pin(async move {
             let mut data = self.data.lock().await;
-            match color {
-                Color::Rgb { r, g, b } => {
-                    data.color = (r, g, b);
-                }
-                Color::White { temperature } => {
-                    let mut color = (0, 0, 0);
-                    let temperature = temperature as f64 / 100.;
-
-                    color.0 = {
-                        let mut red = 255.;
-                        if temperature > 66. {
-                            red = 329.698727466 * (temperature - 60.).powf(-0.1332047592);
-                        }
-                        red as u8
-                    };
-
-                    color.1 = {
-                        (if temperature <= 66. {
-                            (99.4708025861 * temperature.ln()) - 161.1195681661
-                        } else {
-                            288.1221695283 * (temperature - 60.).powf(-0.0755148492)
-                        }) as u8
-                    };
-
-                    color.2 = {
-                        let mut blue = 255.;
-                        if temperature < 65. {
-                            if temperature <= 19. {
-                                blue = 0.;
-                            } else {
-                                blue = (138.5177312231 * (temperature - 10.).ln()) - 305.0447927307;
-                            }
-                        }
-                        blue as u8
-                    };
-
-                    data.color = color;
-                }
-            };
-            let brightness = data.brightness;
-            drop(data);
-            self.set_brightness(brightness).await
+            data.color = color;
+            self.show(&mut data).await
         })
     }
 
-    fn unique_id<'a>(&'a self) -> BoxFuture<'a, Result<String, Box<dyn Error + Send>>> {
+    fn color_temperature_range(&self) -> RangeInclusive<u32> {
+        match &*self.strip.lock().unwrap() {
+            Some(strip) if strip.order.has(Channel::White) && strip.order.has(Channel::Cool) => {
+                WARM_KELVIN..=COOL_KELVIN
+            }
+            _ => DEFAULT_TEMPERATURES,
+        }
+    }
+
+    fn configure_strip(&self, config: &StripConfig) -> Result<(), String> {
+        *self.strip.lock().unwrap() = Some(Strip::try_from(config)?);
+        Ok(())
+    }
+
+    fn upload_program<'a>(&'a self, program: &'a [u8]) -> BoxFuture<'a, Result<(), Error>> {
+        Box::pin(self.program(program))
+    }
+
+    fn write_frame<'a>(&'a self, frame: &'a [u8]) -> BoxFuture<'a, Result<(), Error>> {
+        Box::pin(self.write(frame))
+    }
+
+    fn unique_id<'a>(&'a self) -> BoxFuture<'a, Result<String, LightError>> {
         Box::pin(async move {
+            if let Some(mac) = &self.mac {
+                return Ok(format!("Esp Light {}", mac));
+            }
             let data = self.data.lock().await;
             data.light
                 .addr()
                 .map(|addr| format!("Esp Light {}", addr))
-                .map_err(|e| Box::new(e) as Box<dyn Error + Send>)
+                .map_err(LightError::from)
         })
     }
+
+    fn legacy_ids(&self) -> Vec<String> {
+        self.legacy_id.iter().cloned().collect()
+    }
 }
 
 impl EspLight {
     pub fn new(light: Light) -> Self {
+        // The strip has just connected, so the bridge has its MAC from ARP
+        // whenever it's on the same network.
+        let addr = light.addr().ok();
+        let mac = addr.and_then(mac_address);
         EspLight {
+            legacy_id: mac
+                .as_ref()
+                .and(addr)
+                .map(|addr| format!("Esp Light {}", addr)),
+            mac,
             name: format!("ESP Light {}", COUNT.fetch_add(1, Ordering::SeqCst)),
             data: Mutex::new(LightData {
                 light,
-                color: (255, 255, 255),
+                color: Color::Rgb {
+                    r: 255,
+                    g: 255,
+                    b: 255,
+                },
                 brightness: 255,
             }),
+            strip: std::sync::Mutex::new(None),
+        }
+    }
+}
+
+/// Connected strips by MAC, or by address for those whose MAC is unknown,
+/// still reachable by the address they last connected from.
+#[derive(Default)]
+pub struct EspLights {
+    lights: HashMap<String, Arc<EspLight>>,
+    addrs: HashMap<IpAddr, String>,
+}
+
+impl EspLights {
+    /// Adds a strip that connected, replacing the one with the same MAC
+    /// if it reconnected from a new address.
+    pub async fn insert(&mut self, light: Arc<EspLight>) {
+        let addr = light.addr().await.ok();
+        let key = match (light.mac(), addr) {
+            (Some(mac), _) => mac.to_owned(),
+            (None, Some(addr)) => addr.to_string(),
+            (None, None) => return,
+        };
+        self.addrs.retain(|_, existing| *existing != key);
+        if let Some(addr) = addr {
+            self.addrs.insert(addr, key.clone());
+        }
+        self.lights.insert(key, light);
+    }
+
+    /// Finds a strip by its MAC or by the address it connected from.
+    pub fn get(&self, id: &str) -> Option<&Arc<EspLight>> {
+        match id.parse::<IpAddr>() {
+            Ok(addr) => self.lights.get(self.addrs.get(&addr)?),
+            Err(_) => self.lights.get(&id.to_lowercase()),
         }
     }
 }
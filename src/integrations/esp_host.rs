@@ -0,0 +1,178 @@
+use std::{
+    io,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+const MAGIC: &[u8] = b"\0asm";
+const HEADER_LEN: usize = 8;
+const CUSTOM: u8 = 0;
+const IMPORT: u8 = 2;
+const EXPORT: u8 = 7;
+const FUNCTION: u8 = 0;
+const MEMORY: u8 = 2;
+/// What the firmware's `lights` module provides to programs.
+const HOST_FUNCTIONS: &[&str] = &["now_ms", "strip_length", "color"];
+// The section holds, little-endian: a format version byte, the Unix time
+// of the upload in milliseconds as a u64, the strip length in pixels as a
+// u32 (0 if not configured), and the last color set as red, green, blue and
+// brightness bytes.
+const SECTION: &str = "lights.host";
+const FORMAT: u8 = 1;
+
+/// What the firmware's `lights` host module answers `now_ms`, `strip_length`
+/// and `color` with, appended to each program as it is uploaded so that one
+/// binary runs unchanged on every strip. The firmware adds its own uptime
+/// since the upload to `now_ms`.
+pub(crate) struct HostParameters {
+    pub(crate) strip_length: u32,
+    pub(crate) color: (u8, u8, u8),
+    pub(crate) brightness: u8,
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+fn write_leb128(out: &mut Vec<u8>, mut value: usize) {
+    loop {
+        let byte = (value & 0x7F) as u8;
+        value >>= 7;
+        if value == 0 {
+            out.push(byte);
+            return;
+        }
+        out.push(byte | 0x80);
+    }
+}
+
+/// Reads an unsigned LEB128 number, returning it and the bytes it took up.
+fn read_leb128(data: &[u8]) -> io::Result<(usize, usize)> {
+    let mut value = 0usize;
+    for (i, byte) in data.iter().enumerate().take(5) {
+        value |= ((byte & 0x7F) as usize) << (7 * i);
+        if byte & 0x80 == 0 {
+            return Ok((value, i + 1));
+        }
+    }
+    Err(invalid("malformed number"))
+}
+
+/// Reads a length-prefixed name, returning it and the bytes it took up.
+fn read_name(data: &[u8]) -> io::Result<(&str, usize)> {
+    let (len, read) = read_leb128(data)?;
+    let name = data
+        .get(read..read + len)
+        .ok_or_else(|| invalid("truncated name"))?;
+    let name = std::str::from_utf8(name).map_err(|_| invalid("name isn't utf-8"))?;
+    Ok((name, read + len))
+}
+
+struct Section<'a> {
+    id: u8,
+    /// The whole section, including its id and length.
+    raw: &'a [u8],
+    payload: &'a [u8],
+}
+
+fn sections(module: &[u8]) -> io::Result<Vec<Section<'_>>> {
+    if module.len() < HEADER_LEN || &module[..4] != MAGIC {
+        return Err(invalid("not a wasm module"));
+    }
+    let mut sections = vec![];
+    let mut rest = &module[HEADER_LEN..];
+    while !rest.is_empty() {
+        let (len, read) = read_leb128(&rest[1..])?;
+        let end = 1 + read + len;
+        let raw = rest
+            .get(..end)
+            .ok_or_else(|| invalid("truncated section"))?;
+        sections.push(Section {
+            id: raw[0],
+            raw,
+            payload: &raw[1 + read..],
+        });
+        rest = &rest[end..];
+    }
+    Ok(sections)
+}
+
+/// Whether a section payload is a custom section carrying our parameters.
+fn is_ours(payload: &[u8]) -> bool {
+    match read_leb128(payload) {
+        Ok((len, read)) => payload.get(read..read + len) == Some(SECTION.as_bytes()),
+        Err(_) => false,
+    }
+}
+
+/// Appends the parameters to a wasm module, replacing any left over from an
+/// earlier upload of the same binary.
+pub(crate) fn inject(module: &[u8], parameters: &HostParameters) -> io::Result<Vec<u8>> {
+    let sections = sections(module)?;
+    let mut out = module[..HEADER_LEN].to_vec();
+    for section in sections {
+        if !(section.id == CUSTOM && is_ours(section.payload)) {
+            out.extend_from_slice(section.raw);
+        }
+    }
+
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |time| time.as_millis() as u64);
+    let (r, g, b) = parameters.color;
+    let mut payload = vec![];
+    write_leb128(&mut payload, SECTION.len());
+    payload.extend_from_slice(SECTION.as_bytes());
+    payload.push(FORMAT);
+    payload.extend_from_slice(&now.to_le_bytes());
+    payload.extend_from_slice(&parameters.strip_length.to_le_bytes());
+    payload.extend_from_slice(&[r, g, b, parameters.brightness]);
+
+    out.push(CUSTOM);
+    write_leb128(&mut out, payload.len());
+    out.extend(payload);
+    Ok(out)
+}
+
+/// Checks that a module fits the strip ABI: it exports its `memory` and an
+/// `entry` function, and imports nothing but the host functions.
+pub(crate) fn validate(module: &[u8]) -> io::Result<()> {
+    let (mut memory, mut entry) = (false, false);
+    for section in sections(module)? {
+        let payload = section.payload;
+        match section.id {
+            IMPORT => {
+                let (count, mut at) = read_leb128(payload)?;
+                for _ in 0..count {
+                    let (module, read) = read_name(&payload[at..])?;
+                    at += read;
+                    let (field, read) = read_name(&payload[at..])?;
+                    at += read;
+                    let kind = *payload.get(at).ok_or_else(|| invalid("truncated import"))?;
+                    if module != "lights" || kind != FUNCTION || !HOST_FUNCTIONS.contains(&field) {
+                        return Err(invalid(&format!("unknown import `{}.{}`", module, field)));
+                    }
+                    at += 1 + read_leb128(&payload[at + 1..])?.1;
+                }
+            }
+            EXPORT => {
+                let (count, mut at) = read_leb128(payload)?;
+                for _ in 0..count {
+                    let (name, read) = read_name(&payload[at..])?;
+                    at += read;
+                    let kind = *payload.get(at).ok_or_else(|| invalid("truncated export"))?;
+                    memory |= name == "memory" && kind == MEMORY;
+                    entry |= name == "entry" && kind == FUNCTION;
+                    at += 1 + read_leb128(&payload[at + 1..])?.1;
+                }
+            }
+            _ => {}
+        }
+    }
+    if !memory {
+        return Err(invalid("program doesn't export its memory"));
+    }
+    if !entry {
+        return Err(invalid("program doesn't export an `entry` function"));
+    }
+    Ok(())
+}
@@ -0,0 +1,349 @@
+use crate::{Brightness, LightError, PowerState};
+use async_io::Async;
+use async_lock::Mutex;
+use async_native_tls::{Certificate, Identity, TlsConnector, TlsStream};
+use futures::{
+    channel::{
+        mpsc::{unbounded, UnboundedReceiver, UnboundedSender},
+        oneshot,
+    },
+    future::BoxFuture,
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader, ReadHalf, WriteHalf},
+    AsyncReadExt, StreamExt,
+};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::{
+    collections::HashMap,
+    io,
+    net::{TcpStream, ToSocketAddrs},
+    path::PathBuf,
+    sync::{
+        self,
+        atomic::{AtomicU8, AtomicUsize, Ordering},
+        Arc,
+    },
+};
+use thiserror::Error;
+
+const PORT: u16 = 8081;
+
+type Stream = TlsStream<Async<TcpStream>>;
+
+/// Connection details for a paired Caseta Smart Bridge, as listed in
+/// `lutron.toml`.
+#[derive(Deserialize)]
+pub struct LutronConfig {
+    pub host: String,
+    /// The paired client certificate and key as a PKCS #12 bundle.
+    pub identity: PathBuf,
+    #[serde(default)]
+    pub password: String,
+    /// The bridge's certificate authority, PEM encoded.
+    pub ca: PathBuf,
+}
+
+#[derive(Debug, Error)]
+pub enum LutronError {
+    #[error("io error: {0}")]
+    Io(#[from] io::Error),
+    #[error("tls error: {0}")]
+    Tls(#[from] async_native_tls::Error),
+    #[error("bridge error: {0}")]
+    Protocol(String),
+}
+
+impl From<LutronError> for LightError {
+    fn from(error: LutronError) -> Self {
+        match error {
+            LutronError::Io(error) => error.into(),
+            LutronError::Protocol(message) => LightError::Protocol(message),
+            error => LightError::other(error),
+        }
+    }
+}
+
+/// A press or release of a Pico remote button.
+#[derive(Debug, Clone)]
+pub struct ButtonEvent {
+    /// Name of the remote the button is on.
+    pub remote: String,
+    pub button: u32,
+    pub pressed: bool,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct Href {
+    #[serde(rename = "href")]
+    href: String,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct Device {
+    name: String,
+    device_type: String,
+    #[serde(default)]
+    local_zones: Vec<Href>,
+    #[serde(default)]
+    button_groups: Vec<Href>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct Button {
+    #[serde(rename = "href")]
+    href: String,
+    button_number: u32,
+    parent: Href,
+}
+
+/// A LEAP session with the bridge. [`LutronBridge::run`] has to be polled for
+/// requests to complete.
+pub struct LutronBridge {
+    writer: Mutex<WriteHalf<Stream>>,
+    reader: Mutex<Option<ReadHalf<Stream>>>,
+    pending: sync::Mutex<HashMap<String, oneshot::Sender<Value>>>,
+    // Button event urls, mapped to the remote name and button number.
+    buttons: sync::Mutex<HashMap<String, (String, u32)>>,
+    listeners: sync::Mutex<Vec<UnboundedSender<ButtonEvent>>>,
+    tag: AtomicUsize,
+}
+
+impl LutronBridge {
+    pub async fn connect(config: &LutronConfig) -> Result<Arc<Self>, LutronError> {
+        let identity = Identity::from_pkcs12(&std::fs::read(&config.identity)?, &config.password)?;
+        let ca = Certificate::from_pem(&std::fs::read(&config.ca)?)?;
+        let addr = (config.host.as_str(), PORT)
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| LutronError::Protocol(format!("unknown host {}", config.host)))?;
+        let stream = Async::<TcpStream>::connect(addr).await?;
+        // The bridge certificate names the bridge rather than its address.
+        let stream = TlsConnector::new()
+            .identity(identity)
+            .add_root_certificate(ca)
+            .danger_accept_invalid_hostnames(true)
+            .connect(config.host.as_str(), stream)
+            .await?;
+        let (reader, writer) = stream.split();
+        Ok(Arc::new(LutronBridge {
+            writer: Mutex::new(writer),
+            reader: Mutex::new(Some(reader)),
+            pending: sync::Mutex::new(HashMap::new()),
+            buttons: sync::Mutex::new(HashMap::new()),
+            listeners: sync::Mutex::new(vec![]),
+            tag: AtomicUsize::new(1),
+        }))
+    }
+
+    /// Reads responses and events until the connection drops.
+    pub async fn run(&self) -> Result<(), LutronError> {
+        let reader = self
+            .reader
+            .lock()
+            .await
+            .take()
+            .ok_or_else(|| LutronError::Protocol("session already running".to_owned()))?;
+        let mut lines = BufReader::new(reader).lines();
+        while let Some(line) = lines.next().await {
+            let message: Value = match serde_json::from_str(&line?) {
+                Ok(message) => message,
+                Err(_) => continue,
+            };
+            let header = &message["Header"];
+            if let Some(tag) = header["ClientTag"].as_str() {
+                if let Some(sender) = self.pending.lock().unwrap().remove(tag) {
+                    let _ = sender.send(message);
+                    continue;
+                }
+            }
+            if let Some(url) = header["Url"].as_str() {
+                self.dispatch_button(url, &message["Body"]);
+            }
+        }
+        Ok(())
+    }
+
+    fn dispatch_button(&self, url: &str, body: &Value) {
+        let (remote, button) = match self.buttons.lock().unwrap().get(url) {
+            Some(button) => button.clone(),
+            None => return,
+        };
+        let pressed = match body["ButtonStatus"]["ButtonEvent"]["EventType"].as_str() {
+            Some("Press") => true,
+            Some("Release") => false,
+            _ => return,
+        };
+        let event = ButtonEvent {
+            remote,
+            button,
+            pressed,
+        };
+        self.listeners
+            .lock()
+            .unwrap()
+            .retain(|listener| listener.unbounded_send(event.clone()).is_ok());
+    }
+
+    async fn request(
+        &self,
+        communique: &str,
+        url: &str,
+        body: Option<Value>,
+    ) -> Result<Value, LutronError> {
+        let tag = self.tag.fetch_add(1, Ordering::SeqCst).to_string();
+        let mut message = json!({
+            "CommuniqueType": communique,
+            "Header": { "ClientTag": tag, "Url": url },
+        });
+        if let Some(body) = body {
+            message["Body"] = body;
+        }
+        let (sender, receiver) = oneshot::channel();
+        self.pending.lock().unwrap().insert(tag, sender);
+        let mut line =
+            serde_json::to_vec(&message).map_err(|e| LutronError::Protocol(e.to_string()))?;
+        line.extend_from_slice(b"\r\n");
+        self.writer.lock().await.write_all(&line).await?;
+        let response = receiver
+            .await
+            .map_err(|_| LutronError::Io(io::ErrorKind::ConnectionAborted.into()))?;
+        let status = response["Header"]["StatusCode"].as_str().unwrap_or("");
+        if !status.starts_with('2') {
+            return Err(LutronError::Protocol(format!("{} {}", url, status)));
+        }
+        Ok(response["Body"].clone())
+    }
+
+    async fn devices(&self) -> Result<Vec<Device>, LutronError> {
+        let body = self.request("ReadRequest", "/device", None).await?;
+        serde_json::from_value(body["Devices"].clone())
+            .map_err(|e| LutronError::Protocol(format!("malformed device list: {}", e)))
+    }
+
+    /// Every dimmer paired with the bridge.
+    pub async fn dimmers(self: &Arc<Self>) -> Result<Vec<LutronLight>, LutronError> {
+        Ok(self
+            .devices()
+            .await?
+            .into_iter()
+            .filter(|device| device.device_type.contains("Dimmer"))
+            .filter_map(|device| {
+                let zone = device.local_zones.into_iter().next()?;
+                Some(LutronLight {
+                    bridge: self.clone(),
+                    name: device.name,
+                    zone: zone.href,
+                    level: AtomicU8::new(100),
+                })
+            })
+            .collect())
+    }
+
+    /// Subscribes to every Pico remote button, returning a stream of presses
+    /// and releases.
+    pub async fn buttons(&self) -> Result<UnboundedReceiver<ButtonEvent>, LutronError> {
+        let remotes = self
+            .devices()
+            .await?
+            .into_iter()
+            .filter(|device| device.device_type.starts_with("Pico"))
+            .flat_map(|device| {
+                let name = device.name;
+                device
+                    .button_groups
+                    .into_iter()
+                    .map(move |group| (group.href, name.clone()))
+            })
+            .collect::<HashMap<_, _>>();
+        let body = self.request("ReadRequest", "/button", None).await?;
+        let buttons: Vec<Button> = serde_json::from_value(body["Buttons"].clone())
+            .map_err(|e| LutronError::Protocol(format!("malformed button list: {}", e)))?;
+        for button in buttons {
+            if let Some(remote) = remotes.get(&button.parent.href) {
+                let url = format!("{}/status/event", button.href);
+                self.buttons
+                    .lock()
+                    .unwrap()
+                    .insert(url.clone(), (remote.clone(), button.button_number));
+                self.request("SubscribeRequest", &url, None).await?;
+            }
+        }
+        let (sender, receiver) = unbounded();
+        self.listeners.lock().unwrap().push(sender);
+        Ok(receiver)
+    }
+}
+
+/// A Caseta dimmer. It has no color, and comes back on at the last level it
+/// was set to.
+pub struct LutronLight {
+    bridge: Arc<LutronBridge>,
+    name: String,
+    zone: String,
+    /// Percentage, kept so turning on restores the previous level.
+    level: AtomicU8,
+}
+
+impl LutronLight {
+    async fn go_to_level(&self, level: u8) -> Result<(), LightError> {
+        let url = format!("{}/commandprocessor", self.zone);
+        let body = json!({
+            "Command": {
+                "CommandType": "GoToLevel",
+                "Parameter": [{ "Type": "Level", "Value": level }],
+            },
+        });
+        self.bridge
+            .request("CreateRequest", &url, Some(body))
+            .await
+            .map(|_| ())
+            .map_err(LightError::from)
+    }
+}
+
+impl crate::Light for LutronLight {
+    fn name(&self) -> String {
+        self.name.clone()
+    }
+
+    fn vendor(&self) -> &'static str {
+        "lutron"
+    }
+
+    fn unique_id<'a>(&'a self) -> BoxFuture<'a, Result<String, LightError>> {
+        Box::pin(async move { Ok(format!("Lutron Light {}", self.zone)) })
+    }
+
+    fn set_power_state<'a>(&'a self, state: PowerState) -> BoxFuture<'a, Result<(), LightError>> {
+        Box::pin(async move {
+            match state {
+                PowerState::On => self.go_to_level(self.level.load(Ordering::SeqCst)).await,
+                PowerState::Off => self.go_to_level(0).await,
+            }
+        })
+    }
+
+    fn set_brightness<'a>(
+        &'a self,
+        brightness: Brightness,
+    ) -> BoxFuture<'a, Result<(), LightError>> {
+        Box::pin(async move {
+            let level = brightness.percent().max(1);
+            self.level.store(level, Ordering::SeqCst);
+            self.go_to_level(level).await
+        })
+    }
+
+    // Groups mixing dimmers with color lights still send color to every
+    // member, so it's accepted and ignored.
+    fn set_color<'a>(&'a self, _: crate::Color) -> BoxFuture<'a, Result<(), LightError>> {
+        Box::pin(async { Ok(()) })
+    }
+
+    fn supports_color(&self) -> bool {
+        false
+    }
+}
@@ -0,0 +1,96 @@
+use std::time::Duration;
+
+use async_io::Timer;
+use futures::future::BoxFuture;
+
+use crate::{App, Brightness, Color, Id, Light, LightError, PowerState};
+
+const VENDOR: &str = "mock";
+/// How long simulated lights take to answer, about as long as a LAN bulb.
+const SIMULATED_LATENCY: Duration = Duration::from_millis(80);
+
+fn id(index: u8) -> String {
+    format!("mock-{}", index)
+}
+
+/// A light that only exists in memory, accepting every command after
+/// `latency`.
+pub(crate) struct MockLight {
+    index: u8,
+    latency: Duration,
+}
+
+impl MockLight {
+    pub(crate) fn new(index: u8) -> Self {
+        MockLight {
+            index,
+            latency: Duration::from_secs(0),
+        }
+    }
+
+    fn simulated(index: u8) -> Self {
+        MockLight {
+            index,
+            latency: SIMULATED_LATENCY,
+        }
+    }
+
+    fn respond<'a>(&self) -> BoxFuture<'a, Result<(), LightError>> {
+        let latency = self.latency;
+        Box::pin(async move {
+            if latency > Duration::from_secs(0) {
+                Timer::after(latency).await;
+            }
+            Ok(())
+        })
+    }
+}
+
+impl Light for MockLight {
+    fn name(&self) -> String {
+        format!("Mock Light {}", self.index)
+    }
+
+    fn vendor(&self) -> &'static str {
+        VENDOR
+    }
+
+    fn unique_id<'a>(&'a self) -> BoxFuture<'a, Result<String, LightError>> {
+        Box::pin(async move { Ok(id(self.index)) })
+    }
+
+    fn set_power_state<'a>(&'a self, _: PowerState) -> BoxFuture<'a, Result<(), LightError>> {
+        self.respond()
+    }
+
+    fn set_brightness<'a>(&'a self, _: Brightness) -> BoxFuture<'a, Result<(), LightError>> {
+        self.respond()
+    }
+
+    fn set_color<'a>(&'a self, _: Color) -> BoxFuture<'a, Result<(), LightError>> {
+        self.respond()
+    }
+}
+
+impl App {
+    /// Provides `count` simulated lights with the ids `mock-1` onwards and
+    /// drops any others, so an action can be tried, such as by Google's
+    /// reviewers, without hardware. Zero removes them all.
+    pub async fn simulate(&mut self, count: u8) {
+        let surplus = self
+            .lights()
+            .filter(|light| light.light().vendor() == VENDOR)
+            .map(|light| light.id())
+            .filter(|existing| !(1..=count).any(|index| id(index) == *existing))
+            .collect::<Vec<_>>();
+        for existing in surplus {
+            self.remove(&existing);
+            let _ = self.registry.forget(&existing);
+        }
+        let missing = (1..=count)
+            .filter(|index| !self.by_id.contains_key(&Id(id(*index))))
+            .map(MockLight::simulated)
+            .collect::<Vec<_>>();
+        self.push_lights(missing).await;
+    }
+}
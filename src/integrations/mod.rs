@@ -1,41 +1,191 @@
-use std::sync::Arc;
+use std::{error::Error as StdError, net::IpAddr, ops::RangeInclusive, sync::Arc};
 
-use crate::Light;
+use crate::{DeviceKind, Error, Light, LightError, Thermostat};
 
 pub mod broadlink;
+pub mod broadlink_rm;
+pub mod deconz;
 pub mod esp;
+pub(crate) mod esp_host;
+pub mod lutron;
+pub(crate) mod mock;
 // pub mod sengled;
 pub mod tuya;
+mod tuya_local;
+pub mod wiz;
 
 impl<T: Light> Light for Arc<T> {
     fn name(&self) -> String {
         T::name(self)
     }
 
-    fn unique_id<'a>(
-        &'a self,
-    ) -> futures::future::BoxFuture<'a, Result<String, Box<dyn std::error::Error + Send>>> {
+    fn vendor(&self) -> &'static str {
+        T::vendor(self)
+    }
+
+    fn unique_id<'a>(&'a self) -> futures::future::BoxFuture<'a, Result<String, LightError>> {
         T::unique_id(self)
     }
 
     fn set_power_state<'a>(
         &'a self,
         state: crate::PowerState,
-    ) -> futures::future::BoxFuture<'a, Result<(), Box<dyn std::error::Error + Send>>> {
+    ) -> futures::future::BoxFuture<'a, Result<(), LightError>> {
         T::set_power_state(self, state)
     }
 
     fn set_brightness<'a>(
         &'a self,
-        brightness: u8,
-    ) -> futures::future::BoxFuture<'a, Result<(), Box<dyn std::error::Error + Send>>> {
+        brightness: crate::Brightness,
+    ) -> futures::future::BoxFuture<'a, Result<(), LightError>> {
         T::set_brightness(self, brightness)
     }
 
     fn set_color<'a>(
         &'a self,
         color: crate::Color,
-    ) -> futures::future::BoxFuture<'a, Result<(), Box<dyn std::error::Error + Send>>> {
+    ) -> futures::future::BoxFuture<'a, Result<(), LightError>> {
         T::set_color(self, color)
     }
+
+    fn legacy_ids(&self) -> Vec<String> {
+        T::legacy_ids(self)
+    }
+
+    fn members(&self) -> Option<Vec<String>> {
+        T::members(self)
+    }
+
+    fn role(&self) -> crate::Role {
+        T::role(self)
+    }
+
+    fn online(&self) -> bool {
+        T::online(self)
+    }
+
+    fn supports_color(&self) -> bool {
+        T::supports_color(self)
+    }
+
+    fn color_temperature_range(&self) -> RangeInclusive<u32> {
+        T::color_temperature_range(self)
+    }
+
+    fn configure_strip(&self, strip: &lights_api::StripConfig) -> Result<(), String> {
+        T::configure_strip(self, strip)
+    }
+
+    fn upload_program<'a>(
+        &'a self,
+        program: &'a [u8],
+    ) -> futures::future::BoxFuture<'a, Result<(), Error>> {
+        T::upload_program(self, program)
+    }
+
+    fn write_frame<'a>(
+        &'a self,
+        frame: &'a [u8],
+    ) -> futures::future::BoxFuture<'a, Result<(), Error>> {
+        T::write_frame(self, frame)
+    }
+
+    fn kind(&self) -> DeviceKind {
+        T::kind(self)
+    }
+
+    fn set_fan_speed<'a>(
+        &'a self,
+        percent: u8,
+    ) -> futures::future::BoxFuture<'a, Result<(), LightError>> {
+        T::set_fan_speed(self, percent)
+    }
+
+    fn set_thermostat<'a>(
+        &'a self,
+        thermostat: Thermostat,
+    ) -> futures::future::BoxFuture<'a, Result<(), LightError>> {
+        T::set_thermostat(self, thermostat)
+    }
+}
+
+/// Looks up the hardware address of a LAN peer in the kernel ARP cache, which
+/// will hold an entry for any device we've recently exchanged packets with.
+pub(crate) fn mac_address(addr: IpAddr) -> Option<String> {
+    let table = std::fs::read_to_string("/proc/net/arp").ok()?;
+    let addr = addr.to_string();
+    table.lines().skip(1).find_map(|line| {
+        let mut columns = line.split_whitespace();
+        if columns.next()? != addr {
+            return None;
+        }
+        let mac = columns.nth(2)?;
+        if mac == "00:00:00:00:00:00" {
+            None
+        } else {
+            Some(mac.to_lowercase())
+        }
+    })
+}
+
+// What vendor libraries that only describe failures in their messages say
+// when a token is no longer accepted or the cloud wants fewer requests,
+// matched against whole words of the lowercased message.
+const AUTH_STATUSES: &[&str] = &["401", "403", "unauthorized", "forbidden"];
+const AUTH_MESSAGES: &[&str] = &["token invalid", "token expired", "invalid token"];
+const RATE_STATUSES: &[&str] = &["429", "frequentlyinvoke"];
+const RATE_MESSAGES: &[&str] = &["too many requests", "once in 60 seconds", "too frequently"];
+
+/// Sorts an error from a vendor library into the kinds retries and
+/// assistants tell apart, by the HTTP status or vendor code it mentions.
+pub(crate) fn vendor_error<E: StdError + Send + 'static>(error: E) -> LightError {
+    let message = error.to_string().to_lowercase();
+    let words = message
+        .split(|c: char| !c.is_ascii_alphanumeric())
+        .collect::<Vec<_>>();
+    let mentions = |statuses: &[&str], messages: &[&str]| {
+        statuses.iter().any(|status| words.contains(status))
+            || messages.iter().any(|text| message.contains(text))
+    };
+    if mentions(AUTH_STATUSES, AUTH_MESSAGES) {
+        LightError::AuthExpired
+    } else if mentions(RATE_STATUSES, RATE_MESSAGES) {
+        LightError::RateLimited
+    } else {
+        LightError::other(error)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+
+    use super::vendor_error;
+    use crate::LightError;
+
+    fn classify(message: &str) -> LightError {
+        vendor_error(io::Error::new(io::ErrorKind::Other, message))
+    }
+
+    #[test]
+    fn sorts_vendor_errors() {
+        assert!(matches!(
+            classify("HTTP status 401 Unauthorized"),
+            LightError::AuthExpired
+        ));
+        assert!(matches!(
+            classify("{\"code\":1010,\"msg\":\"token invalid\"}"),
+            LightError::AuthExpired
+        ));
+        assert!(matches!(classify("status: 429"), LightError::RateLimited));
+        assert!(matches!(
+            classify("FrequentlyInvoke: you cannot auth exceed once in 60 seconds"),
+            LightError::RateLimited
+        ));
+        // Numbers that only contain a status aren't taken for one.
+        assert!(matches!(
+            classify("device 14290 unreachable"),
+            LightError::Other(_)
+        ));
+    }
 }
@@ -1,11 +1,10 @@
-use crate::PowerState;
+use crate::{LightError, PowerState};
 use futures::{
     future::{BoxFuture, Either},
     TryFutureExt,
 };
 use lights_sengled::{Color, Device, SengledApi};
 use std::{
-    error::Error,
     sync::{
         atomic::{AtomicUsize, Ordering},
         Arc,
@@ -25,33 +24,37 @@ impl crate::Light for SengledLight {
         self.name.clone()
     }
 
+    fn vendor(&self) -> &'static str {
+        "sengled"
+    }
+
     fn set_power_state<'a>(
         &'a self,
         state: crate::PowerState,
-    ) -> BoxFuture<'a, Result<(), Box<dyn Error + Send>>> {
+    ) -> BoxFuture<'a, Result<(), LightError>> {
         let fut = match state {
             PowerState::On => Either::Left(async move { self.api.turn_on(&self.light).await }),
             PowerState::Off => Either::Right(async move { self.api.turn_off(&self.light).await }),
         };
-        Box::pin(fut.map_err(|e| Box::new(e) as Box<dyn Error + Send>))
+        Box::pin(fut.map_err(LightError::other))
     }
 
     fn set_brightness<'a>(
         &'a self,
-        brightness: u8,
-    ) -> BoxFuture<'a, Result<(), Box<dyn Error + Send>>> {
+        brightness: crate::Brightness,
+    ) -> BoxFuture<'a, Result<(), LightError>> {
         Box::pin(async move {
             self.api
-                .set_brightness(&self.light, brightness)
+                .set_brightness(&self.light, brightness.into())
                 .await
-                .map_err(|e| Box::new(e) as Box<dyn Error + Send>)
+                .map_err(LightError::other)
         })
     }
 
     fn set_color<'a>(
         &'a self,
         color: crate::Color,
-    ) -> BoxFuture<'a, Result<(), Box<dyn Error + Send>>> {
+    ) -> BoxFuture<'a, Result<(), LightError>> {
         Box::pin(async move {
             self.api
                 .set_color(
@@ -66,11 +69,11 @@ impl crate::Light for SengledLight {
                     },
                 )
                 .await
-                .map_err(|e| Box::new(e) as Box<dyn Error + Send>)
+                .map_err(LightError::other)
         })
     }
 
-    fn unique_id<'a>(&'a self) -> BoxFuture<'a, Result<String, Box<dyn Error + Send>>> {
+    fn unique_id<'a>(&'a self) -> BoxFuture<'a, Result<String, LightError>> {
         Box::pin(async move { Ok(format!("Sengled Light {:?}", self.light.uuid())) })
     }
 }
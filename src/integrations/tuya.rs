@@ -1,156 +1,496 @@
-use crate::{Color, PowerState};
-use futures::{
-    future::{BoxFuture, Either},
-    TryFutureExt,
+use super::{
+    tuya_local::{LocalAddresses, LocalDevice},
+    vendor_error,
 };
-use lights_tuya::{AccessToken, HsbColor, Light, State, TuyaApi};
+use crate::{
+    poll::Poll, storage::storage, vault::vault, App, Brightness, Color, LightError, LightState,
+    PowerState, ReportedState,
+};
+use async_lock::{Mutex, RwLock};
+use futures::future::BoxFuture;
+use lights_tuya::{AccessToken, Error as TuyaError, HsbColor, Light, State, TuyaApi};
 use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
 use std::{
-    error::Error,
-    io::{Read, Write},
+    collections::HashMap,
+    error::Error as StdError,
+    future::Future,
+    ops::RangeInclusive,
     sync::{
         atomic::{AtomicUsize, Ordering},
         Arc,
     },
+    time::{Duration, Instant},
 };
 
 static COUNT: AtomicUsize = AtomicUsize::new(1);
 
+const TOKEN_KEY: &str = "TUYA_TOKEN";
+const DEVICES_KEY: &str = "devices";
+const ADDRESSES_KEY: &str = "local";
+const RENEW_INTERVAL: Duration = Duration::from_secs(60);
+// Covered by the `23` data point, and assumed for cloud-only bulbs too.
+const MIN_KELVIN: u32 = 2700;
+const MAX_KELVIN: u32 = 6500;
+
 #[derive(Serialize, Deserialize)]
 struct DevicesFile {
     devices: Vec<Light>,
 }
 
 pub struct TuyaLight {
-    api: Arc<TuyaApi>,
+    session: Arc<TuyaSession>,
+    local: Option<Arc<LocalDevice>>,
     name: String,
     light: Light,
 }
 
+/// A Tuya cloud session shared by every light discovered with it, able to
+/// refresh its token once the cloud rejects it, and to log back in with the
+/// stored credentials if the refresh token has expired too.
+pub struct TuyaSession {
+    api: RwLock<Arc<TuyaApi>>,
+    user: String,
+    pass: String,
+    renewed: Mutex<Option<Instant>>,
+}
+
+impl TuyaSession {
+    pub fn new(api: TuyaApi, user: String, pass: String) -> Self {
+        TuyaSession {
+            api: RwLock::new(Arc::new(api)),
+            user,
+            pass,
+            renewed: Mutex::new(None),
+        }
+    }
+
+    async fn renew(&self, stale: &Arc<TuyaApi>) -> Result<(), LightError> {
+        let mut api = self.api.write().await;
+        if !Arc::ptr_eq(&api, stale) {
+            return Ok(());
+        }
+        let fresh = match stale.refresh().await {
+            Ok(fresh) => fresh,
+            Err(_) => {
+                // Logins are rate limited, so failed ones aren't retried
+                // for a while.
+                let mut renewed = self.renewed.lock().await;
+                if let Some(at) = *renewed {
+                    if at.elapsed() < RENEW_INTERVAL {
+                        return Err(LightError::AuthExpired);
+                    }
+                }
+                *renewed = Some(Instant::now());
+                TuyaApi::new(&self.user, &self.pass)
+                    .await
+                    .map_err(|_| LightError::AuthExpired)?
+            }
+        };
+        if let Err(e) = store_token(&fresh) {
+            eprintln!("failed to persist tuya access token: {:?}", e);
+        }
+        *api = Arc::new(fresh);
+        Ok(())
+    }
+
+    /// Runs `command`, renewing the token and running it again if the
+    /// cloud rejected the token. Other failures, such as an offline device,
+    /// are returned as they are.
+    async fn call<'a, F, Fut>(&'a self, command: F) -> Result<(), LightError>
+    where
+        F: Fn(Arc<TuyaApi>) -> Fut,
+        Fut: Future<Output = Result<(), TuyaError>> + 'a,
+    {
+        let api = self.api.read().await.clone();
+        match command(api.clone()).await {
+            Err(e) if e.is_auth() => {
+                self.renew(&api).await?;
+                let api = self.api.read().await.clone();
+                command(api).await.map_err(light_error)
+            }
+            result => result.map_err(light_error),
+        }
+    }
+}
+
+fn light_error(e: TuyaError) -> LightError {
+    if e.is_auth() {
+        LightError::AuthExpired
+    } else {
+        vendor_error(e)
+    }
+}
+
+fn hsb(r: u8, g: u8, b: u8) -> HsbColor {
+    let r = r as f64 / 255.;
+    let g = g as f64 / 255.;
+    let b = b as f64 / 255.;
+    let cmax = r.max(g.max(b));
+    let cmin = r.min(g.min(b));
+    let diff = cmax - cmin;
+    HsbColor {
+        brightness: (cmax * 100.) as u8,
+        hue: if cmax == cmin {
+            0.
+        } else if cmax == r {
+            (60. * ((g - b) / diff) + 360.) % 360.
+        } else if cmax == g {
+            (60. * ((b - r) / diff) + 120.) % 360.
+        } else if cmax == b {
+            (60. * ((r - g) / diff) + 240.) % 360.
+        } else {
+            panic!("cmax is not the value of any component")
+        } as u16,
+        saturation: if cmax == 0. { 0. } else { diff / cmax } as f32,
+    }
+}
+
+fn rgb(hue: f64, saturation: f64, value: f64) -> (u8, u8, u8) {
+    let chroma = value * saturation;
+    let x = chroma * (1. - ((hue / 60.) % 2. - 1.).abs());
+    let (r, g, b) = match (hue / 60.) as u32 {
+        0 => (chroma, x, 0.),
+        1 => (x, chroma, 0.),
+        2 => (0., chroma, x),
+        3 => (0., x, chroma),
+        4 => (x, 0., chroma),
+        _ => (chroma, 0., x),
+    };
+    let channel = |c: f64| ((c + value - chroma) * 255.).round() as u8;
+    (channel(r), channel(g), channel(b))
+}
+
+// Data points of the newer layout: `20` power, `21` mode, `22` brightness,
+// `23` color temperature and `24` color as hex hue, saturation and value.
+const DP_BRIGHTNESS: RangeInclusive<u32> = 10..=1000;
+
+fn brightness_dp(brightness: Brightness) -> Value {
+    json!(brightness.to_range(DP_BRIGHTNESS))
+}
+
+fn color_dps(color: Color) -> Value {
+    match color {
+        Color::Rgb { r, g, b } => {
+            let color = hsb(r, g, b);
+            json!({
+                "21": "colour",
+                "24": format!(
+                    "{:04x}{:04x}{:04x}",
+                    color.hue,
+                    (color.saturation * 1000.) as u16,
+                    color.brightness as u16 * 10
+                ),
+            })
+        }
+        Color::White { temperature } => json!({
+            "21": "white",
+            "23": (temperature.max(MIN_KELVIN).min(MAX_KELVIN) - MIN_KELVIN) * 1000
+                / (MAX_KELVIN - MIN_KELVIN),
+        }),
+    }
+}
+
+fn parse_color(dps: &Value) -> Option<Color> {
+    match dps["21"].as_str()? {
+        "colour" => {
+            let hex = dps["24"].as_str()?;
+            let field = |range| u16::from_str_radix(hex.get(range)?, 16).ok();
+            let (r, g, b) = rgb(
+                field(0..4)? as f64 % 360.,
+                field(4..8)? as f64 / 1000.,
+                field(8..12)? as f64 / 1000.,
+            );
+            Some(Color::Rgb { r, g, b })
+        }
+        "white" => Some(Color::White {
+            temperature: MIN_KELVIN + dps["23"].as_u64()? as u32 * (MAX_KELVIN - MIN_KELVIN) / 1000,
+        }),
+        _ => None,
+    }
+}
+
+/// The parts of `dps` that differ from the cached state. Comparisons happen
+/// in the device's encoding, so rounding doesn't look like a change.
+fn changes(cached: LightState, dps: &Value) -> ReportedState {
+    let mut changed = ReportedState::default();
+    if let Some(on) = dps["20"].as_bool() {
+        if on != cached.on {
+            changed.on = Some(on);
+        }
+    }
+    if let Some(level) = dps["22"].as_u64() {
+        if dps["22"] != brightness_dp(cached.brightness.into()) {
+            let level = level.min(*DP_BRIGHTNESS.end() as u64) as u32;
+            changed.brightness = Some(Brightness::from_range(level, DP_BRIGHTNESS).into());
+        }
+    }
+    let expected = cached.color.map(color_dps).unwrap_or_default();
+    let key = match dps["21"].as_str() {
+        Some("colour") => "24",
+        _ => "23",
+    };
+    if dps["21"] != expected["21"] || dps[key] != expected[key] {
+        changed.color = parse_color(dps);
+    }
+    changed
+}
+
+fn store_token(api: &TuyaApi) -> Result<(), Box<dyn StdError>> {
+    let mut token = vec![];
+    api.dump_token().write_to(&mut token)?;
+    vault().put(TOKEN_KEY, &token)?;
+    Ok(())
+}
+
+fn id(light: &Light) -> String {
+    format!("Tuya Light {}", light.id())
+}
+
 impl crate::Light for TuyaLight {
     fn name(&self) -> String {
         self.name.clone()
     }
 
+    fn vendor(&self) -> &'static str {
+        "tuya"
+    }
+
     fn set_power_state<'a>(
         &'a self,
         state: crate::PowerState,
-    ) -> BoxFuture<'a, Result<(), Box<dyn Error + Send>>> {
-        let fut = match state {
-            PowerState::On => {
-                Either::Left(async move { self.api.set_state(&self.light, State::On).await })
-            }
-            PowerState::Off => {
-                Either::Right(async move { self.api.set_state(&self.light, State::Off).await })
+    ) -> BoxFuture<'a, Result<(), LightError>> {
+        Box::pin(async move {
+            if self
+                .try_local(json!({ "20": matches!(state, PowerState::On) }))
+                .await
+            {
+                return Ok(());
             }
-        };
-        Box::pin(fut.map_err(|e| Box::new(e) as Box<dyn Error + Send>))
+            self.session
+                .call(move |api| async move {
+                    api.set_state(
+                        &self.light,
+                        match state {
+                            PowerState::On => State::On,
+                            PowerState::Off => State::Off,
+                        },
+                    )
+                    .await
+                })
+                .await
+        })
     }
 
     fn set_brightness<'a>(
         &'a self,
-        brightness: u8,
-    ) -> BoxFuture<'a, Result<(), Box<dyn Error + Send>>> {
+        brightness: Brightness,
+    ) -> BoxFuture<'a, Result<(), LightError>> {
         Box::pin(async move {
-            self.api
-                .set_brightness(&self.light, brightness)
+            if self
+                .try_local(json!({ "22": brightness_dp(brightness) }))
+                .await
+            {
+                return Ok(());
+            }
+            self.session
+                .call(move |api| async move { api.set_brightness(&self.light, brightness.into()).await })
                 .await
-                .map_err(|e| Box::new(e) as Box<dyn Error + Send>)
         })
     }
 
-    fn set_color<'a>(&'a self, color: Color) -> BoxFuture<'a, Result<(), Box<dyn Error + Send>>> {
+    fn set_color<'a>(&'a self, color: Color) -> BoxFuture<'a, Result<(), LightError>> {
         Box::pin(async move {
-            match color {
-                Color::Rgb { r, g, b } => self
-                    .api
-                    .set_color(&self.light, {
-                        let r = r as f64 / 255.;
-                        let g = g as f64 / 255.;
-                        let b = b as f64 / 255.;
-                        let cmax = r.max(g.max(b));
-                        let cmin = r.min(g.min(b));
-                        let diff = cmax - cmin;
-                        HsbColor {
-                            brightness: (cmax * 100.) as u8,
-                            hue: if cmax == cmin {
-                                0.
-                            } else if cmax == r {
-                                (60. * ((g - b) / diff) + 360.) % 360.
-                            } else if cmax == g {
-                                (60. * ((b - r) / diff) + 120.) % 360.
-                            } else if cmax == b {
-                                (60. * ((r - g) / diff) + 240.) % 360.
-                            } else {
-                                panic!("cmax is not the value of any component")
-                            } as u16,
-                            saturation: if cmax == 0. { 0. } else { diff / cmax } as f32,
-                        }
-                    })
-                    .await
-                    .map_err(|e| Box::new(e) as Box<dyn Error + Send>),
-                Color::White { temperature } => self
-                    .api
-                    .set_color_temperature(&self.light, temperature)
-                    .await
-                    .map_err(|e| Box::new(e) as Box<dyn Error + Send>),
+            if self.try_local(color_dps(color)).await {
+                return Ok(());
             }
+            self.session
+                .call(move |api| async move {
+                    match color {
+                        Color::Rgb { r, g, b } => api.set_color(&self.light, hsb(r, g, b)).await,
+                        Color::White { temperature } => {
+                            api.set_color_temperature(&self.light, temperature).await
+                        }
+                    }
+                })
+                .await
         })
     }
 
-    fn unique_id<'a>(&'a self) -> BoxFuture<'a, Result<String, Box<dyn Error + Send>>> {
-        Box::pin(async move { Ok(format!("Tuya Light {}", self.light.id())) })
+    fn unique_id<'a>(&'a self) -> BoxFuture<'a, Result<String, LightError>> {
+        Box::pin(async move { Ok(id(&self.light)) })
+    }
+
+    fn color_temperature_range(&self) -> RangeInclusive<u32> {
+        MIN_KELVIN..=MAX_KELVIN
     }
 }
 
 impl TuyaLight {
-    pub fn new(light: Light, api: Arc<TuyaApi>) -> Self {
+    pub(crate) fn new(
+        light: Light,
+        session: Arc<TuyaSession>,
+        local: Option<Arc<LocalDevice>>,
+    ) -> Self {
         TuyaLight {
             name: format!("Tuya Light {}", COUNT.fetch_add(1, Ordering::SeqCst)),
             light,
-            api,
+            session,
+            local,
+        }
+    }
+
+    async fn try_local(&self, dps: Value) -> bool {
+        if let Some(local) = &self.local {
+            match local.set(dps).await {
+                Ok(()) => return true,
+                Err(e) => eprintln!(
+                    "local control of {} failed, falling back to cloud: {:?}",
+                    self.light.id(),
+                    e
+                ),
+            }
         }
+        false
     }
 }
 
 pub async fn tuya_scan<T: AsRef<str>, U: AsRef<str>>(
     user: T,
     pass: U,
-) -> Result<Vec<TuyaLight>, Box<dyn Error>> {
-    let key_path = std::path::Path::new("tuya/access_token");
-    let api = Arc::new(if key_path.exists() {
-        let file = std::fs::OpenOptions::new().read(true).open(key_path)?;
-        TuyaApi::from_token(AccessToken::read_from(file)?)
+) -> Result<Vec<TuyaLight>, Box<dyn StdError>> {
+    scan(user, pass, false).await
+}
+
+/// Like [`tuya_scan`], but always asks the cloud for the current device list
+/// and rewrites the cached copy.
+pub async fn tuya_rescan<T: AsRef<str>, U: AsRef<str>>(
+    user: T,
+    pass: U,
+) -> Result<Vec<TuyaLight>, Box<dyn StdError>> {
+    scan(user, pass, true).await
+}
+
+async fn scan<T: AsRef<str>, U: AsRef<str>>(
+    user: T,
+    pass: U,
+    refresh: bool,
+) -> Result<Vec<TuyaLight>, Box<dyn StdError>> {
+    let api = if let Some(token) = vault().get(TOKEN_KEY)? {
+        TuyaApi::from_token(AccessToken::read_from(&token[..])?)
     } else {
-        let api = TuyaApi::new(user, pass).await?;
-        let file = std::fs::OpenOptions::new()
-            .create(true)
-            .write(true)
-            .open(key_path)?;
-        api.dump_token().write_to(file)?;
+        let api = TuyaApi::new(&user, &pass).await?;
+        store_token(&api)?;
         api
-    });
-    let devices_path = std::path::Path::new("tuya/devices.toml");
-    Ok(if devices_path.exists() {
-        let mut buf = String::new();
-        std::fs::File::open(devices_path)?.read_to_string(&mut buf)?;
-        let DevicesFile { devices } = toml::from_str(&buf)?;
-        devices
-    } else {
-        let devices = api.scan().await?;
-        std::fs::OpenOptions::new()
-            .write(true)
-            .create(true)
-            .open(devices_path)?
-            .write_all(
-                toml::to_string(&DevicesFile {
+    };
+    let session = Arc::new(TuyaSession::new(
+        api,
+        user.as_ref().to_owned(),
+        pass.as_ref().to_owned(),
+    ));
+    let mut addresses = storage()
+        .store::<LocalAddresses>("tuya")
+        .get(ADDRESSES_KEY)?
+        .map_or_else(HashMap::new, |local| {
+            local
+                .devices
+                .into_iter()
+                .map(|address| (address.id.clone(), address))
+                .collect()
+        });
+    let cache = storage().store::<DevicesFile>("tuya");
+    let devices = match cache.get(DEVICES_KEY)? {
+        Some(DevicesFile { devices }) if !refresh => devices,
+        _ => {
+            let devices = session.api.read().await.scan().await?;
+            cache.put(
+                DEVICES_KEY,
+                &DevicesFile {
                     devices: devices.clone(),
-                })?
-                .as_bytes(),
+                },
             )?;
-        devices
+            devices
+        }
+    };
+    let mut lights = Vec::with_capacity(devices.len());
+    for light in devices {
+        let local = match addresses.remove(light.id()) {
+            Some(address) => local_key(&session, &light, refresh)
+                .await
+                .map(|key| Arc::new(LocalDevice::new(address, key))),
+            None => None,
+        };
+        lights.push(TuyaLight::new(light, session.clone(), local));
+    }
+    Ok(lights)
+}
+
+/// The local key of a bulb with a LAN address, kept in the vault and fetched
+/// from the cloud when missing or rescanning. Bulbs without one are only
+/// controlled through the cloud.
+async fn local_key(session: &TuyaSession, light: &Light, refresh: bool) -> Option<String> {
+    let name = format!("TUYA_LOCAL_KEY_{}", light.id());
+    if !refresh {
+        if let Some(key) = vault().secret(&name) {
+            return Some(key);
+        }
+    }
+    let api = session.api.read().await.clone();
+    match api.local_key(light).await {
+        Ok(key) => {
+            if let Err(e) = vault().put(&name, key.as_bytes()) {
+                eprintln!("failed to store local key of {}: {:?}", light.id(), e);
+            }
+            Some(key)
+        }
+        Err(e) => {
+            eprintln!("failed to fetch local key of {}: {}", light.id(), e);
+            None
+        }
+    }
+}
+
+/// Lights reachable over the LAN, polled for changes made elsewhere, such as
+/// from the Smart Life app. The cloud API can't be asked for state, so lights
+/// without a LAN address or local key aren't covered.
+pub struct TuyaPoller {
+    devices: Vec<(String, Arc<LocalDevice>)>,
+}
+
+struct PolledLight {
+    id: String,
+    device: Arc<LocalDevice>,
+}
+
+impl Poll for PolledLight {
+    fn id(&self) -> String {
+        self.id.clone()
+    }
+
+    fn vendor(&self) -> &'static str {
+        "tuya"
+    }
+
+    fn poll(&self, cached: LightState) -> BoxFuture<'_, Result<ReportedState, LightError>> {
+        Box::pin(async move { Ok(changes(cached, &self.device.query().await?)) })
+    }
+}
+
+impl TuyaPoller {
+    pub fn new(lights: &[TuyaLight]) -> Self {
+        TuyaPoller {
+            devices: lights
+                .iter()
+                .filter_map(|light| Some((id(&light.light), light.local.clone()?)))
+                .collect(),
+        }
+    }
+
+    /// Hands the lights to the poll scheduler, which reads them as the
+    /// `tuya` quota allows.
+    pub fn schedule(self, app: &App) {
+        for (id, device) in self.devices {
+            app.add_polled(Arc::new(PolledLight { id, device }));
+        }
     }
-    .into_iter()
-    .map(|light| TuyaLight::new(light, api.clone()))
-    .collect())
 }
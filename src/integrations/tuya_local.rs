@@ -0,0 +1,182 @@
+use std::{
+    io,
+    net::{IpAddr, SocketAddr, TcpStream},
+    sync::atomic::{AtomicU32, Ordering},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use aes::Aes128;
+use async_io::{Async, Timer};
+use block_modes::{block_padding::Pkcs7, BlockMode, Ecb};
+use futures::{
+    future::{select, Either},
+    pin_mut, AsyncReadExt, AsyncWriteExt,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+const PORT: u16 = 6668;
+const TIMEOUT: Duration = Duration::from_secs(2);
+const PREFIX: u32 = 0x0000_55AA;
+const SUFFIX: u32 = 0x0000_AA55;
+const CONTROL: u32 = 7;
+const DP_QUERY: u32 = 10;
+const VERSION: &[u8] = b"3.3";
+
+type Aes128Ecb = Ecb<Aes128, Pkcs7>;
+
+/// The bulbs controlled over the LAN, stored as `local` in the `tuya`
+/// namespace. Their local keys are secrets, fetched from the cloud during
+/// the scan and kept in the vault instead.
+#[derive(Serialize, Deserialize)]
+pub(crate) struct LocalAddresses {
+    pub(crate) devices: Vec<LocalAddress>,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub(crate) struct LocalAddress {
+    pub(crate) id: String,
+    pub(crate) ip: IpAddr,
+}
+
+/// A bulb reachable over the Tuya 3.3 LAN protocol, using the newer
+/// (`20`-`24`) data point layout.
+pub(crate) struct LocalDevice {
+    address: LocalAddress,
+    local_key: String,
+    sequence: AtomicU32,
+}
+
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = 0xFFFF_FFFFu32;
+    for byte in data {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ 0xEDB8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+impl LocalDevice {
+    pub(crate) fn new(address: LocalAddress, local_key: String) -> Self {
+        LocalDevice {
+            address,
+            local_key,
+            sequence: AtomicU32::new(1),
+        }
+    }
+
+    fn cipher(&self) -> io::Result<Aes128Ecb> {
+        Aes128Ecb::new_var(self.local_key.as_bytes(), &[])
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "invalid local key"))
+    }
+
+    // Only control frames carry the protocol version ahead of the payload.
+    fn frame(&self, command: u32, payload: Value) -> io::Result<Vec<u8>> {
+        let payload = serde_json::to_vec(&payload)?;
+        let mut data = vec![];
+        if command == CONTROL {
+            data.extend_from_slice(VERSION);
+            data.extend_from_slice(&[0; 12]);
+        }
+        data.extend(self.cipher()?.encrypt_vec(&payload));
+
+        let mut frame = vec![];
+        frame.extend_from_slice(&PREFIX.to_be_bytes());
+        frame.extend_from_slice(&self.sequence.fetch_add(1, Ordering::SeqCst).to_be_bytes());
+        frame.extend_from_slice(&command.to_be_bytes());
+        frame.extend_from_slice(&(data.len() as u32 + 8).to_be_bytes());
+        frame.extend(data);
+        frame.extend_from_slice(&crc32(&frame).to_be_bytes());
+        frame.extend_from_slice(&SUFFIX.to_be_bytes());
+        Ok(frame)
+    }
+
+    /// Sends a frame, returning the still encrypted payload of the reply.
+    async fn exchange(&self, frame: &[u8]) -> io::Result<Vec<u8>> {
+        let mut stream =
+            Async::<TcpStream>::connect(SocketAddr::new(self.address.ip, PORT)).await?;
+        stream.write_all(frame).await?;
+        let mut header = [0; 16];
+        stream.read_exact(&mut header).await?;
+        let len = u32::from_be_bytes([header[12], header[13], header[14], header[15]]) as usize;
+        if len < 12 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "truncated reply",
+            ));
+        }
+        let mut body = vec![0; len];
+        stream.read_exact(&mut body).await?;
+        let code = u32::from_be_bytes([body[0], body[1], body[2], body[3]]);
+        if code != 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                format!("device rejected command with code {}", code),
+            ));
+        }
+        body.truncate(len - 8);
+        body.drain(..4);
+        Ok(body)
+    }
+
+    async fn timed(&self, frame: Vec<u8>) -> io::Result<Vec<u8>> {
+        let exchange = self.exchange(&frame);
+        let timeout = Timer::after(TIMEOUT);
+        pin_mut!(exchange);
+        match select(exchange, timeout).await {
+            Either::Left((result, _)) => result,
+            Either::Right(_) => Err(io::ErrorKind::TimedOut.into()),
+        }
+    }
+
+    fn timestamp() -> String {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|time| time.as_secs())
+            .unwrap_or(0)
+            .to_string()
+    }
+
+    pub(crate) async fn set(&self, dps: Value) -> io::Result<()> {
+        let frame = self.frame(
+            CONTROL,
+            json!({
+                "devId": self.address.id,
+                "uid": self.address.id,
+                "t": LocalDevice::timestamp(),
+                "dps": dps,
+            }),
+        )?;
+        self.timed(frame).await?;
+        Ok(())
+    }
+
+    /// Reads every data point the bulb currently reports.
+    pub(crate) async fn query(&self) -> io::Result<Value> {
+        let frame = self.frame(
+            DP_QUERY,
+            json!({
+                "gwId": self.address.id,
+                "devId": self.address.id,
+                "uid": self.address.id,
+                "t": LocalDevice::timestamp(),
+            }),
+        )?;
+        let mut reply = self.timed(frame).await?;
+        if reply.starts_with(VERSION) {
+            reply.drain(..(VERSION.len() + 12).min(reply.len()));
+        }
+        let reply = self
+            .cipher()?
+            .decrypt_vec(&reply)
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "undecryptable reply"))?;
+        let reply: Value = serde_json::from_slice(&reply)?;
+        Ok(reply["dps"].clone())
+    }
+}
@@ -0,0 +1,199 @@
+use crate::{Brightness, LightError, PowerState};
+use async_io::{Async, Timer};
+use futures::{
+    future::{select, BoxFuture, Either},
+    pin_mut,
+};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::{
+    collections::HashSet,
+    io,
+    net::{SocketAddr, UdpSocket},
+    ops::RangeInclusive,
+    sync::atomic::{AtomicUsize, Ordering},
+    time::{Duration, Instant},
+};
+
+static COUNT: AtomicUsize = AtomicUsize::new(1);
+
+const PORT: u16 = 38899;
+const TIMEOUT: Duration = Duration::from_millis(500);
+// Commands go over UDP, so a lost datagram is retried before giving up.
+const ATTEMPTS: usize = 3;
+const MIN_KELVIN: u32 = 2200;
+const MAX_KELVIN: u32 = 6500;
+
+/// The state a bulb reports through `getPilot`.
+#[derive(Deserialize, Debug, Clone)]
+pub struct Pilot {
+    pub state: bool,
+    /// Percentage, 10-100.
+    pub dimming: Option<u8>,
+    pub r: Option<u8>,
+    pub g: Option<u8>,
+    pub b: Option<u8>,
+    pub temp: Option<u32>,
+}
+
+#[derive(Deserialize)]
+struct Response {
+    result: Option<Value>,
+    error: Option<Value>,
+}
+
+#[derive(Deserialize)]
+struct Registration {
+    mac: String,
+}
+
+pub struct WizLight {
+    name: String,
+    addr: SocketAddr,
+    mac: String,
+}
+
+async fn with_timeout<F: std::future::Future<Output = io::Result<T>>, T>(
+    future: F,
+    timeout: Duration,
+) -> io::Result<T> {
+    pin_mut!(future);
+    match select(future, Timer::after(timeout)).await {
+        Either::Left((result, _)) => result,
+        Either::Right(_) => Err(io::ErrorKind::TimedOut.into()),
+    }
+}
+
+impl WizLight {
+    async fn exchange(&self, message: &[u8]) -> io::Result<Vec<u8>> {
+        let socket = Async::<UdpSocket>::bind(([0, 0, 0, 0], 0))?;
+        socket.get_ref().connect(self.addr)?;
+        let mut buf = [0; 1024];
+        let mut last = io::ErrorKind::TimedOut.into();
+        for _ in 0..ATTEMPTS {
+            socket.send(message).await?;
+            match with_timeout(socket.recv(&mut buf), TIMEOUT).await {
+                Ok(len) => return Ok(buf[..len].to_vec()),
+                Err(e) => last = e,
+            }
+        }
+        Err(last)
+    }
+
+    async fn call(&self, method: &str, params: Value) -> Result<Value, LightError> {
+        let message = serde_json::to_vec(&json!({ "method": method, "params": params }))
+            .map_err(LightError::other)?;
+        let reply = self.exchange(&message).await?;
+        let response: Response = serde_json::from_slice(&reply)
+            .map_err(|e| LightError::Protocol(format!("malformed reply: {}", e)))?;
+        match (response.result, response.error) {
+            (_, Some(error)) => Err(LightError::Protocol(error.to_string())),
+            (Some(result), None) => Ok(result),
+            (None, None) => Err(LightError::Protocol("empty reply".to_owned())),
+        }
+    }
+
+    async fn set_pilot(&self, params: Value) -> Result<(), LightError> {
+        self.call("setPilot", params).await.map(|_| ())
+    }
+
+    /// Reads back what the bulb is currently showing.
+    pub async fn pilot(&self) -> Result<Pilot, LightError> {
+        let result = self.call("getPilot", json!({})).await?;
+        serde_json::from_value(result)
+            .map_err(|e| LightError::Protocol(format!("malformed pilot: {}", e)))
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+}
+
+impl crate::Light for WizLight {
+    fn name(&self) -> String {
+        self.name.clone()
+    }
+
+    fn vendor(&self) -> &'static str {
+        "wiz"
+    }
+
+    fn unique_id<'a>(&'a self) -> BoxFuture<'a, Result<String, LightError>> {
+        Box::pin(async move { Ok(format!("WiZ Light {}", self.mac)) })
+    }
+
+    fn set_power_state<'a>(&'a self, state: PowerState) -> BoxFuture<'a, Result<(), LightError>> {
+        Box::pin(self.set_pilot(json!({ "state": matches!(state, PowerState::On) })))
+    }
+
+    fn set_brightness<'a>(
+        &'a self,
+        brightness: Brightness,
+    ) -> BoxFuture<'a, Result<(), LightError>> {
+        // Bulbs don't accept dimming below 10%.
+        let dimming = brightness.to_range(10..=100);
+        Box::pin(self.set_pilot(json!({ "dimming": dimming })))
+    }
+
+    fn set_color<'a>(&'a self, color: crate::Color) -> BoxFuture<'a, Result<(), LightError>> {
+        let params = match color {
+            crate::Color::Rgb { r, g, b } => json!({ "r": r, "g": g, "b": b }),
+            crate::Color::White { temperature } => {
+                json!({ "temp": temperature.max(MIN_KELVIN).min(MAX_KELVIN) })
+            }
+        };
+        Box::pin(self.set_pilot(params))
+    }
+
+    fn color_temperature_range(&self) -> RangeInclusive<u32> {
+        MIN_KELVIN..=MAX_KELVIN
+    }
+}
+
+/// Broadcasts a registration request and collects the bulbs that answer
+/// within `window`.
+pub async fn wiz_discover(window: Duration) -> io::Result<Vec<WizLight>> {
+    let socket = Async::<UdpSocket>::bind(([0, 0, 0, 0], 0))?;
+    socket.get_ref().set_broadcast(true)?;
+    let registration = serde_json::to_vec(&json!({
+        "method": "registration",
+        "params": {
+            "phoneMac": "AAAAAAAAAAAA",
+            "register": false,
+            "phoneIp": "1.2.3.4",
+            "id": "1",
+        },
+    }))?;
+    socket
+        .send_to(
+            &registration,
+            SocketAddr::from(([255, 255, 255, 255], PORT)),
+        )
+        .await?;
+
+    let deadline = Instant::now() + window;
+    let mut seen = HashSet::new();
+    let mut lights = vec![];
+    let mut buf = [0; 1024];
+    while let Some(remaining) = deadline.checked_duration_since(Instant::now()) {
+        let (len, addr) = match with_timeout(socket.recv_from(&mut buf), remaining).await {
+            Ok(received) => received,
+            Err(e) if e.kind() == io::ErrorKind::TimedOut => break,
+            Err(e) => return Err(e),
+        };
+        let registration = serde_json::from_slice::<Response>(&buf[..len])
+            .ok()
+            .and_then(|response| response.result)
+            .and_then(|result| serde_json::from_value::<Registration>(result).ok());
+        if let Some(Registration { mac }) = registration {
+            if seen.insert(mac.clone()) {
+                lights.push(WizLight {
+                    name: format!("WiZ Light {}", COUNT.fetch_add(1, Ordering::SeqCst)),
+                    addr,
+                    mac,
+                });
+            }
+        }
+    }
+    Ok(lights)
+}
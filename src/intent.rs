@@ -0,0 +1,230 @@
+use std::{future::Future, ops::RangeInclusive, sync::Arc, time::Duration};
+
+use async_io::Timer;
+use async_lock::RwLock;
+use futures::{
+    channel::oneshot,
+    future::{select, Either},
+};
+
+use crate::{
+    policy::Origin, temperatures, App, Brightness, Color, DeviceKind, Error, LightError,
+    LightWrapper, Role, Thermostat, ThermostatMode,
+};
+
+/// A light as an assistant should list it.
+pub(crate) struct DeviceSync {
+    pub(crate) id: String,
+    pub(crate) name: String,
+    pub(crate) room_hint: Option<String>,
+    pub(crate) structure: Option<String>,
+    pub(crate) supports_color: bool,
+    pub(crate) temperatures: RangeInclusive<u32>,
+    pub(crate) kind: DeviceKind,
+}
+
+/// The current state of a light.
+pub(crate) struct DeviceQuery {
+    pub(crate) id: String,
+    pub(crate) online: bool,
+    pub(crate) on: bool,
+    pub(crate) brightness: Brightness,
+    pub(crate) supports_color: bool,
+    /// `None` when group members disagree on color.
+    pub(crate) color: Option<Color>,
+    /// Only for fans, and `None` until one is set.
+    pub(crate) fan_speed: Option<u8>,
+    /// Only for thermostats, and `None` until one is set.
+    pub(crate) thermostat: Option<Thermostat>,
+}
+
+/// One change an assistant asked for, already translated from its own
+/// representation.
+#[derive(Clone, Copy)]
+pub(crate) enum DeviceCommand {
+    Power(bool),
+    Brightness(Brightness),
+    Color(Color),
+    /// In percent of a fan's fastest speed.
+    FanSpeed(u8),
+    /// In degrees Celsius.
+    Setpoint(f32),
+    Mode(ThermostatMode),
+}
+
+/// Every light that isn't hidden, with any name and room it was given.
+pub(crate) fn sync(app: &App) -> Vec<DeviceSync> {
+    app.lights()
+        .filter_map(|light| {
+            let (name, room_hint) = match light.light().role() {
+                Role::Device => (
+                    light.name(),
+                    app.registry.get(&light.id()).and_then(|device| device.room),
+                ),
+                Role::Hidden => return None,
+                Role::Named { name, room_hint } => (name, room_hint),
+            };
+            Some(DeviceSync {
+                id: light.id(),
+                name,
+                room_hint,
+                structure: structure(app, light),
+                supports_color: light.light().supports_color(),
+                temperatures: temperatures(light.light()),
+                kind: light.light().kind(),
+            })
+        })
+        .collect()
+}
+
+/// The structure a device was assigned to, or the one all members of a group
+/// are in.
+pub(crate) fn structure(app: &App, light: &LightWrapper) -> Option<String> {
+    match light.light().members() {
+        None => app
+            .registry
+            .get(&light.id())
+            .and_then(|device| device.structure),
+        Some(members) => {
+            let mut structures = members
+                .iter()
+                .map(|id| app.registry.get(id).and_then(|device| device.structure));
+            let first = structures.next()??;
+            if structures.all(|structure| structure.as_ref() == Some(&first)) {
+                Some(first)
+            } else {
+                None
+            }
+        }
+    }
+}
+
+/// Whether a light can be seen from `structure`, where `None` sees all of
+/// them, leaving out devices that aren't exposed.
+pub(crate) fn visible(app: &App, id: &str, structure: Option<&str>) -> bool {
+    let exposed = app.registry.get(id).map_or(true, |device| device.exposed);
+    if !exposed {
+        return false;
+    }
+    match structure {
+        None => true,
+        Some(structure) => app
+            .light(id)
+            .and_then(|light| self::structure(app, light))
+            .map_or(false, |own| own == structure),
+    }
+}
+
+pub(crate) fn query_device(app: &App, light: &LightWrapper) -> DeviceQuery {
+    let state = app.state(light);
+    let appliance = *light.appliance.lock().unwrap();
+    DeviceQuery {
+        id: light.id(),
+        online: light.online(),
+        on: state.on,
+        brightness: state.brightness.into(),
+        supports_color: light.light().supports_color(),
+        color: state.color,
+        fan_speed: appliance.fan_speed,
+        thermostat: appliance.thermostat,
+    }
+}
+
+/// The states of those of `ids` that exist.
+pub(crate) fn query(app: &App, ids: &[String]) -> Vec<DeviceQuery> {
+    app.lights()
+        .filter(|light| ids.contains(&light.id()))
+        .map(|light| query_device(app, light))
+        .collect()
+}
+
+/// Whether a light is still reachable after a command failed with `error`.
+pub(crate) fn reachable(error: &Error) -> bool {
+    !matches!(
+        error,
+        Error::Light(LightError::Offline) | Error::Light(LightError::TimedOut)
+    )
+}
+
+pub(crate) async fn execute(
+    app: &App,
+    id: &str,
+    commands: &[DeviceCommand],
+    origin: Origin,
+) -> Result<(), Error> {
+    app.permit(id, origin)?;
+    for command in commands {
+        match *command {
+            DeviceCommand::Power(on) => app.set_state(id, on.into()).await?,
+            // Power on first so that power-on defaults don't override the
+            // requested brightness or color.
+            DeviceCommand::Brightness(brightness) => {
+                app.set_state(id, true.into()).await?;
+                app.set_brightness(id, brightness.into()).await?;
+            }
+            DeviceCommand::Color(color) => {
+                app.set_state(id, true.into()).await?;
+                app.set_color(id, color).await?;
+            }
+            DeviceCommand::FanSpeed(percent) => {
+                app.set_state(id, true.into()).await?;
+                app.set_fan_speed(id, percent).await?;
+            }
+            DeviceCommand::Setpoint(celsius) => app.set_thermostat(id, None, Some(celsius)).await?,
+            DeviceCommand::Mode(mode) => app.set_thermostat(id, Some(mode), None).await?,
+        }
+    }
+    app.attribute(id, &origin.into());
+    Ok(())
+}
+
+/// Runs commands in the background, waiting for them for at most `budget`.
+/// If they take longer, `None` is returned and `late` is given the light's
+/// state once they finish.
+pub(crate) async fn execute_within<F, Fut>(
+    shared: &Arc<RwLock<App>>,
+    app: &App,
+    id: &str,
+    commands: Vec<DeviceCommand>,
+    origin: Origin,
+    budget: Duration,
+    late: F,
+) -> Option<Result<(), Error>>
+where
+    F: FnOnce(DeviceQuery) -> Fut + Send + 'static,
+    Fut: Future<Output = ()> + Send,
+{
+    let (sender, receiver) = oneshot::channel();
+    app.spawner.spawn(Box::pin({
+        let shared = shared.clone();
+        let id = id.to_owned();
+        async move {
+            let result = execute(&*shared.read().await, &id, &commands, origin).await;
+            let _ = sender.send(result);
+        }
+    }));
+    match select(receiver, Timer::after(budget)).await {
+        Either::Left((result, _)) => {
+            Some(result.unwrap_or_else(|canceled| Err(LightError::other(canceled).into())))
+        }
+        Either::Right((_, receiver)) => {
+            let shared = shared.clone();
+            let id = id.to_owned();
+            app.spawner.spawn(Box::pin(async move {
+                match receiver.await {
+                    Ok(Ok(())) => {}
+                    Ok(Err(e)) => eprintln!("pending command for {} failed: {}", id, e),
+                    Err(_) => return,
+                }
+                let state = {
+                    let app = shared.read().await;
+                    app.light(&id).map(|light| query_device(&app, light))
+                };
+                if let Some(state) = state {
+                    late(state).await;
+                }
+            }));
+            None
+        }
+    }
+}
@@ -1,43 +1,219 @@
 use std::{
-    collections::HashMap,
+    collections::{BTreeSet, HashMap, HashSet},
     error::Error as StdError,
+    future::Future,
+    io,
+    ops::RangeInclusive,
     sync::{
-        atomic::{AtomicBool, AtomicU32, AtomicU8, Ordering},
-        Arc,
+        atomic::{AtomicBool, AtomicU32, AtomicU8, AtomicUsize, Ordering},
+        Arc, Mutex,
     },
+    time::{Duration, Instant},
 };
 
+mod aggregate;
+mod ambient;
+pub use ambient::{ambient, AmbientConfig, AmbientSegment};
+mod alert;
+mod appliance;
+pub use aggregate::add_all_lights;
+use appliance::ApplianceState;
+mod auto_off;
+pub use auto_off::auto_off;
+use auto_off::AutoOff;
+mod astro;
+pub use astro::{Location, SolarEvent};
 mod auth;
 pub use auth::auth;
+mod backup;
+mod cluster;
+pub use cluster::{cluster, ClusterConfig};
+mod compile;
+mod composite;
+mod conformance;
+pub use conformance::{selftest, SelftestError};
+mod brightness;
+pub use brightness::Brightness;
+mod dedup;
+use dedup::{Command, Confirmed, DEFAULT_DEDUP_WINDOW};
+mod dimming;
+pub use dimming::DimmingCurve;
+mod dmx;
+pub use dmx::{dmx, DmxConfig, DmxStrip};
 mod fulfill;
-pub use fulfill::fulfill;
+use fulfill::{Budgets, Hooks, Replies};
+mod graphql;
+pub use graphql::graphql;
+mod health;
+mod history;
+mod hue;
+pub use hue::{hue, HueConfig};
+mod intent;
+pub use health::{health, Discovery, Health};
+use history::History;
+#[cfg(feature = "grpc")]
+mod grpc;
+pub use fulfill::{fulfill, fulfill_structure, Execution, Outcome};
+#[cfg(feature = "grpc")]
+pub use grpc::grpc;
+mod limit;
+mod manual;
+use manual::Overrides;
+mod mqtt;
+mod notifications;
+pub use notifications::notifications;
+mod openapi;
+mod openrgb;
+mod palette;
+mod policy;
+mod poll;
+use poll::Polling;
+pub use poll::{poll, PollQuota};
+mod programs;
+use limit::Limiter;
+pub use limit::RateLimit;
+pub use mqtt::{mqtt, MqttConfig};
+pub use openapi::openapi;
+pub use openrgb::{openrgb, Mirror, OpenRgbConfig};
+pub use programs::{ProgramSource, ProgramSourceError, ProgramSync, ProgramSyncConfig};
+mod record;
+mod registry;
+use record::RecordingLight;
+pub use record::{replay, Recorder, ReplayError};
+use registry::{OfflineLight, RegisteredDevice, Registry};
 mod request_sync;
-use futures::future::BoxFuture;
-use request_sync::request_sync;
+mod rules;
+pub use rules::{Action, Announcement, Rule, RulesConfig, Trigger};
+mod scene;
+mod schema;
+mod seasons;
+pub use seasons::{seasons, SeasonalEffect, SeasonsConfig};
+mod sensor;
+use sensor::Presses;
+pub use sensor::{press_button, report_sensor, sensors};
+mod setup;
+mod smartthings;
+mod source;
+use async_io::Timer;
+use futures::{
+    channel::mpsc::{unbounded, UnboundedReceiver, UnboundedSender},
+    future::{select, BoxFuture, Either},
+};
+pub use setup::{credentials, rotate_credentials_key, setup, Config, ConfigError};
+pub use smartthings::smartthings;
+mod spawn;
+mod stats;
+use stats::Stats;
+mod storage;
+mod telemetry;
+mod temporary;
+mod traffic;
+mod transfer;
+mod tunnel;
+mod upload;
+use request_sync::SyncScheduler;
+use serde::{Deserialize, Serialize};
+#[cfg(feature = "smol")]
+pub use spawn::SmolSpawner;
+pub use spawn::Spawner;
+#[cfg(feature = "tokio")]
+pub use spawn::TokioSpawner;
+use telemetry::Telemetry;
+use temporary::Hold;
 use thiserror::Error;
+pub use traffic::{serve_logged, TrafficLog};
+pub use transfer::TransferLimits;
+use transfer::{ProgramTransfer, Transfers};
+pub use tunnel::{tunnel, TunnelConfig, TunnelError};
 mod api;
 pub mod hook;
-pub use api::api;
+pub use api::{api, restore_groups};
+mod ui;
+pub use ui::ui;
+mod vault;
+mod weather;
+pub use vault::{credential, VaultError};
+pub use weather::{weather, WeatherConfig};
 
 mod integrations;
-pub use integrations::broadlink::BroadlinkLight;
-pub use integrations::esp::EspLight;
+pub use integrations::broadlink::{BroadlinkLight, WhiteMode};
+use integrations::broadlink_rm::Blaster;
+pub use integrations::broadlink_rm::{
+    RmBlasterConfig, RmConfig, RmDevice, RmDeviceConfig, RmDeviceKind,
+};
+pub use integrations::deconz::{deconz_pair, DeconzBridge, DeconzConfig, DeconzError, DeconzLight};
+pub use integrations::esp::{EspLight, EspLights};
+pub use integrations::lutron::{ButtonEvent, LutronBridge, LutronConfig, LutronError, LutronLight};
 // pub use integrations::sengled::SengledLight;
-pub use integrations::tuya::{tuya_scan, TuyaLight};
+pub use integrations::tuya::{tuya_rescan, tuya_scan, TuyaLight, TuyaPoller};
+pub use integrations::wiz::{wiz_discover, Pilot, WizLight};
 
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, Serialize, Deserialize)]
 pub enum PowerState {
     On,
     Off,
 }
 
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, PartialEq, Serialize, Deserialize)]
 pub enum Color {
     Rgb { r: u8, g: u8, b: u8 },
     White { temperature: u32 },
 }
 
 impl Color {
+    /// A white given in mireds, the micro reciprocal degrees Zigbee lights
+    /// take temperatures in.
+    pub fn from_mireds(mireds: u32) -> Self {
+        Color::White {
+            temperature: reciprocal(mireds),
+        }
+    }
+    /// The temperature of a white in mireds.
+    pub fn mireds(&self) -> Option<u32> {
+        match self {
+            Color::White { temperature } => Some(reciprocal(*temperature)),
+            Color::Rgb { .. } => None,
+        }
+    }
+    /// Brings a white's temperature into `range`, such as that of a light.
+    pub fn clamped(self, range: &RangeInclusive<u32>) -> Self {
+        match self {
+            Color::White { temperature } => Color::White {
+                temperature: temperature.max(*range.start()).min(*range.end()),
+            },
+            color => color,
+        }
+    }
+    /// Approximates white temperatures as RGB for lights without dedicated
+    /// white channels.
+    pub fn to_rgb(&self) -> (u8, u8, u8) {
+        let temperature = match self {
+            Color::Rgb { r, g, b } => return (*r, *g, *b),
+            Color::White { temperature } => *temperature as f64 / 100.,
+        };
+
+        let red = if temperature > 66. {
+            329.698727466 * (temperature - 60.).powf(-0.1332047592)
+        } else {
+            255.
+        };
+
+        let green = if temperature <= 66. {
+            (99.4708025861 * temperature.ln()) - 161.1195681661
+        } else {
+            288.1221695283 * (temperature - 60.).powf(-0.0755148492)
+        };
+
+        let blue = if temperature >= 65. {
+            255.
+        } else if temperature <= 19. {
+            0.
+        } else {
+            (138.5177312231 * (temperature - 10.).ln()) - 305.0447927307
+        };
+
+        (red as u8, green as u8, blue as u8)
+    }
     pub(crate) fn to_spectrum(&self) -> u32 {
         let (r, g, b) = match self {
             Color::Rgb { r, g, b } => (*r, *g, *b),
@@ -47,6 +223,13 @@ impl Color {
     }
 }
 
+/// Kelvin and mireds are each a million over the other, rounded to the
+/// nearest.
+pub(crate) fn reciprocal(value: u32) -> u32 {
+    let value = value.max(1);
+    (1_000_000 + value / 2) / value
+}
+
 impl From<bool> for PowerState {
     fn from(data: bool) -> Self {
         match data {
@@ -71,7 +254,7 @@ impl AtomicColor {
             red: AtomicU8::new(255),
             green: AtomicU8::new(255),
             blue: AtomicU8::new(255),
-            temperature: AtomicU32::new(65000),
+            temperature: AtomicU32::new(DEFAULT_TEMPERATURE),
         }
     }
     fn store(&self, color: Color, ordering: Ordering) {
@@ -106,27 +289,206 @@ impl AtomicColor {
 pub trait Light {
     fn name(&self) -> String;
 
-    fn unique_id<'a>(&'a self) -> BoxFuture<'a, Result<String, Box<dyn StdError + Send>>>;
+    fn vendor(&self) -> &'static str;
 
-    fn set_power_state<'a>(
-        &'a self,
-        state: PowerState,
-    ) -> BoxFuture<'a, Result<(), Box<dyn StdError + Send>>>;
+    fn unique_id<'a>(&'a self) -> BoxFuture<'a, Result<String, LightError>>;
+
+    /// Ids earlier versions gave the device. If one of them is registered,
+    /// the device keeps being addressed by it, so Google doesn't see it as
+    /// a new device after the id format changes.
+    fn legacy_ids(&self) -> Vec<String> {
+        vec![]
+    }
+
+    fn set_power_state<'a>(&'a self, state: PowerState) -> BoxFuture<'a, Result<(), LightError>>;
 
     fn set_brightness<'a>(
         &'a self,
-        brightness: u8,
-    ) -> BoxFuture<'a, Result<(), Box<dyn StdError + Send>>>;
+        brightness: Brightness,
+    ) -> BoxFuture<'a, Result<(), LightError>>;
+
+    fn set_color<'a>(&'a self, color: Color) -> BoxFuture<'a, Result<(), LightError>>;
+
+    /// Ids of the lights this one controls, if it is a group.
+    fn members(&self) -> Option<Vec<String>> {
+        None
+    }
+
+    fn role(&self) -> Role {
+        Role::Device
+    }
+
+    /// Whether the device has been found since startup.
+    fn online(&self) -> bool {
+        true
+    }
+
+    /// Whether [`Light::set_color`] has any effect, which decides if the
+    /// light is synced with the color setting trait.
+    fn supports_color(&self) -> bool {
+        true
+    }
+
+    /// White temperatures in Kelvin the light can show. Requests outside
+    /// of it are clamped before they reach the light.
+    fn color_temperature_range(&self) -> RangeInclusive<u32> {
+        DEFAULT_TEMPERATURES
+    }
+
+    /// Applies the layout of an addressable LED strip, refusing it with a
+    /// reason if the light isn't one or the layout is invalid.
+    fn configure_strip(&self, _: &lights_api::StripConfig) -> Result<(), String> {
+        Err("not an LED strip".to_owned())
+    }
+
+    /// Runs a compiled program on the light, for strips with programmable
+    /// firmware.
+    fn upload_program<'a>(&'a self, _: &'a [u8]) -> BoxFuture<'a, Result<(), Error>> {
+        Box::pin(async { Err(Error::Unsupported) })
+    }
+
+    /// Shows a frame of raw channel levels, laid out as the strip expects.
+    fn write_frame<'a>(&'a self, _: &'a [u8]) -> BoxFuture<'a, Result<(), Error>> {
+        Box::pin(async { Err(Error::Unsupported) })
+    }
+
+    /// What the device is, which decides the Google type and traits it is
+    /// synced with and the commands it is sent.
+    fn kind(&self) -> DeviceKind {
+        DeviceKind::Light
+    }
+
+    /// Sets a fan's speed, in percent of its fastest. Only called on
+    /// [`DeviceKind::Fan`] devices.
+    fn set_fan_speed<'a>(&'a self, _: u8) -> BoxFuture<'a, Result<(), LightError>> {
+        Box::pin(async { Err(LightError::Protocol("not a fan".to_owned())) })
+    }
 
-    fn set_color<'a>(&'a self, color: Color)
-        -> BoxFuture<'a, Result<(), Box<dyn StdError + Send>>>;
+    /// Sets a thermostat's mode and the temperature it holds. Only called on
+    /// [`DeviceKind::Thermostat`] devices.
+    fn set_thermostat<'a>(&'a self, _: Thermostat) -> BoxFuture<'a, Result<(), LightError>> {
+        Box::pin(async { Err(LightError::Protocol("not a thermostat".to_owned())) })
+    }
+}
+
+/// What a device is to assistants. Most are lights.
+#[derive(Clone, PartialEq)]
+pub enum DeviceKind {
+    Light,
+    /// Only switched on and off, such as a TV driven by an IR blaster.
+    Switch,
+    /// Switched on and off, with a speed in percent.
+    Fan,
+    /// Holds a temperature, such as an air conditioner driven by an IR
+    /// blaster.
+    Thermostat {
+        modes: Vec<ThermostatMode>,
+        /// Setpoints the device accepts, in degrees Celsius.
+        range: (f32, f32),
+    },
+}
+
+/// Named as Google names them.
+#[derive(Clone, Copy, PartialEq, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ThermostatMode {
+    Off,
+    Heat,
+    Cool,
+    HeatCool,
+    Auto,
+    #[serde(rename = "fan-only")]
+    FanOnly,
+    Dry,
+    Eco,
 }
 
+/// What a thermostat is set to.
+#[derive(Clone, Copy, PartialEq, Debug, Serialize, Deserialize)]
+pub struct Thermostat {
+    pub mode: ThermostatMode,
+    pub celsius: f32,
+}
+
+/// How a light is presented to Google during SYNC.
+pub enum Role {
+    /// A regular device, named after [`Light::name`].
+    Device,
+    /// Left out of SYNC and only reachable through the API.
+    Hidden,
+    Named {
+        name: String,
+        room_hint: Option<String>,
+    },
+}
+
+pub(crate) const DEFAULT_TEMPERATURES: RangeInclusive<u32> = 2000..=7500;
+/// The white lights are assumed to show until they're set or report a color.
+pub(crate) const DEFAULT_TEMPERATURE: u32 = 4000;
+
+/// The temperatures a light can show, or the default ones if it claims a
+/// range no bulb has, such as one that's empty or starts below 1000K.
+pub(crate) fn temperatures(light: &dyn Light) -> RangeInclusive<u32> {
+    let range = light.color_temperature_range();
+    if range.is_empty() || *range.start() < 1000 {
+        DEFAULT_TEMPERATURES
+    } else {
+        range
+    }
+}
+
+// Cloud APIs that throttle aggressively when a group command fans out.
+const CLOUD_VENDORS: &[&str] = &["tuya", "sengled"];
+const CLOUD_RATE_LIMIT: RateLimit = RateLimit {
+    concurrency: 2,
+    interval: Duration::from_millis(100),
+};
+
+const DEFAULT_COMMAND_TIMEOUT: Duration = Duration::from_secs(10);
+/// Largest program accepted for an ESP strip, beyond what fits in its flash.
+const MAX_PROGRAM_BYTES: usize = 1 << 20;
+/// Largest raw frame, five channels for each pixel of the longest strip.
+const MAX_FRAME_BYTES: usize = u16::MAX as usize * 5;
+
 #[derive(Hash, PartialEq, Eq, Clone)]
 struct Id(String);
 
 pub struct App {
     by_id: HashMap<Id, Arc<LightWrapper>>,
+    sync: SyncScheduler,
+    subscribers: Mutex<Vec<UnboundedSender<String>>>,
+    recorder: Option<Arc<Recorder>>,
+    limiters: HashMap<String, Limiter>,
+    timeouts: HashMap<String, Duration>,
+    default_timeout: Duration,
+    dimming: HashMap<String, DimmingCurve>,
+    default_dimming: DimmingCurve,
+    dedup: HashMap<String, Duration>,
+    default_dedup: Duration,
+    location: Option<Location>,
+    spawner: Arc<dyn Spawner>,
+    health: Arc<Health>,
+    registry: Arc<Registry>,
+    /// Ids of every device, leaving out groups and composites.
+    devices: Arc<Mutex<BTreeSet<String>>>,
+    hooks: Hooks,
+    budgets: Budgets,
+    replies: Replies,
+    strict_payloads: bool,
+    policies: Mutex<HashMap<String, lights_api::Policy>>,
+    polling: Arc<Mutex<Polling>>,
+    transfers: Arc<Transfers>,
+    auto_off: Mutex<AutoOff>,
+    overrides: Mutex<Overrides>,
+    rules: Vec<Rule>,
+    /// The last reading of each sensor since startup.
+    sensors: Mutex<HashMap<String, lights_api::SensorReading>>,
+    presses: Mutex<Presses>,
+    telemetry: Mutex<Telemetry>,
+    blasters: HashMap<String, Arc<Blaster>>,
+    /// Devices standing in as offline since startup or a reload, which
+    /// haven't been discovered yet by this process.
+    placeholders: HashSet<Id>,
 }
 
 struct LightWrapper {
@@ -135,6 +497,47 @@ struct LightWrapper {
     brightness: AtomicU8,
     is_on: AtomicBool,
     color: AtomicColor,
+    defaults: Mutex<PowerOnDefaults>,
+    /// Bumped on every change the device accepts.
+    revision: AtomicUsize,
+    held: Mutex<Option<Hold>>,
+    history: Mutex<History>,
+    /// Cleared when a command times out or finds the device offline.
+    responsive: AtomicBool,
+    stats: Mutex<Stats>,
+    /// Fan speed and thermostat setting, for devices that aren't lights.
+    appliance: Mutex<ApplianceState>,
+    /// What last changed the light, kept apart from `revision` so that
+    /// recording it doesn't count as a change.
+    source: Mutex<Option<lights_api::Source>>,
+    confirmed: Mutex<Confirmed>,
+}
+
+/// State a device reports having changed to on its own, such as from a
+/// wall switch or the vendor's app. Fields left `None` didn't change.
+#[derive(Clone, Default)]
+pub struct ReportedState {
+    pub on: Option<bool>,
+    /// The device's own level, before any dimming curve is undone.
+    pub brightness: Option<u8>,
+    pub color: Option<Color>,
+}
+
+/// Brightness and color applied when a light comes on from off, or when it
+/// is rediscovered after dropping off the network.
+#[derive(Clone, Copy, Default)]
+pub(crate) struct PowerOnDefaults {
+    pub(crate) brightness: Option<u8>,
+    pub(crate) color: Option<Color>,
+}
+
+/// The effective state of a light, aggregated over members for groups.
+#[derive(Clone, Copy)]
+pub(crate) struct LightState {
+    pub(crate) on: bool,
+    pub(crate) brightness: u8,
+    /// `None` when group members disagree on color.
+    pub(crate) color: Option<Color>,
 }
 
 impl LightWrapper {
@@ -153,45 +556,415 @@ impl LightWrapper {
     fn is_on(&self) -> bool {
         self.is_on.load(Ordering::SeqCst)
     }
-    fn color(&self) -> u32 {
-        self.color.load(Ordering::SeqCst).to_spectrum()
-    }
     fn rgb_color(&self) -> Color {
         self.color.load(Ordering::SeqCst)
     }
+    /// The light's own cached state, ignoring any members.
+    fn own_state(&self) -> LightState {
+        LightState {
+            on: self.is_on(),
+            brightness: self.brightness(),
+            color: Some(self.rgb_color()),
+        }
+    }
+    fn online(&self) -> bool {
+        self.light.online() && self.responsive.load(Ordering::SeqCst)
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum LightError {
+    #[error("device offline")]
+    Offline,
+    #[error("authentication expired")]
+    AuthExpired,
+    #[error("rate limited")]
+    RateLimited,
+    #[error("command timed out")]
+    TimedOut,
+    #[error("protocol error: {0}")]
+    Protocol(String),
+    #[error("{0}")]
+    Other(Box<dyn StdError + Send>),
+}
+
+impl LightError {
+    pub fn other<E: StdError + Send + 'static>(error: E) -> Self {
+        LightError::Other(Box::new(error))
+    }
+}
+
+impl From<io::Error> for LightError {
+    fn from(error: io::Error) -> Self {
+        match error.kind() {
+            io::ErrorKind::ConnectionRefused
+            | io::ErrorKind::ConnectionReset
+            | io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::NotConnected
+            | io::ErrorKind::BrokenPipe
+            | io::ErrorKind::TimedOut => LightError::Offline,
+            _ => LightError::other(error),
+        }
+    }
+}
+
+/// Whether a light only passes commands on to other lights.
+fn forwards(light: &dyn Light) -> bool {
+    light.members().is_some() || light.vendor() == "composite"
 }
 
 #[derive(Debug, Error)]
 pub enum Error {
     #[error("light error: {0}")]
-    Light(#[from] Box<dyn StdError + Send>),
+    Light(#[from] LightError),
     #[error("nonexistent light accessed")]
     Absent,
+    #[error("nothing to undo")]
+    NothingToUndo,
+    #[error("refused by policy: {0}")]
+    Policy(String),
+    #[error("invalid configuration: {0}")]
+    InvalidConfig(String),
+    #[error("this instance is on standby")]
+    Standby,
+    #[error("not supported by this light")]
+    Unsupported,
+    #[error("invalid payload: {0}")]
+    Payload(String),
+}
+
+impl From<Error> for LightError {
+    fn from(error: Error) -> Self {
+        match error {
+            Error::Light(error) => error,
+            error => LightError::other(error),
+        }
+    }
 }
 
 impl App {
+    #[cfg(feature = "smol")]
     pub fn new() -> App {
+        App::with_spawner(SmolSpawner)
+    }
+    pub fn with_spawner<S: Spawner + 'static>(spawner: S) -> App {
+        let health = Arc::new(Health::default());
         App {
             by_id: HashMap::new(),
+            sync: SyncScheduler::new(&spawner, health.clone()),
+            subscribers: Mutex::new(vec![]),
+            recorder: None,
+            limiters: CLOUD_VENDORS
+                .iter()
+                .map(|vendor| ((*vendor).to_owned(), Limiter::new(CLOUD_RATE_LIMIT)))
+                .collect(),
+            timeouts: HashMap::new(),
+            default_timeout: DEFAULT_COMMAND_TIMEOUT,
+            dimming: HashMap::new(),
+            default_dimming: DimmingCurve::default(),
+            dedup: HashMap::new(),
+            default_dedup: DEFAULT_DEDUP_WINDOW,
+            location: None,
+            spawner: Arc::new(spawner),
+            health,
+            registry: Arc::new(Registry::default()),
+            devices: Arc::new(Mutex::new(BTreeSet::new())),
+            hooks: Hooks::default(),
+            budgets: Budgets::default(),
+            replies: Replies::default(),
+            strict_payloads: false,
+            policies: Mutex::new(HashMap::new()),
+            polling: Arc::new(Mutex::new(Polling::default())),
+            transfers: Arc::new(Transfers::default()),
+            auto_off: Mutex::new(AutoOff::default()),
+            overrides: Mutex::new(Overrides::default()),
+            rules: vec![],
+            sensors: Mutex::new(HashMap::new()),
+            presses: Mutex::new(Presses::default()),
+            telemetry: Mutex::new(Telemetry::default()),
+            blasters: HashMap::new(),
+            placeholders: HashSet::new(),
         }
     }
-    pub async fn push_light<T: Light + Sync + Send + 'static>(&mut self, light: T) {
-        if let Ok(id) = light.unique_id().await {
-            let id = Id(id);
-            let light = Arc::new(LightWrapper {
-                id: id.clone(),
-                light: Box::new(light),
+    /// Adds every device seen by earlier runs as offline until its
+    /// integration finds it again, so a SYNC during startup doesn't drop it.
+    pub fn restore_devices(&mut self) {
+        self.registry = Arc::new(Registry::load());
+        for (id, device) in self.registry.devices() {
+            if !self.by_id.contains_key(&Id(id.clone())) {
+                self.insert_placeholder(id, &device);
+            }
+        }
+    }
+    fn insert_placeholder(&mut self, id: String, device: &RegisteredDevice) {
+        self.insert(
+            Id(id.clone()),
+            Box::new(OfflineLight::new(id.clone(), device)),
+        );
+        self.placeholders.insert(Id(id));
+    }
+    /// Ends the startup phase, letting queued sync requests reach Google.
+    pub fn finish_startup(&self) {
+        self.sync.release();
+    }
+    fn remember(&self, id: &str, light: &dyn Light) {
+        if light.online() && light.members().is_none() {
+            self.registry.remember(id, light);
+        }
+    }
+    /// Assigns the room Google is told a device is in.
+    pub(crate) fn set_room(&self, id: &str, room: Option<String>) -> Result<(), Error> {
+        self.registry.set_room(id, room)?;
+        self.sync.schedule();
+        Ok(())
+    }
+    /// Assigns the structure a device is in, which scopes what Google
+    /// accounts linked to that structure are told about.
+    pub(crate) fn set_structure(&self, id: &str, structure: Option<String>) -> Result<(), Error> {
+        self.registry.set_structure(id, structure)?;
+        self.sync.schedule();
+        Ok(())
+    }
+    /// Lists a device to Google or leaves it out.
+    pub(crate) fn set_exposed(&self, id: &str, exposed: bool) -> Result<(), Error> {
+        self.registry.set_exposed(id, exposed)?;
+        self.sync.schedule();
+        Ok(())
+    }
+    /// Lays out an LED strip, remembering the layout for when it reconnects.
+    pub(crate) fn set_strip(&self, id: &str, strip: lights_api::StripConfig) -> Result<(), Error> {
+        let wrapper = self.light(id).ok_or(Error::Absent)?;
+        if !wrapper.light().online() {
+            return Err(Error::Light(LightError::Offline));
+        }
+        wrapper
+            .light()
+            .configure_strip(&strip)
+            .map_err(Error::InvalidConfig)?;
+        self.registry.set_strip(id, strip)?;
+        // White channels can change the temperatures Google is told about.
+        self.remember(id, wrapper.light());
+        self.sync.schedule();
+        Ok(())
+    }
+    /// Runs a compiled program on an ESP strip.
+    pub(crate) async fn upload_program(&self, id: &str, program: &[u8]) -> Result<(), Error> {
+        self.program_transfer(id, program)?.send(program).await
+    }
+    /// Checks that a program can be sent to a strip, for sending once the
+    /// app is released, as transfers can wait minutes for their turn.
+    pub(crate) fn program_transfer(
+        &self,
+        id: &str,
+        program: &[u8],
+    ) -> Result<ProgramTransfer, Error> {
+        if program.len() > MAX_PROGRAM_BYTES {
+            return Err(Error::Payload(format!(
+                "program of {} bytes, over the limit of {}",
+                program.len(),
+                MAX_PROGRAM_BYTES
+            )));
+        }
+        let wrapper = self.by_id.get(&Id(id.into())).ok_or(Error::Absent)?;
+        if !wrapper.light().online() {
+            return Err(Error::Light(LightError::Offline));
+        }
+        Ok(ProgramTransfer {
+            wrapper: wrapper.clone(),
+            transfers: self.transfers.clone(),
+        })
+    }
+    /// Shows a frame of raw channel levels on a strip.
+    pub(crate) async fn write_frame(&self, id: &str, frame: &[u8]) -> Result<(), Error> {
+        if frame.len() > MAX_FRAME_BYTES {
+            return Err(Error::Payload(format!(
+                "frame of {} bytes, over the limit of {}",
+                frame.len(),
+                MAX_FRAME_BYTES
+            )));
+        }
+        let wrapper = self.light(id).ok_or(Error::Absent)?;
+        if !wrapper.light().online() {
+            return Err(Error::Light(LightError::Offline));
+        }
+        wrapper.light().write_frame(frame).await
+    }
+    /// Drops a device from the registry, and from the bridge if it hasn't
+    /// been rediscovered since startup.
+    pub(crate) fn forget(&mut self, id: &str) -> Result<(), Error> {
+        self.registry.forget(id)?;
+        let key = Id(id.to_owned());
+        if self
+            .by_id
+            .get(&key)
+            .map_or(false, |known| !known.light().online())
+        {
+            self.remove(id);
+        }
+        Ok(())
+    }
+    pub(crate) fn remove(&mut self, id: &str) {
+        self.by_id.remove(&Id(id.to_owned()));
+        self.devices.lock().unwrap().remove(id);
+        self.sync.schedule();
+    }
+    pub fn health(&self) -> Arc<Health> {
+        self.health.clone()
+    }
+    pub fn set_location(&mut self, location: Location) {
+        self.location = Some(location);
+    }
+    pub fn location(&self) -> Option<Location> {
+        self.location
+    }
+    pub fn set_rate_limit<T: Into<String>>(&mut self, vendor: T, limit: RateLimit) {
+        self.limiters.insert(vendor.into(), Limiter::new(limit));
+    }
+    /// How long a command may take before the device is treated as offline.
+    pub fn set_default_timeout(&mut self, timeout: Duration) {
+        self.default_timeout = timeout;
+    }
+    pub fn set_timeout<T: Into<String>>(&mut self, vendor: T, timeout: Duration) {
+        self.timeouts.insert(vendor.into(), timeout);
+    }
+    pub fn set_default_dimming_curve(&mut self, curve: DimmingCurve) {
+        self.default_dimming = curve;
+    }
+    pub fn set_dimming_curve<T: Into<String>>(&mut self, vendor: T, curve: DimmingCurve) {
+        self.dimming.insert(vendor.into(), curve);
+    }
+    /// The curve brightness goes through on its way to `light`. Lights that
+    /// pass commands on to others leave it to them.
+    pub(crate) fn dimming_curve(&self, light: &dyn Light) -> DimmingCurve {
+        if forwards(light) {
+            return DimmingCurve::Linear;
+        }
+        *self
+            .dimming
+            .get(light.vendor())
+            .unwrap_or(&self.default_dimming)
+    }
+    /// Fails on a standby instance, where only the active one may control
+    /// devices or change what's stored.
+    pub(crate) fn check_active(&self) -> Result<(), Error> {
+        if self.health.standby() {
+            return Err(Error::Standby);
+        }
+        Ok(())
+    }
+    async fn dispatch<F: Future<Output = Result<(), LightError>>>(
+        &self,
+        wrapper: &LightWrapper,
+        command: F,
+    ) -> Result<(), Error> {
+        self.check_active()?;
+        let vendor = wrapper.light().vendor();
+        let timeout = *self.timeouts.get(vendor).unwrap_or(&self.default_timeout);
+        // Dropping the command on expiry releases whatever connection it
+        // was blocked on. Time spent waiting on the limiter isn't the
+        // device's, so latency is measured from here.
+        let command = async move {
+            let started = Instant::now();
+            let result = match select(Box::pin(command), Timer::after(timeout)).await {
+                Either::Left((result, _)) => result,
+                Either::Right(_) => Err(LightError::TimedOut),
+            };
+            (result, started.elapsed())
+        };
+        let (result, latency) = match self.limiters.get(vendor) {
+            Some(limiter) => limiter.run(command).await,
+            None => command.await,
+        };
+        wrapper.stats.lock().unwrap().record(latency, &result);
+        wrapper.responsive.store(
+            !matches!(result, Err(LightError::TimedOut) | Err(LightError::Offline)),
+            Ordering::SeqCst,
+        );
+        result?;
+        Ok(())
+    }
+    pub fn set_recorder(&mut self, recorder: Recorder) {
+        self.recorder = Some(Arc::new(recorder));
+    }
+    fn insert(&mut self, id: Id, light: Box<dyn Light + Sync + Send>) {
+        self.placeholders.remove(&id);
+        if !forwards(light.as_ref()) {
+            self.devices.lock().unwrap().insert(id.0.clone());
+        }
+        // Groups and composites pass commands on to lights that are recorded
+        // themselves, so recording them too would replay everything twice.
+        let light = match &self.recorder {
+            Some(recorder) if !forwards(light.as_ref()) => {
+                Box::new(RecordingLight::new(light, recorder.clone(), id.0.clone()))
+            }
+            _ => light,
+        };
+        let defaults = self
+            .by_id
+            .get(&id)
+            .map(|previous| *previous.defaults.lock().unwrap())
+            .unwrap_or_default();
+        self.by_id.insert(
+            id.clone(),
+            Arc::new(LightWrapper {
+                id,
+                light,
                 brightness: AtomicU8::new(0),
                 color: AtomicColor::new(),
                 is_on: AtomicBool::new(false),
-            });
-            self.by_id.insert(id, light.clone());
-            smol::spawn(async move {
-                if let Err(e) = request_sync().await {
-                    eprintln!("sync request failed: {:?}", e);
+                defaults: Mutex::new(defaults),
+                revision: AtomicUsize::new(0),
+                held: Mutex::new(None),
+                history: Mutex::new(History::default()),
+                responsive: AtomicBool::new(true),
+                stats: Mutex::new(Stats::default()),
+                appliance: Mutex::new(ApplianceState::default()),
+                source: Mutex::new(None),
+                confirmed: Mutex::new(Confirmed::default()),
+            }),
+        );
+    }
+    /// The id a device is addressed by, which stays the one it was first
+    /// registered under when its integration moves to a new id format.
+    fn public_id(&self, light: &dyn Light, id: String) -> String {
+        if let Some(alias) = self.registry.alias(&id) {
+            return alias;
+        }
+        if self.registry.get(&id).is_some() {
+            return id;
+        }
+        match light
+            .legacy_ids()
+            .into_iter()
+            .find(|legacy| self.registry.get(legacy).is_some())
+        {
+            Some(legacy) => {
+                self.registry.add_alias(&id, &legacy);
+                legacy
+            }
+            None => id,
+        }
+    }
+    pub async fn push_light<T: Light + Sync + Send + 'static>(&mut self, light: T) {
+        if let Ok(id) = light.unique_id().await {
+            let id = self.public_id(&light, id);
+            // Only a device this process had found before, not one restored
+            // from the registry, is coming back.
+            let reconnected = self.by_id.contains_key(&Id(id.clone()))
+                && !self.placeholders.contains(&Id(id.clone()));
+            if let Some(strip) = self.registry.get(&id).and_then(|device| device.strip) {
+                if let Err(e) = light.configure_strip(&strip) {
+                    eprintln!("failed to restore strip layout of {}: {}", id, e);
                 }
-            })
-            .detach();
+            }
+            self.remember(&id, &light);
+            self.insert(Id(id.clone()), Box::new(light));
+            if reconnected {
+                if let Err(e) = self.apply_defaults(&id).await {
+                    eprintln!("failed to restore defaults for {}: {:?}", id, e);
+                }
+            }
+            self.sync.schedule();
         }
     }
     pub async fn push_lights<I: IntoIterator<Item = T>, T: Light + Sync + Send + 'static>(
@@ -200,49 +973,169 @@ impl App {
     ) {
         for light in lights {
             if let Ok(id) = light.unique_id().await {
-                let id = Id(id);
-                let light = Arc::new(LightWrapper {
-                    id: id.clone(),
-                    brightness: AtomicU8::new(0),
-                    is_on: AtomicBool::new(false),
-                    color: AtomicColor::new(),
-                    light: Box::new(light),
-                });
-                self.by_id.insert(id, light.clone());
+                let id = self.public_id(&light, id);
+                self.remember(&id, &light);
+                self.insert(Id(id), Box::new(light));
             }
         }
-        smol::spawn(async move {
-            if let Err(e) = request_sync().await {
-                eprintln!("sync request failed: {:?}", e);
+        self.sync.schedule();
+    }
+    pub fn force_sync(&self) {
+        self.sync.force();
+    }
+    pub fn subscribe(&self) -> UnboundedReceiver<String> {
+        let (sender, receiver) = unbounded();
+        self.subscribers.lock().unwrap().push(sender);
+        receiver
+    }
+    /// Updates the cached state of a light that changed outside the bridge.
+    pub fn report_state(&self, id: &str, state: ReportedState) -> Result<(), Error> {
+        let wrapper = self.by_id.get(&Id(id.into())).ok_or(Error::Absent)?;
+        if let Some(on) = state.on {
+            let was_on = wrapper.is_on.swap(on, Ordering::SeqCst);
+            if !on {
+                self.disarm_auto_off(id);
+            } else if !was_on {
+                self.arm_auto_off(id);
             }
-        })
-        .detach();
+        }
+        if let Some(level) = state.brightness {
+            let brightness = self.dimming_curve(wrapper.light()).invert(level);
+            wrapper.brightness.store(brightness, Ordering::SeqCst);
+        }
+        if let Some(color) = state.color {
+            let color = color.clamped(&temperatures(wrapper.light()));
+            wrapper.color.store(color, Ordering::SeqCst);
+        }
+        *wrapper.source.lock().unwrap() = Some(lights_api::Source::Device);
+        self.note_change(id, &lights_api::Source::Device);
+        wrapper.revision.fetch_add(1, Ordering::SeqCst);
+        self.notify(&wrapper.id);
+        Ok(())
+    }
+    fn notify(&self, id: &Id) {
+        self.subscribers
+            .lock()
+            .unwrap()
+            .retain(|subscriber| subscriber.unbounded_send(id.0.clone()).is_ok());
     }
     fn lights(&self) -> impl ExactSizeIterator<Item = &LightWrapper> {
         self.by_id.values().map(|light| light.as_ref())
     }
+    fn light(&self, id: &str) -> Option<&LightWrapper> {
+        self.by_id.get(&Id(id.into())).map(|light| light.as_ref())
+    }
+    fn state(&self, light: &LightWrapper) -> LightState {
+        let own = light.own_state();
+        let members = match light.light().members() {
+            Some(members) => members,
+            None => return own,
+        };
+        let members = members
+            .iter()
+            .filter_map(|id| self.light(id))
+            .collect::<Vec<_>>();
+        if members.is_empty() {
+            return own;
+        }
+        let color = members[0].rgb_color();
+        LightState {
+            on: members.iter().any(|member| member.is_on()),
+            brightness: (members
+                .iter()
+                .map(|member| member.brightness() as usize)
+                .sum::<usize>()
+                / members.len()) as u8,
+            color: if members.iter().all(|member| member.rgb_color() == color) {
+                Some(color)
+            } else {
+                None
+            },
+        }
+    }
+    fn set_defaults(&self, id: &str, defaults: PowerOnDefaults) -> Result<(), Error> {
+        let wrapper = self.by_id.get(&Id(id.into())).ok_or(Error::Absent)?;
+        *wrapper.defaults.lock().unwrap() = defaults;
+        Ok(())
+    }
+    async fn apply_defaults(&self, id: &str) -> Result<(), Error> {
+        let wrapper = self.by_id.get(&Id(id.into())).ok_or(Error::Absent)?;
+        let defaults = *wrapper.defaults.lock().unwrap();
+        if let Some(brightness) = defaults.brightness {
+            self.set_brightness(id, brightness.into()).await?;
+        }
+        if let Some(color) = defaults.color {
+            self.set_color(id, color).await?;
+        }
+        Ok(())
+    }
+    // The cached state only changes once the device has accepted the
+    // command, so QUERY never reports a state that failed to apply.
     async fn set_state(&self, id: &str, state: PowerState) -> Result<(), Error> {
         let wrapper = self.by_id.get(&Id(id.into())).ok_or(Error::Absent)?;
-        wrapper.is_on.store(
-            match state {
-                PowerState::On => true,
-                PowerState::Off => false,
-            },
-            Ordering::SeqCst,
-        );
-        wrapper.light().set_power_state(state).await?;
+        let on = matches!(state, PowerState::On);
+        if self.duplicate(wrapper, Command::Power, wrapper.is_on() == on) {
+            return Ok(());
+        }
+        let before = wrapper.own_state();
+        self.dispatch(wrapper, wrapper.light().set_power_state(state))
+            .await?;
+        wrapper.confirm(Command::Power);
+        wrapper.history.lock().unwrap().record(before);
+        wrapper.revision.fetch_add(1, Ordering::SeqCst);
+        let was_on = wrapper.is_on.swap(on, Ordering::SeqCst);
+        match state {
+            PowerState::On if !was_on => self.arm_auto_off(id),
+            PowerState::Off => self.disarm_auto_off(id),
+            PowerState::On => {}
+        }
+        self.notify(&wrapper.id);
+        if !was_on && matches!(state, PowerState::On) {
+            self.apply_defaults(id).await?;
+        }
         Ok(())
     }
-    async fn set_brightness(&self, id: &str, brightness: u8) -> Result<(), Error> {
+    async fn set_brightness(&self, id: &str, brightness: Brightness) -> Result<(), Error> {
         let wrapper = self.by_id.get(&Id(id.into())).ok_or(Error::Absent)?;
+        let brightness = u8::from(brightness);
+        if self.duplicate(
+            wrapper,
+            Command::Brightness,
+            wrapper.brightness() == brightness,
+        ) {
+            return Ok(());
+        }
+        let before = wrapper.own_state();
+        match &self.recorder {
+            Some(recorder) if !forwards(wrapper.light()) => {
+                recorder.brightness(wrapper.light().vendor(), id, brightness)
+            }
+            _ => {}
+        }
+        let level = self.dimming_curve(wrapper.light()).apply(brightness);
+        self.dispatch(wrapper, wrapper.light().set_brightness(level.into()))
+            .await?;
+        wrapper.confirm(Command::Brightness);
+        wrapper.history.lock().unwrap().record(before);
         wrapper.brightness.store(brightness, Ordering::SeqCst);
-        wrapper.light().set_brightness(brightness).await?;
+        wrapper.revision.fetch_add(1, Ordering::SeqCst);
+        self.notify(&wrapper.id);
         Ok(())
     }
     async fn set_color(&self, id: &str, color: Color) -> Result<(), Error> {
         let wrapper = self.by_id.get(&Id(id.into())).ok_or(Error::Absent)?;
+        let color = color.clamped(&temperatures(wrapper.light()));
+        if self.duplicate(wrapper, Command::Color, wrapper.rgb_color() == color) {
+            return Ok(());
+        }
+        let before = wrapper.own_state();
+        self.dispatch(wrapper, wrapper.light().set_color(color))
+            .await?;
+        wrapper.confirm(Command::Color);
+        wrapper.history.lock().unwrap().record(before);
         wrapper.color.store(color, Ordering::SeqCst);
-        wrapper.light().set_color(color).await?;
+        wrapper.revision.fetch_add(1, Ordering::SeqCst);
+        self.notify(&wrapper.id);
         Ok(())
     }
 }
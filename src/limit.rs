@@ -0,0 +1,74 @@
+use std::{
+    future::Future,
+    sync::atomic::{AtomicUsize, Ordering},
+    time::{Duration, Instant},
+};
+
+use async_io::Timer;
+use async_lock::{Mutex, Semaphore};
+use serde::Deserialize;
+
+/// Throttling applied to every command sent to lights of a single vendor.
+#[derive(Clone, Copy, Deserialize)]
+pub struct RateLimit {
+    /// Maximum number of commands in flight at once.
+    pub concurrency: usize,
+    /// Minimum spacing between the start of consecutive commands.
+    #[serde(rename = "interval_ms", with = "millis")]
+    pub interval: Duration,
+}
+
+mod millis {
+    use std::time::Duration;
+
+    use serde::{Deserialize, Deserializer};
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Duration, D::Error> {
+        u64::deserialize(deserializer).map(Duration::from_millis)
+    }
+}
+
+pub(crate) struct Limiter {
+    permits: Semaphore,
+    interval: Duration,
+    next: Mutex<Instant>,
+    queued: AtomicUsize,
+}
+
+impl Limiter {
+    pub(crate) fn new(limit: RateLimit) -> Self {
+        Limiter {
+            permits: Semaphore::new(limit.concurrency.max(1)),
+            interval: limit.interval,
+            next: Mutex::new(Instant::now()),
+            queued: AtomicUsize::new(0),
+        }
+    }
+
+    /// Commands currently waiting for or holding a slot.
+    pub(crate) fn queued(&self) -> usize {
+        self.queued.load(Ordering::SeqCst)
+    }
+
+    /// Waits for a free slot, queueing behind earlier commands, then runs
+    /// `command`.
+    pub(crate) async fn run<F: Future>(&self, command: F) -> F::Output {
+        self.queued.fetch_add(1, Ordering::SeqCst);
+        let output = self.throttle(command).await;
+        self.queued.fetch_sub(1, Ordering::SeqCst);
+        output
+    }
+
+    async fn throttle<F: Future>(&self, command: F) -> F::Output {
+        let _permit = self.permits.acquire().await;
+        {
+            let mut next = self.next.lock().await;
+            let now = Instant::now();
+            if *next > now {
+                Timer::at(*next).await;
+            }
+            *next = now.max(*next) + self.interval;
+        }
+        command.await
+    }
+}
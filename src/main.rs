@@ -1,74 +1,321 @@
-use std::{collections::HashMap, convert::Infallible, io::Read, net::IpAddr, sync::Arc};
+use std::{
+    collections::HashMap,
+    convert::Infallible,
+    net::{SocketAddr, ToSocketAddrs},
+    sync::Arc,
+    time::Duration,
+};
 
 use async_compat::Compat;
 use bytes::Bytes;
-use futures::{pin_mut, StreamExt};
+use futures::{channel::oneshot, pin_mut, StreamExt};
 use lights::{
     hook::{hook, HookData},
-    tuya_scan, BroadlinkLight, EspLight,
+    serve_logged, tuya_scan, wiz_discover, BroadlinkLight, Config, DeconzBridge, DeconzConfig,
+    DeconzError, DimmingCurve, Discovery, EspLight, EspLights, LutronBridge, LutronConfig,
+    MqttConfig, OpenRgbConfig, PollQuota, ProgramSync, RateLimit, Recorder, RmConfig, RulesConfig,
+    TrafficLog, TuyaPoller, WhiteMode,
 };
 use lights_broadlink::discover;
 use lights_esp_strip::listen;
 use smol::{
     block_on,
     lock::{Mutex, RwLock},
+    Timer,
 };
-use warp::Filter;
+use warp::{http::StatusCode, path::Tail, Filter};
 
 const AUTH_TOKEN: &'static str = env!("ESP_AUTH_TOKEN");
 
+// Give discovery a head start so replayed commands find their lights.
+const REPLAY_DELAY: Duration = Duration::from_secs(10);
+const MQTT_RETRY: Duration = Duration::from_secs(5);
+const OPENRGB_RETRY: Duration = Duration::from_secs(5);
+// Local discovery has no completion signal, so it gets a fixed window before
+// the first sync request; cloud discovery is waited for up to the timeout the
+// sync scheduler holds requests back for.
+const LOCAL_DISCOVERY: Duration = Duration::from_secs(10);
+const TUYA_POLLS_PER_MINUTE: u32 = 60;
+const WIZ_DISCOVERY_WINDOW: Duration = Duration::from_secs(3);
+// How often pairing is retried while waiting for the deCONZ gateway to be
+// unlocked from Phoscon.
+const DECONZ_PAIR_RETRY: Duration = Duration::from_secs(5);
+
 fn main() {
+    let config_path = std::env::var("LIGHTS_CONFIG").unwrap_or_else(|_| "lights.toml".to_owned());
+    if std::env::args().any(|arg| arg == "--setup") {
+        if let Err(e) = block_on(lights::setup(&config_path)) {
+            eprintln!("setup failed: {}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
+    if std::env::args().any(|arg| arg == "--credentials") {
+        if let Err(e) = block_on(lights::credentials(&config_path)) {
+            eprintln!("failed to update credentials: {}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
+    if std::env::args().any(|arg| arg == "--rotate-credentials-key") {
+        if let Err(e) = lights::rotate_credentials_key(&config_path) {
+            eprintln!("failed to rotate credentials key: {}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
+    let config = match Config::load(&config_path) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("failed to load {}: {}", config_path, e);
+            std::process::exit(1);
+        }
+    };
+    config.export();
+
+    if std::env::args().any(|arg| arg == "--selftest") {
+        if let Err(e) = block_on(lights::selftest()) {
+            eprintln!("selftest failed: {}", e);
+            std::process::exit(1);
+        }
+        println!("selftest passed");
+        return;
+    }
+
     block_on(async move {
-        let app = Arc::new(RwLock::new(lights::App::new()));
+        let mut app = lights::App::new();
+        app.set_strict_payloads(std::env::var("LIGHTS_STRICT_PAYLOADS").is_ok());
+        if let Ok(path) = std::env::var("LIGHTS_RECORD") {
+            app.set_recorder(
+                Recorder::create(path, std::env::var("LIGHTS_DRY_RUN").is_ok()).unwrap(),
+            );
+        }
+        if let Ok(limits) = std::fs::read_to_string("limits.toml") {
+            let limits: HashMap<String, RateLimit> = toml::from_str(&limits).unwrap();
+            for (vendor, limit) in limits {
+                app.set_rate_limit(vendor, limit);
+            }
+        }
+        // Milliseconds by vendor, with `default` applying to the rest.
+        if let Ok(timeouts) = std::fs::read_to_string("timeouts.toml") {
+            let timeouts: HashMap<String, u64> = toml::from_str(&timeouts).unwrap();
+            for (vendor, timeout) in timeouts {
+                let timeout = Duration::from_millis(timeout);
+                match vendor.as_str() {
+                    "default" => app.set_default_timeout(timeout),
+                    _ => app.set_timeout(vendor, timeout),
+                }
+            }
+        }
+        // Milliseconds by vendor within which a command repeating one the
+        // light just accepted is skipped, with `default` applying to the rest.
+        if let Ok(windows) = std::fs::read_to_string("dedup.toml") {
+            let windows: HashMap<String, u64> = toml::from_str(&windows).unwrap();
+            for (vendor, window) in windows {
+                let window = Duration::from_millis(window);
+                match vendor.as_str() {
+                    "default" => app.set_default_dedup_window(window),
+                    _ => app.set_dedup_window(vendor, window),
+                }
+            }
+        }
+        // Milliseconds by EXECUTE command name, with `default` applying to
+        // the rest. Commands without a budget are always waited for.
+        if let Ok(budgets) = std::fs::read_to_string("budgets.toml") {
+            let budgets: HashMap<String, u64> = toml::from_str(&budgets).unwrap();
+            for (command, budget) in budgets {
+                let budget = Duration::from_millis(budget);
+                match command.as_str() {
+                    "default" => app.set_default_execute_budget(budget),
+                    _ => app.set_execute_budget(command, budget),
+                }
+            }
+        }
+        // Curves by vendor, with `default` applying to the rest.
+        if let Ok(curves) = std::fs::read_to_string("dimming.toml") {
+            let curves: HashMap<String, DimmingCurve> = toml::from_str(&curves).unwrap();
+            for (vendor, curve) in curves {
+                match vendor.as_str() {
+                    "default" => app.set_default_dimming_curve(curve),
+                    _ => app.set_dimming_curve(vendor, curve),
+                }
+            }
+        }
+        if let Ok(limits) = std::fs::read_to_string("transfers.toml") {
+            app.set_transfer_limits(toml::from_str(&limits).unwrap());
+        }
+        if let Ok(rules) = std::fs::read_to_string("rules.toml") {
+            let rules: RulesConfig = toml::from_str(&rules).unwrap();
+            app.set_rules(rules.rules);
+        }
+        // Quotas by vendor for devices whose changes have to be read.
+        if let Ok(quotas) = std::fs::read_to_string("polling.toml") {
+            let quotas: HashMap<String, PollQuota> = toml::from_str(&quotas).unwrap();
+            for (vendor, quota) in quotas {
+                app.set_poll_quota(vendor, quota);
+            }
+        }
+        // Seconds between reads of LAN-reachable Tuya bulbs, for setups from
+        // before `polling.toml`.
+        if let Some(interval_secs) = std::env::var("TUYA_POLL")
+            .ok()
+            .and_then(|secs| secs.parse().ok())
+        {
+            app.set_poll_quota(
+                "tuya",
+                PollQuota {
+                    interval_secs,
+                    per_minute: TUYA_POLLS_PER_MINUTE,
+                },
+            );
+        }
+        if let Ok(location) = std::fs::read_to_string("location.toml") {
+            app.set_location(toml::from_str(&location).unwrap());
+        } else if let Some(location) = config.location {
+            app.set_location(location);
+        }
+        app.restore_devices();
+        app.restore_policies();
+        app.restore_override_holds();
+        // Simulated lights for trying the action without hardware, which
+        // `Simulate` requests can change later.
+        if let Some(count) = std::env::var("LIGHTS_SIMULATE")
+            .ok()
+            .and_then(|count| count.parse().ok())
+        {
+            app.simulate(count).await;
+        }
+        if let Ok(config) = std::fs::read_to_string("broadlink.toml") {
+            let config: RmConfig = toml::from_str(&config).unwrap();
+            if let Err(e) = app.add_blasters(config).await {
+                eprintln!("broadlink blasters disabled: {}", e);
+            }
+        }
+        let app = Arc::new(RwLock::new(app));
+        lights::restore_groups(&app).await;
+        lights::add_all_lights(&app, std::env::var("LIGHTS_EXPOSE_ALL").is_ok()).await;
+        let health = app.read().await.health();
+        smol::spawn(lights::poll(app.clone())).detach();
+        smol::spawn(lights::auto_off(app.clone())).detach();
+        if let Ok(config) = std::fs::read_to_string("weather.toml") {
+            smol::spawn(lights::weather(
+                app.clone(),
+                toml::from_str(&config).unwrap(),
+            ))
+            .detach();
+        }
+        if let Ok(config) = std::fs::read_to_string("seasons.toml") {
+            smol::spawn(lights::seasons(
+                app.clone(),
+                toml::from_str(&config).unwrap(),
+            ))
+            .detach();
+        }
+        if let Ok(config) = std::fs::read_to_string("cluster.toml") {
+            smol::spawn(lights::cluster(
+                app.clone(),
+                toml::from_str(&config).unwrap(),
+            ))
+            .detach();
+        }
+
+        if let Some(path) = std::env::args().skip_while(|arg| arg != "--replay").nth(1) {
+            smol::spawn({
+                let app = app.clone();
+                async move {
+                    Timer::after(REPLAY_DELAY).await;
+                    if let Err(e) = lights::replay(path, app).await {
+                        eprintln!("replay failed: {}", e);
+                    }
+                }
+            })
+            .detach();
+        }
+
+        let white_mode = match std::env::var("BROADLINK_WHITE_MODE").as_deref() {
+            Ok("mix") => WhiteMode::Mix,
+            Ok("rgb") => WhiteMode::Rgb,
+            _ => WhiteMode::Kelvin,
+        };
 
         smol::spawn({
             let app = app.clone();
+            let health = health.clone();
             async move {
+                health.report_discovery("broadlink", Discovery::Running);
                 let stream = discover();
                 pin_mut!(stream);
                 while let Some(Ok(light)) = stream.next().await {
                     let mut light = light.connect().await.unwrap();
                     light.set_transition_duration(0).await.unwrap();
                     let mut app = app.write().await;
-                    app.push_light(BroadlinkLight::new(light)).await;
+                    app.push_light(BroadlinkLight::new(light).with_white_mode(white_mode))
+                        .await;
                 }
+                health.report_discovery("broadlink", Discovery::Failed("discovery stopped".into()));
             }
         })
         .detach();
 
-        let esp_lights = Arc::new(Mutex::new(HashMap::new()));
+        let esp_lights = Arc::new(Mutex::new(EspLights::default()));
 
         smol::spawn({
             let app = app.clone();
             let esp_lights = esp_lights.clone();
+            let health = health.clone();
             async move {
+                health.report_discovery("esp", Discovery::Running);
                 let stream = listen(5000);
                 pin_mut!(stream);
                 while let Some(Ok(light)) = stream.next().await {
                     let mut app = app.write().await;
                     let light = Arc::new(EspLight::new(light));
                     app.push_light(light.clone()).await;
-                    esp_lights
-                        .lock()
-                        .await
-                        .insert(light.addr().await.unwrap(), light);
+                    esp_lights.lock().await.insert(light).await;
                 }
+                health.report_discovery("esp", Discovery::Failed("listener stopped".into()));
             }
         })
         .detach();
 
-        let fulfill = warp::path("fulfill").and(warp::body::json()).and_then({
-            let app = app.clone();
-            move |data| {
+        // `/fulfill/<structure>` serves an account linked to one structure.
+        let fulfill = warp::path("fulfill")
+            .and(warp::path::tail())
+            .and(warp::body::bytes())
+            .and_then({
                 let app = app.clone();
-                async move {
-                    Ok::<_, Infallible>(warp::reply::json(
-                        &lights::fulfill(data, &*app.read().await).await,
-                    ))
+                move |tail: Tail, data: Bytes| {
+                    let app = app.clone();
+                    async move {
+                        let structure =
+                            Some(tail.as_str()).filter(|structure| !structure.is_empty());
+                        // Says what was wrong with a request that can't be parsed
+                        // at all, rather than warp's generic rejection.
+                        Ok::<_, Infallible>(match serde_json::from_slice(&data) {
+                            Ok(request) => warp::reply::with_status(
+                                warp::reply::json(
+                                    &lights::fulfill_structure(request, &app, structure).await,
+                                ),
+                                StatusCode::OK,
+                            ),
+                            Err(e) => warp::reply::with_status(
+                                warp::reply::json(&serde_json::json!({ "error": e.to_string() })),
+                                StatusCode::BAD_REQUEST,
+                            ),
+                        })
+                    }
                 }
+            });
+
+        if let Ok(config) = std::fs::read_to_string("programs.toml") {
+            match ProgramSync::new(toml::from_str(&config).unwrap()) {
+                Ok(sync) => smol::spawn(sync.run()).detach(),
+                Err(e) => eprintln!("program sync disabled: {}", e),
             }
-        });
+        }
 
+        // Deprecated for the API's `ProgramUpload`, which keeps the token out
+        // of the path and answers with what went wrong.
         let upload = warp::path!("upload" / String / String)
             .and(warp::body::bytes())
             .and_then({
@@ -80,12 +327,10 @@ fn main() {
                             if token != AUTH_TOKEN {
                                 return Ok::<_, Infallible>(format!(""));
                             }
+                            eprintln!("/upload is deprecated, use the ProgramUpload request");
                             let binary: &[u8] = binary.as_ref();
-                            let addr: Result<IpAddr, _> = id.parse();
-                            if let Ok(addr) = addr {
-                                if let Some(light) = esp_lights.lock().await.get(&addr) {
-                                    light.try_program(&binary).await;
-                                }
+                            if let Some(light) = esp_lights.lock().await.get(&id) {
+                                light.try_program(&binary).await;
                             }
                             Ok::<_, Infallible>(format!(""))
                         }
@@ -93,10 +338,12 @@ fn main() {
                 }
             });
 
-        let run_program = warp::path("run_program")
-            .and(warp::body::json())
-            .and_then(|data: HookData| async move { hook(data).await });
+        let run_program = warp::path("run_program").and(warp::body::json()).and_then({
+            let app = app.clone();
+            move |data: HookData| hook(app.clone(), data)
+        });
 
+        // Deprecated for the API's `RawWrite`.
         let write = warp::path!("write" / String / String)
             .and(warp::body::bytes())
             .and_then({
@@ -106,51 +353,267 @@ fn main() {
                         if token != AUTH_TOKEN {
                             return Ok::<_, Infallible>(format!(""));
                         }
+                        eprintln!("/write is deprecated, use the RawWrite request");
                         let binary: &[u8] = binary.as_ref();
-                        let addr: Result<IpAddr, _> = id.parse();
-                        if let Ok(addr) = addr {
-                            if let Some(light) = esp_lights.lock().await.get(&addr) {
-                                light.try_write(&binary).await;
-                            }
+                        if let Some(light) = esp_lights.lock().await.get(&id) {
+                            light.try_write(&binary).await;
                         }
                         Ok::<_, Infallible>(format!(""))
                     }
                 }
             });
 
+        if let Ok(broker) = std::env::var("MQTT_BROKER") {
+            let config = MqttConfig {
+                broker: broker.to_socket_addrs().unwrap().next().unwrap(),
+                credentials: lights::credential("MQTT_USER").zip(lights::credential("MQTT_PASS")),
+                client_id: std::env::var("MQTT_CLIENT_ID").ok(),
+            };
+            smol::spawn({
+                let app = app.clone();
+                async move {
+                    loop {
+                        if let Err(e) = lights::mqtt(app.clone(), &config).await {
+                            eprintln!("mqtt connection failed: {}", e);
+                        }
+                        Timer::after(MQTT_RETRY).await;
+                    }
+                }
+            })
+            .detach();
+        }
+
+        if let Ok(config) = std::fs::read_to_string("openrgb.toml") {
+            let config: OpenRgbConfig = toml::from_str(&config).unwrap();
+            smol::spawn({
+                let app = app.clone();
+                async move {
+                    loop {
+                        if let Err(e) = lights::openrgb(app.clone(), &config).await {
+                            eprintln!("openrgb connection failed: {}", e);
+                        }
+                        Timer::after(OPENRGB_RETRY).await;
+                    }
+                }
+            })
+            .detach();
+        }
+
         smol::spawn({
             let app = app.clone();
+            let health = health.clone();
             async move {
-                let lights = tuya_scan(
-                    std::env::var("TUYA_USER").unwrap(),
-                    std::env::var("TUYA_PASS").unwrap(),
+                health.report_discovery("wiz", Discovery::Running);
+                match wiz_discover(WIZ_DISCOVERY_WINDOW).await {
+                    Ok(lights) => {
+                        app.write().await.push_lights(lights).await;
+                        health.report_discovery("wiz", Discovery::Complete);
+                    }
+                    Err(e) => {
+                        eprintln!("wiz discovery failed: {}", e);
+                        health.report_discovery("wiz", Discovery::Failed(e.to_string()));
+                    }
+                }
+            }
+        })
+        .detach();
+
+        if let Ok(config) = std::fs::read_to_string("deconz.toml") {
+            let config: DeconzConfig = toml::from_str(&config).unwrap();
+            smol::spawn({
+                let app = app.clone();
+                let health = health.clone();
+                async move {
+                    health.report_discovery("deconz", Discovery::Running);
+                    let result = async {
+                        let bridge = loop {
+                            match DeconzBridge::connect(&config).await {
+                                Err(DeconzError::Locked) => {
+                                    eprintln!("{}", DeconzError::Locked);
+                                    Timer::after(DECONZ_PAIR_RETRY).await;
+                                }
+                                result => break result?,
+                            }
+                        };
+                        let lights = bridge.lights().await?;
+                        app.write().await.push_lights(lights).await;
+                        Ok::<_, DeconzError>(bridge)
+                    }
+                    .await;
+                    match result {
+                        Ok(bridge) => {
+                            health.report_discovery("deconz", Discovery::Complete);
+                            let mut updates = bridge.updates();
+                            smol::spawn({
+                                let app = app.clone();
+                                async move {
+                                    while let Some((id, state)) = updates.next().await {
+                                        let _ = app.read().await.report_state(&id, state);
+                                    }
+                                }
+                            })
+                            .detach();
+                            let mut readings = bridge.sensor_readings();
+                            smol::spawn(async move {
+                                while let Some((id, reading)) = readings.next().await {
+                                    lights::report_sensor(&app, &id, reading).await;
+                                }
+                            })
+                            .detach();
+                            if let Err(e) = bridge.run().await {
+                                eprintln!("deconz event stream failed: {}", e);
+                            }
+                        }
+                        Err(e) => {
+                            eprintln!("deconz discovery failed: {}", e);
+                            health.report_discovery("deconz", Discovery::Failed(e.to_string()));
+                        }
+                    }
+                }
+            })
+            .detach();
+        }
+
+        if let Ok(config) = std::fs::read_to_string("lutron.toml") {
+            let config: LutronConfig = toml::from_str(&config).unwrap();
+            smol::spawn({
+                let app = app.clone();
+                let health = health.clone();
+                async move {
+                    health.report_discovery("lutron", Discovery::Running);
+                    let result = async {
+                        let bridge = LutronBridge::connect(&config).await?;
+                        smol::spawn({
+                            let bridge = bridge.clone();
+                            async move {
+                                if let Err(e) = bridge.run().await {
+                                    eprintln!("lutron connection failed: {}", e);
+                                }
+                            }
+                        })
+                        .detach();
+                        let dimmers = bridge.dimmers().await?;
+                        app.write().await.push_lights(dimmers).await;
+                        bridge.buttons().await
+                    }
+                    .await;
+                    match result {
+                        Ok(mut buttons) => {
+                            health.report_discovery("lutron", Discovery::Complete);
+                            while let Some(event) = buttons.next().await {
+                                let remote = format!("Lutron Remote {}", event.remote);
+                                lights::press_button(&app, &remote, event.button, event.pressed)
+                                    .await;
+                            }
+                        }
+                        Err(e) => {
+                            eprintln!("lutron discovery failed: {}", e);
+                            health.report_discovery("lutron", Discovery::Failed(e.to_string()));
+                        }
+                    }
+                }
+            })
+            .detach();
+        }
+
+        let (tuya_done, tuya_discovered) = oneshot::channel::<()>();
+
+        smol::spawn({
+            let app = app.clone();
+            async move {
+                Timer::after(LOCAL_DISCOVERY).await;
+                // The sender is dropped if the scan fails, which also settles it.
+                let _ = tuya_discovered.await;
+                app.read().await.finish_startup();
+            }
+        })
+        .detach();
+
+        smol::spawn({
+            let app = app.clone();
+            let health = health.clone();
+            async move {
+                health.report_discovery("tuya", Discovery::Running);
+                match tuya_scan(
+                    lights::credential("TUYA_USER").unwrap(),
+                    lights::credential("TUYA_PASS").unwrap(),
                 )
                 .await
-                .unwrap();
-                app.write().await.push_lights(lights).await;
+                .map_err(|e| e.to_string())
+                {
+                    Ok(lights) => {
+                        let poller = TuyaPoller::new(&lights);
+                        app.write().await.push_lights(lights).await;
+                        poller.schedule(&*app.read().await);
+                        health.report_discovery("tuya", Discovery::Complete);
+                        let _ = tuya_done.send(());
+                    }
+                    Err(e) => {
+                        eprintln!("tuya discovery failed: {}", e);
+                        health.report_discovery("tuya", Discovery::Failed(e));
+                    }
+                }
             }
         })
         .detach();
 
-        let server = smol::spawn(Compat::new(
-            warp::serve(
-                lights::api(app.clone())
-                    .or(lights::auth())
-                    .or(fulfill)
-                    .or(upload)
-                    .or(write)
-                    .or(run_program)
-                    .or(warp::path::end().map(|| {
-                        let mut string = String::new();
-                        std::fs::File::open("ui.html")
-                            .unwrap()
-                            .read_to_string(&mut string)
-                            .unwrap();
-                        warp::reply::html(string)
-                    })),
-            )
-            .run(([127, 0, 0, 1], 8080)),
-        ));
+        #[cfg(feature = "grpc")]
+        smol::spawn(Compat::new(
+            tonic::transport::Server::builder()
+                .add_service(lights::grpc(app.clone()))
+                .serve(([127, 0, 0, 1], 50051).into()),
+        ))
+        .detach();
+
+        if let Ok(config) = std::fs::read_to_string("ambient.toml") {
+            smol::spawn(lights::ambient(
+                app.clone(),
+                toml::from_str(&config).unwrap(),
+            ))
+            .detach();
+        }
+        if let Ok(config) = std::fs::read_to_string("dmx.toml") {
+            smol::spawn(lights::dmx(app.clone(), toml::from_str(&config).unwrap())).detach();
+        }
+        if let Ok(config) = std::fs::read_to_string("hue.toml") {
+            smol::spawn(Compat::new(lights::hue(
+                app.clone(),
+                toml::from_str(&config).unwrap(),
+            )))
+            .detach();
+        }
+
+        let routes = lights::api(app.clone())
+            .or(lights::auth(health))
+            .or(fulfill)
+            .or(lights::smartthings(app.clone()))
+            .or(upload)
+            .or(write)
+            .or(run_program)
+            .or(lights::ui(app.clone()))
+            .or(lights::graphql(app.clone()))
+            .or(lights::health(app.clone()))
+            .or(lights::sensors(app.clone()))
+            .or(lights::notifications(app.clone()))
+            .or(lights::openapi());
+        if let Ok(config) = std::fs::read_to_string("tunnel.toml") {
+            smol::spawn(Compat::new(lights::tunnel(
+                warp::service(routes.clone()),
+                toml::from_str(&config).unwrap(),
+            )))
+            .detach();
+        }
+        let addr = SocketAddr::from(([127, 0, 0, 1], 8080));
+        // Logs request and response bodies when set, for debugging rejected
+        // responses.
+        let server = match std::env::var("LIGHTS_TRAFFIC_LOG") {
+            Ok(path) => smol::spawn(Compat::new(serve_logged(
+                warp::service(routes),
+                addr,
+                TrafficLog::open(path).unwrap(),
+            ))),
+            Err(_) => smol::spawn(Compat::new(warp::serve(routes).run(addr))),
+        };
 
         server.await;
     });
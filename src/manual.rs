@@ -0,0 +1,131 @@
+use std::{
+    collections::{HashMap, HashSet},
+    time::{Duration, Instant},
+};
+
+use lights_api::Source;
+
+use crate::{
+    storage::{storage, Store},
+    App, Error, Id,
+};
+
+/// Seconds that rules leave a light alone after someone changes it.
+fn holds() -> Store<u64> {
+    storage().store("override-holds")
+}
+
+/// Whether a change came from someone rather than from an automation.
+fn manual(source: &Source) -> bool {
+    !matches!(
+        source,
+        Source::Rule { .. } | Source::Timer | Source::Season { .. }
+    )
+}
+
+/// The configured hold of each light, until when each light that was
+/// changed by hand is held, and the lights seasonal effects keep from rules.
+#[derive(Default)]
+pub(crate) struct Overrides {
+    hold: HashMap<String, Duration>,
+    until: HashMap<String, Instant>,
+    claimed: HashSet<String>,
+}
+
+impl Overrides {
+    fn held(&self, id: &str) -> bool {
+        self.until
+            .get(id)
+            .map_or(false, |until| *until > Instant::now())
+    }
+}
+
+impl App {
+    /// Loads the override holds saved by earlier runs.
+    pub fn restore_override_holds(&self) {
+        let store = holds();
+        let ids = match store.keys() {
+            Ok(ids) => ids,
+            Err(e) => {
+                eprintln!("failed to list stored override holds: {}", e);
+                return;
+            }
+        };
+        let mut overrides = self.overrides.lock().unwrap();
+        overrides.hold.clear();
+        for id in ids {
+            match store.get(&id) {
+                Ok(Some(secs)) => {
+                    overrides.hold.insert(id, Duration::from_secs(secs));
+                }
+                Ok(None) => {}
+                Err(e) => eprintln!("failed to load override hold for `{}`: {}", id, e),
+            }
+        }
+    }
+    /// Keeps rules from changing a light for `hold` after each change made
+    /// to it by hand, or lets them change it any time if `None`.
+    pub(crate) fn set_override_hold(&self, id: &str, hold: Option<Duration>) -> Result<(), Error> {
+        if !self.by_id.contains_key(&Id(id.into())) {
+            return Err(Error::Absent);
+        }
+        let stored = match hold {
+            Some(hold) => holds().put(id, &hold.as_secs()),
+            None => holds().remove(id),
+        };
+        if let Err(e) = stored {
+            eprintln!("failed to persist override hold for `{}`: {}", id, e);
+        }
+        let mut overrides = self.overrides.lock().unwrap();
+        match hold {
+            Some(hold) => {
+                overrides.hold.insert(id.to_owned(), hold);
+            }
+            None => {
+                overrides.hold.remove(id);
+                overrides.until.remove(id);
+            }
+        }
+        Ok(())
+    }
+    /// Starts or extends the light's hold, if it has one, when `source`
+    /// changed it by hand.
+    pub(crate) fn note_change(&self, id: &str, source: &Source) {
+        if !manual(source) {
+            return;
+        }
+        let mut overrides = self.overrides.lock().unwrap();
+        if let Some(hold) = overrides.hold.get(id).copied() {
+            overrides.until.insert(id.to_owned(), Instant::now() + hold);
+        }
+    }
+    /// Hands a light back to the rules before its hold runs out.
+    pub(crate) fn clear_override(&self, id: &str) -> Result<(), Error> {
+        if !self.by_id.contains_key(&Id(id.into())) {
+            return Err(Error::Absent);
+        }
+        self.overrides.lock().unwrap().until.remove(id);
+        Ok(())
+    }
+    /// Whether rules should leave a light as someone last set it, or to a
+    /// seasonal effect.
+    pub(crate) fn overridden(&self, id: &str) -> bool {
+        let overrides = self.overrides.lock().unwrap();
+        overrides.claimed.contains(id) || overrides.held(id)
+    }
+    /// Whether the light is held after a change by hand, which seasonal
+    /// effects give way to as well.
+    pub(crate) fn held_by_hand(&self, id: &str) -> bool {
+        self.overrides.lock().unwrap().held(id)
+    }
+    /// Keeps rules from changing a light while a seasonal effect shows on
+    /// it, or hands it back to them.
+    pub(crate) fn claim(&self, id: &str, claimed: bool) {
+        let mut overrides = self.overrides.lock().unwrap();
+        if claimed {
+            overrides.claimed.insert(id.to_owned());
+        } else {
+            overrides.claimed.remove(id);
+        }
+    }
+}
@@ -0,0 +1,339 @@
+use std::{
+    io,
+    net::{SocketAddr, TcpStream},
+    sync::Arc,
+    time::Duration,
+};
+
+use async_io::{Async, Timer};
+use async_lock::RwLock;
+use futures::{
+    future::{select, Either},
+    pin_mut, AsyncReadExt, AsyncWriteExt, StreamExt,
+};
+use lights_api::{SensorReading, Source};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use uuid::Uuid;
+
+use crate::{policy::Origin, sensor, App, Color, LightWrapper, PowerState};
+
+const KEEP_ALIVE: u16 = 60;
+const PING_INTERVAL: Duration = Duration::from_secs(30);
+// Brokers close the older of two connections with the same client id.
+const CLIENT_PREFIX: &str = "lights-";
+// Commands and readings are small JSON documents, so anything larger is
+// refused rather than buffered.
+const MAX_PACKET: usize = 64 * 1024;
+const DISCOVERY_PREFIX: &str = "homeassistant";
+
+const CONNECT: u8 = 0x10;
+const CONNACK: u8 = 0x20;
+const PUBLISH: u8 = 0x30;
+const SUBSCRIBE: u8 = 0x82;
+const PINGREQ: u8 = 0xC0;
+
+/// Broker address and optional credentials, usually taken from the
+/// `MQTT_BROKER`, `MQTT_USER` and `MQTT_PASS` environment variables.
+pub struct MqttConfig {
+    pub broker: SocketAddr,
+    pub credentials: Option<(String, String)>,
+    /// `MQTT_CLIENT_ID`, which must differ between instances sharing a
+    /// broker. Made up for each connection if not given.
+    pub client_id: Option<String>,
+}
+
+impl MqttConfig {
+    fn client_id(&self) -> String {
+        self.client_id.clone().unwrap_or_else(|| {
+            let suffix = Uuid::new_v4().to_simple().to_string();
+            format!("{}{}", CLIENT_PREFIX, &suffix[..8])
+        })
+    }
+}
+
+/// Payload accepted on `lights/<id>/set`, following Home Assistant's JSON
+/// light schema.
+#[derive(Deserialize)]
+struct SetCommand {
+    state: Option<String>,
+    brightness: Option<u8>,
+    color: Option<Rgb>,
+    /// Mireds.
+    color_temp: Option<u32>,
+}
+
+#[derive(Deserialize)]
+struct Rgb {
+    r: u8,
+    g: u8,
+    b: u8,
+}
+
+fn string(buf: &mut Vec<u8>, data: &[u8]) {
+    buf.extend_from_slice(&(data.len() as u16).to_be_bytes());
+    buf.extend_from_slice(data);
+}
+
+fn packet(header: u8, body: Vec<u8>) -> Vec<u8> {
+    let mut packet = vec![header];
+    let mut len = body.len();
+    loop {
+        let mut byte = (len % 128) as u8;
+        len /= 128;
+        if len > 0 {
+            byte |= 0x80;
+        }
+        packet.push(byte);
+        if len == 0 {
+            break;
+        }
+    }
+    packet.extend(body);
+    packet
+}
+
+fn publish(topic: &str, payload: &[u8], retain: bool) -> Vec<u8> {
+    let mut body = vec![];
+    string(&mut body, topic.as_bytes());
+    body.extend_from_slice(payload);
+    packet(PUBLISH | retain as u8, body)
+}
+
+async fn read_packet(mut stream: &Async<TcpStream>) -> io::Result<(u8, Vec<u8>)> {
+    let mut header = [0];
+    stream.read_exact(&mut header).await?;
+    let mut len = 0;
+    let mut shift = 0;
+    loop {
+        let mut byte = [0];
+        stream.read_exact(&mut byte).await?;
+        len |= ((byte[0] & 0x7F) as usize) << shift;
+        if byte[0] & 0x80 == 0 {
+            break;
+        }
+        shift += 7;
+        if shift > 21 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "malformed remaining length",
+            ));
+        }
+    }
+    if len > MAX_PACKET {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("packet of {} bytes", len),
+        ));
+    }
+    let mut body = vec![0; len];
+    stream.read_exact(&mut body).await?;
+    Ok((header[0], body))
+}
+
+// Home Assistant only allows `[a-zA-Z0-9_-]` in discovery node ids.
+fn node_id(id: &str) -> String {
+    id.chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect()
+}
+
+fn state_payload(app: &App, light: &LightWrapper) -> Value {
+    let state = app.state(light);
+    let mut payload = json!({
+        "state": if state.on { "ON" } else { "OFF" },
+        "brightness": state.brightness,
+    });
+    match state.color {
+        Some(Color::Rgb { r, g, b }) => {
+            payload["color_mode"] = json!("rgb");
+            payload["color"] = json!({ "r": r, "g": g, "b": b });
+        }
+        Some(color @ Color::White { .. }) => {
+            payload["color_mode"] = json!("color_temp");
+            payload["color_temp"] = json!(color.mireds());
+        }
+        None => {}
+    }
+    payload
+}
+
+fn discovery_payload(light: &LightWrapper) -> Value {
+    let id = light.id();
+    json!({
+        "name": light.name(),
+        "unique_id": node_id(&id),
+        "schema": "json",
+        "state_topic": format!("lights/{}/state", id),
+        "command_topic": format!("lights/{}/set", id),
+        "brightness": true,
+        "supported_color_modes": ["rgb", "color_temp"],
+    })
+}
+
+async fn handle(app: &Arc<RwLock<App>>, topic: &str, payload: &[u8]) {
+    if let Some(id) = topic
+        .strip_prefix("sensors/")
+        .and_then(|topic| topic.strip_suffix("/state"))
+    {
+        return report_sensor(app, id, payload).await;
+    }
+    let id = match topic
+        .strip_prefix("lights/")
+        .and_then(|topic| topic.strip_suffix("/set"))
+    {
+        Some(id) => id,
+        None => return,
+    };
+    let command: SetCommand = match serde_json::from_slice(payload) {
+        Ok(command) => command,
+        Err(e) => {
+            eprintln!("malformed mqtt command for {}: {}", id, e);
+            return;
+        }
+    };
+    let app = app.read().await;
+    let result = async {
+        app.check_active()?;
+        app.permit(id, Origin::Api)?;
+        match command.state.as_deref() {
+            Some("ON") => app.set_state(id, PowerState::On).await?,
+            Some("OFF") => return app.set_state(id, PowerState::Off).await,
+            _ => {}
+        }
+        if let Some(Rgb { r, g, b }) = command.color {
+            app.set_color(id, Color::Rgb { r, g, b }).await?;
+        } else if let Some(mireds) = command.color_temp {
+            app.set_color(id, Color::from_mireds(mireds)).await?;
+        }
+        if let Some(brightness) = command.brightness {
+            app.set_brightness(id, brightness.into()).await?;
+        }
+        Ok(())
+    }
+    .await;
+    match result {
+        Ok(()) => app.attribute(id, &Source::Mqtt),
+        Err(e) => eprintln!("mqtt command for {} failed: {:?}", id, e),
+    }
+}
+
+async fn report_sensor(app: &Arc<RwLock<App>>, id: &str, payload: &[u8]) {
+    let reading: SensorReading = match serde_json::from_slice(payload) {
+        Ok(reading) => reading,
+        Err(e) => {
+            eprintln!("malformed mqtt reading for sensor {}: {}", id, e);
+            return;
+        }
+    };
+    sensor::report_sensor(app, id, reading).await;
+}
+
+async fn receive(app: &Arc<RwLock<App>>, stream: &Async<TcpStream>) -> io::Result<()> {
+    loop {
+        let (header, body) = read_packet(stream).await?;
+        if header & 0xF0 != PUBLISH || body.len() < 2 {
+            continue;
+        }
+        let len = u16::from_be_bytes([body[0], body[1]]) as usize;
+        let topic = body
+            .get(2..2 + len)
+            .and_then(|topic| std::str::from_utf8(topic).ok())
+            .unwrap_or_default();
+        // QoS 1 and 2 publishes carry a packet identifier after the topic.
+        let start = if header & 0x06 != 0 { 4 + len } else { 2 + len };
+        handle(app, topic, body.get(start..).unwrap_or_default()).await;
+    }
+}
+
+async fn transmit(app: &RwLock<App>, mut stream: &Async<TcpStream>) -> io::Result<()> {
+    let mut changes = {
+        let app = app.read().await;
+        let changes = app.subscribe();
+        for light in app.lights() {
+            let id = light.id();
+            stream
+                .write_all(&publish(
+                    &format!("{}/light/{}/config", DISCOVERY_PREFIX, node_id(&id)),
+                    discovery_payload(light).to_string().as_bytes(),
+                    true,
+                ))
+                .await?;
+            stream
+                .write_all(&publish(
+                    &format!("lights/{}/state", id),
+                    state_payload(&app, light).to_string().as_bytes(),
+                    true,
+                ))
+                .await?;
+        }
+        changes
+    };
+    loop {
+        let change = changes.next();
+        let ping = Timer::after(PING_INTERVAL);
+        pin_mut!(change);
+        match select(change, ping).await {
+            Either::Left((Some(id), _)) => {
+                let payload = {
+                    let app = app.read().await;
+                    app.light(&id).map(|light| state_payload(&app, light))
+                };
+                if let Some(payload) = payload {
+                    stream
+                        .write_all(&publish(
+                            &format!("lights/{}/state", id),
+                            payload.to_string().as_bytes(),
+                            true,
+                        ))
+                        .await?;
+                }
+            }
+            Either::Left((None, _)) => return Ok(()),
+            Either::Right(_) => stream.write_all(&[PINGREQ, 0]).await?,
+        }
+    }
+}
+
+/// Publishes every light's state to `lights/<id>/state` along with Home
+/// Assistant discovery messages, and applies commands sent to
+/// `lights/<id>/set`. Readings published to `sensors/<id>/state`, as the
+/// same JSON as `POST /sensors/<id>`, go to the sensor. Returns once the
+/// broker connection is lost.
+pub async fn mqtt(app: Arc<RwLock<App>>, config: &MqttConfig) -> io::Result<()> {
+    let stream = Async::<TcpStream>::connect(config.broker).await?;
+    let mut body = vec![];
+    string(&mut body, b"MQTT");
+    body.push(4);
+    body.push(match config.credentials {
+        Some(_) => 0xC2,
+        None => 0x02,
+    });
+    body.extend_from_slice(&KEEP_ALIVE.to_be_bytes());
+    string(&mut body, config.client_id().as_bytes());
+    if let Some((user, pass)) = &config.credentials {
+        string(&mut body, user.as_bytes());
+        string(&mut body, pass.as_bytes());
+    }
+    (&stream).write_all(&packet(CONNECT, body)).await?;
+    let (header, body) = read_packet(&stream).await?;
+    if header != CONNACK || body.get(1) != Some(&0) {
+        return Err(io::Error::new(
+            io::ErrorKind::ConnectionRefused,
+            "broker rejected connection",
+        ));
+    }
+    let mut body = vec![0, 1];
+    string(&mut body, b"lights/+/set");
+    body.push(0);
+    string(&mut body, b"sensors/+/state");
+    body.push(0);
+    (&stream).write_all(&packet(SUBSCRIBE, body)).await?;
+
+    let receive = receive(&app, &stream);
+    let transmit = transmit(&app, &stream);
+    pin_mut!(receive, transmit);
+    match select(receive, transmit).await {
+        Either::Left((result, _)) | Either::Right((result, _)) => result,
+    }
+}
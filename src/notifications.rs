@@ -0,0 +1,54 @@
+use std::sync::Arc;
+
+use async_lock::RwLock;
+use serde_json::Value;
+use warp::{filters::BoxedFilter, http::StatusCode, Filter, Reply};
+
+use crate::{intent::visible, request_sync::send_notification, ui::authorized, App};
+
+impl App {
+    /// Whether Google knows of a device, and so can announce notifications
+    /// about it.
+    fn listed(&self, id: &str) -> bool {
+        (self.light(id).is_some() && visible(self, id, None))
+            || self
+                .registry
+                .sensor(id)
+                .map_or(false, |sensor| sensor.exposed)
+    }
+    /// Sends a notification about a device in the background.
+    pub(crate) fn announce(&self, id: &str, notification: Value) {
+        let id = id.to_owned();
+        self.spawner.spawn(Box::pin(async move {
+            if let Err(e) = send_notification(&id, notification).await {
+                eprintln!("failed to send notification about {}: {:?}", id, e);
+            }
+        }));
+    }
+}
+
+/// `POST /notifications/<id>` relays a notification about a device to
+/// Google, in the form [`send_notification`] takes, for automations that run
+/// outside the bridge.
+pub fn notifications(app: Arc<RwLock<App>>) -> BoxedFilter<(impl Reply,)> {
+    warp::post()
+        .and(warp::path!("notifications" / String))
+        .and(authorized())
+        .and(warp::body::json())
+        .and_then(move |id: String, notification: Value| {
+            let app = app.clone();
+            async move {
+                let listed = app.read().await.listed(&id);
+                let reply = if !listed {
+                    warp::reply::with_status(format!("no device {}", id), StatusCode::NOT_FOUND)
+                } else {
+                    match send_notification(&id, notification).await {
+                        Ok(()) => warp::reply::with_status(String::new(), StatusCode::OK),
+                        Err(e) => warp::reply::with_status(e.to_string(), StatusCode::BAD_GATEWAY),
+                    }
+                };
+                Ok::<_, core::convert::Infallible>(reply)
+            }
+        })
+        .boxed()
+}
@@ -0,0 +1,269 @@
+use schemars::{
+    gen::{SchemaGenerator, SchemaSettings},
+    JsonSchema,
+};
+use serde_json::{json, Value};
+use warp::{filters::BoxedFilter, Filter, Reply};
+
+use lights_api::*;
+
+const GOOGLE_FULFILLMENT: &str =
+    "https://developers.google.com/assistant/smarthome/reference/intent/sync";
+
+fn bearer() -> Value {
+    json!([{ "bearer": [] }])
+}
+
+fn json_body(schema: Value) -> Value {
+    json!({ "content": { "application/json": { "schema": schema } } })
+}
+
+fn json_response(description: &str, schema: Value) -> Value {
+    json!({
+        "description": description,
+        "content": { "application/json": { "schema": schema } },
+    })
+}
+
+fn schema<T: JsonSchema>(generator: &mut SchemaGenerator) -> Value {
+    serde_json::to_value(generator.subschema_for::<T>()).unwrap()
+}
+
+fn document() -> Value {
+    let mut generator = SchemaSettings::openapi3().into_generator();
+    let request = schema::<Request>(&mut generator);
+    let enumerate = schema::<EnumerateResponse>(&mut generator);
+    let item = schema::<EnumerateItem>(&mut generator);
+    let reading = schema::<SensorReading>(&mut generator);
+    // Only listed among the schemas, as it arrives over a WebSocket.
+    schema::<Event>(&mut generator);
+    // A request's response is the `<Request>Response` type of the same
+    // name, or a string describing the error.
+    let responses = vec![
+        enumerate.clone(),
+        schema::<CheckAuthResponse>(&mut generator),
+        schema::<MakeGroupResponse>(&mut generator),
+        schema::<AddLightToGroupResponse>(&mut generator),
+        schema::<RemoveLightFromGroupResponse>(&mut generator),
+        schema::<RescanIntegrationResponse>(&mut generator),
+        schema::<SetGroupRoleResponse>(&mut generator),
+        schema::<SetGroupBrightnessModeResponse>(&mut generator),
+        schema::<SetPowerOnDefaultsResponse>(&mut generator),
+        schema::<SetAutoOffResponse>(&mut generator),
+        schema::<RunSceneResponse>(&mut generator),
+        schema::<SnapshotSceneResponse>(&mut generator),
+        schema::<RecallSceneResponse>(&mut generator),
+        schema::<SunTimesResponse>(&mut generator),
+        schema::<SetTemporaryResponse>(&mut generator),
+        schema::<NotifyResponse>(&mut generator),
+        schema::<ExportStateResponse>(&mut generator),
+        schema::<ImportStateResponse>(&mut generator),
+        schema::<ListDevicesResponse>(&mut generator),
+        schema::<SetRoomResponse>(&mut generator),
+        schema::<ForgetDeviceResponse>(&mut generator),
+        schema::<MakeCompositeResponse>(&mut generator),
+        schema::<UndoResponse>(&mut generator),
+        schema::<SetPolicyResponse>(&mut generator),
+        schema::<SetStripResponse>(&mut generator),
+        schema::<DeviceStatsResponse>(&mut generator),
+        schema::<SetStructureResponse>(&mut generator),
+        schema::<SetExposedResponse>(&mut generator),
+        schema::<ListStructureResponse>(&mut generator),
+        schema::<SimulateResponse>(&mut generator),
+        schema::<PressLinkButtonResponse>(&mut generator),
+        schema::<ProgramUploadResponse>(&mut generator),
+        schema::<CompileProgramResponse>(&mut generator),
+        schema::<RawWriteResponse>(&mut generator),
+        schema::<UploadInitResponse>(&mut generator),
+        schema::<UploadAppendResponse>(&mut generator),
+        schema::<UploadCommitResponse>(&mut generator),
+        schema::<ReportSensorResponse>(&mut generator),
+        schema::<ListSensorsResponse>(&mut generator),
+        schema::<SetSensorExposedResponse>(&mut generator),
+        schema::<SensorHistoryResponse>(&mut generator),
+        schema::<LearnCodeResponse>(&mut generator),
+        schema::<ListCodesResponse>(&mut generator),
+        schema::<DeleteCodeResponse>(&mut generator),
+        schema::<SaveColorResponse>(&mut generator),
+        schema::<ListColorsResponse>(&mut generator),
+        schema::<DeleteColorResponse>(&mut generator),
+        schema::<SetOverrideHoldResponse>(&mut generator),
+        schema::<ClearOverrideResponse>(&mut generator),
+        json!({ "type": "string" }),
+    ];
+    let schemas = serde_json::to_value(generator.take_definitions()).unwrap();
+
+    json!({
+        "openapi": "3.0.0",
+        "info": {
+            "title": "lights",
+            "version": env!("CARGO_PKG_VERSION"),
+        },
+        "components": {
+            "schemas": schemas,
+            "securitySchemes": {
+                "bearer": { "type": "http", "scheme": "bearer" },
+            },
+        },
+        "paths": {
+            "/api/{token}": {
+                "post": {
+                    "summary": "Runs a lights-api request. The guest token may only make read-only requests.",
+                    "parameters": [{
+                        "name": "token",
+                        "in": "path",
+                        "required": true,
+                        "schema": { "type": "string" },
+                    }],
+                    "requestBody": json_body(request),
+                    "responses": {
+                        "200": json_response(
+                            "The request's response, or an error message.",
+                            json!({ "oneOf": responses }),
+                        ),
+                    },
+                },
+            },
+            "/api/{token}/enumerate": {
+                "get": {
+                    "summary": "Streams `Enumerate` as one item per line, starting with the lights. Either token may be used.",
+                    "parameters": [{
+                        "name": "token",
+                        "in": "path",
+                        "required": true,
+                        "schema": { "type": "string" },
+                    }],
+                    "responses": {
+                        "200": {
+                            "description": "Newline-delimited JSON items.",
+                            "content": { "application/x-ndjson": { "schema": item } },
+                        },
+                    },
+                },
+            },
+            "/fulfill": {
+                "post": {
+                    "summary": "Google smart home fulfillment webhook for SYNC, QUERY, EXECUTE and DISCONNECT intents.",
+                    "externalDocs": { "url": GOOGLE_FULFILLMENT },
+                    "requestBody": json_body(json!({ "type": "object" })),
+                    "responses": {
+                        "200": json_response(
+                            "The intent's response payload, or an `errorCode` for intents and payloads that aren't understood.",
+                            json!({ "type": "object" }),
+                        ),
+                        "400": json_response(
+                            "The body isn't a fulfillment request.",
+                            json!({
+                                "type": "object",
+                                "properties": { "error": { "type": "string" } },
+                            }),
+                        ),
+                    },
+                },
+            },
+            "/auth/auth": {
+                "get": {
+                    "summary": "OAuth authorization endpoint used during account linking.",
+                    "responses": { "302": { "description": "Redirect back to Google with a code." } },
+                },
+            },
+            "/auth/token": {
+                "post": {
+                    "summary": "OAuth token exchange used during account linking.",
+                    "requestBody": {
+                        "content": { "application/x-www-form-urlencoded": { "schema": { "type": "object" } } },
+                    },
+                    "responses": { "200": { "description": "An access and refresh token." } },
+                },
+            },
+            "/ui/state": {
+                "get": {
+                    "summary": "Every light and group, as shown by the web UI.",
+                    "security": bearer(),
+                    "responses": {
+                        "200": json_response("Current state.", enumerate),
+                    },
+                },
+            },
+            "/events": {
+                "get": {
+                    "summary": "WebSocket sending a light as JSON whenever its state changes, and an `Event` as program transfers to strips go on. The first message sent on it must be an auth token.",
+                    "responses": { "101": { "description": "Switching to the WebSocket protocol." } },
+                },
+            },
+            "/graphql": {
+                "post": {
+                    "summary": "GraphQL queries and mutations. Subscriptions use a WebSocket on the same path, authenticated the same way.",
+                    "security": bearer(),
+                    "responses": { "200": { "description": "A GraphQL response." } },
+                },
+            },
+            "/healthz": {
+                "get": {
+                    "summary": "Liveness check.",
+                    "responses": {
+                        "200": { "description": "Healthy." },
+                        "503": { "description": "Discovery or HomeGraph syncs are failing." },
+                    },
+                },
+            },
+            "/status": {
+                "get": {
+                    "summary": "Integration, sync and token status.",
+                    "security": bearer(),
+                    "responses": { "200": { "description": "Status details as JSON." } },
+                },
+            },
+            "/upload/{token}/{id}": {
+                "post": {
+                    "summary": "Flashes a program onto the ESP strip at the address `id`.",
+                    "requestBody": {
+                        "content": { "application/octet-stream": { "schema": { "type": "string", "format": "binary" } } },
+                    },
+                    "responses": { "200": { "description": "Always empty." } },
+                },
+            },
+            "/write/{token}/{id}": {
+                "post": {
+                    "summary": "Writes raw data to the ESP strip at the address `id`.",
+                    "requestBody": {
+                        "content": { "application/octet-stream": { "schema": { "type": "string", "format": "binary" } } },
+                    },
+                    "responses": { "200": { "description": "Always empty." } },
+                },
+            },
+            "/sensors/{id}": {
+                "post": {
+                    "summary": "Records a reading from the sensor `id`, for sensors and firmware that can only call a webhook.",
+                    "security": bearer(),
+                    "parameters": [{
+                        "name": "id",
+                        "in": "path",
+                        "required": true,
+                        "schema": { "type": "string" },
+                    }],
+                    "requestBody": json_body(reading),
+                    "responses": { "200": { "description": "Always empty." } },
+                },
+            },
+            "/run_program": {
+                "post": {
+                    "summary": "Conversation webhook for running stored programs.",
+                    "requestBody": json_body(json!({ "type": "object" })),
+                    "responses": { "200": { "description": "The webhook response." } },
+                },
+            },
+        },
+    })
+}
+
+/// Serves a description of the HTTP routes and the lights-api protocol as
+/// `GET /openapi.json`.
+pub fn openapi() -> BoxedFilter<(impl Reply,)> {
+    let document = document();
+    warp::path("openapi.json")
+        .and(warp::path::end())
+        .and(warp::get())
+        .map(move || warp::reply::json(&document))
+        .boxed()
+}
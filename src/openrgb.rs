@@ -0,0 +1,300 @@
+use std::{
+    convert::TryInto,
+    io,
+    net::{TcpStream, ToSocketAddrs},
+    sync::Arc,
+    time::Duration,
+};
+
+use async_io::{Async, Timer};
+use async_lock::RwLock;
+use futures::{AsyncReadExt, AsyncWriteExt, StreamExt};
+use lights_api::Source;
+use serde::Deserialize;
+
+use crate::{policy::Origin, App, Color, PowerState};
+
+const MAGIC: &[u8] = b"ORGB";
+const CLIENT_NAME: &[u8] = b"lights\0";
+
+const REQUEST_CONTROLLER_COUNT: u32 = 0;
+const REQUEST_CONTROLLER_DATA: u32 = 1;
+const SET_CLIENT_NAME: u32 = 50;
+const UPDATE_LEDS: u32 = 1050;
+const SET_CUSTOM_MODE: u32 = 1100;
+
+/// Which way colors go between the light and the PC.
+#[derive(Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Mirror {
+    /// The PC's peripherals show the light's color.
+    ToPc,
+    /// The light shows the average color of the PC's peripherals.
+    FromPc,
+}
+
+/// A PC whose peripherals are controlled through OpenRGB's SDK server, from
+/// `openrgb.toml`.
+#[derive(Deserialize)]
+pub struct OpenRgbConfig {
+    #[serde(default = "default_address")]
+    pub address: String,
+    pub light: String,
+    pub mirror: Mirror,
+    /// How often the PC's colors are read when mirroring from it.
+    #[serde(default = "default_poll_ms")]
+    pub poll_ms: u64,
+}
+
+fn default_address() -> String {
+    "localhost:6742".to_owned()
+}
+
+fn default_poll_ms() -> u64 {
+    1000
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+async fn send(mut stream: &Async<TcpStream>, device: u32, id: u32, data: &[u8]) -> io::Result<()> {
+    let mut packet = MAGIC.to_vec();
+    packet.extend_from_slice(&device.to_le_bytes());
+    packet.extend_from_slice(&id.to_le_bytes());
+    packet.extend_from_slice(&(data.len() as u32).to_le_bytes());
+    packet.extend_from_slice(data);
+    stream.write_all(&packet).await
+}
+
+/// Sends a request and waits for its reply, skipping the notifications the
+/// server sends in between, such as when its device list changes.
+async fn request(
+    mut stream: &Async<TcpStream>,
+    device: u32,
+    id: u32,
+    data: &[u8],
+) -> io::Result<Vec<u8>> {
+    send(stream, device, id, data).await?;
+    loop {
+        let mut header = [0; 16];
+        stream.read_exact(&mut header).await?;
+        if &header[..4] != MAGIC {
+            return Err(invalid("bad magic"));
+        }
+        let reply = u32::from_le_bytes(header[8..12].try_into().unwrap());
+        let len = u32::from_le_bytes(header[12..16].try_into().unwrap());
+        let mut body = vec![0; len as usize];
+        stream.read_exact(&mut body).await?;
+        if reply == id {
+            return Ok(body);
+        }
+    }
+}
+
+/// Reads the fields of a controller description in order.
+struct Reader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, len: usize) -> Option<&'a [u8]> {
+        if self.data.len() < len {
+            return None;
+        }
+        let (bytes, rest) = self.data.split_at(len);
+        self.data = rest;
+        Some(bytes)
+    }
+    fn u16(&mut self) -> Option<u16> {
+        Some(u16::from_le_bytes(self.bytes(2)?.try_into().ok()?))
+    }
+    fn u32(&mut self) -> Option<u32> {
+        Some(u32::from_le_bytes(self.bytes(4)?.try_into().ok()?))
+    }
+    fn string(&mut self) -> Option<()> {
+        let len = self.u16()? as usize;
+        self.bytes(len).map(drop)
+    }
+}
+
+/// The LED colors of a controller, from the description of protocol
+/// version 0, which is what the server sends clients that don't negotiate.
+fn colors(data: &[u8]) -> Option<Vec<(u8, u8, u8)>> {
+    let mut reader = Reader { data };
+    // Size and device type.
+    reader.bytes(8)?;
+    // Name, description, version, serial and location.
+    for _ in 0..5 {
+        reader.string()?;
+    }
+    let modes = reader.u16()?;
+    // Active mode.
+    reader.u32()?;
+    for _ in 0..modes {
+        reader.string()?;
+        // Value, flags, speeds, color limits, speed, direction and color
+        // mode.
+        reader.bytes(36)?;
+        let colors = reader.u16()? as usize;
+        reader.bytes(colors * 4)?;
+    }
+    let zones = reader.u16()?;
+    for _ in 0..zones {
+        reader.string()?;
+        // Type and LED counts.
+        reader.bytes(16)?;
+        let matrix = reader.u16()? as usize;
+        reader.bytes(matrix)?;
+    }
+    let leds = reader.u16()?;
+    for _ in 0..leds {
+        reader.string()?;
+        reader.u32()?;
+    }
+    let count = reader.u16()?;
+    (0..count)
+        .map(|_| {
+            let color = reader.bytes(4)?;
+            Some((color[0], color[1], color[2]))
+        })
+        .collect()
+}
+
+/// Every controller's LED colors.
+async fn controllers(stream: &Async<TcpStream>) -> io::Result<Vec<Vec<(u8, u8, u8)>>> {
+    let count = request(stream, 0, REQUEST_CONTROLLER_COUNT, &[]).await?;
+    let count = u32::from_le_bytes(
+        count
+            .get(..4)
+            .ok_or_else(|| invalid("short controller count"))?
+            .try_into()
+            .unwrap(),
+    );
+    let mut controllers = vec![];
+    for device in 0..count {
+        let data = request(stream, device, REQUEST_CONTROLLER_DATA, &[]).await?;
+        controllers.push(colors(&data).ok_or_else(|| invalid("malformed controller data"))?);
+    }
+    Ok(controllers)
+}
+
+/// The light's color as the PC should show it, black when it's off.
+fn shown(app: &App, id: &str) -> Option<(u8, u8, u8)> {
+    let state = app.state(app.light(id)?);
+    if !state.on {
+        return Some((0, 0, 0));
+    }
+    let (r, g, b) = state.color?.to_rgb();
+    let scale = |channel: u8| (channel as u32 * state.brightness as u32 / 255) as u8;
+    Some((scale(r), scale(g), scale(b)))
+}
+
+async fn show(stream: &Async<TcpStream>, (r, g, b): (u8, u8, u8)) -> io::Result<()> {
+    for (device, leds) in controllers(stream).await?.into_iter().enumerate() {
+        let device = device as u32;
+        let mut data = ((4 + 2 + leds.len() * 4) as u32).to_le_bytes().to_vec();
+        data.extend_from_slice(&(leds.len() as u16).to_le_bytes());
+        for _ in &leds {
+            data.extend_from_slice(&[r, g, b, 0]);
+        }
+        send(stream, device, SET_CUSTOM_MODE, &[]).await?;
+        send(stream, device, UPDATE_LEDS, &data).await?;
+    }
+    Ok(())
+}
+
+async fn to_pc(app: &RwLock<App>, stream: &Async<TcpStream>, id: &str) -> io::Result<()> {
+    let mut changes = app.read().await.subscribe();
+    let mut last = None;
+    loop {
+        let color = shown(&*app.read().await, id);
+        if let Some(color) = color.filter(|color| last != Some(*color)) {
+            show(stream, color).await?;
+            last = Some(color);
+        }
+        loop {
+            match changes.next().await {
+                Some(changed) if changed == id => break,
+                Some(_) => {}
+                None => return Ok(()),
+            }
+        }
+    }
+}
+
+/// Shows the average of the PC's LEDs on the light, normalized so that its
+/// brightest channel sets the brightness.
+async fn from_pc(
+    app: &RwLock<App>,
+    stream: &Async<TcpStream>,
+    id: &str,
+    poll: Duration,
+) -> io::Result<()> {
+    let mut last = None;
+    loop {
+        let leds = controllers(stream)
+            .await?
+            .into_iter()
+            .flatten()
+            .collect::<Vec<_>>();
+        if !leds.is_empty() {
+            let average = |channel: fn(&(u8, u8, u8)) -> u8| {
+                (leds.iter().map(|led| channel(led) as u32).sum::<u32>() / leds.len() as u32) as u8
+            };
+            let color = (
+                average(|led| led.0),
+                average(|led| led.1),
+                average(|led| led.2),
+            );
+            if last != Some(color) {
+                last = Some(color);
+                let app = app.read().await;
+                match mirror(&app, id, color).await {
+                    Ok(()) => app.attribute(id, &Source::OpenRgb),
+                    Err(e) => eprintln!("failed to mirror the PC's color to {}: {}", id, e),
+                }
+            }
+        }
+        Timer::after(poll).await;
+    }
+}
+
+async fn mirror(app: &App, id: &str, (r, g, b): (u8, u8, u8)) -> Result<(), crate::Error> {
+    app.permit(id, Origin::Api)?;
+    let brightest = r.max(g).max(b);
+    if brightest == 0 {
+        return app.set_state(id, PowerState::Off).await;
+    }
+    let scale = |channel: u8| (channel as u32 * 255 / brightest as u32) as u8;
+    app.set_state(id, PowerState::On).await?;
+    app.set_color(
+        id,
+        Color::Rgb {
+            r: scale(r),
+            g: scale(g),
+            b: scale(b),
+        },
+    )
+    .await?;
+    app.set_brightness(id, brightest.into()).await
+}
+
+/// Keeps a light and a PC's peripherals the same color through OpenRGB's SDK
+/// server, in the direction configured. Returns once the connection is lost.
+pub async fn openrgb(app: Arc<RwLock<App>>, config: &OpenRgbConfig) -> io::Result<()> {
+    let addr = config
+        .address
+        .to_socket_addrs()?
+        .next()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no address for server"))?;
+    let stream = Async::<TcpStream>::connect(addr).await?;
+    send(&stream, 0, SET_CLIENT_NAME, CLIENT_NAME).await?;
+    match config.mirror {
+        Mirror::ToPc => to_pc(&app, &stream, &config.light).await,
+        Mirror::FromPc => {
+            let poll = Duration::from_millis(config.poll_ms);
+            from_pc(&app, &stream, &config.light, poll).await
+        }
+    }
+}
@@ -0,0 +1,45 @@
+use lights_api::NamedColor;
+
+use crate::{api::api_color, App, Color, Error};
+
+impl App {
+    /// A color as given to the API, with saved ones looked up by name.
+    pub(crate) fn resolve_color(&self, color: lights_api::Color) -> Result<Color, Error> {
+        Ok(match color {
+            lights_api::Color::Rgb { red, green, blue } => Color::Rgb {
+                r: red,
+                g: green,
+                b: blue,
+            },
+            lights_api::Color::White { temp } => Color::White { temperature: temp },
+            lights_api::Color::Named { name } => self
+                .registry
+                .color(&name)
+                .ok_or_else(|| Error::Payload(format!("no color named `{}`", name)))?,
+        })
+    }
+    /// Saves a color under `name`. A named color is saved as what it is
+    /// now, so the two don't change together.
+    pub(crate) fn save_color(&self, name: &str, color: lights_api::Color) -> Result<(), Error> {
+        let name = name.trim();
+        if name.is_empty() {
+            return Err(Error::Payload("colors need a name".to_owned()));
+        }
+        let color = self.resolve_color(color)?;
+        self.registry.save_color(name, color);
+        Ok(())
+    }
+    pub(crate) fn colors(&self) -> Vec<NamedColor> {
+        self.registry
+            .colors()
+            .into_iter()
+            .map(|(name, color)| NamedColor {
+                name,
+                color: api_color(color),
+            })
+            .collect()
+    }
+    pub(crate) fn delete_color(&self, name: &str) -> Result<(), Error> {
+        self.registry.remove_color(name)
+    }
+}
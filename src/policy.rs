@@ -0,0 +1,117 @@
+use std::{
+    collections::{HashMap, HashSet},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use lights_api::{Policy, QuietHours};
+
+use crate::{
+    storage::{storage, Store},
+    App, Error, Id,
+};
+
+const MINUTES_PER_DAY: i64 = 24 * 60;
+
+fn policies() -> Store<Policy> {
+    storage().store("policies")
+}
+
+/// Where a command came from, which decides what a policy lets through.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Origin {
+    /// Google or a SmartThings hub.
+    Assistant,
+    Api,
+    /// The override token, which quiet hours don't apply to.
+    Override,
+}
+
+fn minute_of_day(offset_minutes: i16) -> u16 {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |time| time.as_secs() as i64);
+    (now / 60 + offset_minutes as i64).rem_euclid(MINUTES_PER_DAY) as u16
+}
+
+fn quiet(hours: &QuietHours) -> bool {
+    let now = minute_of_day(hours.utc_offset_minutes);
+    if hours.start <= hours.end {
+        hours.start <= now && now < hours.end
+    } else {
+        now >= hours.start || now < hours.end
+    }
+}
+
+fn check(id: &str, policy: &Policy, origin: Origin) -> Result<(), Error> {
+    if policy.locked && origin == Origin::Assistant {
+        return Err(Error::Policy(format!("{} is locked", id)));
+    }
+    if origin != Origin::Override && policy.quiet_hours.as_ref().map_or(false, quiet) {
+        return Err(Error::Policy(format!("{} is in quiet hours", id)));
+    }
+    Ok(())
+}
+
+impl App {
+    /// Loads the policies saved by earlier runs.
+    pub fn restore_policies(&mut self) {
+        let store = policies();
+        let ids = match store.keys() {
+            Ok(ids) => ids,
+            Err(e) => {
+                eprintln!("failed to list stored policies: {}", e);
+                return;
+            }
+        };
+        let mut loaded = HashMap::new();
+        for id in ids {
+            match store.get(&id) {
+                Ok(Some(policy)) => {
+                    loaded.insert(id, policy);
+                }
+                Ok(None) => {}
+                Err(e) => eprintln!("failed to load policy for `{}`: {}", id, e),
+            }
+        }
+        *self.policies.lock().unwrap() = loaded;
+    }
+    pub(crate) fn set_policy(&self, id: &str, policy: Policy) -> Result<(), Error> {
+        if !self.by_id.contains_key(&Id(id.into())) {
+            return Err(Error::Absent);
+        }
+        if let Err(e) = policies().put(id, &policy) {
+            eprintln!("failed to persist policy for `{}`: {}", id, e);
+        }
+        self.policies.lock().unwrap().insert(id.to_owned(), policy);
+        Ok(())
+    }
+    /// Refuses a command if the policy of the light, or of any light it
+    /// controls, doesn't allow it from `origin` right now.
+    pub(crate) fn permit(&self, id: &str, origin: Origin) -> Result<(), Error> {
+        let policies = self.policies.lock().unwrap();
+        if policies.is_empty() {
+            return Ok(());
+        }
+        let mut pending = vec![id.to_owned()];
+        let mut seen = HashSet::new();
+        while let Some(id) = pending.pop() {
+            if !seen.insert(id.clone()) {
+                continue;
+            }
+            if let Some(policy) = policies.get(&id) {
+                check(&id, policy, origin)?;
+            }
+            if let Some(members) = self.light(&id).and_then(|light| light.light().members()) {
+                pending.extend(members);
+            }
+        }
+        Ok(())
+    }
+    pub(crate) fn permit_all<'a, I: IntoIterator<Item = &'a str>>(
+        &self,
+        ids: I,
+        origin: Origin,
+    ) -> Result<(), Error> {
+        ids.into_iter().try_for_each(|id| self.permit(id, origin))
+    }
+}
@@ -0,0 +1,215 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use async_io::Timer;
+use async_lock::RwLock;
+use futures::future::BoxFuture;
+use serde::Deserialize;
+
+use crate::{App, LightError, LightState, ReportedState};
+
+const TICK: Duration = Duration::from_secs(1);
+/// Devices commanded within this long are polled at the vendor's interval,
+/// the rest less often.
+const RECENT: Duration = Duration::from_secs(10 * 60);
+const IDLE_FACTOR: u32 = 4;
+const MAX_BACKOFF: Duration = Duration::from_secs(15 * 60);
+
+/// How often a vendor's devices may be read, from `polling.toml`. Devices of
+/// vendors without a quota aren't polled.
+#[derive(Clone, Copy, Deserialize)]
+pub struct PollQuota {
+    /// Seconds between reads of a device that was recently commanded.
+    pub interval_secs: u64,
+    /// Reads allowed per minute across all of the vendor's devices.
+    pub per_minute: u32,
+}
+
+/// A device whose changes have to be read rather than being pushed.
+pub(crate) trait Poll: Send + Sync {
+    fn id(&self) -> String;
+    fn vendor(&self) -> &'static str;
+    /// Reads the device, returning what differs from `cached`.
+    fn poll(&self, cached: LightState) -> BoxFuture<'_, Result<ReportedState, LightError>>;
+}
+
+struct Vendor {
+    quota: PollQuota,
+    tokens: f64,
+    refilled: Instant,
+    /// Doubled on each rate limit response and cleared on success.
+    backoff: Duration,
+    resume: Instant,
+}
+
+impl Vendor {
+    fn new(quota: PollQuota) -> Self {
+        let now = Instant::now();
+        Vendor {
+            quota,
+            tokens: quota.per_minute as f64,
+            refilled: now,
+            backoff: Duration::from_secs(0),
+            resume: now,
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let per_second = self.quota.per_minute as f64 / 60.;
+        self.tokens = (self.tokens + now.duration_since(self.refilled).as_secs_f64() * per_second)
+            .min(self.quota.per_minute as f64);
+        self.refilled = now;
+    }
+}
+
+struct Polled {
+    device: Arc<dyn Poll>,
+    last: Option<Instant>,
+}
+
+/// Every polled device and the quotas of their vendors.
+#[derive(Default)]
+pub(crate) struct Polling {
+    vendors: HashMap<String, Vendor>,
+    devices: Vec<Polled>,
+}
+
+impl Polling {
+    /// Takes the devices to read now out of the quotas, recently commanded
+    /// and longest waiting first.
+    fn due(&mut self, now: Instant, recent: impl Fn(&str) -> bool) -> Vec<Arc<dyn Poll>> {
+        let mut due = vec![];
+        for (name, vendor) in &mut self.vendors {
+            vendor.refill(now);
+            if now < vendor.resume {
+                continue;
+            }
+            let interval = Duration::from_secs(vendor.quota.interval_secs);
+            let mut candidates = self
+                .devices
+                .iter_mut()
+                .filter(|polled| polled.device.vendor() == name)
+                .filter_map(|polled| {
+                    let recent = recent(&polled.device.id());
+                    let every = if recent {
+                        interval
+                    } else {
+                        interval * IDLE_FACTOR
+                    };
+                    match polled.last {
+                        Some(last) if now.duration_since(last) < every => None,
+                        _ => Some((recent, polled)),
+                    }
+                })
+                .collect::<Vec<_>>();
+            candidates.sort_by_key(|(recent, polled)| (!*recent, polled.last));
+            for (_, polled) in candidates {
+                if vendor.tokens < 1. {
+                    break;
+                }
+                vendor.tokens -= 1.;
+                polled.last = Some(now);
+                due.push(polled.device.clone());
+            }
+        }
+        due
+    }
+
+    fn finished(&mut self, vendor: &str, rate_limited: bool) {
+        let vendor = match self.vendors.get_mut(vendor) {
+            Some(vendor) => vendor,
+            None => return,
+        };
+        if rate_limited {
+            let floor = Duration::from_secs(vendor.quota.interval_secs);
+            vendor.backoff = (vendor.backoff * 2).max(floor).min(MAX_BACKOFF);
+            vendor.resume = Instant::now() + vendor.backoff;
+            vendor.tokens = 0.;
+        } else {
+            vendor.backoff = Duration::from_secs(0);
+        }
+    }
+}
+
+impl App {
+    pub fn set_poll_quota<T: Into<String>>(&self, vendor: T, quota: PollQuota) {
+        self.polling
+            .lock()
+            .unwrap()
+            .vendors
+            .insert(vendor.into(), Vendor::new(quota));
+    }
+    /// The polled device with an id, for reading it outside the schedule.
+    pub(crate) fn polled(&self, id: &str) -> Option<Arc<dyn Poll>> {
+        self.polling
+            .lock()
+            .unwrap()
+            .devices
+            .iter()
+            .find(|polled| polled.device.id() == id)
+            .map(|polled| polled.device.clone())
+    }
+    pub(crate) fn add_polled(&self, device: Arc<dyn Poll>) {
+        let mut polling = self.polling.lock().unwrap();
+        let id = device.id();
+        polling.devices.retain(|polled| polled.device.id() != id);
+        polling.devices.push(Polled { device, last: None });
+    }
+}
+
+/// Reads a polled device and reports what changed on it.
+pub(crate) async fn refresh(app: &RwLock<App>, device: &dyn Poll) -> Result<(), LightError> {
+    let id = device.id();
+    let cached = {
+        let app = app.read().await;
+        match app.light(&id) {
+            Some(light) => LightState {
+                brightness: app.dimming_curve(light.light()).apply(light.brightness()),
+                ..light.own_state()
+            },
+            None => return Ok(()),
+        }
+    };
+    let changed = device.poll(cached).await?;
+    if changed.on.is_some() || changed.brightness.is_some() || changed.color.is_some() {
+        let _ = app.read().await.report_state(&id, changed);
+    }
+    Ok(())
+}
+
+/// Reads polled devices as their vendors' quotas allow and reports what
+/// changed on them.
+pub async fn poll(app: Arc<RwLock<App>>) {
+    let polling: Arc<Mutex<Polling>> = app.read().await.polling.clone();
+    loop {
+        Timer::after(TICK).await;
+        let due = {
+            let app = app.read().await;
+            let now = Instant::now();
+            let recent = |id: &str| {
+                app.light(id).map_or(false, |light| {
+                    light
+                        .stats
+                        .lock()
+                        .unwrap()
+                        .last_command()
+                        .map_or(false, |last| now.duration_since(last) < RECENT)
+                })
+            };
+            polling.lock().unwrap().due(now, recent)
+        };
+        for device in due {
+            let result = refresh(&app, &*device).await;
+            polling.lock().unwrap().finished(
+                device.vendor(),
+                matches!(result, Err(LightError::RateLimited)),
+            );
+            if let Err(e) = result {
+                eprintln!("polling {} failed: {}", device.id(), e);
+            }
+        }
+    }
+}
@@ -0,0 +1,280 @@
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+    process::Command,
+    thread,
+    time::Duration,
+};
+
+use async_io::Timer;
+use futures::channel::oneshot;
+use openssl::{
+    pkey::{PKey, Public},
+    sign::Verifier,
+};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use thiserror::Error;
+
+use crate::storage::{storage, StorageError};
+
+const CHECKOUTS: &str = "program-sources";
+
+#[derive(Debug, Error)]
+pub enum ProgramSourceError {
+    #[error("http error: {0}")]
+    Http(String),
+    #[error("git error: {0}")]
+    Git(String),
+    #[error("io error: {0}")]
+    Io(#[from] io::Error),
+    #[error(transparent)]
+    Storage(#[from] StorageError),
+    #[error("invalid public key: {0}")]
+    Key(#[from] openssl::error::ErrorStack),
+    #[error("invalid program name `{0}`")]
+    Name(String),
+    #[error("checksum of `{0}` doesn't match")]
+    Checksum(String),
+    #[error("signature of `{0}` is missing or invalid")]
+    Signature(String),
+}
+
+impl From<surf::Error> for ProgramSourceError {
+    fn from(error: surf::Error) -> Self {
+        ProgramSourceError::Http(error.to_string())
+    }
+}
+
+/// Somewhere new and updated programs are published.
+#[derive(Deserialize, Clone, Debug)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum ProgramSource {
+    /// A git repository whose `<name>.wasm` files are programs, each with
+    /// optional `<name>.sha256` and `<name>.sig` files beside it.
+    Git {
+        url: String,
+        #[serde(default)]
+        branch: Option<String>,
+    },
+    /// A JSON array of `{ name, url, sha256, signature }` entries.
+    Index { url: String },
+}
+
+#[derive(Deserialize)]
+struct IndexEntry {
+    name: String,
+    url: String,
+    sha256: String,
+    #[serde(default)]
+    signature: Option<String>,
+}
+
+/// A program as published, before it is checked.
+struct Candidate {
+    name: String,
+    binary: Vec<u8>,
+    sha256: Option<String>,
+    /// Base64 Ed25519 signature of the binary.
+    signature: Option<String>,
+}
+
+/// Sources listed in `programs.toml`.
+#[derive(Deserialize)]
+pub struct ProgramSyncConfig {
+    #[serde(default, rename = "source")]
+    pub sources: Vec<ProgramSource>,
+    /// PEM Ed25519 key every program must be signed with, if set.
+    #[serde(default)]
+    pub public_key: Option<String>,
+    #[serde(default = "default_interval")]
+    pub interval_secs: u64,
+}
+
+fn default_interval() -> u64 {
+    3600
+}
+
+fn valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+pub(crate) async fn blocking<T: Send + 'static, F: FnOnce() -> T + Send + 'static>(task: F) -> T {
+    let (sender, receiver) = oneshot::channel();
+    thread::spawn(move || {
+        let _ = sender.send(task());
+    });
+    receiver.await.expect("blocking task panicked")
+}
+
+fn git(args: &[&str], dir: &Path) -> Result<(), ProgramSourceError> {
+    let output = Command::new("git").args(args).current_dir(dir).output()?;
+    if output.status.success() {
+        Ok(())
+    } else {
+        Err(ProgramSourceError::Git(
+            String::from_utf8_lossy(&output.stderr).trim().to_owned(),
+        ))
+    }
+}
+
+/// Clones or fast-forwards a repository, returning the programs in it.
+fn checkout(
+    url: &str,
+    branch: Option<&str>,
+    dir: PathBuf,
+) -> Result<Vec<Candidate>, ProgramSourceError> {
+    if dir.join(".git").exists() {
+        git(&["pull", "--ff-only", "--quiet"], &dir)?;
+    } else {
+        let parent = dir.parent().expect("checkouts live in the data directory");
+        fs::create_dir_all(parent)?;
+        let mut args = vec!["clone", "--quiet", "--depth", "1"];
+        if let Some(branch) = branch {
+            args.extend(&["--branch", branch]);
+        }
+        let target = dir.to_string_lossy().into_owned();
+        args.extend(&[url, &target]);
+        git(&args, parent)?;
+    }
+    let mut candidates = vec![];
+    for entry in fs::read_dir(&dir)? {
+        let path = entry?.path();
+        if path.extension().and_then(|ext| ext.to_str()) != Some("wasm") {
+            continue;
+        }
+        let name = match path.file_stem().and_then(|name| name.to_str()) {
+            Some(name) => name.to_owned(),
+            None => continue,
+        };
+        let sidecar = |ext: &str| {
+            fs::read_to_string(path.with_extension(ext))
+                .ok()
+                .map(|text| text.trim().to_owned())
+        };
+        candidates.push(Candidate {
+            binary: fs::read(&path)?,
+            sha256: sidecar("sha256"),
+            signature: sidecar("sig"),
+            name,
+        });
+    }
+    Ok(candidates)
+}
+
+impl ProgramSource {
+    async fn fetch(&self) -> Result<Vec<Candidate>, ProgramSourceError> {
+        match self {
+            ProgramSource::Git { url, branch } => {
+                let dir = storage()
+                    .namespace(CHECKOUTS)
+                    .join(format!("{:x}", Sha256::digest(url.as_bytes())));
+                let (url, branch) = (url.clone(), branch.clone());
+                blocking(move || checkout(&url, branch.as_deref(), dir)).await
+            }
+            ProgramSource::Index { url } => {
+                let entries: Vec<IndexEntry> = surf::get(url).recv_json().await?;
+                let mut candidates = vec![];
+                for entry in entries {
+                    candidates.push(Candidate {
+                        binary: surf::get(&entry.url).recv_bytes().await?,
+                        name: entry.name,
+                        sha256: Some(entry.sha256),
+                        signature: entry.signature,
+                    });
+                }
+                Ok(candidates)
+            }
+        }
+    }
+}
+
+/// Keeps the stored programs up to date with their sources. Programs it
+/// adds show up in the API and the voice hook like uploaded ones.
+pub struct ProgramSync {
+    sources: Vec<ProgramSource>,
+    key: Option<PKey<Public>>,
+    interval: Duration,
+}
+
+impl ProgramSync {
+    pub fn new(config: ProgramSyncConfig) -> Result<Self, ProgramSourceError> {
+        Ok(ProgramSync {
+            sources: config.sources,
+            key: config
+                .public_key
+                .map(|pem| PKey::public_key_from_pem(pem.as_bytes()))
+                .transpose()?,
+            interval: Duration::from_secs(config.interval_secs),
+        })
+    }
+
+    fn verify(&self, candidate: &Candidate) -> Result<(), ProgramSourceError> {
+        if !valid_name(&candidate.name) {
+            return Err(ProgramSourceError::Name(candidate.name.clone()));
+        }
+        if let Some(expected) = &candidate.sha256 {
+            let actual = format!("{:x}", Sha256::digest(&candidate.binary));
+            if !actual.eq_ignore_ascii_case(expected) {
+                return Err(ProgramSourceError::Checksum(candidate.name.clone()));
+            }
+        }
+        if let Some(key) = &self.key {
+            let invalid = || ProgramSourceError::Signature(candidate.name.clone());
+            let signature = candidate
+                .signature
+                .as_ref()
+                .and_then(|signature| base64::decode(signature).ok())
+                .ok_or_else(invalid)?;
+            let mut verifier = Verifier::new_without_digest(key)?;
+            if !verifier.verify_oneshot(&signature, &candidate.binary)? {
+                return Err(invalid());
+            }
+        }
+        Ok(())
+    }
+
+    /// Fetches every source once, returning the names of programs that were
+    /// added or changed.
+    pub async fn sync(&self) -> Vec<String> {
+        let programs = storage().blobs("programs");
+        let mut updated = vec![];
+        for source in &self.sources {
+            let candidates = match source.fetch().await {
+                Ok(candidates) => candidates,
+                Err(e) => {
+                    eprintln!("failed to fetch programs from {:?}: {}", source, e);
+                    continue;
+                }
+            };
+            for candidate in candidates {
+                if let Err(e) = self.verify(&candidate) {
+                    eprintln!("skipped program: {}", e);
+                    continue;
+                }
+                if programs.get(&candidate.name).ok().flatten().as_ref() == Some(&candidate.binary)
+                {
+                    continue;
+                }
+                match programs.put(&candidate.name, &candidate.binary) {
+                    Ok(()) => updated.push(candidate.name),
+                    Err(e) => eprintln!("failed to store program `{}`: {}", candidate.name, e),
+                }
+            }
+        }
+        updated
+    }
+
+    pub async fn run(self) {
+        loop {
+            let updated = self.sync().await;
+            if !updated.is_empty() {
+                eprintln!("synced programs: {}", updated.join(", "));
+            }
+            Timer::after(self.interval).await;
+        }
+    }
+}
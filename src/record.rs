@@ -0,0 +1,282 @@
+use std::{
+    fs::{File, OpenOptions},
+    io::{self, BufRead, BufReader, Write},
+    ops::RangeInclusive,
+    path::Path,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use async_io::Timer;
+use async_lock::RwLock;
+use futures::future::BoxFuture;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::{App, Brightness, Color, DeviceKind, Light, LightError, PowerState, Role, Thermostat};
+
+#[derive(Serialize, Deserialize, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+enum Command {
+    PowerState { state: PowerState },
+    Brightness { brightness: u8 },
+    Color { color: Color },
+    FanSpeed { percent: u8 },
+    Thermostat { thermostat: Thermostat },
+}
+
+#[derive(Serialize, Deserialize)]
+struct Entry {
+    at: u64,
+    vendor: String,
+    device: String,
+    command: Command,
+}
+
+/// Appends every command sent to a light to a JSON lines file, optionally
+/// swallowing the command instead of forwarding it to the hardware.
+pub struct Recorder {
+    file: Mutex<File>,
+    start: Instant,
+    dry_run: bool,
+}
+
+impl Recorder {
+    pub fn create<P: AsRef<Path>>(path: P, dry_run: bool) -> io::Result<Self> {
+        Ok(Recorder {
+            file: Mutex::new(OpenOptions::new().create(true).append(true).open(path)?),
+            start: Instant::now(),
+            dry_run,
+        })
+    }
+
+    fn record(&self, vendor: &str, device: &str, command: Command) {
+        let entry = Entry {
+            at: self.start.elapsed().as_millis() as u64,
+            vendor: vendor.to_owned(),
+            device: device.to_owned(),
+            command,
+        };
+        let mut file = self.file.lock().unwrap();
+        if let Err(e) = serde_json::to_writer(&mut *file, &entry)
+            .map_err(io::Error::from)
+            .and_then(|_| writeln!(file))
+        {
+            eprintln!("failed to record command: {:?}", e);
+        }
+    }
+
+    /// Records a brightness as requested, before any dimming curve, so that
+    /// replaying it through the app applies the curve once.
+    pub(crate) fn brightness(&self, vendor: &str, device: &str, brightness: u8) {
+        self.record(vendor, device, Command::Brightness { brightness });
+    }
+}
+
+pub(crate) struct RecordingLight {
+    light: Box<dyn Light + Sync + Send>,
+    recorder: Arc<Recorder>,
+    device: String,
+}
+
+impl RecordingLight {
+    pub(crate) fn new(
+        light: Box<dyn Light + Sync + Send>,
+        recorder: Arc<Recorder>,
+        device: String,
+    ) -> Self {
+        RecordingLight {
+            light,
+            recorder,
+            device,
+        }
+    }
+
+    fn forward<'a>(&'a self, command: Command) -> BoxFuture<'a, Result<(), LightError>> {
+        self.recorder
+            .record(self.light.vendor(), &self.device, command);
+        self.send(command)
+    }
+
+    fn send<'a>(&'a self, command: Command) -> BoxFuture<'a, Result<(), LightError>> {
+        if self.recorder.dry_run {
+            return Box::pin(async move { Ok(()) });
+        }
+        match command {
+            Command::PowerState { state } => self.light.set_power_state(state),
+            Command::Brightness { brightness } => self.light.set_brightness(brightness.into()),
+            Command::Color { color } => self.light.set_color(color),
+            Command::FanSpeed { percent } => self.light.set_fan_speed(percent),
+            Command::Thermostat { thermostat } => self.light.set_thermostat(thermostat),
+        }
+    }
+}
+
+impl Light for RecordingLight {
+    fn name(&self) -> String {
+        self.light.name()
+    }
+
+    fn vendor(&self) -> &'static str {
+        self.light.vendor()
+    }
+
+    fn unique_id<'a>(&'a self) -> BoxFuture<'a, Result<String, LightError>> {
+        self.light.unique_id()
+    }
+
+    fn set_power_state<'a>(&'a self, state: PowerState) -> BoxFuture<'a, Result<(), LightError>> {
+        self.forward(Command::PowerState { state })
+    }
+
+    fn set_brightness<'a>(
+        &'a self,
+        brightness: Brightness,
+    ) -> BoxFuture<'a, Result<(), LightError>> {
+        // Recorded by the app, which knows the level before the curve.
+        self.send(Command::Brightness {
+            brightness: brightness.into(),
+        })
+    }
+
+    fn set_color<'a>(&'a self, color: Color) -> BoxFuture<'a, Result<(), LightError>> {
+        self.forward(Command::Color { color })
+    }
+
+    fn legacy_ids(&self) -> Vec<String> {
+        self.light.legacy_ids()
+    }
+
+    fn members(&self) -> Option<Vec<String>> {
+        self.light.members()
+    }
+
+    fn role(&self) -> Role {
+        self.light.role()
+    }
+
+    fn online(&self) -> bool {
+        self.light.online()
+    }
+
+    fn supports_color(&self) -> bool {
+        self.light.supports_color()
+    }
+
+    fn color_temperature_range(&self) -> RangeInclusive<u32> {
+        self.light.color_temperature_range()
+    }
+
+    fn configure_strip(&self, strip: &lights_api::StripConfig) -> Result<(), String> {
+        self.light.configure_strip(strip)
+    }
+
+    fn upload_program<'a>(&'a self, program: &'a [u8]) -> BoxFuture<'a, Result<(), crate::Error>> {
+        self.light.upload_program(program)
+    }
+
+    fn write_frame<'a>(&'a self, frame: &'a [u8]) -> BoxFuture<'a, Result<(), crate::Error>> {
+        self.light.write_frame(frame)
+    }
+
+    fn kind(&self) -> DeviceKind {
+        self.light.kind()
+    }
+
+    fn set_fan_speed<'a>(&'a self, percent: u8) -> BoxFuture<'a, Result<(), LightError>> {
+        self.forward(Command::FanSpeed { percent })
+    }
+
+    fn set_thermostat<'a>(
+        &'a self,
+        thermostat: Thermostat,
+    ) -> BoxFuture<'a, Result<(), LightError>> {
+        self.forward(Command::Thermostat { thermostat })
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum ReplayError {
+    #[error("failed to read recording: {0}")]
+    Io(#[from] io::Error),
+    #[error("malformed recording entry: {0}")]
+    Parse(#[from] serde_json::Error),
+}
+
+/// Re-issues a session captured by a [`Recorder`], preserving the original
+/// spacing between commands.
+pub async fn replay<P: AsRef<Path>>(path: P, app: Arc<RwLock<App>>) -> Result<(), ReplayError> {
+    let start = Instant::now();
+    for line in BufReader::new(File::open(path)?).lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let entry: Entry = serde_json::from_str(&line)?;
+        Timer::at(start + Duration::from_millis(entry.at)).await;
+        let app = app.read().await;
+        let result = match entry.command {
+            Command::PowerState { state } => app.set_state(&entry.device, state).await,
+            Command::Brightness { brightness } => {
+                app.set_brightness(&entry.device, brightness.into()).await
+            }
+            Command::Color { color } => app.set_color(&entry.device, color).await,
+            Command::FanSpeed { percent } => app.set_fan_speed(&entry.device, percent).await,
+            Command::Thermostat { thermostat } => {
+                app.set_thermostat(
+                    &entry.device,
+                    Some(thermostat.mode),
+                    Some(thermostat.celsius),
+                )
+                .await
+            }
+        };
+        if let Err(e) = result {
+            eprintln!(
+                "failed to replay command for {} ({}): {:?}",
+                entry.device, entry.vendor, e
+            );
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use async_lock::RwLock;
+
+    use super::{replay, Recorder};
+    use crate::{conformance::InertSpawner, integrations::mock::MockLight, App, DimmingCurve};
+
+    fn app(curve: DimmingCurve) -> App {
+        let mut app = App::with_spawner(InertSpawner);
+        app.set_default_dimming_curve(curve);
+        app
+    }
+
+    #[test]
+    fn replay_applies_the_dimming_curve_once() {
+        futures::executor::block_on(async {
+            let path = std::env::temp_dir().join(format!("replay-{}.jsonl", std::process::id()));
+            let _ = std::fs::remove_file(&path);
+            let curve = DimmingCurve::Gamma(2.2);
+            let mut recording = app(curve);
+            recording.set_recorder(Recorder::create(&path, true).unwrap());
+            recording.push_lights(vec![MockLight::new(1)]).await;
+            recording
+                .set_brightness("mock-1", 100.into())
+                .await
+                .unwrap();
+
+            let mut replaying = app(curve);
+            replaying.push_lights(vec![MockLight::new(1)]).await;
+            let replaying = Arc::new(RwLock::new(replaying));
+            replay(&path, replaying.clone()).await.unwrap();
+            let _ = std::fs::remove_file(&path);
+            let app = replaying.read().await;
+            assert_eq!(app.light("mock-1").unwrap().brightness(), 100);
+        });
+    }
+}
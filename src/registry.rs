@@ -0,0 +1,469 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    ops::RangeInclusive,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex,
+    },
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use futures::future::{ready, BoxFuture};
+use lights_api::{SensorKind, StripConfig};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    fulfill::light_traits,
+    storage::{storage, Store},
+    temperatures, Brightness, Color, Error, Light, LightError, PowerState, DEFAULT_TEMPERATURES,
+};
+
+const REGISTRY_KEY: &str = "registry";
+const ALIASES_KEY: &str = "aliases";
+const SENSORS_KEY: &str = "sensors";
+const COLORS_KEY: &str = "colors";
+
+// `Light::vendor` hands out static strings, so stored vendors are matched
+// back to the integrations that exist.
+const VENDORS: &[&str] = &[
+    "broadlink",
+    "broadlink-rm",
+    "deconz",
+    "esp",
+    "lutron",
+    "mock",
+    "sengled",
+    "tuya",
+    "wiz",
+];
+
+/// What's remembered about a device between runs, whether or not it is
+/// currently connected.
+#[derive(Clone, Serialize, Deserialize)]
+pub(crate) struct RegisteredDevice {
+    pub(crate) name: String,
+    pub(crate) vendor: String,
+    /// Google traits the device was last synced with.
+    #[serde(default)]
+    pub(crate) traits: Vec<String>,
+    #[serde(default)]
+    pub(crate) room: Option<String>,
+    /// The home the device is in, for bridges serving more than one.
+    #[serde(default)]
+    pub(crate) structure: Option<String>,
+    /// White temperatures the device was last synced with, in Kelvin.
+    #[serde(default)]
+    pub(crate) color_temperature_range: Option<(u32, u32)>,
+    /// Layout of the device if it is an LED strip.
+    #[serde(default)]
+    pub(crate) strip: Option<StripConfig>,
+    /// Whether Google is told about the device, which those that are only
+    /// parts of a group or kept for testing can be left out of.
+    #[serde(default = "exposed")]
+    pub(crate) exposed: bool,
+    /// Unix timestamp of the last time an integration reported the device.
+    #[serde(default)]
+    pub(crate) last_seen: u64,
+}
+
+fn exposed() -> bool {
+    true
+}
+
+/// An input device, such as a motion sensor, that has reported to the
+/// bridge.
+#[derive(Clone, Serialize, Deserialize)]
+pub(crate) struct RegisteredSensor {
+    pub(crate) name: String,
+    pub(crate) kind: SensorKind,
+    #[serde(default)]
+    pub(crate) room: Option<String>,
+    /// Unlike devices, sensors are only listed to Google once asked to be.
+    #[serde(default)]
+    pub(crate) exposed: bool,
+    #[serde(default)]
+    pub(crate) last_seen: u64,
+}
+
+fn store() -> Store<HashMap<String, RegisteredDevice>> {
+    storage().store("devices")
+}
+
+/// Current ids of devices by the legacy id they are still addressed by.
+fn alias_store() -> Store<HashMap<String, String>> {
+    storage().store("devices")
+}
+
+fn sensor_store() -> Store<HashMap<String, RegisteredSensor>> {
+    storage().store("devices")
+}
+
+/// Colors saved by name, such as "movie amber".
+fn color_store() -> Store<BTreeMap<String, Color>> {
+    storage().store("devices")
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|since| since.as_secs())
+        .unwrap_or(0)
+}
+
+/// Kept only in memory unless loaded from storage.
+#[derive(Default)]
+pub(crate) struct Registry {
+    devices: Mutex<HashMap<String, RegisteredDevice>>,
+    aliases: Mutex<HashMap<String, String>>,
+    sensors: Mutex<HashMap<String, RegisteredSensor>>,
+    colors: Mutex<BTreeMap<String, Color>>,
+    persistent: bool,
+    /// Set on standby instances, which leave storage to the active one.
+    standby: AtomicBool,
+}
+
+impl Registry {
+    pub(crate) fn load() -> Self {
+        let devices = match store().get(REGISTRY_KEY) {
+            Ok(devices) => devices.unwrap_or_default(),
+            Err(e) => {
+                eprintln!("failed to load device registry: {}", e);
+                HashMap::new()
+            }
+        };
+        let aliases = match alias_store().get(ALIASES_KEY) {
+            Ok(aliases) => aliases.unwrap_or_default(),
+            Err(e) => {
+                eprintln!("failed to load device id aliases: {}", e);
+                HashMap::new()
+            }
+        };
+        let sensors = match sensor_store().get(SENSORS_KEY) {
+            Ok(sensors) => sensors.unwrap_or_default(),
+            Err(e) => {
+                eprintln!("failed to load sensor registry: {}", e);
+                HashMap::new()
+            }
+        };
+        let colors = match color_store().get(COLORS_KEY) {
+            Ok(colors) => colors.unwrap_or_default(),
+            Err(e) => {
+                eprintln!("failed to load saved colors: {}", e);
+                BTreeMap::new()
+            }
+        };
+        Registry {
+            devices: Mutex::new(devices),
+            aliases: Mutex::new(aliases),
+            sensors: Mutex::new(sensors),
+            colors: Mutex::new(colors),
+            persistent: true,
+            standby: AtomicBool::new(false),
+        }
+    }
+
+    pub(crate) fn set_standby(&self, standby: bool) {
+        self.standby.store(standby, Ordering::SeqCst);
+    }
+
+    /// Replaces the devices, aliases, sensors and colors in memory with the
+    /// stored ones.
+    pub(crate) fn reload(&self) {
+        if !self.persistent {
+            return;
+        }
+        match store().get(REGISTRY_KEY) {
+            Ok(devices) => *self.devices.lock().unwrap() = devices.unwrap_or_default(),
+            Err(e) => eprintln!("failed to reload device registry: {}", e),
+        }
+        match alias_store().get(ALIASES_KEY) {
+            Ok(aliases) => *self.aliases.lock().unwrap() = aliases.unwrap_or_default(),
+            Err(e) => eprintln!("failed to reload device id aliases: {}", e),
+        }
+        match sensor_store().get(SENSORS_KEY) {
+            Ok(sensors) => *self.sensors.lock().unwrap() = sensors.unwrap_or_default(),
+            Err(e) => eprintln!("failed to reload sensor registry: {}", e),
+        }
+        match color_store().get(COLORS_KEY) {
+            Ok(colors) => *self.colors.lock().unwrap() = colors.unwrap_or_default(),
+            Err(e) => eprintln!("failed to reload saved colors: {}", e),
+        }
+    }
+
+    fn save(&self, devices: &HashMap<String, RegisteredDevice>) {
+        if !self.persistent || self.standby.load(Ordering::SeqCst) {
+            return;
+        }
+        if let Err(e) = store().put(REGISTRY_KEY, devices) {
+            eprintln!("failed to persist device registry: {}", e);
+        }
+    }
+
+    pub(crate) fn devices(&self) -> Vec<(String, RegisteredDevice)> {
+        self.devices
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(id, device)| (id.clone(), device.clone()))
+            .collect()
+    }
+
+    /// The legacy id a device with the current id `id` is addressed by.
+    pub(crate) fn alias(&self, id: &str) -> Option<String> {
+        self.aliases.lock().unwrap().get(id).cloned()
+    }
+
+    pub(crate) fn add_alias(&self, id: &str, legacy: &str) {
+        let mut aliases = self.aliases.lock().unwrap();
+        aliases.insert(id.to_owned(), legacy.to_owned());
+        self.save_aliases(&aliases);
+    }
+
+    fn save_aliases(&self, aliases: &HashMap<String, String>) {
+        if !self.persistent || self.standby.load(Ordering::SeqCst) {
+            return;
+        }
+        if let Err(e) = alias_store().put(ALIASES_KEY, aliases) {
+            eprintln!("failed to persist device id aliases: {}", e);
+        }
+    }
+
+    pub(crate) fn get(&self, id: &str) -> Option<RegisteredDevice> {
+        self.devices.lock().unwrap().get(id).cloned()
+    }
+
+    /// Records a device an integration just reported, keeping the room,
+    /// structure, strip layout and exposure assigned to it in earlier runs.
+    pub(crate) fn remember(&self, id: &str, light: &dyn Light) {
+        let mut devices = self.devices.lock().unwrap();
+        let (room, structure, strip) = devices
+            .get(id)
+            .map(|device| {
+                (
+                    device.room.clone(),
+                    device.structure.clone(),
+                    device.strip.clone(),
+                )
+            })
+            .unwrap_or_default();
+        let exposed = devices.get(id).map_or(true, |device| device.exposed);
+        devices.insert(
+            id.to_owned(),
+            RegisteredDevice {
+                name: light.name(),
+                vendor: light.vendor().to_owned(),
+                traits: light_traits(light),
+                room,
+                structure,
+                color_temperature_range: {
+                    let range = temperatures(light);
+                    Some((*range.start(), *range.end()))
+                },
+                strip,
+                exposed,
+                last_seen: now(),
+            },
+        );
+        self.save(&devices);
+    }
+
+    pub(crate) fn set_room(&self, id: &str, room: Option<String>) -> Result<(), Error> {
+        let mut devices = self.devices.lock().unwrap();
+        devices.get_mut(id).ok_or(Error::Absent)?.room = room;
+        self.save(&devices);
+        Ok(())
+    }
+
+    pub(crate) fn set_structure(&self, id: &str, structure: Option<String>) -> Result<(), Error> {
+        let mut devices = self.devices.lock().unwrap();
+        devices.get_mut(id).ok_or(Error::Absent)?.structure = structure;
+        self.save(&devices);
+        Ok(())
+    }
+
+    pub(crate) fn set_exposed(&self, id: &str, exposed: bool) -> Result<(), Error> {
+        let mut devices = self.devices.lock().unwrap();
+        devices.get_mut(id).ok_or(Error::Absent)?.exposed = exposed;
+        self.save(&devices);
+        Ok(())
+    }
+
+    pub(crate) fn set_strip(&self, id: &str, strip: StripConfig) -> Result<(), Error> {
+        let mut devices = self.devices.lock().unwrap();
+        devices.get_mut(id).ok_or(Error::Absent)?.strip = Some(strip);
+        self.save(&devices);
+        Ok(())
+    }
+
+    pub(crate) fn sensors(&self) -> Vec<(String, RegisteredSensor)> {
+        self.sensors
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(id, sensor)| (id.clone(), sensor.clone()))
+            .collect()
+    }
+
+    pub(crate) fn sensor(&self, id: &str) -> Option<RegisteredSensor> {
+        self.sensors.lock().unwrap().get(id).cloned()
+    }
+
+    /// Records a sensor that just reported, named after its id the first
+    /// time.
+    pub(crate) fn remember_sensor(&self, id: &str, kind: SensorKind) {
+        let mut sensors = self.sensors.lock().unwrap();
+        let sensor = sensors
+            .entry(id.to_owned())
+            .or_insert_with(|| RegisteredSensor {
+                name: id.to_owned(),
+                kind,
+                room: None,
+                exposed: false,
+                last_seen: 0,
+            });
+        sensor.kind = kind;
+        sensor.last_seen = now();
+        self.save_sensors(&sensors);
+    }
+
+    pub(crate) fn set_sensor_exposed(&self, id: &str, exposed: bool) -> Result<(), Error> {
+        let mut sensors = self.sensors.lock().unwrap();
+        sensors.get_mut(id).ok_or(Error::Absent)?.exposed = exposed;
+        self.save_sensors(&sensors);
+        Ok(())
+    }
+
+    fn save_sensors(&self, sensors: &HashMap<String, RegisteredSensor>) {
+        if !self.persistent || self.standby.load(Ordering::SeqCst) {
+            return;
+        }
+        if let Err(e) = sensor_store().put(SENSORS_KEY, sensors) {
+            eprintln!("failed to persist sensor registry: {}", e);
+        }
+    }
+
+    pub(crate) fn colors(&self) -> Vec<(String, Color)> {
+        self.colors
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(name, color)| (name.clone(), *color))
+            .collect()
+    }
+
+    /// The color saved under `name`, in any case.
+    pub(crate) fn color(&self, name: &str) -> Option<Color> {
+        self.colors
+            .lock()
+            .unwrap()
+            .iter()
+            .find(|(saved, _)| saved.eq_ignore_ascii_case(name))
+            .map(|(_, color)| *color)
+    }
+
+    pub(crate) fn save_color(&self, name: &str, color: Color) {
+        let mut colors = self.colors.lock().unwrap();
+        colors.retain(|saved, _| !saved.eq_ignore_ascii_case(name));
+        colors.insert(name.to_owned(), color);
+        self.save_colors(&colors);
+    }
+
+    pub(crate) fn remove_color(&self, name: &str) -> Result<(), Error> {
+        let mut colors = self.colors.lock().unwrap();
+        let before = colors.len();
+        colors.retain(|saved, _| !saved.eq_ignore_ascii_case(name));
+        if colors.len() == before {
+            return Err(Error::Absent);
+        }
+        self.save_colors(&colors);
+        Ok(())
+    }
+
+    fn save_colors(&self, colors: &BTreeMap<String, Color>) {
+        if !self.persistent || self.standby.load(Ordering::SeqCst) {
+            return;
+        }
+        if let Err(e) = color_store().put(COLORS_KEY, colors) {
+            eprintln!("failed to persist saved colors: {}", e);
+        }
+    }
+
+    pub(crate) fn forget(&self, id: &str) -> Result<(), Error> {
+        let mut devices = self.devices.lock().unwrap();
+        devices.remove(id).ok_or(Error::Absent)?;
+        self.save(&devices);
+        let mut aliases = self.aliases.lock().unwrap();
+        aliases.retain(|_, legacy| legacy != id);
+        self.save_aliases(&aliases);
+        Ok(())
+    }
+}
+
+/// Stands in for a registered device that hasn't been rediscovered yet.
+pub(crate) struct OfflineLight {
+    id: String,
+    name: String,
+    vendor: &'static str,
+    color: bool,
+    temperatures: Option<(u32, u32)>,
+}
+
+impl OfflineLight {
+    pub(crate) fn new(id: String, device: &RegisteredDevice) -> Self {
+        OfflineLight {
+            id,
+            name: device.name.clone(),
+            vendor: VENDORS
+                .iter()
+                .find(|vendor| **vendor == device.vendor)
+                .copied()
+                .unwrap_or("unknown"),
+            color: device
+                .traits
+                .iter()
+                .any(|t| t == "action.devices.traits.ColorSetting"),
+            temperatures: device.color_temperature_range,
+        }
+    }
+}
+
+impl Light for OfflineLight {
+    fn name(&self) -> String {
+        self.name.clone()
+    }
+
+    fn vendor(&self) -> &'static str {
+        self.vendor
+    }
+
+    fn unique_id<'a>(&'a self) -> BoxFuture<'a, Result<String, LightError>> {
+        Box::pin(ready(Ok(self.id.clone())))
+    }
+
+    fn set_power_state<'a>(&'a self, _: PowerState) -> BoxFuture<'a, Result<(), LightError>> {
+        Box::pin(ready(Err(LightError::Offline)))
+    }
+
+    fn set_brightness<'a>(&'a self, _: Brightness) -> BoxFuture<'a, Result<(), LightError>> {
+        Box::pin(ready(Err(LightError::Offline)))
+    }
+
+    fn set_color<'a>(&'a self, _: Color) -> BoxFuture<'a, Result<(), LightError>> {
+        Box::pin(ready(Err(LightError::Offline)))
+    }
+
+    fn online(&self) -> bool {
+        false
+    }
+
+    fn supports_color(&self) -> bool {
+        self.color
+    }
+
+    fn color_temperature_range(&self) -> RangeInclusive<u32> {
+        match self.temperatures {
+            Some((min, max)) => min..=max,
+            None => DEFAULT_TEMPERATURES,
+        }
+    }
+}
@@ -1,5 +1,32 @@
+use std::{
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use async_io::Timer;
+use futures::{
+    channel::{
+        mpsc::{unbounded, UnboundedReceiver, UnboundedSender},
+        oneshot,
+    },
+    future::select,
+    StreamExt,
+};
 use serde::Serialize;
-use surf::Body;
+use serde_json::{json, Value};
+use surf::{Body, StatusCode};
+use uuid::Uuid;
+
+use crate::{health::Health, vault::credential, Spawner};
+
+const DEBOUNCE: Duration = Duration::from_secs(5);
+// A steady trickle of changes would otherwise hold a request back forever.
+const MAX_DEBOUNCE: Duration = Duration::from_secs(30);
+const INITIAL_BACKOFF: Duration = Duration::from_secs(2);
+const MAX_ATTEMPTS: u32 = 6;
+// Requests are held back until startup is finished, or this long at most,
+// should discovery never settle or nothing finish it.
+const STARTUP_TIMEOUT: Duration = Duration::from_secs(60);
 
 #[derive(Serialize)]
 #[serde(rename_all = "camelCase")]
@@ -7,15 +34,161 @@ struct SyncRequestBody {
     agent_user_id: &'static str,
 }
 
-pub async fn request_sync() -> Result<(), surf::Error> {
+async fn send(token: &str) -> Result<surf::Response, surf::Error> {
     surf::post("https://homegraph.googleapis.com/v1/devices:requestSync")
-        .header(
-            "Authorization",
-            format!("Bearer {}", std::env::var("HOME_GRAPH_TOKEN").unwrap()),
-        )
+        .header("Authorization", format!("Bearer {}", token))
         .body(Body::from_json(&SyncRequestBody {
             agent_user_id: "haha.yes",
         })?)
-        .await?;
+        .await
+}
+
+fn token() -> Result<String, surf::Error> {
+    credential("HOME_GRAPH_TOKEN")
+        .ok_or_else(|| surf::Error::from_str(StatusCode::Unauthorized, "HOME_GRAPH_TOKEN not set"))
+}
+
+/// Asks Google to SYNC, failing unless it accepts the request.
+pub async fn request_sync() -> Result<(), surf::Error> {
+    check_token(&token()?).await
+}
+
+/// Makes a sync request with `token`, failing unless Google accepts it.
+pub(crate) async fn check_token(token: &str) -> Result<(), surf::Error> {
+    let response = send(token).await?;
+    if !response.status().is_success() {
+        return Err(surf::Error::from_str(
+            response.status(),
+            format!("HomeGraph responded with {}", response.status()),
+        ));
+    }
+    Ok(())
+}
+
+async fn report(body: Value) -> Result<(), surf::Error> {
+    let token = token()?;
+    let response =
+        surf::post("https://homegraph.googleapis.com/v1/devices:reportStateAndNotification")
+            .header("Authorization", format!("Bearer {}", token))
+            .body(Body::from_json(&body)?)
+            .await?;
+    if !response.status().is_success() {
+        return Err(surf::Error::from_str(
+            response.status(),
+            format!("HomeGraph responded with {}", response.status()),
+        ));
+    }
     Ok(())
 }
+
+/// Tells Google the state of one device, in the form of a QUERY response,
+/// such as once a command answered as pending has finished.
+pub(crate) async fn report_state(id: &str, state: Value) -> Result<(), surf::Error> {
+    report(json!({
+        "requestId": Uuid::new_v4().to_string(),
+        "agentUserId": "haha.yes",
+        "payload": { "devices": { "states": { id: state } } },
+    }))
+    .await
+}
+
+/// Has Google announce a proactive notification about a device, given by
+/// trait as the notifications API takes it, such as
+/// `{"SensorState":{"priority":0,"name":"WaterLeak","currentSensorState":"leak"}}`.
+pub(crate) async fn send_notification(id: &str, notification: Value) -> Result<(), surf::Error> {
+    report(json!({
+        "requestId": Uuid::new_v4().to_string(),
+        "eventId": Uuid::new_v4().to_string(),
+        "agentUserId": "haha.yes",
+        "payload": { "devices": { "notifications": { id: notification } } },
+    }))
+    .await
+}
+
+enum SyncKind {
+    Debounced,
+    Forced,
+}
+
+pub(crate) struct SyncScheduler {
+    sender: UnboundedSender<SyncKind>,
+    health: Arc<Health>,
+    ready: Mutex<Option<oneshot::Sender<()>>>,
+}
+
+impl SyncScheduler {
+    pub(crate) fn new(spawner: &dyn Spawner, health: Arc<Health>) -> Self {
+        let (sender, receiver) = unbounded();
+        let (ready, startup) = oneshot::channel();
+        spawner.spawn(Box::pin(run(receiver, startup, health.clone())));
+        SyncScheduler {
+            sender,
+            health,
+            ready: Mutex::new(Some(ready)),
+        }
+    }
+    /// Lets requests scheduled so far, and any later ones, reach Google.
+    pub(crate) fn release(&self) {
+        if let Some(ready) = self.ready.lock().unwrap().take() {
+            let _ = ready.send(());
+        }
+    }
+    fn send(&self, kind: SyncKind) {
+        if self.sender.unbounded_send(kind).is_ok() {
+            self.health.sync_queued();
+        }
+    }
+    pub(crate) fn schedule(&self) {
+        self.send(SyncKind::Debounced);
+    }
+    pub(crate) fn force(&self) {
+        self.send(SyncKind::Forced);
+    }
+}
+
+async fn run(
+    mut receiver: UnboundedReceiver<SyncKind>,
+    startup: oneshot::Receiver<()>,
+    health: Arc<Health>,
+) {
+    // Google replaces its device list with whatever the next SYNC returns, so
+    // nothing is requested until startup discovery has settled.
+    select(startup, Timer::after(STARTUP_TIMEOUT)).await;
+    while let Some(kind) = receiver.next().await {
+        health.sync_dequeued();
+        let mut forced = matches!(kind, SyncKind::Forced);
+        let deadline = Instant::now() + MAX_DEBOUNCE;
+        while !forced {
+            let left = deadline.saturating_duration_since(Instant::now());
+            if left == Duration::from_secs(0) {
+                break;
+            }
+            Timer::after(DEBOUNCE.min(left)).await;
+            let mut quiet = true;
+            while let Ok(Some(kind)) = receiver.try_next() {
+                health.sync_dequeued();
+                quiet = false;
+                forced |= matches!(kind, SyncKind::Forced);
+            }
+            if quiet {
+                break;
+            }
+        }
+        let mut backoff = INITIAL_BACKOFF;
+        let mut result = Ok(());
+        for attempt in 1..=MAX_ATTEMPTS {
+            result = request_sync().await.map_err(|e| e.to_string());
+            match &result {
+                Ok(()) => break,
+                Err(e) => {
+                    eprintln!("sync request failed (attempt {}): {}", attempt, e);
+                    if attempt < MAX_ATTEMPTS {
+                        Timer::after(backoff).await;
+                        backoff *= 2;
+                    }
+                }
+            }
+        }
+        health.sync_finished(result);
+    }
+}
@@ -0,0 +1,341 @@
+use std::sync::Arc;
+
+use async_lock::RwLock;
+use lights_api::{LightId, Pattern, Press, SensorReading, Source, WeatherCondition};
+use serde::Deserialize;
+
+use crate::{
+    alert,
+    api::{scenes, start_alert, start_scene},
+    policy::Origin,
+    ui::Scope,
+    App, Color, PowerState,
+};
+
+/// Automations run on sensor readings, including the weather, from
+/// `rules.toml`.
+#[derive(Default, Deserialize)]
+pub struct RulesConfig {
+    #[serde(default, rename = "rule")]
+    pub rules: Vec<Rule>,
+}
+
+/// Switches lights or a scene when a sensor reports, such as turning the
+/// hallway on when it sees motion or a fan plug on above 27°C.
+#[derive(Clone, Deserialize)]
+pub struct Rule {
+    pub sensor: String,
+    /// The button the rule is for on sensors with more than one, or any of
+    /// them if unset.
+    #[serde(default)]
+    pub button: Option<u32>,
+    pub when: Trigger,
+    pub then: Action,
+    #[serde(default)]
+    pub lights: Vec<String>,
+    /// A scene saved with `SnapshotScene`, for the scene actions.
+    #[serde(default)]
+    pub scene: Option<String>,
+    /// Degrees Celsius or percent humidity for the climate triggers, which
+    /// fire as a reading crosses it.
+    #[serde(default)]
+    pub threshold: Option<f32>,
+    /// For the `color` and `notify` actions.
+    #[serde(default)]
+    pub color: Option<lights_api::Color>,
+    /// How `notify` flashes the lights, `flash` unless set.
+    #[serde(default)]
+    pub pattern: Option<Pattern>,
+    #[serde(default = "default_cycles")]
+    pub cycles: u32,
+    /// Announced on Google's speakers each time the rule fires, even if its
+    /// action fails, so `on` or `off` with no lights only announces.
+    #[serde(default)]
+    pub announce: Option<Announcement>,
+}
+
+fn default_cycles() -> u32 {
+    3
+}
+
+/// A proactive notification about a device listed to Google, given by trait
+/// as Google's notifications API takes it.
+#[derive(Clone, Deserialize)]
+pub struct Announcement {
+    pub device: String,
+    pub notification: serde_json::Value,
+}
+
+#[derive(Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Trigger {
+    Occupied,
+    Vacant,
+    Opened,
+    Closed,
+    Click,
+    DoubleClick,
+    Hold,
+    TemperatureAbove,
+    TemperatureBelow,
+    HumidityAbove,
+    HumidityBelow,
+    /// Rain, drizzle or a thunderstorm starts.
+    Raining,
+    Snowing,
+    /// Frost is forecast, having not been.
+    Frost,
+}
+
+impl Trigger {
+    /// The trigger a reading fires on its own, leaving out those that
+    /// depend on a threshold.
+    fn of(reading: SensorReading) -> Option<Self> {
+        Some(match reading {
+            SensorReading::Motion { occupied: true } => Trigger::Occupied,
+            SensorReading::Motion { occupied: false } => Trigger::Vacant,
+            SensorReading::Contact { open: true } => Trigger::Opened,
+            SensorReading::Contact { open: false } => Trigger::Closed,
+            SensorReading::Button { press, .. } => match press {
+                Press::Click => Trigger::Click,
+                Press::DoubleClick => Trigger::DoubleClick,
+                Press::Hold => Trigger::Hold,
+            },
+            SensorReading::Climate { .. } | SensorReading::Weather { .. } => return None,
+        })
+    }
+
+    /// Whether the weather is as the trigger waits for, for the weather
+    /// triggers.
+    fn holds(self, reading: Option<SensorReading>) -> bool {
+        let (condition, frost) = match reading {
+            Some(SensorReading::Weather {
+                condition, frost, ..
+            }) => (condition, frost),
+            _ => return false,
+        };
+        match self {
+            Trigger::Raining => matches!(
+                condition,
+                WeatherCondition::Drizzle | WeatherCondition::Rain | WeatherCondition::Thunderstorm
+            ),
+            Trigger::Snowing => condition == WeatherCondition::Snow,
+            Trigger::Frost => frost,
+            _ => false,
+        }
+    }
+}
+
+fn climate(reading: Option<SensorReading>) -> (Option<f32>, Option<f32>) {
+    match reading {
+        Some(SensorReading::Climate { celsius, humidity }) => (celsius, humidity),
+        Some(SensorReading::Weather { celsius, .. }) => (celsius, None),
+        _ => (None, None),
+    }
+}
+
+/// Whether a measurement went past `threshold`, above it or below it, from
+/// the other side. The first measurement counts if it is past it.
+fn crossed(before: Option<f32>, now: Option<f32>, threshold: f32, above: bool) -> bool {
+    let past = |value: f32| {
+        if above {
+            value > threshold
+        } else {
+            value < threshold
+        }
+    };
+    match now {
+        Some(now) if past(now) => before.map_or(true, |before| !past(before)),
+        _ => false,
+    }
+}
+
+#[derive(Clone, Copy, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Action {
+    On,
+    Off,
+    /// Switches the lights off if any of them are on, and on otherwise.
+    Toggle,
+    Scene,
+    /// Switches the scene's lights off if any it turns on are on, and runs
+    /// the scene otherwise.
+    ToggleScene,
+    /// Switches the lights on in `color`.
+    Color,
+    /// Flashes the lights in `color`, then puts them back as they were.
+    Notify,
+}
+
+impl Rule {
+    fn matches(
+        &self,
+        sensor: &str,
+        previous: Option<SensorReading>,
+        reading: SensorReading,
+    ) -> bool {
+        if self.sensor != sensor {
+            return false;
+        }
+        let (celsius, humidity) = climate(Some(reading));
+        let (last_celsius, last_humidity) = climate(previous);
+        // Nothing crosses a missing threshold.
+        let threshold = self.threshold.unwrap_or(f32::NAN);
+        match self.when {
+            Trigger::TemperatureAbove => crossed(last_celsius, celsius, threshold, true),
+            Trigger::TemperatureBelow => crossed(last_celsius, celsius, threshold, false),
+            Trigger::HumidityAbove => crossed(last_humidity, humidity, threshold, true),
+            Trigger::HumidityBelow => crossed(last_humidity, humidity, threshold, false),
+            Trigger::Raining | Trigger::Snowing | Trigger::Frost => {
+                self.when.holds(Some(reading)) && !self.when.holds(previous)
+            }
+            when => {
+                let button = match reading {
+                    SensorReading::Button { button, .. } => Some(button),
+                    _ => None,
+                };
+                Trigger::of(reading) == Some(when)
+                    && self.button.map_or(true, |wanted| button == Some(wanted))
+            }
+        }
+    }
+}
+
+impl App {
+    pub fn set_rules(&mut self, rules: Vec<Rule>) {
+        self.rules = rules;
+    }
+    fn any_on<'a, I: IntoIterator<Item = &'a String>>(&self, lights: I) -> bool {
+        lights
+            .into_iter()
+            .any(|id| self.light(id).map_or(false, |light| self.state(light).on))
+    }
+    /// Switches lights on or off, going on past any that fail and leaving out
+    /// those held after a change by hand. Policies apply as they do to API
+    /// requests.
+    async fn switch(&self, lights: &[String], state: PowerState, source: &Source) {
+        for light in lights.iter().filter(|light| !self.overridden(light)) {
+            let result = async {
+                self.permit(light, Origin::Api)?;
+                self.set_state(light, state).await
+            }
+            .await;
+            match result {
+                Ok(()) => self.attribute(light, source),
+                Err(e) => eprintln!("rule failed on {}: {}", light, e),
+            }
+        }
+    }
+    /// Switches lights on in a color, as `switch` does.
+    async fn paint(&self, lights: &[String], color: Color, source: &Source) {
+        for light in lights.iter().filter(|light| !self.overridden(light)) {
+            let result = async {
+                self.permit(light, Origin::Api)?;
+                self.set_state(light, PowerState::On).await?;
+                self.set_color(light, color).await
+            }
+            .await;
+            match result {
+                Ok(()) => self.attribute(light, source),
+                Err(e) => eprintln!("rule failed on {}: {}", light, e),
+            }
+        }
+    }
+}
+
+async fn run(app: &Arc<RwLock<App>>, rule: &Rule) -> Result<(), String> {
+    let source = Source::Rule {
+        sensor: rule.sensor.clone(),
+    };
+    let state = match rule.then {
+        Action::On => Some(PowerState::On),
+        Action::Off => Some(PowerState::Off),
+        Action::Toggle if app.read().await.any_on(&rule.lights) => Some(PowerState::Off),
+        Action::Toggle => Some(PowerState::On),
+        Action::Scene | Action::ToggleScene | Action::Color | Action::Notify => None,
+    };
+    if let Some(state) = state {
+        app.read().await.switch(&rule.lights, state, &source).await;
+        return Ok(());
+    }
+    if let Action::Color | Action::Notify = rule.then {
+        let color = rule
+            .color
+            .clone()
+            .ok_or_else(|| "color rule without a color".to_owned())?;
+        let color = app
+            .read()
+            .await
+            .resolve_color(color)
+            .map_err(|e| e.to_string())?;
+        if let Action::Color = rule.then {
+            app.read().await.paint(&rule.lights, color, &source).await;
+            return Ok(());
+        }
+        let pattern = match rule.pattern {
+            Some(Pattern::Pulse) => alert::Pattern::Pulse,
+            Some(Pattern::Flash) | None => alert::Pattern::Flash,
+        };
+        let lights = {
+            let app = app.read().await;
+            rule.lights
+                .iter()
+                .filter(|light| !app.overridden(light) && app.permit(light, Origin::Api).is_ok())
+                .map(|light| LightId(light.clone()))
+                .collect()
+        };
+        return start_alert(app, lights, color, pattern, rule.cycles).await;
+    }
+    let name = rule
+        .scene
+        .as_deref()
+        .ok_or_else(|| "scene rule without a scene".to_owned())?;
+    let mut entries = scenes()
+        .get(name)
+        .map_err(|e| e.to_string())?
+        .ok_or_else(|| format!("no scene `{}`", name))?;
+    {
+        let app = app.read().await;
+        entries.retain(|entry| !app.overridden(entry.light.as_str()));
+    }
+    if let Action::ToggleScene = rule.then {
+        let lights = entries
+            .iter()
+            .filter(|entry| entry.on)
+            .map(|entry| entry.light.0.clone())
+            .collect::<Vec<_>>();
+        let app = app.read().await;
+        if app.any_on(&lights) {
+            app.switch(&lights, PowerState::Off, &source).await;
+            return Ok(());
+        }
+    }
+    start_scene(app, entries, Scope::Full, source).await
+}
+
+/// Runs the rules a sensor's reading triggers.
+pub(crate) async fn run_rules(
+    app: &Arc<RwLock<App>>,
+    sensor: &str,
+    previous: Option<SensorReading>,
+    reading: SensorReading,
+) {
+    let triggered = app
+        .read()
+        .await
+        .rules
+        .iter()
+        .filter(|rule| rule.matches(sensor, previous, reading))
+        .cloned()
+        .collect::<Vec<_>>();
+    for rule in triggered {
+        if let Err(e) = run(app, &rule).await {
+            eprintln!("rule for sensor {} failed: {}", sensor, e);
+        }
+        if let Some(announcement) = rule.announce {
+            app.read()
+                .await
+                .announce(&announcement.device, announcement.notification);
+        }
+    }
+}
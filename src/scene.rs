@@ -0,0 +1,144 @@
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use async_io::Timer;
+use async_lock::RwLock;
+use futures::future::join_all;
+use lights_api::Source;
+
+use crate::{poll::refresh, App, Color, Error, PowerState};
+
+const STEP: Duration = Duration::from_millis(100);
+
+/// A single light's part in a scene. Entries start `delay` after the scene
+/// does and fade brightness and color over `transition`.
+#[derive(Clone)]
+pub(crate) struct SceneEntry {
+    pub(crate) light: String,
+    pub(crate) on: bool,
+    pub(crate) brightness: Option<u8>,
+    pub(crate) color: Option<Color>,
+    pub(crate) transition: Duration,
+    pub(crate) delay: Duration,
+}
+
+fn lerp(from: u8, to: u8, progress: f32) -> u8 {
+    (from as f32 + (to as f32 - from as f32) * progress).round() as u8
+}
+
+fn blend(from: Color, to: Color, progress: f32) -> Color {
+    match (from, to) {
+        (
+            Color::Rgb { r, g, b },
+            Color::Rgb {
+                r: tr,
+                g: tg,
+                b: tb,
+            },
+        ) => Color::Rgb {
+            r: lerp(r, tr, progress),
+            g: lerp(g, tg, progress),
+            b: lerp(b, tb, progress),
+        },
+        (
+            Color::White { temperature },
+            Color::White {
+                temperature: target,
+            },
+        ) => Color::White {
+            temperature: (temperature as f32 + (target as f32 - temperature as f32) * progress)
+                as u32,
+        },
+        _ => to,
+    }
+}
+
+// Switching off is never faded, since dimming to zero would leave the cached
+// brightness at zero for the next time the light comes on.
+async fn transition(app: &RwLock<App>, entry: &SceneEntry) -> Result<(), Error> {
+    let id = entry.light.as_str();
+    if !entry.on {
+        return app.read().await.set_state(id, PowerState::Off).await;
+    }
+    let (brightness, color, was_on) = {
+        let app = app.read().await;
+        let light = app.light(id).ok_or(Error::Absent)?;
+        (light.brightness(), light.rgb_color(), light.is_on())
+    };
+    let brightness = if was_on { brightness } else { 0 };
+    app.read().await.set_state(id, PowerState::On).await?;
+    let steps = (entry.transition.as_millis() / STEP.as_millis()).max(1) as u32;
+    let start = Instant::now();
+    for step in 1..=steps {
+        let progress = step as f32 / steps as f32;
+        let app = app.read().await;
+        if let Some(target) = entry.brightness {
+            app.set_brightness(id, lerp(brightness, target, progress).into())
+                .await?;
+        }
+        if let Some(target) = entry.color {
+            app.set_color(id, blend(color, target, progress)).await?;
+        }
+        drop(app);
+        if step < steps {
+            Timer::at(start + STEP * step).await;
+        }
+    }
+    Ok(())
+}
+
+/// Runs every entry of a scene concurrently, each after its own delay, and
+/// reports the first failure once all of them have finished.
+pub(crate) async fn run_scene(
+    app: Arc<RwLock<App>>,
+    entries: Vec<SceneEntry>,
+    source: Source,
+) -> Result<(), Error> {
+    let start = Instant::now();
+    join_all(entries.iter().map(|entry| {
+        let app = app.clone();
+        let source = &source;
+        async move {
+            Timer::at(start + entry.delay).await;
+            transition(&app, entry).await?;
+            app.read().await.attribute(&entry.light, source);
+            Ok(())
+        }
+    }))
+    .await
+    .into_iter()
+    .collect()
+}
+
+/// Entries that bring `lights` back to the state they are in now. Devices
+/// that have to be polled are read first, rather than trusting the cache.
+pub(crate) async fn snapshot(
+    app: &RwLock<App>,
+    lights: &[String],
+) -> Result<Vec<SceneEntry>, Error> {
+    for id in lights {
+        let polled = app.read().await.polled(id);
+        if let Some(device) = polled {
+            if let Err(e) = refresh(app, &*device).await {
+                eprintln!("failed to read {}, using its cached state: {}", id, e);
+            }
+        }
+    }
+    let app = app.read().await;
+    lights
+        .iter()
+        .map(|id| {
+            let state = app.state(app.light(id).ok_or(Error::Absent)?);
+            Ok(SceneEntry {
+                light: id.clone(),
+                on: state.on,
+                brightness: Some(state.brightness),
+                color: state.color,
+                transition: Duration::from_secs(0),
+                delay: Duration::from_secs(0),
+            })
+        })
+        .collect()
+}
@@ -0,0 +1,56 @@
+use serde::{de::DeserializeOwned, Serialize};
+use serde_json::Value;
+
+/// A payload read into its model, with the paths of any fields the model
+/// doesn't know, such as ones Google added to its format since.
+pub(crate) struct Read<T> {
+    pub(crate) value: T,
+    pub(crate) unknown: Vec<String>,
+}
+
+impl<T> Read<T> {
+    pub(crate) fn map<U, F: FnOnce(T) -> U>(self, f: F) -> Read<U> {
+        Read {
+            value: f(self.value),
+            unknown: self.unknown,
+        }
+    }
+}
+
+/// Reads `raw`, found at `path` of the request, into its model. Fields are
+/// unknown when writing the model back out leaves them behind, so models
+/// must serialize every field they read under the name they read it by.
+pub(crate) fn read<T: DeserializeOwned + Serialize>(
+    raw: Value,
+    path: &str,
+) -> Result<Read<T>, serde_json::Error> {
+    let value = T::deserialize(&raw)?;
+    let known = serde_json::to_value(&value)?;
+    let mut unknown = vec![];
+    collect(&raw, &known, path, &mut unknown);
+    Ok(Read { value, unknown })
+}
+
+fn collect(raw: &Value, known: &Value, path: &str, unknown: &mut Vec<String>) {
+    match (raw, known) {
+        (Value::Object(raw), Value::Object(known)) => {
+            for (key, value) in raw {
+                let path = if path.is_empty() {
+                    key.clone()
+                } else {
+                    format!("{}.{}", path, key)
+                };
+                match known.get(key) {
+                    Some(known) => collect(value, known, &path, unknown),
+                    None => unknown.push(path),
+                }
+            }
+        }
+        (Value::Array(raw), Value::Array(known)) => {
+            for (index, (value, known)) in raw.iter().zip(known).enumerate() {
+                collect(value, known, &format!("{}[{}]", path, index), unknown);
+            }
+        }
+        _ => {}
+    }
+}
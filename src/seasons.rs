@@ -0,0 +1,217 @@
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use async_io::Timer;
+use async_lock::RwLock;
+use futures::{
+    future::{select, Either},
+    StreamExt,
+};
+use lights_api::Source;
+use serde::Deserialize;
+
+use crate::{policy::Origin, storage::storage, App, Error};
+
+const CHECK: Duration = Duration::from_secs(60);
+const SECS_PER_DAY: i64 = 86400;
+
+/// Programs shown on strips on the dates of a season or holiday, from
+/// `seasons.toml`.
+#[derive(Deserialize)]
+pub struct SeasonsConfig {
+    /// Minutes the local time is ahead of UTC, which decides when dates
+    /// start. By default, the solar time zone of the configured location.
+    #[serde(default)]
+    pub utc_offset_minutes: Option<i64>,
+    pub effects: Vec<SeasonalEffect>,
+}
+
+/// A stored program run on `lights` from one date to another, both included,
+/// given as `[month, day]`. An effect ending before it starts, such as from
+/// December to January, spans the new year.
+#[derive(Deserialize)]
+pub struct SeasonalEffect {
+    pub name: String,
+    pub program: String,
+    pub lights: Vec<String>,
+    pub from: (u32, u32),
+    pub until: (u32, u32),
+    /// Of effects on the same light and date, the highest runs, or the one
+    /// listed first of those as high.
+    #[serde(default)]
+    pub priority: i32,
+    /// Whether rules leave the lights alone while the effect runs, rather
+    /// than taking them over for the rest of the day.
+    #[serde(default)]
+    pub over_rules: bool,
+}
+
+impl SeasonalEffect {
+    fn active(&self, date: (u32, u32)) -> bool {
+        if self.from <= self.until {
+            self.from <= date && date <= self.until
+        } else {
+            date >= self.from || date <= self.until
+        }
+    }
+    fn source(&self) -> Source {
+        Source::Season {
+            effect: self.name.clone(),
+        }
+    }
+}
+
+/// Days since the Unix epoch at `offset_minutes` from UTC, with the month and
+/// day of the month.
+fn today(offset_minutes: i64) -> (i64, (u32, u32)) {
+    let secs = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_secs() as i64);
+    let days = (secs + offset_minutes * 60).div_euclid(SECS_PER_DAY);
+    // Howard Hinnant's `civil_from_days`, with years starting in March so
+    // that leap days come last.
+    let shifted = days + 719468;
+    let of_era = shifted.rem_euclid(146097);
+    let year = (of_era - of_era / 1460 + of_era / 36524 - of_era / 146096) / 365;
+    let of_year = of_era - (365 * year + year / 4 - year / 100);
+    let month = (5 * of_year + 2) / 153;
+    let day = of_year - (153 * month + 2) / 5 + 1;
+    let month = if month < 10 { month + 3 } else { month - 9 };
+    (days, (month as u32, day as u32))
+}
+
+struct Seasons {
+    config: SeasonsConfig,
+    /// The effect showing on each light.
+    showing: HashMap<String, usize>,
+    /// Lights changed by something else while an effect showed, and the day
+    /// they were, which effects leave alone until the next.
+    taken: HashMap<String, i64>,
+    /// Lights the last start of an effect failed on, so that failures are
+    /// only logged once.
+    failing: HashSet<String>,
+}
+
+impl Seasons {
+    /// The effect that should show on a light today.
+    fn winner(&self, light: &str, date: (u32, u32)) -> Option<usize> {
+        self.config
+            .effects
+            .iter()
+            .enumerate()
+            .filter(|(_, effect)| effect.active(date) && effect.lights.iter().any(|l| l == light))
+            .rev()
+            .max_by_key(|(_, effect)| effect.priority)
+            .map(|(index, _)| index)
+    }
+    /// Gives up lights that something other than their effect changed.
+    fn notice(&mut self, app: &App, day: i64) {
+        let effects = &self.config.effects;
+        let taken = self
+            .showing
+            .iter()
+            .filter(|(light, index)| {
+                let source = app.light(light).and_then(|wrapper| app.source(wrapper));
+                source != Some(effects[**index].source())
+            })
+            .map(|(light, _)| light.clone())
+            .collect::<Vec<_>>();
+        for light in taken {
+            self.showing.remove(&light);
+            app.claim(&light, false);
+            self.taken.insert(light, day);
+        }
+    }
+    async fn start(&self, app: &App, light: &str, effect: &SeasonalEffect) -> Result<(), Error> {
+        app.permit(light, Origin::Api)?;
+        let program = storage()
+            .blobs("programs")
+            .get(&effect.program)
+            .map_err(|e| Error::InvalidConfig(e.to_string()))?
+            .ok_or_else(|| Error::InvalidConfig(format!("no program `{}`", effect.program)))?;
+        app.upload_program(light, &program).await?;
+        app.attribute(light, &effect.source());
+        app.claim(light, effect.over_rules);
+        Ok(())
+    }
+    /// Starts and ends effects for the date.
+    async fn update(&mut self, app: &App, (day, date): (i64, (u32, u32))) {
+        self.notice(app, day);
+        self.taken.retain(|_, taken| *taken == day);
+        let mut lights = self
+            .config
+            .effects
+            .iter()
+            .flat_map(|effect| effect.lights.iter().cloned())
+            .collect::<Vec<_>>();
+        lights.sort();
+        lights.dedup();
+        for light in lights {
+            let winner = self.winner(&light, date);
+            let showing = self.showing.get(&light).copied();
+            if winner == showing || self.taken.contains_key(&light) {
+                continue;
+            }
+            match winner {
+                Some(index) if !app.held_by_hand(&light) => {
+                    let effect = &self.config.effects[index];
+                    match self.start(app, &light, effect).await {
+                        Ok(()) => {
+                            self.failing.remove(&light);
+                            self.showing.insert(light, index);
+                        }
+                        Err(e) => {
+                            if self.failing.insert(light.clone()) {
+                                eprintln!("failed to start {} on {}: {}", effect.name, light, e);
+                            }
+                        }
+                    }
+                }
+                Some(_) => {}
+                None => {
+                    self.showing.remove(&light);
+                    app.claim(&light, false);
+                    if let Err(e) = app.reapply(&light).await {
+                        eprintln!("failed to restore {} after its effect: {}", light, e);
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Runs seasonal effects on their dates, handing each light back to its
+/// usual control when its effect ends or when someone changes it.
+pub async fn seasons(app: Arc<RwLock<App>>, config: SeasonsConfig) {
+    let (location, mut changes) = {
+        let app = app.read().await;
+        (app.location(), app.subscribe())
+    };
+    let offset = config.utc_offset_minutes.unwrap_or_else(|| {
+        location.map_or(0, |location| (location.longitude / 15.).round() as i64 * 60)
+    });
+    let mut seasons = Seasons {
+        config,
+        showing: HashMap::new(),
+        taken: HashMap::new(),
+        failing: HashSet::new(),
+    };
+    let mut check = Timer::after(Duration::from_secs(0));
+    loop {
+        match select(changes.next(), &mut check).await {
+            Either::Left((Some(changed), _)) => {
+                if seasons.showing.contains_key(&changed) {
+                    seasons.notice(&*app.read().await, today(offset).0);
+                }
+            }
+            Either::Left((None, _)) => return,
+            Either::Right(_) => {
+                seasons.update(&*app.read().await, today(offset)).await;
+                check = Timer::after(CHECK);
+            }
+        }
+    }
+}
@@ -0,0 +1,170 @@
+use std::{
+    collections::HashMap,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use async_lock::RwLock;
+use lights_api::{Press, SensorId, SensorReading};
+use warp::{filters::BoxedFilter, Filter, Reply};
+
+use crate::{
+    fulfill::sensor_state, request_sync::report_state, rules::run_rules, ui::authorized, App, Error,
+};
+
+/// Held at least this long, a press is a hold rather than a click.
+const HOLD: Duration = Duration::from_millis(800);
+/// A click this soon after the last one on the same button is a double
+/// click.
+const DOUBLE_CLICK: Duration = Duration::from_millis(400);
+
+/// Timing of buttons that only report being pressed and released, by
+/// sensor and button.
+#[derive(Default)]
+pub(crate) struct Presses {
+    down: HashMap<(String, u32), Instant>,
+    clicked: HashMap<(String, u32), Instant>,
+}
+
+impl Presses {
+    fn release(&mut self, key: (String, u32), now: Instant) -> Option<Press> {
+        let down = self.down.remove(&key)?;
+        if now.duration_since(down) >= HOLD {
+            return Some(Press::Hold);
+        }
+        match self.clicked.remove(&key) {
+            Some(clicked) if now.duration_since(clicked) <= DOUBLE_CLICK => {
+                Some(Press::DoubleClick)
+            }
+            _ => {
+                self.clicked.insert(key, now);
+                Some(Press::Click)
+            }
+        }
+    }
+}
+
+impl App {
+    /// Records a reading from an input device, registering it the first time
+    /// it reports. Returns the reading before it if rules should run, along
+    /// with the reading itself, completed from the one before.
+    fn record_reading(
+        &self,
+        id: &str,
+        reading: SensorReading,
+    ) -> Option<(Option<SensorReading>, SensorReading)> {
+        self.registry.remember_sensor(id, reading.kind());
+        let mut sensors = self.sensors.lock().unwrap();
+        let previous = sensors.get(id).copied();
+        let reading = match (reading, previous) {
+            (
+                SensorReading::Climate { celsius, humidity },
+                Some(SensorReading::Climate {
+                    celsius: last_celsius,
+                    humidity: last_humidity,
+                }),
+            ) => SensorReading::Climate {
+                celsius: celsius.or(last_celsius),
+                humidity: humidity.or(last_humidity),
+            },
+            _ => reading,
+        };
+        sensors.insert(id.to_owned(), reading);
+        drop(sensors);
+        if let SensorReading::Climate { celsius, humidity } = reading {
+            self.telemetry.lock().unwrap().record(id, celsius, humidity);
+        }
+        // Sensors repeat a state for as long as it holds, such as motion
+        // sensors while they see movement, which only counts once.
+        if previous == Some(reading) && !reading.momentary() {
+            return None;
+        }
+        let exposed = self
+            .registry
+            .sensor(id)
+            .map_or(false, |sensor| sensor.exposed);
+        if exposed && !reading.momentary() {
+            let id = id.to_owned();
+            self.spawner.spawn(Box::pin(async move {
+                if let Err(e) = report_state(&id, sensor_state(Some(reading))).await {
+                    eprintln!("failed to report state of sensor {}: {:?}", id, e);
+                }
+            }));
+        }
+        Some((previous, reading))
+    }
+    pub(crate) fn sensor_reading(&self, id: &str) -> Option<SensorReading> {
+        self.sensors.lock().unwrap().get(id).copied()
+    }
+    pub(crate) fn sensors(&self) -> Vec<lights_api::Sensor> {
+        let mut sensors = self
+            .registry
+            .sensors()
+            .into_iter()
+            .map(|(id, sensor)| lights_api::Sensor {
+                reading: self.sensor_reading(&id),
+                id: SensorId(id),
+                name: sensor.name,
+                kind: sensor.kind,
+                room: sensor.room,
+                exposed: sensor.exposed,
+                last_seen: sensor.last_seen,
+            })
+            .collect::<Vec<_>>();
+        sensors.sort_by(|a, b| a.id.cmp(&b.id));
+        sensors
+    }
+    /// Lists a sensor to Google or leaves it out.
+    pub(crate) fn set_sensor_exposed(&self, id: &str, exposed: bool) -> Result<(), Error> {
+        self.registry.set_sensor_exposed(id, exposed)?;
+        self.sync.schedule();
+        Ok(())
+    }
+}
+
+/// Records a reading from an input device and runs the rules it triggers.
+pub async fn report_sensor(app: &Arc<RwLock<App>>, id: &str, reading: SensorReading) {
+    let recorded = app.read().await.record_reading(id, reading);
+    if let Some((previous, reading)) = recorded {
+        run_rules(app, id, previous, reading).await;
+    }
+}
+
+/// Reports a click, double click or hold of a button that only tells when
+/// it is pressed and released, such as on a Lutron Pico remote. A double
+/// click follows the click it starts with, and a hold is reported once the
+/// button is released.
+pub async fn press_button(app: &Arc<RwLock<App>>, sensor: &str, button: u32, pressed: bool) {
+    let press = {
+        let app = app.read().await;
+        let mut presses = app.presses.lock().unwrap();
+        let key = (sensor.to_owned(), button);
+        if pressed {
+            presses.down.insert(key, Instant::now());
+            None
+        } else {
+            presses.release(key, Instant::now())
+        }
+    };
+    if let Some(press) = press {
+        report_sensor(app, sensor, SensorReading::Button { button, press }).await;
+    }
+}
+
+/// `POST /sensors/<id>` records a reading, such as
+/// `{"Motion":{"occupied":true}}`, for sensors and firmware that can only
+/// call a webhook.
+pub fn sensors(app: Arc<RwLock<App>>) -> BoxedFilter<(impl Reply,)> {
+    warp::post()
+        .and(warp::path!("sensors" / String))
+        .and(authorized())
+        .and(warp::body::json())
+        .and_then(move |id: String, reading: SensorReading| {
+            let app = app.clone();
+            async move {
+                report_sensor(&app, &id, reading).await;
+                Ok::<_, core::convert::Infallible>(warp::reply())
+            }
+        })
+        .boxed()
+}
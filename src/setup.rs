@@ -0,0 +1,352 @@
+#[cfg(unix)]
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+use std::{
+    env,
+    fs::{self, OpenOptions},
+    io::{self, BufRead, Write},
+    path::Path,
+};
+
+use lights_tuya::TuyaApi;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::{
+    request_sync::check_token,
+    storage::Storage,
+    vault::{configured_key, generate_key, keyring_store, KeySource, Vault, VaultError},
+    Location,
+};
+
+/// Settings written by `--setup`, standing in for the environment variables
+/// of the same names.
+#[derive(Serialize, Deserialize, Default)]
+pub struct Config {
+    /// `LIGHTS_DATA`
+    pub data_dir: Option<String>,
+    /// `LIGHTS_CREDENTIALS_KEY`, which the credentials vault is encrypted
+    /// with. The OS keyring is used when neither is set.
+    pub credentials_key: Option<String>,
+    /// `HOME_GRAPH_TOKEN`
+    pub home_graph_token: Option<String>,
+    /// `TUYA_USER`
+    pub tuya_user: Option<String>,
+    /// `TUYA_PASS`
+    pub tuya_pass: Option<String>,
+    /// `MQTT_BROKER`
+    pub mqtt_broker: Option<String>,
+    /// `MQTT_USER`
+    pub mqtt_user: Option<String>,
+    /// `MQTT_PASS`
+    pub mqtt_pass: Option<String>,
+    pub location: Option<Location>,
+}
+
+#[derive(Debug, Error)]
+pub enum ConfigError {
+    #[error("io error: {0}")]
+    Io(#[from] io::Error),
+    #[error("invalid config: {0}")]
+    Parse(#[from] toml::de::Error),
+    #[error("failed to encode config: {0}")]
+    Encode(#[from] toml::ser::Error),
+    #[error(transparent)]
+    Vault(#[from] VaultError),
+}
+
+impl Config {
+    /// Reads the file at `path`, or the default config if there isn't one.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, ConfigError> {
+        match fs::read_to_string(path) {
+            Ok(data) => Ok(toml::from_str(&data)?),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Config::default()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Sets the environment variables the file stands in for, leaving any
+    /// that are already set so the environment still takes precedence. Must
+    /// be called before anything reads them.
+    pub fn export(&self) {
+        let vars = [
+            ("LIGHTS_DATA", &self.data_dir),
+            ("LIGHTS_CREDENTIALS_KEY", &self.credentials_key),
+            ("HOME_GRAPH_TOKEN", &self.home_graph_token),
+            ("TUYA_USER", &self.tuya_user),
+            ("TUYA_PASS", &self.tuya_pass),
+            ("MQTT_BROKER", &self.mqtt_broker),
+            ("MQTT_USER", &self.mqtt_user),
+            ("MQTT_PASS", &self.mqtt_pass),
+        ];
+        for (name, value) in vars.iter() {
+            if let Some(value) = value {
+                if env::var_os(name).is_none() {
+                    env::set_var(name, value);
+                }
+            }
+        }
+    }
+}
+
+/// Asks a question on the terminal, returning `None` for an empty answer
+/// when there is no default.
+fn ask(question: &str, default: Option<&str>) -> io::Result<Option<String>> {
+    match default {
+        Some(default) => print!("{} [{}]: ", question, default),
+        None => print!("{}: ", question),
+    }
+    io::stdout().flush()?;
+    let mut answer = String::new();
+    if io::stdin().lock().read_line(&mut answer)? == 0 {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    let answer = answer.trim();
+    Ok(if answer.is_empty() {
+        default.map(str::to_owned)
+    } else {
+        Some(answer.to_owned())
+    })
+}
+
+fn data_dir_writable(dir: &str) -> io::Result<()> {
+    fs::create_dir_all(dir)?;
+    let probe = Path::new(dir).join(".setup");
+    fs::write(&probe, b"")?;
+    fs::remove_file(probe)
+}
+
+fn location(latitude: &str, longitude: &str) -> Result<Location, String> {
+    let parse = |value: &str, limit: f64| {
+        value
+            .parse::<f64>()
+            .ok()
+            .filter(|value| value.abs() <= limit)
+            .ok_or_else(|| format!("`{}` isn't a coordinate", value))
+    };
+    Ok(Location {
+        latitude: parse(latitude, 90.)?,
+        longitude: parse(longitude, 180.)?,
+    })
+}
+
+/// Writes the config readable only by its owner, as it can hold the
+/// credentials key.
+fn save(path: &Path, config: &Config) -> Result<(), ConfigError> {
+    let data = toml::to_string_pretty(config)?;
+    let mut options = OpenOptions::new();
+    options.create(true).write(true).truncate(true);
+    #[cfg(unix)]
+    options.mode(0o600);
+    let mut file = options.open(path)?;
+    // A config written before keeps its mode when opened.
+    #[cfg(unix)]
+    file.set_permissions(fs::Permissions::from_mode(0o600))?;
+    file.write_all(data.as_bytes())?;
+    println!("wrote {}", path.display());
+    Ok(())
+}
+
+/// The credentials key, generating one into the config if there is none
+/// anywhere yet.
+fn credentials_key(config: &mut Config) -> Result<(String, KeySource), ConfigError> {
+    if env::var_os("LIGHTS_CREDENTIALS_KEY").is_none() {
+        if let Some(key) = &config.credentials_key {
+            return Ok((key.clone(), KeySource::Environment));
+        }
+    }
+    if let Some(found) = configured_key() {
+        return Ok(found);
+    }
+    let key = generate_key()?;
+    println!("generated a credentials key, kept in the config");
+    config.credentials_key = Some(key.clone());
+    Ok((key, KeySource::Environment))
+}
+
+fn open_storage(config: &Config) -> Result<Storage, ConfigError> {
+    let dir = env::var("LIGHTS_DATA")
+        .ok()
+        .or_else(|| config.data_dir.clone())
+        .unwrap_or_else(|| "data".to_owned());
+    Ok(Storage::open(dir).map_err(VaultError::from)?)
+}
+
+fn open_vault(config: &mut Config) -> Result<Vault, ConfigError> {
+    let (key, _) = credentials_key(config)?;
+    let storage = open_storage(config)?;
+    let vault = Vault::open(&storage, Some(key))?;
+    vault.migrate(&storage)?;
+    Ok(vault)
+}
+
+/// Asks for the credentials of the cloud integrations, checking each, and
+/// stores them in the vault rather than the config.
+async fn enter_credentials(config: &mut Config, vault: &Vault) -> Result<(), ConfigError> {
+    let current = |name: &str, plain: &Option<String>| vault.secret(name).or_else(|| plain.clone());
+
+    let home_graph_token = current("HOME_GRAPH_TOKEN", &config.home_graph_token);
+    loop {
+        let token = match ask(
+            "HomeGraph access token (empty to skip)",
+            home_graph_token.as_deref(),
+        )? {
+            Some(token) => token,
+            None => break,
+        };
+        match check_token(&token).await {
+            Ok(()) => {
+                vault.put("HOME_GRAPH_TOKEN", token.as_bytes())?;
+                break;
+            }
+            Err(e) => println!("HomeGraph rejected the token: {}", e),
+        }
+    }
+
+    let (tuya_user, tuya_pass) = (
+        current("TUYA_USER", &config.tuya_user),
+        current("TUYA_PASS", &config.tuya_pass),
+    );
+    loop {
+        let user = match ask("Tuya user (empty to skip)", tuya_user.as_deref())? {
+            Some(user) => user,
+            None => break,
+        };
+        let pass = ask("Tuya password", tuya_pass.as_deref())?.unwrap_or_default();
+        match TuyaApi::new(&user, &pass).await {
+            Ok(_) => {
+                vault.put("TUYA_USER", user.as_bytes())?;
+                vault.put("TUYA_PASS", pass.as_bytes())?;
+                // The cached session belongs to the old account.
+                vault.remove("TUYA_TOKEN")?;
+                break;
+            }
+            Err(e) => println!("Tuya login failed: {}", e),
+        }
+    }
+
+    if config.mqtt_broker.is_some() {
+        let mqtt_user = current("MQTT_USER", &config.mqtt_user);
+        if let Some(user) = ask("MQTT user (empty for none)", mqtt_user.as_deref())? {
+            let mqtt_pass = current("MQTT_PASS", &config.mqtt_pass);
+            let pass = ask("MQTT password", mqtt_pass.as_deref())?.unwrap_or_default();
+            vault.put("MQTT_USER", user.as_bytes())?;
+            vault.put("MQTT_PASS", pass.as_bytes())?;
+        }
+    }
+
+    let tunnel_token = vault.secret("TUNNEL_TOKEN");
+    if let Some(token) = ask(
+        "Tunnel relay token (empty to skip)",
+        tunnel_token.as_deref(),
+    )? {
+        vault.put("TUNNEL_TOKEN", token.as_bytes())?;
+    }
+
+    config.home_graph_token = None;
+    config.tuya_user = None;
+    config.tuya_pass = None;
+    config.mqtt_user = None;
+    config.mqtt_pass = None;
+    Ok(())
+}
+
+/// Asks for the credentials of every cloud integration again, such as after
+/// a password change, keeping the rest of the config.
+pub async fn credentials<P: AsRef<Path>>(path: P) -> Result<(), ConfigError> {
+    let path = path.as_ref();
+    let mut config = Config::load(path)?;
+    let vault = open_vault(&mut config)?;
+    enter_credentials(&mut config, &vault).await?;
+    save(path, &config)
+}
+
+/// Seals the stored credentials under a new key, which replaces the old one
+/// in the config or the keyring, wherever that was kept.
+pub fn rotate_credentials_key<P: AsRef<Path>>(path: P) -> Result<(), ConfigError> {
+    let path = path.as_ref();
+    let mut config = Config::load(path)?;
+    let storage = open_storage(&config)?;
+    let (old, source) = credentials_key(&mut config)?;
+    let old = Vault::open(&storage, Some(old))?;
+    old.migrate(&storage)?;
+    let key = generate_key()?;
+    old.rotate(&Vault::open(&storage, Some(key.clone()))?)?;
+    // The credentials can only be read with the new key from here on, so it
+    // is shown if it can't be saved.
+    let saved = match source {
+        KeySource::Keyring => keyring_store(&key).map_err(ConfigError::from),
+        KeySource::Environment => {
+            if env::var_os("LIGHTS_CREDENTIALS_KEY").is_some() {
+                println!(
+                    "LIGHTS_CREDENTIALS_KEY is set, replace it with the new key from the config"
+                );
+            }
+            config.credentials_key = Some(key.clone());
+            save(path, &config)
+        }
+    };
+    if saved.is_err() {
+        eprintln!("failed to save the new credentials key: {}", key);
+    }
+    saved
+}
+
+/// Interactively collects settings, checking each one as it's entered, and
+/// writes them to `path`. Answers from an existing file are offered as
+/// defaults, and optional settings are skipped by leaving them empty.
+pub async fn setup<P: AsRef<Path>>(path: P) -> Result<(), ConfigError> {
+    let path = path.as_ref();
+    let mut config = Config::load(path)?;
+
+    loop {
+        let dir = ask(
+            "Data directory",
+            Some(config.data_dir.as_deref().unwrap_or("data")),
+        )?
+        .unwrap_or_default();
+        match data_dir_writable(&dir) {
+            Ok(()) => {
+                config.data_dir = Some(dir);
+                break;
+            }
+            Err(e) => println!("can't write to `{}`: {}", dir, e),
+        }
+    }
+
+    config.mqtt_broker = ask(
+        "MQTT broker address (empty to skip)",
+        config.mqtt_broker.as_deref(),
+    )?;
+    let vault = open_vault(&mut config)?;
+    enter_credentials(&mut config, &vault).await?;
+
+    loop {
+        let current = config.location.map(|location| {
+            (
+                location.latitude.to_string(),
+                location.longitude.to_string(),
+            )
+        });
+        let latitude = match ask(
+            "Latitude (empty to skip)",
+            current.as_ref().map(|(latitude, _)| latitude.as_str()),
+        )? {
+            Some(latitude) => latitude,
+            None => break,
+        };
+        let longitude = ask(
+            "Longitude, positive east",
+            current.as_ref().map(|(_, longitude)| longitude.as_str()),
+        )?
+        .unwrap_or_default();
+        match location(&latitude, &longitude) {
+            Ok(location) => {
+                config.location = Some(location);
+                break;
+            }
+            Err(e) => println!("{}", e),
+        }
+    }
+
+    save(path, &config)
+}
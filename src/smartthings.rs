@@ -0,0 +1,422 @@
+use std::{convert::Infallible, sync::Arc};
+
+use async_lock::RwLock;
+use bytes::Bytes;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use warp::{filters::BoxedFilter, http::StatusCode, Filter, Reply};
+
+use crate::{
+    auth::valid_token,
+    intent::{self, DeviceCommand, DeviceQuery, DeviceSync},
+    policy::Origin,
+    App, Brightness, Color, DeviceKind, Error, LightError,
+};
+
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+struct Headers {
+    schema: String,
+    version: String,
+    interaction_type: String,
+    request_id: String,
+}
+
+#[derive(Deserialize)]
+struct Authentication {
+    token: String,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Request {
+    headers: Headers,
+    authentication: Option<Authentication>,
+    #[serde(default)]
+    devices: Vec<DeviceRequest>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct DeviceRequest {
+    external_device_id: String,
+    #[serde(default)]
+    commands: Vec<Command>,
+}
+
+#[derive(Deserialize)]
+struct Command {
+    capability: String,
+    command: String,
+    #[serde(default)]
+    arguments: Vec<Value>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Response {
+    headers: Headers,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    devices: Option<Vec<Device>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    device_state: Option<Vec<DeviceState>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    global_error: Option<ErrorDetail>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Device {
+    external_device_id: String,
+    friendly_name: String,
+    manufacturer_info: ManufacturerInfo,
+    device_handler_type: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    device_context: Option<DeviceContext>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct ManufacturerInfo {
+    manufacturer_name: &'static str,
+    model_name: &'static str,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct DeviceContext {
+    room_name: String,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct DeviceState {
+    external_device_id: String,
+    states: Vec<State>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    device_error: Vec<ErrorDetail>,
+}
+
+#[derive(Serialize)]
+struct State {
+    component: &'static str,
+    capability: &'static str,
+    attribute: &'static str,
+    value: Value,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct ErrorDetail {
+    error_enum: &'static str,
+    detail: String,
+}
+
+impl ErrorDetail {
+    fn new(error_enum: &'static str, detail: impl ToString) -> Self {
+        ErrorDetail {
+            error_enum,
+            detail: detail.to_string(),
+        }
+    }
+}
+
+fn error_enum(error: &Error) -> &'static str {
+    match error {
+        Error::Absent => "DEVICE-DELETED",
+        Error::Light(LightError::Offline) | Error::Light(LightError::TimedOut) => "DEVICE-OFFLINE",
+        Error::Light(LightError::RateLimited) => "RESOURCE-CONSTRAINT-VIOLATION",
+        Error::Light(LightError::AuthExpired)
+        | Error::Light(LightError::Protocol(_))
+        | Error::Light(LightError::Other(_))
+        | Error::NothingToUndo
+        | Error::InvalidConfig(_)
+        | Error::Payload(_)
+        | Error::Unsupported
+        | Error::Policy(_)
+        | Error::Standby => "DEVICE-UNAVAILABLE",
+    }
+}
+
+fn state(capability: &'static str, attribute: &'static str, value: Value) -> State {
+    State {
+        component: "main",
+        capability,
+        attribute,
+        value,
+    }
+}
+
+fn rgb(hue: f64, saturation: f64) -> Color {
+    let chroma = saturation;
+    let x = chroma * (1. - ((hue / 60.) % 2. - 1.).abs());
+    let (r, g, b) = match (hue / 60.) as u32 {
+        0 => (chroma, x, 0.),
+        1 => (x, chroma, 0.),
+        2 => (0., chroma, x),
+        3 => (0., x, chroma),
+        4 => (x, 0., chroma),
+        _ => (chroma, 0., x),
+    };
+    let m = 1. - chroma;
+    let channel = |value: f64| ((value + m) * 255.).round() as u8;
+    Color::Rgb {
+        r: channel(r),
+        g: channel(g),
+        b: channel(b),
+    }
+}
+
+/// Hue in degrees and saturation from 0 to 1.
+fn hue_saturation(color: Color) -> (f64, f64) {
+    let (r, g, b) = color.to_rgb();
+    let r = r as f64 / 255.;
+    let g = g as f64 / 255.;
+    let b = b as f64 / 255.;
+    let cmax = r.max(g.max(b));
+    let diff = cmax - r.min(g.min(b));
+    let hue = if diff == 0. {
+        0.
+    } else if cmax == r {
+        (60. * ((g - b) / diff) + 360.) % 360.
+    } else if cmax == g {
+        60. * ((b - r) / diff) + 120.
+    } else {
+        60. * ((r - g) / diff) + 240.
+    };
+    (hue, if cmax == 0. { 0. } else { diff / cmax })
+}
+
+fn device(device: DeviceSync) -> Device {
+    Device {
+        external_device_id: device.id,
+        friendly_name: device.name,
+        manufacturer_info: ManufacturerInfo {
+            manufacturer_name: "lights",
+            model_name: "light",
+        },
+        device_handler_type: if device.supports_color {
+            "c2c-rgbw-color-bulb"
+        } else {
+            "c2c-dimmer"
+        },
+        device_context: device
+            .room_hint
+            .map(|room_name| DeviceContext { room_name }),
+    }
+}
+
+fn device_state(query: DeviceQuery, mut device_error: Vec<ErrorDetail>) -> DeviceState {
+    let mut states = vec![
+        state(
+            "st.switch",
+            "switch",
+            json!(if query.on { "on" } else { "off" }),
+        ),
+        state("st.switchLevel", "level", json!(query.brightness.percent())),
+        state(
+            "st.healthCheck",
+            "healthStatus",
+            json!(if query.online { "online" } else { "offline" }),
+        ),
+    ];
+    match query.color.filter(|_| query.supports_color) {
+        Some(Color::White { temperature }) => states.push(state(
+            "st.colorTemperature",
+            "colorTemperature",
+            json!(temperature),
+        )),
+        Some(color) => {
+            let (hue, saturation) = hue_saturation(color);
+            states.push(state(
+                "st.colorControl",
+                "hue",
+                json!((hue / 3.6).round() as u8),
+            ));
+            states.push(state(
+                "st.colorControl",
+                "saturation",
+                json!((saturation * 100.).round() as u8),
+            ));
+        }
+        None => {}
+    }
+    if !query.online && device_error.is_empty() {
+        device_error.push(ErrorDetail::new("DEVICE-OFFLINE", "device offline"));
+    }
+    DeviceState {
+        external_device_id: query.id,
+        states,
+        device_error,
+    }
+}
+
+/// SmartThings' commands in the form every assistant shares, given the
+/// light's current color for commands that only change part of it.
+fn device_command(command: &Command, current: Option<Color>) -> Result<DeviceCommand, String> {
+    let number = |index: usize| command.arguments.get(index).and_then(Value::as_f64);
+    let (hue, saturation) = current.map_or((0., 0.), hue_saturation);
+    let command = match (command.capability.as_str(), command.command.as_str()) {
+        ("st.switch", "on") => Some(DeviceCommand::Power(true)),
+        ("st.switch", "off") => Some(DeviceCommand::Power(false)),
+        ("st.switchLevel", "setLevel") => number(0).map(|level| {
+            DeviceCommand::Brightness(Brightness::from_percent(level.max(0.).round() as u32))
+        }),
+        ("st.colorControl", "setColor") => {
+            let color = command.arguments.get(0);
+            let component = |name| color.and_then(|color| color[name].as_f64());
+            match (component("hue"), component("saturation")) {
+                (Some(hue), Some(saturation)) => {
+                    Some(DeviceCommand::Color(rgb(hue * 3.6, saturation / 100.)))
+                }
+                _ => None,
+            }
+        }
+        ("st.colorControl", "setHue") => {
+            number(0).map(|hue| DeviceCommand::Color(rgb(hue * 3.6, saturation)))
+        }
+        ("st.colorControl", "setSaturation") => {
+            number(0).map(|saturation| DeviceCommand::Color(rgb(hue, saturation / 100.)))
+        }
+        ("st.colorTemperature", "setColorTemperature") => number(0).map(|temperature| {
+            DeviceCommand::Color(Color::White {
+                temperature: temperature as u32,
+            })
+        }),
+        _ => {
+            return Err(format!(
+                "{}.{} isn't supported",
+                command.capability, command.command
+            ))
+        }
+    };
+    command.ok_or_else(|| "missing or invalid arguments".to_owned())
+}
+
+async fn command(app: &App, request: &DeviceRequest) -> DeviceState {
+    let id = &request.external_device_id;
+    let light = match app.light(id) {
+        Some(light) => light,
+        None => {
+            return DeviceState {
+                external_device_id: id.clone(),
+                states: vec![],
+                device_error: vec![ErrorDetail::new("DEVICE-DELETED", Error::Absent)],
+            }
+        }
+    };
+    let mut current = intent::query_device(app, light).color;
+    let mut commands = vec![];
+    for command in &request.commands {
+        match device_command(command, current) {
+            Ok(command) => {
+                if let DeviceCommand::Color(color) = command {
+                    current = Some(color);
+                }
+                commands.push(command);
+            }
+            Err(e) => {
+                return device_state(
+                    intent::query_device(app, light),
+                    vec![ErrorDetail::new("CAPABILITY-NOT-SUPPORTED", e)],
+                )
+            }
+        }
+    }
+    let errors = match intent::execute(app, id, &commands, Origin::Assistant).await {
+        Ok(()) => vec![],
+        Err(e) => vec![ErrorDetail::new(error_enum(&e), e)],
+    };
+    device_state(intent::query_device(app, light), errors)
+}
+
+async fn interact(request: Request, app: &App) -> Response {
+    let mut response = Response {
+        headers: request.headers.clone(),
+        devices: None,
+        device_state: None,
+        global_error: None,
+    };
+    let authorized = request
+        .authentication
+        .as_ref()
+        .map_or(false, |authentication| valid_token(&authentication.token));
+    if !authorized {
+        response.headers.interaction_type = "interactionResult".to_owned();
+        response.global_error = Some(ErrorDetail::new("TOKEN-EXPIRED", "token not recognized"));
+        return response;
+    }
+    match request.headers.interaction_type.as_str() {
+        "discoveryRequest" => {
+            // Only lights have a device handler here.
+            response.devices = Some(
+                intent::sync(app)
+                    .into_iter()
+                    .filter(|sync| sync.kind == DeviceKind::Light)
+                    .map(device)
+                    .collect(),
+            );
+            response.headers.interaction_type = "discoveryResponse".to_owned();
+        }
+        "stateRefreshRequest" => {
+            let mut states = vec![];
+            for device in &request.devices {
+                let id = &device.external_device_id;
+                states.push(match app.light(id) {
+                    Some(light) => device_state(intent::query_device(app, light), vec![]),
+                    None => DeviceState {
+                        external_device_id: id.clone(),
+                        states: vec![],
+                        device_error: vec![ErrorDetail::new("DEVICE-DELETED", Error::Absent)],
+                    },
+                });
+            }
+            response.device_state = Some(states);
+            response.headers.interaction_type = "stateRefreshResponse".to_owned();
+        }
+        "commandRequest" => {
+            let mut states = vec![];
+            for device in &request.devices {
+                states.push(command(app, device).await);
+            }
+            response.device_state = Some(states);
+            response.headers.interaction_type = "commandResponse".to_owned();
+        }
+        // Nothing is kept per SmartThings installation, so there is nothing
+        // to set up or clean up.
+        "grantCallbackAccess" | "integrationDeleted" | "interactionResult" => {}
+        other => {
+            response.headers.interaction_type = "interactionResult".to_owned();
+            response.global_error = Some(ErrorDetail::new(
+                "INVALID-INTERACTION-TYPE",
+                format!("unsupported interaction type {}", other),
+            ));
+        }
+    }
+    response
+}
+
+/// `POST /smartthings` answers SmartThings Schema connector interactions,
+/// with account linking done through the same OAuth endpoints as Google.
+pub fn smartthings(app: Arc<RwLock<App>>) -> BoxedFilter<(impl Reply,)> {
+    warp::post()
+        .and(warp::path("smartthings"))
+        .and(warp::path::end())
+        .and(warp::body::bytes())
+        .and_then(move |data: Bytes| {
+            let app = app.clone();
+            async move {
+                Ok::<_, Infallible>(match serde_json::from_slice(&data) {
+                    Ok(request) => warp::reply::with_status(
+                        warp::reply::json(&interact(request, &*app.read().await).await),
+                        StatusCode::OK,
+                    ),
+                    Err(e) => warp::reply::with_status(
+                        warp::reply::json(&json!({ "error": e.to_string() })),
+                        StatusCode::BAD_REQUEST,
+                    ),
+                })
+            }
+        })
+        .boxed()
+}
@@ -0,0 +1,49 @@
+use std::collections::HashSet;
+
+use lights_api::Source;
+
+use crate::{policy::Origin, App, LightWrapper};
+
+impl From<Origin> for Source {
+    fn from(origin: Origin) -> Self {
+        match origin {
+            Origin::Assistant => Source::Assistant,
+            Origin::Api => Source::Api,
+            Origin::Override => Source::Override,
+        }
+    }
+}
+
+impl App {
+    /// Records what last changed a light and, for groups, each of their
+    /// members.
+    pub(crate) fn attribute(&self, id: &str, source: &Source) {
+        let mut pending = vec![id.to_owned()];
+        let mut seen = HashSet::new();
+        while let Some(id) = pending.pop() {
+            if !seen.insert(id.clone()) {
+                continue;
+            }
+            let wrapper = match self.light(&id) {
+                Some(wrapper) => wrapper,
+                None => continue,
+            };
+            let changed = {
+                let mut current = wrapper.source.lock().unwrap();
+                let changed = current.as_ref() != Some(source);
+                *current = Some(source.clone());
+                changed
+            };
+            if changed {
+                self.notify(&wrapper.id);
+            }
+            self.note_change(&id, source);
+            if let Some(members) = wrapper.light().members() {
+                pending.extend(members);
+            }
+        }
+    }
+    pub(crate) fn source(&self, light: &LightWrapper) -> Option<Source> {
+        light.source.lock().unwrap().clone()
+    }
+}
@@ -0,0 +1,25 @@
+use futures::future::BoxFuture;
+
+pub trait Spawner: Send + Sync {
+    fn spawn(&self, task: BoxFuture<'static, ()>);
+}
+
+#[cfg(feature = "smol")]
+pub struct SmolSpawner;
+
+#[cfg(feature = "smol")]
+impl Spawner for SmolSpawner {
+    fn spawn(&self, task: BoxFuture<'static, ()>) {
+        smol::spawn(task).detach();
+    }
+}
+
+#[cfg(feature = "tokio")]
+pub struct TokioSpawner;
+
+#[cfg(feature = "tokio")]
+impl Spawner for TokioSpawner {
+    fn spawn(&self, task: BoxFuture<'static, ()>) {
+        tokio::spawn(task);
+    }
+}
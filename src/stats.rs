@@ -0,0 +1,108 @@
+use std::{
+    collections::VecDeque,
+    time::{Duration, Instant},
+};
+
+use lights_api::{DeviceStatistics, LightId};
+
+use crate::{forwards, App, LightError};
+
+/// How many of a device's most recent commands the statistics cover.
+const WINDOW: usize = 200;
+
+enum Outcome {
+    Succeeded,
+    TimedOut,
+    Failed,
+}
+
+/// Latency and outcome of a device's recent commands, oldest first.
+#[derive(Default)]
+pub(crate) struct Stats {
+    samples: VecDeque<(Duration, Outcome)>,
+    last: Option<Instant>,
+}
+
+impl Stats {
+    pub(crate) fn record(&mut self, latency: Duration, result: &Result<(), LightError>) {
+        if self.samples.len() == WINDOW {
+            self.samples.pop_front();
+        }
+        let outcome = match result {
+            Ok(()) => Outcome::Succeeded,
+            Err(LightError::TimedOut) => Outcome::TimedOut,
+            Err(_) => Outcome::Failed,
+        };
+        self.samples.push_back((latency, outcome));
+        self.last = Some(Instant::now());
+    }
+
+    /// When the device was last sent a command.
+    pub(crate) fn last_command(&self) -> Option<Instant> {
+        self.last
+    }
+
+    fn summary(&self, id: String, name: String, integration: String) -> DeviceStatistics {
+        let mut latencies = self
+            .samples
+            .iter()
+            .map(|(latency, _)| *latency)
+            .collect::<Vec<_>>();
+        latencies.sort();
+        // Nearest-rank, so a single slow command shows up in p99 of a
+        // short window.
+        let percentile = |p: usize| {
+            let rank = (latencies.len() * p + 99) / 100;
+            latencies
+                .get(rank.max(1) - 1)
+                .map(|latency| latency.as_millis() as u32)
+        };
+        let count = |f: fn(&Outcome) -> bool| {
+            self.samples
+                .iter()
+                .filter(|(_, outcome)| f(outcome))
+                .count() as u32
+        };
+        let commands = self.samples.len() as u32;
+        let succeeded = count(|outcome| matches!(outcome, Outcome::Succeeded));
+        DeviceStatistics {
+            id: LightId(id),
+            name,
+            integration,
+            commands,
+            success_rate: if commands == 0 {
+                1.
+            } else {
+                succeeded as f32 / commands as f32
+            },
+            timeouts: count(|outcome| matches!(outcome, Outcome::TimedOut)),
+            p50_ms: percentile(50),
+            p90_ms: percentile(90),
+            p99_ms: percentile(99),
+        }
+    }
+}
+
+impl App {
+    /// Statistics of every device, slowest first. Groups and composites are
+    /// left out, since their commands are counted on their members.
+    pub(crate) fn device_stats(&self) -> Vec<DeviceStatistics> {
+        let mut devices = self
+            .lights()
+            .filter(|light| !forwards(light.light()))
+            .map(|light| {
+                light.stats.lock().unwrap().summary(
+                    light.id(),
+                    light.name(),
+                    light.light().vendor().to_owned(),
+                )
+            })
+            .collect::<Vec<_>>();
+        devices.sort_by(|a, b| {
+            b.p90_ms
+                .cmp(&a.p90_ms)
+                .then(a.success_rate.partial_cmp(&b.success_rate).unwrap())
+        });
+        devices
+    }
+}
@@ -0,0 +1,268 @@
+use std::{
+    fs::{self, OpenOptions},
+    io::{self, Write},
+    marker::PhantomData,
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use lazy_static::lazy_static;
+use serde::{de::DeserializeOwned, Serialize};
+use thiserror::Error;
+
+const VERSION_FILE: &str = "version";
+
+static WRITES: AtomicUsize = AtomicUsize::new(0);
+
+lazy_static! {
+    static ref STORAGE: Storage = {
+        let root = std::env::var("LIGHTS_DATA").unwrap_or_else(|_| "data".to_owned());
+        Storage::open(root).expect("failed to open data directory")
+    };
+}
+
+/// The data directory shared by everything the server persists, opened from
+/// `LIGHTS_DATA` (default `data`) on first use.
+pub(crate) fn storage() -> &'static Storage {
+    &STORAGE
+}
+
+#[derive(Debug, Error)]
+pub enum StorageError {
+    #[error("storage io error: {0}")]
+    Io(#[from] io::Error),
+    #[error("malformed stored value: {0}")]
+    Format(#[from] serde_json::Error),
+    #[error("failed to convert legacy file: {0}")]
+    Legacy(#[from] toml::de::Error),
+    #[error("invalid storage key `{0}`")]
+    Key(String),
+}
+
+// Keys and namespaces become paths in the data directory, so anything that
+// could escape it or collide with a temporary file is refused.
+pub(crate) fn valid(name: &str) -> bool {
+    !name.is_empty()
+        && !name.starts_with('.')
+        && !name.ends_with(".tmp")
+        && !name.contains('/')
+        && !name.contains('\\')
+}
+
+pub(crate) struct Storage {
+    root: PathBuf,
+}
+
+/// Writes `data` next to `path` and renames it into place, so readers see
+/// either the old or the new contents, never a torn write. Each write gets
+/// its own temporary file, so concurrent writes can't mix.
+fn write_atomic(path: &Path, data: &[u8]) -> io::Result<()> {
+    let name = path
+        .file_name()
+        .map_or_else(Default::default, |name| name.to_string_lossy());
+    let tmp = path.with_file_name(format!(
+        "{}.{}.{}.tmp",
+        name,
+        std::process::id(),
+        WRITES.fetch_add(1, Ordering::SeqCst)
+    ));
+    let mut file = OpenOptions::new()
+        .create(true)
+        .write(true)
+        .truncate(true)
+        .open(&tmp)?;
+    file.write_all(data)?;
+    file.sync_all()?;
+    fs::rename(tmp, path)
+}
+
+// Each entry upgrades the directory from the version equal to its index.
+const MIGRATIONS: &[fn(&Storage) -> Result<(), StorageError>] =
+    &[import_legacy_files, import_tuya_addresses];
+
+/// Moves the ad-hoc files used before the data directory existed into their
+/// namespaces.
+fn import_legacy_files(storage: &Storage) -> Result<(), StorageError> {
+    let tuya = storage.blobs("tuya");
+    if let Ok(token) = fs::read("tuya/access_token") {
+        tuya.put("access_token", &token)?;
+    }
+    if let Ok(devices) = fs::read_to_string("tuya/devices.toml") {
+        let devices: toml::Value = toml::from_str(&devices)?;
+        storage
+            .store::<toml::Value>("tuya")
+            .put("devices", &devices)?;
+    }
+    if let Ok(entries) = fs::read_dir("programs") {
+        let programs = storage.blobs("programs");
+        for entry in entries {
+            let path = entry?.path();
+            if let Some(name) = path.file_stem().and_then(|name| name.to_str()) {
+                programs.put(name, &fs::read(&path)?)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Keeps the addresses of the bulbs listed in `tuya/local.toml`, leaving out
+/// their local keys, which are fetched from the cloud into the vault since.
+fn import_tuya_addresses(storage: &Storage) -> Result<(), StorageError> {
+    if let Ok(local) = fs::read_to_string("tuya/local.toml") {
+        let mut local: toml::Value = toml::from_str(&local)?;
+        if let Some(devices) = local.get_mut("devices").and_then(|d| d.as_array_mut()) {
+            for device in devices
+                .iter_mut()
+                .filter_map(|device| device.as_table_mut())
+            {
+                device.remove("local_key");
+            }
+        }
+        storage.store::<toml::Value>("tuya").put("local", &local)?;
+    }
+    Ok(())
+}
+
+impl Storage {
+    pub(crate) fn open<P: Into<PathBuf>>(root: P) -> Result<Self, StorageError> {
+        let storage = Storage { root: root.into() };
+        fs::create_dir_all(&storage.root)?;
+        let version_path = storage.root.join(VERSION_FILE);
+        let mut version = fs::read_to_string(&version_path)
+            .ok()
+            .and_then(|version| version.trim().parse::<usize>().ok())
+            .unwrap_or(0);
+        while let Some(migration) = MIGRATIONS.get(version) {
+            migration(&storage)?;
+            version += 1;
+            write_atomic(&version_path, version.to_string().as_bytes())?;
+        }
+        Ok(storage)
+    }
+
+    /// Every stored item as `(namespace, key, contents)`.
+    pub(crate) fn entries(&self) -> Result<Vec<(String, String, Vec<u8>)>, StorageError> {
+        let mut entries = vec![];
+        for entry in fs::read_dir(&self.root)? {
+            let entry = entry?;
+            if !entry.file_type()?.is_dir() {
+                continue;
+            }
+            if let Some(namespace) = entry.file_name().to_str() {
+                let blobs = self.blobs(namespace);
+                for key in blobs.keys()? {
+                    if let Some(data) = blobs.get(&key)? {
+                        entries.push((namespace.to_owned(), key, data));
+                    }
+                }
+            }
+        }
+        Ok(entries)
+    }
+
+    /// The directory of a namespace, for data kept as plain files.
+    pub(crate) fn namespace(&self, namespace: &str) -> PathBuf {
+        self.root.join(namespace)
+    }
+
+    /// JSON values of a single type, one file per key.
+    pub(crate) fn store<T: Serialize + DeserializeOwned>(&self, namespace: &str) -> Store<T> {
+        Store {
+            blobs: self.blobs(namespace),
+            _marker: PhantomData,
+        }
+    }
+
+    /// Opaque byte strings, one file per key.
+    pub(crate) fn blobs(&self, namespace: &str) -> Blobs {
+        Blobs {
+            dir: self.namespace(namespace),
+        }
+    }
+}
+
+pub(crate) struct Blobs {
+    dir: PathBuf,
+}
+
+impl Blobs {
+    fn path(&self, key: &str) -> Result<PathBuf, StorageError> {
+        if !valid(key) {
+            return Err(StorageError::Key(key.to_owned()));
+        }
+        Ok(self.dir.join(key))
+    }
+
+    pub(crate) fn get(&self, key: &str) -> Result<Option<Vec<u8>>, StorageError> {
+        match fs::read(self.path(key)?) {
+            Ok(data) => Ok(Some(data)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    pub(crate) fn put(&self, key: &str, data: &[u8]) -> Result<(), StorageError> {
+        let path = self.path(key)?;
+        fs::create_dir_all(&self.dir)?;
+        write_atomic(&path, data)?;
+        Ok(())
+    }
+
+    pub(crate) fn remove(&self, key: &str) -> Result<(), StorageError> {
+        match fs::remove_file(self.path(key)?) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+
+    pub(crate) fn keys(&self) -> Result<Vec<String>, StorageError> {
+        let entries = match fs::read_dir(&self.dir) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(vec![]),
+            Err(e) => return Err(e.into()),
+        };
+        let mut keys = vec![];
+        for entry in entries {
+            let entry = entry?;
+            // Directories hold plain-file data such as git checkouts, which
+            // isn't made of blobs.
+            if entry.file_type()?.is_dir() {
+                continue;
+            }
+            let path = entry.path();
+            if path.extension().map_or(false, |ext| ext == "tmp") {
+                continue;
+            }
+            if let Some(name) = path.file_name().and_then(|name| name.to_str()) {
+                keys.push(name.to_owned());
+            }
+        }
+        Ok(keys)
+    }
+}
+
+pub(crate) struct Store<T> {
+    blobs: Blobs,
+    _marker: PhantomData<T>,
+}
+
+impl<T: Serialize + DeserializeOwned> Store<T> {
+    pub(crate) fn get(&self, key: &str) -> Result<Option<T>, StorageError> {
+        match self.blobs.get(key)? {
+            Some(data) => Ok(Some(serde_json::from_slice(&data)?)),
+            None => Ok(None),
+        }
+    }
+
+    pub(crate) fn put(&self, key: &str, value: &T) -> Result<(), StorageError> {
+        self.blobs.put(key, &serde_json::to_vec_pretty(value)?)
+    }
+
+    pub(crate) fn remove(&self, key: &str) -> Result<(), StorageError> {
+        self.blobs.remove(key)
+    }
+
+    pub(crate) fn keys(&self) -> Result<Vec<String>, StorageError> {
+        self.blobs.keys()
+    }
+}
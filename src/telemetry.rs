@@ -0,0 +1,63 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use lights_api::ClimateSample;
+
+use crate::{App, Error};
+
+/// Seconds of readings kept for each sensor.
+const RETAINED: u64 = 24 * 60 * 60;
+/// Readings kept for each sensor however often it reports, one every 30
+/// seconds over the retained day.
+const MAX_SAMPLES: usize = 2880;
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|since| since.as_secs())
+        .unwrap_or(0)
+}
+
+/// Recent readings of climate sensors, kept only in memory.
+#[derive(Default)]
+pub(crate) struct Telemetry {
+    samples: HashMap<String, VecDeque<ClimateSample>>,
+}
+
+impl Telemetry {
+    pub(crate) fn record(&mut self, id: &str, celsius: Option<f32>, humidity: Option<f32>) {
+        let at = now();
+        let samples = self.samples.entry(id.to_owned()).or_default();
+        while samples.len() >= MAX_SAMPLES
+            || samples
+                .front()
+                .map_or(false, |oldest| oldest.at + RETAINED < at)
+        {
+            samples.pop_front();
+        }
+        samples.push_back(ClimateSample {
+            at,
+            celsius,
+            humidity,
+        });
+    }
+}
+
+impl App {
+    /// A climate sensor's readings over the last day, oldest first.
+    pub(crate) fn climate_history(&self, id: &str) -> Result<Vec<ClimateSample>, Error> {
+        self.registry.sensor(id).ok_or(Error::Absent)?;
+        let telemetry = self.telemetry.lock().unwrap();
+        let horizon = now().saturating_sub(RETAINED);
+        Ok(telemetry
+            .samples
+            .get(id)
+            .into_iter()
+            .flatten()
+            .filter(|sample| sample.at >= horizon)
+            .copied()
+            .collect())
+    }
+}
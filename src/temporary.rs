@@ -0,0 +1,133 @@
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use async_io::Timer;
+use async_lock::RwLock;
+use lights_api::Source;
+
+use crate::{App, Error, Id, LightState, PowerState};
+
+static TOKENS: AtomicU64 = AtomicU64::new(1);
+
+/// The state a light had before the first of one or more stacked temporary
+/// overrides, and the override that currently owns it.
+#[derive(Clone)]
+pub(crate) struct Hold {
+    token: u64,
+    saved: LightState,
+    /// What had changed the light to the saved state, put back with it.
+    source: Option<Source>,
+    revision: usize,
+}
+
+impl App {
+    pub(crate) async fn apply(&self, id: &str, state: LightState) -> Result<(), Error> {
+        if state.on {
+            self.set_state(id, PowerState::On).await?;
+        }
+        if let Some(color) = state.color {
+            self.set_color(id, color).await?;
+        }
+        self.set_brightness(id, state.brightness.into()).await?;
+        if !state.on {
+            self.set_state(id, PowerState::Off).await?;
+        }
+        Ok(())
+    }
+
+    /// Sends a light the state it was last set to again, such as once a
+    /// stream of frames stops drawing over it.
+    pub(crate) async fn reapply(&self, id: &str) -> Result<(), Error> {
+        let wrapper = self.light(id).ok_or(Error::Absent)?;
+        self.apply(id, self.state(wrapper)).await
+    }
+
+    /// Starts a temporary override, remembering the state to return to. An
+    /// override started while another is active inherits its saved state, so
+    /// reverting always lands on what the light looked like before either.
+    pub(crate) fn begin_hold(&self, id: &str) -> Result<u64, Error> {
+        let wrapper = self.by_id.get(&Id(id.into())).ok_or(Error::Absent)?;
+        let mut held = wrapper.held.lock().unwrap();
+        let token = TOKENS.fetch_add(1, Ordering::SeqCst);
+        let (saved, source) = match held.as_ref() {
+            Some(hold) => (hold.saved, hold.source.clone()),
+            None => (self.state(wrapper), self.source(wrapper)),
+        };
+        *held = Some(Hold {
+            token,
+            saved,
+            source,
+            revision: wrapper.revision.load(Ordering::SeqCst),
+        });
+        Ok(token)
+    }
+
+    /// Marks the override's own changes as applied, so that only changes made
+    /// after this point count as the user taking over.
+    pub(crate) fn settle_hold(&self, id: &str, token: u64) {
+        if let Some(wrapper) = self.by_id.get(&Id(id.into())) {
+            if let Some(hold) = wrapper.held.lock().unwrap().as_mut() {
+                if hold.token == token {
+                    hold.revision = wrapper.revision.load(Ordering::SeqCst);
+                }
+            }
+        }
+    }
+
+    /// Ends an override, restoring the saved state unless a newer override
+    /// has replaced it or the light was changed in the meantime.
+    pub(crate) async fn release_hold(&self, id: &str, token: u64) -> Result<(), Error> {
+        let wrapper = self.by_id.get(&Id(id.into())).ok_or(Error::Absent)?;
+        let hold = {
+            let mut held = wrapper.held.lock().unwrap();
+            if held.as_ref().map_or(false, |hold| hold.token == token) {
+                held.take()
+            } else {
+                None
+            }
+        };
+        match hold {
+            Some(hold) if hold.revision == wrapper.revision.load(Ordering::SeqCst) => {
+                self.apply(id, hold.saved).await?;
+                // Putting back what was there isn't a change of its own, so
+                // it starts no override hold.
+                *wrapper.source.lock().unwrap() = hold.source;
+                self.notify(&wrapper.id);
+                Ok(())
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Applies `state` to a light and puts back whatever it replaced once
+/// `duration` has passed.
+pub(crate) async fn hold(
+    app: Arc<RwLock<App>>,
+    id: String,
+    state: LightState,
+    duration: Duration,
+    source: Source,
+) -> Result<(), Error> {
+    let token = {
+        let app = app.read().await;
+        let token = app.begin_hold(&id)?;
+        app.apply(&id, state).await?;
+        app.attribute(&id, &source);
+        app.settle_hold(&id, token);
+        token
+    };
+    let spawner = app.read().await.spawner.clone();
+    spawner.spawn(Box::pin(async move {
+        Timer::after(duration).await;
+        if let Err(e) = app.read().await.release_hold(&id, token).await {
+            eprintln!("failed to revert {}: {:?}", id, e);
+        }
+    }));
+    Ok(())
+}
@@ -0,0 +1,192 @@
+use std::{
+    convert::Infallible,
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    net::SocketAddr,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use futures::future::poll_fn;
+use hyper::{
+    body::to_bytes,
+    service::{make_service_fn, service_fn, Service},
+    Body, Request, Response, Server,
+};
+use serde_json::{json, Value};
+
+// Rotated once it passes this size, keeping this many older files as
+// `<path>.1` (newest) through `<path>.<KEEP>`.
+const MAX_BYTES: u64 = 10 * 1024 * 1024;
+const KEEP: usize = 5;
+
+const REDACTED: &str = "[redacted]";
+
+/// Appends exchanges as JSON lines to a file, rotating it as it grows.
+pub struct TrafficLog {
+    path: PathBuf,
+    file: Mutex<File>,
+}
+
+impl TrafficLog {
+    pub fn open<P: Into<PathBuf>>(path: P) -> io::Result<Self> {
+        let path = path.into();
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        Ok(TrafficLog {
+            path,
+            file: Mutex::new(file),
+        })
+    }
+
+    fn rotated(&self, generation: usize) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(format!(".{}", generation));
+        path.into()
+    }
+
+    fn rotate(&self, file: &mut File) -> io::Result<()> {
+        for generation in (1..KEEP).rev() {
+            let from = self.rotated(generation);
+            if from.exists() {
+                fs::rename(from, self.rotated(generation + 1))?;
+            }
+        }
+        fs::rename(&self.path, self.rotated(1))?;
+        *file = File::create(&self.path)?;
+        Ok(())
+    }
+
+    fn record(&self, entry: &Value) {
+        let mut file = self.file.lock().unwrap();
+        let result = writeln!(file, "{}", entry).and_then(|_| {
+            if file.metadata()?.len() > MAX_BYTES {
+                self.rotate(&mut file)?;
+            }
+            Ok(())
+        });
+        if let Err(e) = result {
+            eprintln!("failed to write traffic log: {}", e);
+        }
+    }
+}
+
+fn secret(key: &str) -> bool {
+    let key = key.to_lowercase();
+    key == "code"
+        || key == "archive"
+        || [
+            "token",
+            "password",
+            "passphrase",
+            "secret",
+            "credential",
+            "authorization",
+            "api_key",
+        ]
+        .iter()
+        .any(|word| key.contains(word))
+}
+
+fn redact(value: &mut Value) {
+    match value {
+        Value::Object(fields) => {
+            for (key, value) in fields.iter_mut() {
+                if secret(key) {
+                    *value = Value::String(REDACTED.to_owned());
+                } else {
+                    redact(value);
+                }
+            }
+        }
+        Value::Array(items) => items.iter_mut().for_each(redact),
+        _ => {}
+    }
+}
+
+fn body(bytes: &[u8]) -> Value {
+    match serde_json::from_slice::<Value>(bytes) {
+        Ok(mut value) => {
+            redact(&mut value);
+            value
+        }
+        Err(_) if bytes.is_empty() => Value::Null,
+        Err(_) => json!({ "unparsed_bytes": bytes.len() }),
+    }
+}
+
+/// `/api/<token>` carries the token in the path.
+fn redact_path(path: &str) -> String {
+    match path.strip_prefix("/api/") {
+        Some(_) => format!("/api/{}", REDACTED),
+        None => path.to_owned(),
+    }
+}
+
+/// Covers `/fulfill/<structure>` as well as `/fulfill`. The streamed
+/// enumerate is left alone, since logging it would buffer the whole stream.
+fn logged(path: &str) -> bool {
+    path == "/fulfill"
+        || path.starts_with("/fulfill/")
+        || (path.starts_with("/api/") && !path.ends_with("/enumerate"))
+}
+
+async fn exchange<S>(
+    mut service: S,
+    log: Arc<TrafficLog>,
+    request: Request<Body>,
+) -> Result<Response<Body>, Infallible>
+where
+    S: Service<Request<Body>, Response = Response<Body>, Error = Infallible>,
+{
+    poll_fn(|cx| service.poll_ready(cx)).await?;
+    let path = request.uri().path().to_owned();
+    if !logged(&path) {
+        return service.call(request).await;
+    }
+    let (parts, request_body) = request.into_parts();
+    let method = parts.method.to_string();
+    let request_body = to_bytes(request_body).await.unwrap_or_default();
+    let response = service
+        .call(Request::from_parts(parts, Body::from(request_body.clone())))
+        .await?;
+    let (parts, response_body) = response.into_parts();
+    let response_body = to_bytes(response_body).await.unwrap_or_default();
+    log.record(&json!({
+        "time": SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|since| since.as_secs())
+            .unwrap_or(0),
+        "method": method,
+        "path": redact_path(&path),
+        "status": parts.status.as_u16(),
+        "request": body(&request_body),
+        "response": body(&response_body),
+    }));
+    Ok(Response::from_parts(parts, Body::from(response_body)))
+}
+
+/// Serves like `warp::serve`, also logging the bodies of every exchange
+/// with `/fulfill` and `/api` with tokens and credentials redacted.
+pub async fn serve_logged<S>(service: S, addr: SocketAddr, log: TrafficLog)
+where
+    S: Service<Request<Body>, Response = Response<Body>, Error = Infallible>
+        + Clone
+        + Send
+        + 'static,
+    S::Future: Send,
+{
+    let log = Arc::new(log);
+    let make = make_service_fn(move |_| {
+        let service = service.clone();
+        let log = log.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |request| {
+                exchange(service.clone(), log.clone(), request)
+            }))
+        }
+    });
+    if let Err(e) = Server::bind(&addr).serve(make).await {
+        eprintln!("server failed: {}", e);
+    }
+}
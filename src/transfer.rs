@@ -0,0 +1,176 @@
+use std::{
+    collections::HashMap,
+    future::Future,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use async_io::Timer;
+use async_lock::Semaphore;
+use futures::channel::mpsc::{unbounded, UnboundedReceiver, UnboundedSender};
+use lights_api::{LightId, TransferProgress, TransferState};
+use serde::Deserialize;
+
+use crate::{App, Error, LightWrapper};
+
+/// Limits on programs sent to strips at once, from `transfers.toml`, so
+/// that updating several strips doesn't saturate the access point.
+#[derive(Clone, Copy, Deserialize)]
+pub struct TransferLimits {
+    /// Transfers in progress at once, beyond which they queue.
+    #[serde(default = "default_concurrent")]
+    pub concurrent: usize,
+    /// Bytes per second across every strip.
+    #[serde(default)]
+    pub bytes_per_sec: Option<u64>,
+    /// Bytes per second to any one strip.
+    #[serde(default)]
+    pub device_bytes_per_sec: Option<u64>,
+}
+
+fn default_concurrent() -> usize {
+    2
+}
+
+impl Default for TransferLimits {
+    fn default() -> Self {
+        TransferLimits {
+            concurrent: default_concurrent(),
+            bytes_per_sec: None,
+            device_bytes_per_sec: None,
+        }
+    }
+}
+
+/// When the network as a whole and each strip are next free under the
+/// bandwidth caps.
+#[derive(Default)]
+struct Schedule {
+    network: Option<Instant>,
+    devices: HashMap<String, Instant>,
+}
+
+pub(crate) struct Transfers {
+    limits: TransferLimits,
+    slots: Semaphore,
+    schedule: Mutex<Schedule>,
+    subscribers: Mutex<Vec<UnboundedSender<TransferProgress>>>,
+}
+
+impl Default for Transfers {
+    fn default() -> Self {
+        Transfers::new(TransferLimits::default())
+    }
+}
+
+fn airtime(bytes: u64, per_sec: Option<u64>) -> Duration {
+    per_sec.map_or(Duration::from_secs(0), |per_sec| {
+        Duration::from_secs_f64(bytes as f64 / per_sec.max(1) as f64)
+    })
+}
+
+impl Transfers {
+    fn new(limits: TransferLimits) -> Self {
+        Transfers {
+            limits,
+            slots: Semaphore::new(limits.concurrent.max(1)),
+            schedule: Mutex::new(Schedule::default()),
+            subscribers: Mutex::new(vec![]),
+        }
+    }
+
+    fn report(&self, light: &str, bytes: u64, state: TransferState) {
+        let progress = TransferProgress {
+            light: LightId(light.to_owned()),
+            bytes,
+            state,
+        };
+        self.subscribers
+            .lock()
+            .unwrap()
+            .retain(|subscriber| subscriber.unbounded_send(progress.clone()).is_ok());
+    }
+
+    /// Books `bytes` of airtime on the network and on the strip, returning
+    /// when the transfer may start.
+    fn book(&self, light: &str, bytes: u64) -> Instant {
+        let mut schedule = self.schedule.lock().unwrap();
+        let now = Instant::now();
+        let device = schedule.devices.get(light).copied();
+        let start = schedule
+            .network
+            .into_iter()
+            .chain(device)
+            .fold(now, Instant::max);
+        if self.limits.bytes_per_sec.is_some() {
+            schedule.network = Some(start + airtime(bytes, self.limits.bytes_per_sec));
+        }
+        if self.limits.device_bytes_per_sec.is_some() {
+            schedule.devices.insert(
+                light.to_owned(),
+                start + airtime(bytes, self.limits.device_bytes_per_sec),
+            );
+        }
+        schedule.devices.retain(|_, free| *free > now);
+        start
+    }
+
+    /// Runs a transfer of `bytes` to a strip once a slot is free and the
+    /// caps allow, reporting its progress to subscribers.
+    ///
+    /// The strip protocol sends a program in one message, so the caps pace
+    /// whole transfers, spacing them by the time they take at the capped
+    /// rate, rather than throttling each one.
+    pub(crate) async fn send<F>(&self, light: &str, bytes: u64, transfer: F) -> Result<(), Error>
+    where
+        F: Future<Output = Result<(), Error>>,
+    {
+        self.report(light, 0, TransferState::Queued);
+        let _slot = self.slots.acquire().await;
+        Timer::at(self.book(light, bytes)).await;
+        self.report(light, 0, TransferState::Sending);
+        let result = transfer.await;
+        match &result {
+            Ok(()) => self.report(light, bytes, TransferState::Done),
+            Err(e) => self.report(
+                light,
+                0,
+                TransferState::Failed {
+                    error: e.to_string(),
+                },
+            ),
+        }
+        result
+    }
+}
+
+/// A program transfer to a strip, holding what it needs so that the app
+/// isn't held while it waits.
+pub(crate) struct ProgramTransfer {
+    pub(crate) wrapper: Arc<LightWrapper>,
+    pub(crate) transfers: Arc<Transfers>,
+}
+
+impl ProgramTransfer {
+    pub(crate) async fn send(self, program: &[u8]) -> Result<(), Error> {
+        self.transfers
+            .send(
+                &self.wrapper.id.0,
+                program.len() as u64,
+                self.wrapper.light().upload_program(program),
+            )
+            .await
+    }
+}
+
+impl App {
+    pub fn set_transfer_limits(&mut self, limits: TransferLimits) {
+        self.transfers = Arc::new(Transfers::new(limits));
+    }
+    /// Progress of every program transfer to a strip from now on.
+    pub(crate) fn subscribe_transfers(&self) -> UnboundedReceiver<TransferProgress> {
+        let (sender, receiver) = unbounded();
+        self.transfers.subscribers.lock().unwrap().push(sender);
+        receiver
+    }
+}
@@ -0,0 +1,207 @@
+use std::{
+    collections::HashMap,
+    convert::Infallible,
+    io,
+    net::{TcpStream, ToSocketAddrs},
+    time::Duration,
+};
+
+use async_io::{Async, Timer};
+use async_native_tls::{TlsConnector, TlsStream};
+use async_tungstenite::{
+    client_async,
+    tungstenite::{
+        self,
+        client::IntoClientRequest,
+        http::{header::AUTHORIZATION, HeaderValue},
+        Message,
+    },
+    WebSocketStream,
+};
+use futures::{future::poll_fn, select, stream::FuturesUnordered, SinkExt, StreamExt};
+use hyper::{body::to_bytes, service::Service, Body, Request, Response};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::credential;
+
+const TOKEN: &str = "TUNNEL_TOKEN";
+const RETRY: Duration = Duration::from_secs(5);
+const MAX_RETRY: Duration = Duration::from_secs(5 * 60);
+
+/// A relay forwarding requests over an outbound connection, for bridges
+/// that can't accept connections from Google, from `tunnel.toml`.
+#[derive(Deserialize)]
+pub struct TunnelConfig {
+    /// `wss://` URL of the relay.
+    pub relay: String,
+    /// Path prefixes the relay may reach.
+    #[serde(default = "default_paths")]
+    pub paths: Vec<String>,
+}
+
+fn default_paths() -> Vec<String> {
+    vec!["/fulfill".to_owned()]
+}
+
+#[derive(Debug, Error)]
+pub enum TunnelError {
+    #[error("io error: {0}")]
+    Io(#[from] io::Error),
+    #[error("tls error: {0}")]
+    Tls(#[from] async_native_tls::Error),
+    #[error("websocket error: {0}")]
+    WebSocket(#[from] tungstenite::Error),
+    #[error("relay `{0}` isn't a wss:// url")]
+    Url(String),
+    #[error("no valid {} credential is set", TOKEN)]
+    Token,
+}
+
+/// A request the relay received, with a base64 body.
+#[derive(Deserialize)]
+struct Tunneled {
+    id: u64,
+    method: String,
+    path: String,
+    #[serde(default)]
+    headers: HashMap<String, String>,
+    #[serde(default)]
+    body: String,
+}
+
+#[derive(Serialize)]
+struct TunneledResponse {
+    id: u64,
+    status: u16,
+    headers: HashMap<String, String>,
+    body: String,
+}
+
+type Socket = WebSocketStream<TlsStream<Async<TcpStream>>>;
+
+async fn connect(relay: &str) -> Result<Socket, TunnelError> {
+    let token = credential(TOKEN).ok_or(TunnelError::Token)?;
+    let mut request = relay.into_client_request()?;
+    let uri = request.uri().clone();
+    let host = match (uri.scheme_str(), uri.host()) {
+        (Some("wss"), Some(host)) => host,
+        _ => return Err(TunnelError::Url(relay.to_owned())),
+    };
+    request.headers_mut().insert(
+        AUTHORIZATION,
+        HeaderValue::from_str(&format!("Bearer {}", token)).map_err(|_| TunnelError::Token)?,
+    );
+    let addr = (host, uri.port_u16().unwrap_or(443))
+        .to_socket_addrs()?
+        .next()
+        .ok_or_else(|| TunnelError::Url(relay.to_owned()))?;
+    let stream = Async::<TcpStream>::connect(addr).await?;
+    let stream = TlsConnector::new().connect(host, stream).await?;
+    let (socket, _) = client_async(request, stream).await?;
+    Ok(socket)
+}
+
+fn reply(id: u64, status: u16) -> TunneledResponse {
+    TunneledResponse {
+        id,
+        status,
+        headers: HashMap::new(),
+        body: String::new(),
+    }
+}
+
+async fn handle<S>(mut service: S, paths: &[String], tunneled: Tunneled) -> TunneledResponse
+where
+    S: Service<Request<Body>, Response = Response<Body>, Error = Infallible>,
+{
+    let path = tunneled.path.split('?').next().unwrap_or_default();
+    if !paths
+        .iter()
+        .any(|allowed| path.starts_with(allowed.as_str()))
+    {
+        return reply(tunneled.id, 404);
+    }
+    let body = match base64::decode(&tunneled.body) {
+        Ok(body) => body,
+        Err(_) => return reply(tunneled.id, 400),
+    };
+    let mut request = Request::builder()
+        .method(tunneled.method.as_str())
+        .uri(tunneled.path.as_str());
+    for (name, value) in &tunneled.headers {
+        request = request.header(name.as_str(), value.as_str());
+    }
+    let request = match request.body(Body::from(body)) {
+        Ok(request) => request,
+        Err(_) => return reply(tunneled.id, 400),
+    };
+    let response = async {
+        poll_fn(|cx| service.poll_ready(cx)).await?;
+        service.call(request).await
+    }
+    .await
+    .unwrap_or_else(|infallible| match infallible {});
+    let (parts, body) = response.into_parts();
+    TunneledResponse {
+        id: tunneled.id,
+        status: parts.status.as_u16(),
+        headers: parts
+            .headers
+            .iter()
+            .filter_map(|(name, value)| Some((name.to_string(), value.to_str().ok()?.to_owned())))
+            .collect(),
+        body: base64::encode(to_bytes(body).await.unwrap_or_default()),
+    }
+}
+
+/// Answers requests from the relay until it disconnects, several at once so
+/// a slow command doesn't hold up the rest.
+async fn serve<S>(service: &S, paths: &[String], socket: Socket) -> Result<(), TunnelError>
+where
+    S: Service<Request<Body>, Response = Response<Body>, Error = Infallible> + Clone,
+{
+    let (mut sink, stream) = socket.split();
+    let mut stream = stream.fuse();
+    let mut pending = FuturesUnordered::new();
+    loop {
+        select! {
+            message = stream.next() => match message.transpose()? {
+                Some(Message::Text(text)) => match serde_json::from_str::<Tunneled>(&text) {
+                    Ok(tunneled) => pending.push(handle(service.clone(), paths, tunneled)),
+                    Err(e) => eprintln!("malformed request from tunnel relay: {}", e),
+                },
+                Some(_) => {}
+                None => return Ok(()),
+            },
+            response = pending.select_next_some() => {
+                let response = serde_json::to_string(&response).expect("responses serialize");
+                sink.send(Message::Text(response)).await?;
+            }
+        }
+    }
+}
+
+/// Keeps a connection open to a relay that passes on requests for the
+/// configured paths, so the server can be reached without an open port. The
+/// relay authenticates the bridge by the `TUNNEL_TOKEN` credential.
+pub async fn tunnel<S>(service: S, config: TunnelConfig)
+where
+    S: Service<Request<Body>, Response = Response<Body>, Error = Infallible> + Clone,
+{
+    let mut retry = RETRY;
+    loop {
+        match connect(&config.relay).await {
+            Ok(socket) => {
+                retry = RETRY;
+                match serve(&service, &config.paths, socket).await {
+                    Ok(()) => eprintln!("tunnel relay closed the connection"),
+                    Err(e) => eprintln!("tunnel failed: {}", e),
+                }
+            }
+            Err(e) => eprintln!("failed to connect to tunnel relay: {}", e),
+        }
+        Timer::after(retry).await;
+        retry = (retry * 2).min(MAX_RETRY);
+    }
+}
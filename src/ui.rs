@@ -0,0 +1,291 @@
+use std::{path::Path, sync::Arc, time::Duration};
+
+use async_io::Timer;
+use async_lock::RwLock;
+use futures::{
+    future::{select, Either},
+    stream::{self, SplitStream},
+    SinkExt, StreamExt,
+};
+use include_dir::{include_dir, Dir};
+use lights_api::Event;
+use qrcode::{render::svg, QrCode};
+use serde::Deserialize;
+use serde_json::json;
+use warp::reply::Response;
+use warp::{
+    filters::BoxedFilter,
+    path::Tail,
+    ws::{Message, WebSocket, Ws},
+    Filter, Rejection, Reply,
+};
+
+use crate::{
+    api::{enumerate, light_state},
+    policy::Origin,
+    App,
+};
+
+static UI: Dir = include_dir!("ui");
+/// How long a socket to `/events` has to send its token.
+const AUTH_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Deserialize)]
+struct LightQrQuery {
+    id: String,
+}
+
+/// A strip waiting to be given Wi-Fi credentials with Espressif's
+/// provisioning apps, as named on its console.
+#[derive(Deserialize)]
+struct PairQuery {
+    name: String,
+    pop: Option<String>,
+    #[serde(default = "softap")]
+    transport: String,
+}
+
+fn softap() -> String {
+    "softap".to_owned()
+}
+
+fn content_type(path: &str) -> &'static str {
+    match Path::new(path).extension().and_then(|ext| ext.to_str()) {
+        Some("html") => "text/html; charset=utf-8",
+        Some("js") => "application/javascript",
+        Some("css") => "text/css",
+        Some("json") => "application/json",
+        Some("svg") => "image/svg+xml",
+        Some("png") => "image/png",
+        Some("wasm") => "application/wasm",
+        _ => "application/octet-stream",
+    }
+}
+
+fn asset(path: &str) -> Result<Response, Rejection> {
+    let file = UI.get_file(path).ok_or_else(warp::reject::not_found)?;
+    Ok(
+        warp::reply::with_header(file.contents(), "content-type", content_type(path))
+            .into_response(),
+    )
+}
+
+/// `data` as a QR code, sized for printing on a sticker.
+fn qr(data: &str) -> Result<Response, Rejection> {
+    let code = QrCode::new(data).map_err(|_| warp::reject::not_found())?;
+    let image = code.render::<svg::Color>().min_dimensions(256, 256).build();
+    Ok(warp::reply::with_header(image, "content-type", content_type("qr.svg")).into_response())
+}
+
+/// Escapes a light id for use as a path segment.
+fn path_segment(id: &str) -> String {
+    id.bytes()
+        .map(|byte| match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                (byte as char).to_string()
+            }
+            byte => format!("%{:02X}", byte),
+        })
+        .collect()
+}
+
+/// What a token lets its holder do.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Scope {
+    Full,
+    /// Can see state but not change it, for dashboards left where anyone
+    /// could pick them up.
+    ReadOnly,
+    /// Full access that quiet hours don't apply to.
+    Override,
+}
+
+impl Scope {
+    pub(crate) fn writable(self) -> bool {
+        self != Scope::ReadOnly
+    }
+    pub(crate) fn origin(self) -> Origin {
+        match self {
+            Scope::Override => Origin::Override,
+            _ => Origin::Api,
+        }
+    }
+}
+
+fn configured(token: &str, expected: Option<&str>) -> bool {
+    expected.map_or(false, |configured| {
+        !configured.is_empty() && token == configured
+    })
+}
+
+/// Matches a token against `API_AUTH_TOKEN` and, if they were configured at
+/// build time, the read-only `GUEST_AUTH_TOKEN` and `OVERRIDE_AUTH_TOKEN`.
+pub(crate) fn scope(token: &str) -> Option<Scope> {
+    if token == env!("API_AUTH_TOKEN") {
+        Some(Scope::Full)
+    } else if configured(token, option_env!("OVERRIDE_AUTH_TOKEN")) {
+        Some(Scope::Override)
+    } else if configured(token, option_env!("GUEST_AUTH_TOKEN")) {
+        Some(Scope::ReadOnly)
+    } else {
+        None
+    }
+}
+
+fn bearer(required: Scope) -> impl Filter<Extract = (Scope,), Error = Rejection> + Clone {
+    warp::header::<String>("authorization").and_then(move |header: String| async move {
+        match header.strip_prefix("Bearer ").and_then(scope) {
+            Some(scope) if scope.writable() || required == Scope::ReadOnly => Ok(scope),
+            _ => Err(warp::reject::not_found()),
+        }
+    })
+}
+
+pub(crate) fn authorized() -> impl Filter<Extract = (), Error = Rejection> + Clone {
+    bearer(Scope::Full).map(|_| ()).untuple_one()
+}
+
+/// Like [`authorized`], but passing on the scope of the token.
+pub(crate) fn authorized_scope() -> impl Filter<Extract = (Scope,), Error = Rejection> + Clone {
+    bearer(Scope::Full)
+}
+
+/// Like [`authorized`], but also letting the guest token through.
+pub(crate) fn viewer() -> impl Filter<Extract = (), Error = Rejection> + Clone {
+    bearer(Scope::ReadOnly).map(|_| ()).untuple_one()
+}
+
+/// Waits for the token a socket sends as its first message, which keeps it
+/// out of URLs and the logs they end up in.
+async fn authenticate(incoming: &mut SplitStream<WebSocket>) -> bool {
+    match select(incoming.next(), Timer::after(AUTH_TIMEOUT)).await {
+        Either::Left((Some(Ok(message)), _)) => message.to_str().ok().and_then(scope).is_some(),
+        _ => false,
+    }
+}
+
+async fn push_events(socket: WebSocket, app: Arc<RwLock<App>>) {
+    let (mut sink, mut incoming) = socket.split();
+    if !authenticate(&mut incoming).await {
+        let _ = sink.send(Message::close_with(1008u16, "bad auth")).await;
+        return;
+    }
+    let (changes, transfers, snapshot) = {
+        let app = app.read().await;
+        (
+            app.subscribe(),
+            app.subscribe_transfers(),
+            app.lights()
+                .map(|light| light_state(&app, light))
+                .collect::<Vec<_>>(),
+        )
+    };
+    for light in snapshot {
+        if sink
+            .send(Message::text(serde_json::to_string(&light).unwrap()))
+            .await
+            .is_err()
+        {
+            return;
+        }
+    }
+    let mut events = stream::select(changes.map(Either::Left), transfers.map(Either::Right));
+    while let Some(event) = events.next().await {
+        let message = match event {
+            Either::Left(id) => {
+                let app = app.read().await;
+                match app.light(&id) {
+                    Some(light) => serde_json::to_string(&light_state(&app, light)).unwrap(),
+                    None => continue,
+                }
+            }
+            Either::Right(progress) => serde_json::to_string(&Event::Transfer(progress)).unwrap(),
+        };
+        if sink.send(Message::text(message)).await.is_err() {
+            return;
+        }
+    }
+}
+
+pub fn ui(app: Arc<RwLock<App>>) -> BoxedFilter<(impl Reply,)> {
+    let index = warp::path::end().and_then(|| async { asset("index.html") });
+    let assets = warp::path("static")
+        .and(warp::path::tail())
+        .and_then(|tail: Tail| async move { asset(tail.as_str()) });
+    let state = warp::path!("ui" / "state")
+        .and(warp::get())
+        .and(viewer())
+        .and_then({
+            let app = app.clone();
+            move || {
+                let app = app.clone();
+                async move {
+                    Ok::<_, Rejection>(warp::reply::json(&enumerate(&*app.read().await).await))
+                }
+            }
+        });
+    // Deep links to one light's controls, which the page finds from its own
+    // path.
+    let light = warp::path!("light" / String)
+        .and(warp::get())
+        .and_then(|_: String| async { asset("light.html") });
+    // The link to a light's controls, for a sticker next to it, on the host
+    // the UI was reached through.
+    let light_qr = warp::path!("ui" / "qr" / "light")
+        .and(warp::get())
+        .and(viewer())
+        .and(warp::query())
+        .and(warp::header::<String>("host"))
+        .and(warp::header::optional::<String>("x-forwarded-proto"))
+        .and_then({
+            let app = app.clone();
+            move |query: LightQrQuery, host: String, scheme: Option<String>| {
+                let app = app.clone();
+                async move {
+                    if app.read().await.light(&query.id).is_none() {
+                        return Err(warp::reject::not_found());
+                    }
+                    qr(&format!(
+                        "{}://{}/light/{}",
+                        scheme.as_deref().unwrap_or("http"),
+                        host,
+                        path_segment(&query.id)
+                    ))
+                }
+            }
+        });
+    let pair_qr = warp::path!("ui" / "qr" / "pair")
+        .and(warp::get())
+        .and(viewer())
+        .and(warp::query())
+        .and_then(|query: PairQuery| async move {
+            let mut payload = json!({
+                "ver": "v1",
+                "name": query.name,
+                "transport": query.transport,
+            });
+            if let Some(pop) = query.pop {
+                payload["pop"] = pop.into();
+            }
+            qr(&payload.to_string())
+        });
+    let events = warp::path("events").and(warp::ws()).map(move |ws: Ws| {
+        let app = app.clone();
+        ws.on_upgrade(move |socket| push_events(socket, app))
+            .into_response()
+    });
+    index
+        .or(assets)
+        .unify()
+        .or(state.map(Reply::into_response))
+        .unify()
+        .or(light)
+        .unify()
+        .or(light_qr)
+        .unify()
+        .or(pair_qr)
+        .unify()
+        .or(events)
+        .unify()
+        .boxed()
+}
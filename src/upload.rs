@@ -0,0 +1,209 @@
+use std::{
+    fs::{self, OpenOptions},
+    io::{self, Write},
+    path::PathBuf,
+    sync::Mutex,
+    time::Duration,
+};
+
+use async_lock::RwLock;
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use thiserror::Error;
+
+use crate::{storage::storage, App, MAX_PROGRAM_BYTES};
+
+const NAMESPACE: &str = "uploads";
+const META: &str = "meta";
+const DATA: &str = "data";
+/// Uploads left untouched this long are dropped when another one starts.
+const STALE: Duration = Duration::from_secs(24 * 60 * 60);
+
+lazy_static! {
+    // Keeps retried chunks of the same upload from interleaving.
+    static ref APPENDING: Mutex<()> = Mutex::new(());
+}
+
+#[derive(Debug, Error)]
+pub enum UploadError {
+    #[error("upload io error: {0}")]
+    Io(#[from] io::Error),
+    #[error("malformed upload state: {0}")]
+    Format(#[from] serde_json::Error),
+    #[error("malformed chunk: {0}")]
+    Encoding(#[from] base64::DecodeError),
+    #[error("no upload `{0}` in progress")]
+    Unknown(String),
+    #[error("chunk at offset {offset} leaves a gap after the {received} bytes received")]
+    Gap { offset: u64, received: u64 },
+    #[error("chunk runs past the {0} bytes the upload was started with")]
+    Overrun(u64),
+    #[error("upload of {0} bytes is over the limit of {}", MAX_PROGRAM_BYTES)]
+    TooLarge(u64),
+    #[error("upload is incomplete, with {received} of {size} bytes received")]
+    Incomplete { received: u64, size: u64 },
+    #[error("checksum mismatch")]
+    Checksum,
+    #[error(transparent)]
+    Light(#[from] crate::Error),
+}
+
+/// What an upload was started with, kept beside the bytes received so far.
+#[derive(Serialize, Deserialize)]
+struct Meta {
+    light: String,
+    size: u64,
+    sha256: String,
+}
+
+fn sha256(data: &[u8]) -> String {
+    format!("{:x}", Sha256::digest(data))
+}
+
+// Upload ids become paths in the data directory, so only the hex ids that
+// are handed out are accepted.
+fn dir(upload: &str) -> Result<PathBuf, UploadError> {
+    if upload.is_empty() || !upload.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err(UploadError::Unknown(upload.to_owned()));
+    }
+    Ok(storage().namespace(NAMESPACE).join(upload))
+}
+
+fn meta(upload: &str) -> Result<Meta, UploadError> {
+    match fs::read(dir(upload)?.join(META)) {
+        Ok(meta) => Ok(serde_json::from_slice(&meta)?),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            Err(UploadError::Unknown(upload.to_owned()))
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+fn received(upload: &str) -> Result<u64, UploadError> {
+    match fs::metadata(dir(upload)?.join(DATA)) {
+        Ok(data) => Ok(data.len()),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(0),
+        Err(e) => Err(e.into()),
+    }
+}
+
+fn prune() {
+    let entries = match fs::read_dir(storage().namespace(NAMESPACE)) {
+        Ok(entries) => entries,
+        Err(_) => return,
+    };
+    for entry in entries.flatten() {
+        let path = entry.path();
+        let stale = fs::metadata(path.join(DATA))
+            .or_else(|_| fs::metadata(path.join(META)))
+            .and_then(|metadata| metadata.modified())
+            .ok()
+            .and_then(|modified| modified.elapsed().ok())
+            .map_or(true, |age| age > STALE);
+        if stale {
+            let _ = fs::remove_dir_all(path);
+        }
+    }
+}
+
+impl App {
+    /// Starts uploading a program of `size` bytes for a light, or picks up
+    /// the upload already under way for the same program. Returns the
+    /// upload's id and how many bytes it has received.
+    ///
+    /// Chunks are staged in the data directory rather than in memory, so an
+    /// upload also survives a restart.
+    pub(crate) fn start_upload(
+        &self,
+        light: &str,
+        size: u64,
+        checksum: &str,
+    ) -> Result<(String, u64), UploadError> {
+        if size > MAX_PROGRAM_BYTES as u64 {
+            return Err(UploadError::TooLarge(size));
+        }
+        self.light(light).ok_or(crate::Error::Absent)?;
+        prune();
+        let checksum = checksum.to_lowercase();
+        let upload = sha256(format!("{}\n{}", light, checksum).as_bytes());
+        if let Ok(existing) = meta(&upload) {
+            if existing.size == size {
+                let received = received(&upload)?;
+                return Ok((upload, received));
+            }
+        }
+        let dir = dir(&upload)?;
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir)?;
+        let meta = Meta {
+            light: light.to_owned(),
+            size,
+            sha256: checksum,
+        };
+        fs::write(dir.join(META), serde_json::to_vec(&meta)?)?;
+        Ok((upload, 0))
+    }
+
+    /// Adds a base64 chunk starting at `offset`, returning how many bytes
+    /// the upload has received. Chunks that were already received, such as
+    /// when a reply was lost and the chunk sent again, are skipped.
+    pub(crate) fn append_upload(
+        &self,
+        upload: &str,
+        offset: u64,
+        chunk: &str,
+        checksum: &str,
+    ) -> Result<u64, UploadError> {
+        let meta = meta(upload)?;
+        let chunk = base64::decode(chunk)?;
+        if sha256(&chunk) != checksum.to_lowercase() {
+            return Err(UploadError::Checksum);
+        }
+        let end = offset + chunk.len() as u64;
+        if end > meta.size {
+            return Err(UploadError::Overrun(meta.size));
+        }
+        let _appending = APPENDING.lock().unwrap();
+        let received = received(upload)?;
+        if offset > received {
+            return Err(UploadError::Gap { offset, received });
+        }
+        if end <= received {
+            return Ok(received);
+        }
+        let fresh = &chunk[(received - offset) as usize..];
+        let mut data = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(dir(upload)?.join(DATA))?;
+        data.write_all(fresh)?;
+        data.sync_data()?;
+        Ok(end)
+    }
+}
+
+/// Checks a fully received upload against its checksum and runs it on
+/// its light. A failed checksum drops the upload, while a light that
+/// can't be reached leaves it to be committed again.
+pub(crate) async fn commit_upload(app: &RwLock<App>, upload: &str) -> Result<(), UploadError> {
+    let meta = meta(upload)?;
+    let received = received(upload)?;
+    if received != meta.size {
+        return Err(UploadError::Incomplete {
+            received,
+            size: meta.size,
+        });
+    }
+    let dir = dir(upload)?;
+    let program = fs::read(dir.join(DATA))?;
+    if sha256(&program) != meta.sha256 {
+        let _ = fs::remove_dir_all(&dir);
+        return Err(UploadError::Checksum);
+    }
+    // The app is only held to find the light, not during the transfer.
+    let transfer = app.read().await.program_transfer(&meta.light, &program)?;
+    transfer.send(&program).await?;
+    let _ = fs::remove_dir_all(&dir);
+    Ok(())
+}
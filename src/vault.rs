@@ -0,0 +1,332 @@
+use std::{
+    env,
+    io::Write,
+    process::{Command, Stdio},
+    sync::Once,
+};
+
+use lazy_static::lazy_static;
+use openssl::{
+    hash::MessageDigest,
+    pkcs5::pbkdf2_hmac,
+    rand::rand_bytes,
+    symm::{decrypt_aead, encrypt_aead, Cipher},
+};
+use sha2::{Digest, Sha256};
+use thiserror::Error;
+
+use crate::storage::{storage, Blobs, Storage, StorageError};
+
+const NAMESPACE: &str = "credentials";
+const KEY_VAR: &str = "LIGHTS_CREDENTIALS_KEY";
+// Each entry starts with a format byte. Plain entries are only written
+// while no key is configured, and are sealed once there is one.
+const PLAIN: u8 = 0;
+const SEALED: u8 = 1;
+const NONCE_LEN: usize = 12;
+const TAG_LEN: usize = 16;
+// The salt the key is derived with, kept apart from the credentials so it
+// isn't taken for one.
+const SALT_NAMESPACE: &str = "vault";
+const SALT_KEY: &str = "salt";
+const SALT_LEN: usize = 16;
+const ITERATIONS: usize = 600_000;
+/// Secrets integrations kept in their own namespaces before the vault, as
+/// the namespace, the key and the credential they become.
+const LEGACY: &[(&str, &str, &str)] = &[
+    ("tuya", "access_token", "TUYA_TOKEN"),
+    ("deconz", "api_key", "DECONZ_API_KEY"),
+];
+
+lazy_static! {
+    static ref VAULT: Vault = {
+        let vault = Vault::open(storage(), configured_key().map(|(key, _)| key))
+            .expect("failed to open credentials vault");
+        if let Err(e) = vault.migrate(storage()) {
+            eprintln!("failed to migrate credentials: {}", e);
+        }
+        vault
+    };
+}
+
+/// The credentials of every integration, opened with the configured key on
+/// first use.
+pub(crate) fn vault() -> &'static Vault {
+    &VAULT
+}
+
+/// A credential from the vault, or else the environment variable of the same
+/// name, such as `TUYA_PASS`.
+pub fn credential(name: &str) -> Option<String> {
+    vault().secret(name)
+}
+
+#[derive(Debug, Error)]
+pub enum VaultError {
+    #[error(transparent)]
+    Storage(#[from] StorageError),
+    #[error("encryption error: {0}")]
+    Crypto(#[from] openssl::error::ErrorStack),
+    #[error("credential `{0}` is corrupt or sealed with a different key")]
+    Key(String),
+    #[error("no credentials key is configured")]
+    Locked,
+    #[error("keyring error: {0}")]
+    Keyring(String),
+}
+
+/// Where the credentials key came from, which is where a new one goes.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub(crate) enum KeySource {
+    /// `LIGHTS_CREDENTIALS_KEY`, or `credentials_key` in the config.
+    Environment,
+    Keyring,
+}
+
+pub(crate) fn configured_key() -> Option<(String, KeySource)> {
+    env::var(KEY_VAR)
+        .ok()
+        .filter(|key| !key.is_empty())
+        .map(|key| (key, KeySource::Environment))
+        .or_else(|| keyring_lookup().map(|key| (key, KeySource::Keyring)))
+}
+
+// Keys are kept in the Secret Service through `secret-tool`, where there is
+// one.
+const KEYRING_ATTRIBUTES: &[&str] = &["service", "lights", "key", "credentials"];
+
+fn keyring_lookup() -> Option<String> {
+    let output = Command::new("secret-tool")
+        .arg("lookup")
+        .args(KEYRING_ATTRIBUTES)
+        .output()
+        .ok()?;
+    let key = String::from_utf8(output.stdout).ok()?.trim().to_owned();
+    if output.status.success() && !key.is_empty() {
+        Some(key)
+    } else {
+        None
+    }
+}
+
+pub(crate) fn keyring_store(key: &str) -> Result<(), VaultError> {
+    let keyring = |e: std::io::Error| VaultError::Keyring(e.to_string());
+    let mut child = Command::new("secret-tool")
+        .args(&["store", "--label=lights credentials key"])
+        .args(KEYRING_ATTRIBUTES)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(keyring)?;
+    child
+        .stdin
+        .take()
+        .expect("stdin is piped")
+        .write_all(key.as_bytes())
+        .map_err(keyring)?;
+    let output = child.wait_with_output().map_err(keyring)?;
+    if !output.status.success() {
+        return Err(VaultError::Keyring(
+            String::from_utf8_lossy(&output.stderr).trim().to_owned(),
+        ));
+    }
+    Ok(())
+}
+
+/// A new random credentials key.
+pub(crate) fn generate_key() -> Result<String, VaultError> {
+    let mut key = [0; 32];
+    rand_bytes(&mut key)?;
+    Ok(base64::encode(key))
+}
+
+/// Credentials stored with AES-256-GCM under a key derived from the
+/// configured one with PBKDF2 and a salt stored alongside them. Each entry
+/// is bound to its name, so entries can't be swapped for one another.
+pub(crate) struct Vault {
+    blobs: Blobs,
+    key: Option<[u8; 32]>,
+    /// The key as derived before salting, which entries sealed back then
+    /// are read with until they are sealed again.
+    legacy: Option<[u8; 32]>,
+    warned: Once,
+}
+
+impl Vault {
+    pub(crate) fn open(storage: &Storage, key: Option<String>) -> Result<Self, VaultError> {
+        let (key, legacy) = match key {
+            Some(key) => {
+                let salt = salt(storage)?;
+                let mut derived = [0; 32];
+                pbkdf2_hmac(
+                    key.as_bytes(),
+                    &salt,
+                    ITERATIONS,
+                    MessageDigest::sha256(),
+                    &mut derived,
+                )?;
+                let mut legacy = [0; 32];
+                legacy.copy_from_slice(&Sha256::digest(key.as_bytes()));
+                (Some(derived), Some(legacy))
+            }
+            None => (None, None),
+        };
+        Ok(Vault {
+            blobs: storage.blobs(NAMESPACE),
+            key,
+            legacy,
+            warned: Once::new(),
+        })
+    }
+
+    pub(crate) fn get(&self, name: &str) -> Result<Option<Vec<u8>>, VaultError> {
+        let data = match self.blobs.get(name)? {
+            Some(data) => data,
+            None => return Ok(None),
+        };
+        match data.split_first() {
+            Some((&PLAIN, plain)) => Ok(Some(plain.to_vec())),
+            Some((&SEALED, sealed)) if sealed.len() >= NONCE_LEN + TAG_LEN => {
+                let key = self.key.as_ref().ok_or(VaultError::Locked)?;
+                open_sealed(key, name, sealed)
+                    .or_else(|| {
+                        let legacy = self.legacy.as_ref()?;
+                        open_sealed(legacy, name, sealed)
+                    })
+                    .map(Some)
+                    .ok_or_else(|| VaultError::Key(name.to_owned()))
+            }
+            _ => Err(VaultError::Key(name.to_owned())),
+        }
+    }
+
+    pub(crate) fn put(&self, name: &str, data: &[u8]) -> Result<(), VaultError> {
+        self.blobs.put(name, &self.seal(name, data)?)?;
+        Ok(())
+    }
+
+    pub(crate) fn remove(&self, name: &str) -> Result<(), VaultError> {
+        self.blobs.remove(name)?;
+        Ok(())
+    }
+
+    fn seal(&self, name: &str, data: &[u8]) -> Result<Vec<u8>, VaultError> {
+        let key = match &self.key {
+            Some(key) => key,
+            None => {
+                self.warned.call_once(|| {
+                    eprintln!("no credentials key is configured, storing credentials unencrypted")
+                });
+                let mut entry = vec![PLAIN];
+                entry.extend_from_slice(data);
+                return Ok(entry);
+            }
+        };
+        let mut nonce = [0; NONCE_LEN];
+        rand_bytes(&mut nonce)?;
+        let mut tag = [0; TAG_LEN];
+        let ciphertext = encrypt_aead(
+            Cipher::aes_256_gcm(),
+            key,
+            Some(&nonce),
+            name.as_bytes(),
+            data,
+            &mut tag,
+        )?;
+        let mut entry = vec![SEALED];
+        entry.extend_from_slice(&nonce);
+        entry.extend_from_slice(&tag);
+        entry.extend(ciphertext);
+        Ok(entry)
+    }
+
+    /// A credential as text, or else the environment variable of the same
+    /// name.
+    pub(crate) fn secret(&self, name: &str) -> Option<String> {
+        match self.get(name) {
+            Ok(Some(data)) => return String::from_utf8(data).ok(),
+            Ok(None) => {}
+            Err(e) => eprintln!("failed to read credential {}: {}", name, e),
+        }
+        env::var(name).ok()
+    }
+
+    /// Moves in secrets integrations kept themselves, seals entries written
+    /// before a key was configured, and seals again those sealed under the
+    /// unsalted key.
+    pub(crate) fn migrate(&self, storage: &Storage) -> Result<(), VaultError> {
+        for (namespace, key, name) in LEGACY {
+            let legacy = storage.blobs(namespace);
+            if let Some(data) = legacy.get(key)? {
+                if self.blobs.get(name)?.is_none() {
+                    self.put(name, &data)?;
+                }
+                legacy.remove(key)?;
+            }
+        }
+        if let (Some(key), Some(legacy)) = (&self.key, &self.legacy) {
+            for name in self.blobs.keys()? {
+                let data = match self.blobs.get(&name)? {
+                    Some(data) => data,
+                    None => continue,
+                };
+                match data.split_first() {
+                    Some((&PLAIN, plain)) => self.put(&name, plain)?,
+                    Some((&SEALED, sealed)) if open_sealed(key, &name, sealed).is_none() => {
+                        if let Some(plain) = open_sealed(legacy, &name, sealed) {
+                            self.put(&name, &plain)?;
+                        }
+                    }
+                    _ => {}
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Seals every credential again under the key of `to`. Nothing is
+    /// rewritten unless all of them could be read.
+    pub(crate) fn rotate(&self, to: &Vault) -> Result<(), VaultError> {
+        let mut entries = vec![];
+        for name in self.blobs.keys()? {
+            if let Some(data) = self.get(&name)? {
+                entries.push((name, data));
+            }
+        }
+        for (name, data) in entries {
+            to.put(&name, &data)?;
+        }
+        Ok(())
+    }
+}
+
+/// The salt keys are derived with in `storage`, made the first time.
+fn salt(storage: &Storage) -> Result<Vec<u8>, VaultError> {
+    let blobs = storage.blobs(SALT_NAMESPACE);
+    if let Some(salt) = blobs.get(SALT_KEY)? {
+        return Ok(salt);
+    }
+    let mut salt = vec![0; SALT_LEN];
+    rand_bytes(&mut salt)?;
+    blobs.put(SALT_KEY, &salt)?;
+    Ok(salt)
+}
+
+/// The contents of an entry sealed under `key`, if it was.
+fn open_sealed(key: &[u8; 32], name: &str, sealed: &[u8]) -> Option<Vec<u8>> {
+    if sealed.len() < NONCE_LEN + TAG_LEN {
+        return None;
+    }
+    let (nonce, rest) = sealed.split_at(NONCE_LEN);
+    let (tag, ciphertext) = rest.split_at(TAG_LEN);
+    decrypt_aead(
+        Cipher::aes_256_gcm(),
+        key,
+        Some(nonce),
+        name.as_bytes(),
+        ciphertext,
+        tag,
+    )
+    .ok()
+}
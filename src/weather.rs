@@ -0,0 +1,114 @@
+use std::{sync::Arc, time::Duration};
+
+use async_io::Timer;
+use async_lock::RwLock;
+use lights_api::{SensorReading, WeatherCondition};
+use serde::Deserialize;
+
+use crate::{sensor::report_sensor, App, Location};
+
+const FORECAST_URL: &str = "https://api.open-meteo.com/v1/forecast";
+
+/// Weather from Open-Meteo for where the lights are, reported as a sensor
+/// that rules can name, from `weather.toml`.
+#[derive(Deserialize)]
+pub struct WeatherConfig {
+    #[serde(default = "default_sensor")]
+    pub sensor: String,
+    #[serde(default = "default_interval_secs")]
+    pub interval_secs: u64,
+    /// How far ahead a forecast of freezing counts as frost.
+    #[serde(default = "default_frost_hours")]
+    pub frost_hours: usize,
+}
+
+fn default_sensor() -> String {
+    "weather".to_owned()
+}
+
+fn default_interval_secs() -> u64 {
+    15 * 60
+}
+
+fn default_frost_hours() -> usize {
+    12
+}
+
+#[derive(Deserialize)]
+struct Forecast {
+    current_weather: CurrentWeather,
+    hourly: Hourly,
+}
+
+#[derive(Deserialize)]
+struct CurrentWeather {
+    temperature: f32,
+    weathercode: u32,
+    /// The hour, as listed in `hourly`.
+    time: String,
+}
+
+#[derive(Deserialize)]
+struct Hourly {
+    time: Vec<String>,
+    temperature_2m: Vec<Option<f32>>,
+}
+
+/// The condition of a WMO weather interpretation code.
+fn condition(code: u32) -> WeatherCondition {
+    match code {
+        0 | 1 => WeatherCondition::Clear,
+        45 | 48 => WeatherCondition::Fog,
+        51..=57 => WeatherCondition::Drizzle,
+        61..=67 | 80..=82 => WeatherCondition::Rain,
+        71..=77 | 85 | 86 => WeatherCondition::Snow,
+        95..=99 => WeatherCondition::Thunderstorm,
+        _ => WeatherCondition::Cloudy,
+    }
+}
+
+async fn fetch(location: Location, frost_hours: usize) -> Result<SensorReading, surf::Error> {
+    let url = format!(
+        "{}?latitude={}&longitude={}&current_weather=true&hourly=temperature_2m&forecast_days=2",
+        FORECAST_URL, location.latitude, location.longitude
+    );
+    let forecast: Forecast = surf::get(url).recv_json().await?;
+    let now = forecast
+        .hourly
+        .time
+        .iter()
+        .position(|time| *time == forecast.current_weather.time)
+        .unwrap_or(0);
+    let frost = forecast
+        .hourly
+        .temperature_2m
+        .iter()
+        .skip(now)
+        .take(frost_hours)
+        .flatten()
+        .any(|celsius| *celsius <= 0.);
+    Ok(SensorReading::Weather {
+        condition: condition(forecast.current_weather.weathercode),
+        celsius: Some(forecast.current_weather.temperature),
+        frost,
+    })
+}
+
+/// Reports the weather where the lights are as a sensor reading, running
+/// the rules it triggers, until the bridge stops.
+pub async fn weather(app: Arc<RwLock<App>>, config: WeatherConfig) {
+    let interval = Duration::from_secs(config.interval_secs);
+    loop {
+        match app.read().await.location() {
+            Some(location) => match fetch(location, config.frost_hours).await {
+                Ok(reading) => report_sensor(&app, &config.sensor, reading).await,
+                Err(e) => eprintln!("failed to fetch the weather: {}", e),
+            },
+            None => {
+                eprintln!("weather needs a location, from location.toml or setup");
+                return;
+            }
+        }
+        Timer::after(interval).await;
+    }
+}